@@ -6,9 +6,9 @@ use std::{
     net::Ipv4Addr,
     os::fd::{AsFd, AsRawFd, BorrowedFd},
     str::FromStr,
-    sync::{Mutex, RwLock},
+    sync::{LazyLock, Mutex, RwLock},
     thread::{self, sleep},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Error, Ok, anyhow};
@@ -41,6 +41,9 @@ static INTERVAL_MILIS: OnceStatic<u64> = OnceStatic::new();
 static TIMEOUT_MILIS: OnceStatic<u64> = OnceStatic::new();
 static WND: OnceStatic<u16> = OnceStatic::new();
 
+/// Monotonic origin of timestamps
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Structures
@@ -275,7 +278,7 @@ impl ControlSignalsRef {
 impl RequestRecord {
     /// now: nanos
     fn is_expired(&self, now: u128) -> bool {
-        assert!(now > self.sent);
+        assert!(now >= self.sent);
 
         (now - self.sent) >= (*TIMEOUT_MILIS as u128 * 1000_000)
     }
@@ -402,7 +405,7 @@ impl Stats {
 //// Functions
 
 fn timestamp_now() -> Result<u128> {
-    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos())
+    Ok(START.elapsed().as_nanos())
 }
 
 fn icmp_pack<'a>(
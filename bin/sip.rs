@@ -1,26 +1,102 @@
-use std::env;
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
-use linuxc::iface::get_available_ipv4_ifname;
-use log::info;
-use sip::dev::NetDevice;
+use linuxc::{
+    iface::get_available_ipv4_ifname,
+    signal::{Signal, SignalSet, pthread_sigmask},
+};
+use log::{LevelFilter, info, warn};
+use m6ptr::LazyStatic;
+use sip::{
+    arp::{ARP_QUEUE, ARP_TBL, arp_load, arp_save},
+    capture::capture_start,
+    clock::clock_now,
+    config::{CaptureConf, LOG_CONFIG_DEFAULT, LogConf, RingConf, SipConf},
+    ctl::{CtlServer, ctl_sock_default},
+    dev::{DevIoKind, NetDevice},
+    ip::IP_FORWARD,
+    metrics::{MetricsAddr, MetricsServer},
+    reactor::{Reactor, ReactorHandle},
+    snmp::snmp_write,
+    tcp::{
+        TCPState, tcp_abort_all, tcp_active_count, tcp_close_all,
+        tcp_info_all,
+    },
+    timer::timer_add,
+};
 use anyhow::anyhow;
 
+/// Connections still open after it are reset on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+static LOG_HANDLE: LazyStatic<Option<log4rs::Handle>> =
+    LazyStatic::new(|| None);
+
+/// `log` of the configuration applied last, the base of `LogLevel`
+static LOG_CONF: LazyStatic<LogConf> = LazyStatic::new(LogConf::default);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Configuration file of `--config` to be read again on SIGHUP
+static CONF_PATH: LazyStatic<Option<String>> = LazyStatic::new(|| None);
+
+/// `--forward` is kept over `ip.forward` of reloaded configuration
+static CLI_FORWARD: AtomicBool = AtomicBool::new(false);
+
 /// Simple UDP/IP Network Protocol Stack
 #[derive(Parser)]
 #[clap(name = "SIP")]
 struct Cli {
-    /// If name
+    /// Stack configuration file in YAML
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// If name, repeat it for multiple interfaces
     #[arg(short)]
-    ifname: Option<String>,
+    ifname: Vec<String>,
+
+    /// I/O of `-i` interfaces, `plain`, `ring` or `mmsg`
+    #[arg(long, default_value_t = DevIoKind::Plain)]
+    io: DevIoKind,
+
+    /// Forward IPv4 datagrams not addressed to local (router mode)
+    #[arg(long)]
+    forward: bool,
+
+    /// Load ARP cache from the file and save it on shutdown
+    #[arg(long)]
+    state: Option<String>,
+
+    /// Control socket for sipctl
+    #[arg(long, default_value_t = ctl_sock_default())]
+    ctl: String,
+
+    /// Serve Prometheus metrics on HOST:PORT or unix:PATH
+    #[arg(long)]
+    metrics: Option<MetricsAddr>,
+
+    /// Capture frames into the pcapng file, over `capture` of configuration
+    #[arg(long)]
+    capture: Option<String>,
 }
 
-fn setup_logger() -> anyhow::Result<()> {
-    /* Logger should be configured first! */
-    let mut logconf = log4rs::config::load_config_file(
-        "log4rs.default.yaml",
-        Default::default(),
-    )?;
+fn load_logger_config(conf: &LogConf) -> anyhow::Result<log4rs::Config> {
+    let path = conf.config.as_deref().unwrap_or(LOG_CONFIG_DEFAULT);
+    let mut logconf =
+        log4rs::config::load_config_file(path, Default::default())?;
+
+    if let Some(level) = conf.level {
+        logconf.root_mut().set_level(level);
+    }
 
     if let Ok(levels) = env::var("RUST_LOG") {
         match levels.parse() {
@@ -31,38 +107,276 @@ fn setup_logger() -> anyhow::Result<()> {
         }
     }
 
-    log4rs::init_config(logconf)?;
+    Ok(logconf)
+}
+
+fn setup_logger(conf: &LogConf) -> anyhow::Result<()> {
+    /* Logger should be configured first! */
+    let handle = log4rs::init_config(load_logger_config(conf)?)?;
+
+    *LOG_HANDLE.write().unwrap() = Some(handle);
+    *LOG_CONF.write().unwrap() = conf.clone();
+
+    Ok(())
+}
+
+fn load_config(path: Option<&str>) -> anyhow::Result<SipConf> {
+    let mut conf = match path {
+        Some(path) => SipConf::load(path)?,
+        None => SipConf::default(),
+    };
+
+    conf.ip.forward |= CLI_FORWARD.load(Ordering::Relaxed);
+
+    Ok(conf)
+}
+
+/// SIGHUP, reload configuration, sockets and connections are kept.
+///
+/// Only tunables and logger are applied, interfaces and routes need a
+/// restart
+fn reload() {
+    let path = CONF_PATH.read().unwrap().clone();
+
+    let res = load_config(path.as_deref()).and_then(|conf| {
+        let logconf = load_logger_config(&conf.log)?;
+
+        conf.apply_tunables();
+
+        if let Some(handle) = LOG_HANDLE.read().unwrap().as_ref() {
+            handle.set_config(logconf);
+        }
+
+        *LOG_CONF.write().unwrap() = conf.log;
+
+        Ok(())
+    });
+
+    match res {
+        Ok(()) => info!("configuration reloaded"),
+        Err(err) => warn!("reload: {err:#}"),
+    }
+}
+
+/// Root level from sipctl, it lasts until next reload
+fn set_log_level(level: LevelFilter) -> anyhow::Result<()> {
+    let mut logconf = load_logger_config(&LOG_CONF.read().unwrap())?;
+
+    logconf.root_mut().set_level(level);
+
+    if let Some(handle) = LOG_HANDLE.read().unwrap().as_ref() {
+        handle.set_config(logconf);
+    }
+
+    Ok(())
+}
+
+/// SIGINT/SIGTERM, close connections with FIN and stop after they and
+/// datagrams pending on ARP are drained, a second signal stops at once
+fn shutdown(reactor: &mut Reactor) {
+    if SHUTTING_DOWN.swap(true, Ordering::Relaxed) {
+        warn!("shutdown forced");
+        reactor.stop();
+        return;
+    }
+
+    info!("shutting down");
+
+    tcp_close_all();
+
+    let handle = reactor.handle();
+    let deadline = clock_now() + SHUTDOWN_TIMEOUT;
+
+    timer_add(SHUTDOWN_POLL, move || shutdown_poll(handle, deadline));
+}
+
+fn shutdown_poll(handle: ReactorHandle, deadline: Instant) {
+    let active = tcp_active_count();
+    let pending = ARP_QUEUE.read().unwrap().len();
+
+    if active + pending > 0 && clock_now() < deadline {
+        timer_add(SHUTDOWN_POLL, move || shutdown_poll(handle, deadline));
+        return;
+    }
+
+    if active > 0 {
+        warn!("reset {active} TCP connections not closed in time");
+        tcp_abort_all();
+    }
+
+    if let Err(err) = handle.stop() {
+        warn!("shutdown: {err}");
+    }
+}
+
+fn spawn_signals(sigset: SignalSet, handle: ReactorHandle) -> io::Result<()> {
+    thread::Builder::new().name("ctl-signals".to_owned()).spawn(move || {
+        loop {
+            let res = match sigset.wait() {
+                Signal::SIGHUP => handle.post(|_| reload()),
+                _ => handle.post(shutdown),
+            };
+
+            if let Err(err) = res {
+                warn!("ctl-signals: {err}");
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+fn load_state(path: &str) -> anyhow::Result<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+
+    let n = arp_load(BufReader::new(File::open(path)?))?;
+
+    info!("{n} ARP records loaded from {path}");
+
+    Ok(())
+}
+
+fn save_state(path: &str) -> anyhow::Result<()> {
+    arp_save(BufWriter::new(File::create(path)?))?;
+
+    info!("state saved to {path}");
 
     Ok(())
 }
 
+fn print_stats() {
+    let conns = tcp_info_all();
+    let arp_n = ARP_TBL
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|rec| rec.is_valid)
+        .count();
+
+    let conn_n = conns.iter().filter(|(key, _)| key.remote.is_some()).count();
+    let tw_n = conns
+        .iter()
+        .filter(|(_, info)| info.state == TCPState::TimeWait)
+        .count();
+
+    println!("--- sip statistics ---");
+    println!(
+        "{conn_n} TCP connections ({tw_n} in TIME-WAIT), {arp_n} ARP records"
+    );
+
+    for (key, info) in conns {
+        if let Some(remote) = key.remote {
+            println!("{} -> {remote}\n\t{info}", key.local);
+        }
+    }
+
+    if let Err(err) = snmp_write(io::stdout().lock()) {
+        warn!("print stats: {err}");
+    }
+}
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    let ifname = match cli.ifname {
-        Some(ifname) => ifname,
-        None => {
-            let mut ifname_list = get_available_ipv4_ifname()?;
+    CLI_FORWARD.store(cli.forward, Ordering::Relaxed);
 
-            if ifname_list.is_empty() {
-                Err(anyhow!("No available ifname"))?
-            }
+    let conf = load_config(cli.config.as_deref())?;
 
-            ifname_list.remove(0)
-        },
-    };
+    *CONF_PATH.write().unwrap() = cli.config.clone();
 
-    setup_logger().unwrap();
+    /* interfaces of `-i` not in configuration file */
 
-    let dev = NetDevice::init(ifname.as_str()).unwrap();
+    let mut ifname_list = cli
+        .ifname
+        .into_iter()
+        .filter(|ifname| {
+            conf.interfaces.iter().all(|iface| &iface.name != ifname)
+        })
+        .collect::<Vec<_>>();
 
-    info!("dev init: {:#?}", dev);
+    if ifname_list.is_empty() && conf.interfaces.is_empty() {
+        ifname_list = get_available_ipv4_ifname()?;
 
-    loop {
-        match dev.input() {
-            Ok(_) => (),
-            Err(err) => println!("{err:#?}"),
+        if ifname_list.is_empty() {
+            Err(anyhow!("No available ifname"))?
         }
+
+        ifname_list.truncate(1);
     }
+
+    setup_logger(&conf.log).unwrap();
+
+    /* signals are handled by a thread, so block them before any spawns */
+
+    let sigset = Signal::SIGINT | Signal::SIGTERM | Signal::SIGHUP;
+
+    pthread_sigmask(Default::default(), sigset)?;
+
+    conf.apply_tunables();
+
+    let mut devs = conf.init_devices()?;
+
+    for ifname in ifname_list.iter() {
+        let mut dev = NetDevice::init(ifname.as_str())?;
+
+        dev.set_io(cli.io, &RingConf::default())?;
+        devs.push(dev.register());
+    }
+
+    for dev in devs.iter() {
+        info!("dev init: {:#?}", dev);
+    }
+
+    if IP_FORWARD.load(Ordering::Relaxed) {
+        info!("IPv4 forwarding enabled");
+    }
+
+    let capture = match &cli.capture {
+        Some(path) => Some(CaptureConf::new(path)),
+        None => conf.capture.clone(),
+    };
+
+    if let Some(capture) = &capture {
+        capture_start(capture)?;
+    }
+
+    if let Some(path) = &cli.state
+        && let Err(err) = load_state(path)
+    {
+        warn!("load state: {err:#}");
+    }
+
+    let mut reactor = Reactor::new()?;
+
+    for dev in devs {
+        reactor.register(Box::new(dev))?;
+    }
+
+    let ctl = CtlServer::bind(&cli.ctl)?.with_log_level(set_log_level);
+
+    info!("control socket on {}", ctl.path().display());
+
+    reactor.register(Box::new(ctl))?;
+
+    if let Some(addr) = &cli.metrics {
+        reactor.register(Box::new(MetricsServer::bind(addr)?))?;
+
+        info!("metrics on {addr}");
+    }
+
+    spawn_signals(sigset, reactor.handle())?;
+
+    reactor.run()?;
+
+    if let Some(path) = &cli.state
+        && let Err(err) = save_state(path)
+    {
+        warn!("save state: {err:#}");
+    }
+
+    print_stats();
+
+    Ok(())
 }
@@ -0,0 +1,260 @@
+use std::net::Ipv4Addr;
+
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use sip::{
+    config::{CaptureConf, Ipv4Net, RouteConf},
+    ctl::{CtlRequest, CtlResponse, ctl_request, ctl_sock_default},
+    route::RouteEntry,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Administration of a running SIP daemon over its control socket
+#[derive(Parser)]
+#[clap(name = "sipctl")]
+struct Cli {
+    /// Control socket of the daemon
+    #[arg(short, long, default_value_t = ctl_sock_default())]
+    socket: String,
+
+    /// Print responses as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// List interfaces and their addresses
+    Iface,
+
+    /// Add or remove secondary addresses
+    Addr {
+        #[command(subcommand)]
+        cmd: AddrCmd,
+    },
+
+    /// List ARP cache, or flush it
+    Neigh {
+        #[command(subcommand)]
+        cmd: Option<NeighCmd>,
+    },
+
+    /// List routes, or add and remove them
+    Route {
+        #[command(subcommand)]
+        cmd: Option<RouteCmd>,
+    },
+
+    /// List TCP and UDP sockets
+    Sock,
+
+    /// Show counters of the stack
+    Stats,
+
+    /// Set level of root logger: off, error, warn, info, debug or trace
+    Log { level: String },
+
+    /// Capture frames of all devices into pcapng file, or stop it
+    Capture {
+        #[command(subcommand)]
+        cmd: CaptureCmd,
+    },
+}
+
+#[derive(Subcommand)]
+enum AddrCmd {
+    Add { dev: String, addr: Ipv4Net },
+    Del { dev: String, addr: Ipv4Addr },
+}
+
+#[derive(Subcommand)]
+enum NeighCmd {
+    Flush,
+}
+
+#[derive(Subcommand)]
+enum RouteCmd {
+    Add {
+        /// `ADDR/PREFIX` or `default`
+        dst: Ipv4Net,
+
+        #[arg(long)]
+        via: Option<Ipv4Addr>,
+
+        #[arg(long)]
+        dev: String,
+
+        #[arg(long, default_value_t = 0)]
+        metric: u32,
+    },
+    Del {
+        dst: Ipv4Net,
+
+        #[arg(long)]
+        dev: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CaptureCmd {
+    Start {
+        path: String,
+
+        /// Rotate the file when it reaches the size in bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+
+        /// Rotated files kept as `PATH.1` to `PATH.N`
+        #[arg(long)]
+        keep: Option<usize>,
+    },
+    Stop,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl Cmd {
+    fn into_request(self) -> CtlRequest {
+        match self {
+            Self::Iface => CtlRequest::Interfaces,
+            Self::Addr {
+                cmd: AddrCmd::Add { dev, addr },
+            } => CtlRequest::AddrAdd { dev, addr },
+            Self::Addr {
+                cmd: AddrCmd::Del { dev, addr },
+            } => CtlRequest::AddrDel { dev, addr },
+            Self::Neigh { cmd: None } => CtlRequest::Neighbours,
+            Self::Neigh {
+                cmd: Some(NeighCmd::Flush),
+            } => CtlRequest::ArpFlush,
+            Self::Route { cmd: None } => CtlRequest::Routes,
+            Self::Route {
+                cmd:
+                    Some(RouteCmd::Add {
+                        dst,
+                        via,
+                        dev,
+                        metric,
+                    }),
+            } => CtlRequest::RouteAdd(RouteConf {
+                dst,
+                gateway: via,
+                dev,
+                metric,
+            }),
+            Self::Route {
+                cmd: Some(RouteCmd::Del { dst, dev }),
+            } => CtlRequest::RouteDel { dst, dev },
+            Self::Sock => CtlRequest::Sockets,
+            Self::Stats => CtlRequest::Counters,
+            Self::Log { level } => CtlRequest::LogLevel { level },
+            Self::Capture {
+                cmd:
+                    CaptureCmd::Start {
+                        path,
+                        max_size,
+                        keep,
+                    },
+            } => {
+                let mut conf = CaptureConf::new(&path);
+
+                conf.max_size = max_size;
+                conf.keep = keep.unwrap_or(conf.keep);
+
+                CtlRequest::CaptureStart(conf)
+            }
+            Self::Capture {
+                cmd: CaptureCmd::Stop,
+            } => CtlRequest::CaptureStop,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn print_response(resp: CtlResponse) -> anyhow::Result<()> {
+    match resp {
+        CtlResponse::Ok => (),
+        CtlResponse::Error(msg) => Err(anyhow!(msg))?,
+        CtlResponse::Interfaces(ifaces) => {
+            for iface in ifaces {
+                println!(
+                    "{}: mtu {} link/ether {}",
+                    iface.name, iface.mtu, iface.mac
+                );
+
+                for addr in iface.addrs {
+                    println!("    inet {addr}");
+                }
+
+                println!("    gateway {}", iface.gateway);
+            }
+        }
+        CtlResponse::Neighbours(neighs) => {
+            for neigh in neighs {
+                println!(
+                    "{} lladdr {} expires {}s",
+                    neigh.ip, neigh.mac, neigh.expires
+                );
+            }
+        }
+        CtlResponse::Routes(routes) => {
+            for route in routes {
+                println!("{}", RouteEntry::from(&route));
+            }
+        }
+        CtlResponse::Sockets(socks) => {
+            println!("{:<5} {:<12} {:<21} Peer", "Proto", "State", "Local");
+
+            for sock in socks {
+                let remote = sock
+                    .remote
+                    .map(|remote| remote.to_string())
+                    .unwrap_or_else(|| "*".to_owned());
+
+                println!(
+                    "{:<5} {:<12} {:<21} {remote}",
+                    sock.proto,
+                    sock.state,
+                    sock.local.to_string()
+                );
+
+                if let Some(info) = sock.info {
+                    println!("\t{info}");
+                }
+            }
+        }
+        CtlResponse::Counters(counters) => {
+            for (name, val) in counters {
+                println!("{name:<32} {val}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let resp = ctl_request(&cli.socket, &cli.cmd.into_request())?;
+
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&resp)?);
+
+        if let CtlResponse::Error(msg) = resp {
+            Err(anyhow!(msg))?
+        }
+
+        return Ok(());
+    }
+
+    print_response(resp)
+}
@@ -0,0 +1,426 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    mem::MaybeUninit,
+    net::Ipv4Addr,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use clap::Parser;
+use linuxc::iface::get_available_ipv4_ifname;
+use osimodel::{
+    datalink::{
+        Eth, Mac,
+        arp::{ARP, ARPOpKind},
+    },
+    network::{
+        icmp::{ICMP, ICMPTypeKind},
+        ip::{IPv4, ProtocolKind},
+    },
+};
+use sip::{
+    bpf::{bpf_attach, bpf_compile},
+    dev::packet_open,
+    filter::{ETH_P_8021Q, ETH_P_ARP, ETH_P_IP, Filter},
+    ip::{IP_OFFMASK, ip_hdrlen},
+    pcapng::{Direction, LINKTYPE_ETHERNET, PcapngWriter},
+    tcp::{TCP, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN, TCP_URG},
+    udp::UDP,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Room for 802.1Q tag
+const SNAPLEN: usize = Eth::FRAME_LEN + VLAN_HLEN;
+
+const VLAN_HLEN: usize = 4;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Print frames of an interface one line per frame, like tcpdump
+#[derive(Parser)]
+#[clap(name = "sniff")]
+struct Cli {
+    /// Interface, the first one with IPv4 address if it's none
+    #[arg(short)]
+    ifname: Option<String>,
+
+    /// Also write frames into the pcapng file
+    #[arg(short)]
+    write: Option<String>,
+
+    /// Exit after the number of frames
+    #[arg(short)]
+    count: Option<usize>,
+
+    /// Put the interface into promiscuous mode
+    #[arg(short, long)]
+    promisc: bool,
+
+    /// Like `host 10.0.0.1 and (tcp or port 53)`
+    #[arg(trailing_var_arg = true)]
+    filter: Vec<String>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Header at the front of `buf`, and the rest
+fn header<T: Copy>(buf: &[u8]) -> Option<(T, &[u8])> {
+    if buf.len() < size_of::<T>() {
+        return None;
+    }
+
+    let hdr = unsafe { buf.as_ptr().cast::<T>().read_unaligned() };
+
+    Some((hdr, &buf[size_of::<T>()..]))
+}
+
+fn decode(frame: &[u8]) -> String {
+    let Some((ethh, mut rest)) = header::<Eth>(frame)
+    else {
+        return format!("truncated Ethernet, len {}", frame.len());
+    };
+
+    let mut line = format!("{} > {}, ", ethh.src, ethh.dst);
+    let mut ethertype = u16::from_be_bytes(frame[12..14].try_into().unwrap());
+
+    if ethertype == ETH_P_8021Q {
+        let Some(([tci0, tci1, ty0, ty1], inner)) = header::<[u8; 4]>(rest)
+        else {
+            return line + "truncated 802.1Q";
+        };
+
+        let tci = u16::from_be_bytes([tci0, tci1]);
+
+        line += &format!("vlan {} p {}, ", tci & 0x0fff, tci >> 13);
+        ethertype = u16::from_be_bytes([ty0, ty1]);
+        rest = inner;
+    }
+
+    line += &match ethertype {
+        ETH_P_ARP => decode_arp(rest),
+        ETH_P_IP => decode_ipv4(rest),
+        len @ ..=1500 => format!("802.3, len {len}"),
+        _ => format!("ethertype 0x{ethertype:04x}, len {}", rest.len()),
+    };
+
+    line
+}
+
+fn decode_arp(pkt: &[u8]) -> String {
+    let Some((arph, _)) = header::<ARP>(pkt)
+    else {
+        return "ARP, truncated".to_owned();
+    };
+
+    let spa: Ipv4Addr = arph.spa.into();
+    let tpa: Ipv4Addr = arph.tpa.into();
+    let sha = arph.sha;
+
+    match arph.op.to_kind() {
+        ARPOpKind::Request => format!("ARP, Request who-has {tpa} tell {spa}"),
+        ARPOpKind::Reply => format!("ARP, Reply {spa} is-at {sha}"),
+        op => format!("ARP, {op:?} {spa} > {tpa}"),
+    }
+}
+
+fn decode_ipv4(pkt: &[u8]) -> String {
+    let Some((iph, _)) = header::<IPv4>(pkt)
+    else {
+        return "IP, truncated".to_owned();
+    };
+
+    let hlen = ip_hdrlen(&iph);
+    let totlen = iph.totlen.tot_len() as usize;
+
+    if hlen < size_of::<IPv4>() || totlen < hlen || pkt.len() < hlen {
+        return format!("IP, bad header len {hlen} totlen {totlen}");
+    }
+
+    let src: Ipv4Addr = iph.src.into();
+    let dst: Ipv4Addr = iph.dst.into();
+    let off = iph.flags_off.to_bits() & IP_OFFMASK;
+    // Ethernet padding is stripped
+    let payload = &pkt[hlen..totlen.min(pkt.len())];
+    let suffix = format!(
+        ", ttl {}, id {}, len {totlen}",
+        iph.ttl.to_bits(),
+        iph.id.to_bits()
+    );
+
+    if off != 0 {
+        return format!("IP {src} > {dst}: frag off {}{suffix}", off * 8);
+    }
+
+    let proto: ProtocolKind = iph.proto.into();
+
+    let l4 = match proto {
+        ProtocolKind::ICMP => match header::<ICMP>(payload) {
+            Some((icmph, _)) => {
+                let ty: ICMPTypeKind = icmph.ty.into();
+
+                format!(
+                    "IP {src} > {dst}: ICMP {ty:?}, code {}",
+                    icmph.code.to_bits()
+                )
+            }
+            None => format!("IP {src} > {dst}: ICMP truncated"),
+        },
+        ProtocolKind::UDP => match header::<UDP>(payload) {
+            Some((udph, data)) => format!(
+                "IP {src}.{} > {dst}.{}: UDP, length {}",
+                udph.src_port(),
+                udph.dst_port(),
+                data.len()
+            ),
+            None => format!("IP {src} > {dst}: UDP truncated"),
+        },
+        ProtocolKind::TCP => match header::<TCP>(payload) {
+            Some((tcph, _)) => format!(
+                "IP {src}.{} > {dst}.{}: Flags [{}], seq {}, ack {}, win {}, \
+                 length {}",
+                tcph.src_port(),
+                tcph.dst_port(),
+                tcp_flags(tcph.flags()),
+                tcph.seq(),
+                tcph.ack(),
+                tcph.wnd(),
+                payload.len().saturating_sub(tcph.doff())
+            ),
+            None => format!("IP {src} > {dst}: TCP truncated"),
+        },
+        proto => format!("IP {src} > {dst}: {proto:?}"),
+    };
+
+    l4 + &suffix
+}
+
+/// Like tcpdump, `.` is ACK
+fn tcp_flags(flags: u8) -> String {
+    [
+        (TCP_SYN, 'S'),
+        (TCP_FIN, 'F'),
+        (TCP_RST, 'R'),
+        (TCP_PSH, 'P'),
+        (TCP_URG, 'U'),
+        (TCP_ACK, '.'),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| flags & bit != 0)
+    .map(|(_, c)| c)
+    .collect()
+}
+
+fn set_sockopt<T>(sd: &OwnedFd, name: i32, val: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            sd.as_raw_fd(),
+            libc::SOL_PACKET,
+            name,
+            val as *const _ as *const _,
+            size_of::<T>() as u32,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn set_promisc(sd: &OwnedFd, ifindex: i32) -> io::Result<()> {
+    let mreq = libc::packet_mreq {
+        mr_ifindex: ifindex,
+        mr_type: libc::PACKET_MR_PROMISC as u16,
+        mr_alen: 0,
+        mr_address: [0; 8],
+    };
+
+    set_sockopt(sd, libc::PACKET_ADD_MEMBERSHIP, &mreq)
+}
+
+/// 802.1Q tag stripped by the kernel (or the NIC) in `PACKET_AUXDATA`
+fn cmsg_vlan(msg: &libc::msghdr) -> Option<[u8; VLAN_HLEN]> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+
+        if hdr.cmsg_level == libc::SOL_PACKET
+            && hdr.cmsg_type == libc::PACKET_AUXDATA
+        {
+            let aux = unsafe {
+                libc::CMSG_DATA(cmsg)
+                    .cast::<libc::tpacket_auxdata>()
+                    .read_unaligned()
+            };
+
+            if aux.tp_status & libc::TP_STATUS_VLAN_VALID == 0 {
+                return None;
+            }
+
+            let tpid = if aux.tp_status & libc::TP_STATUS_VLAN_TPID_VALID != 0
+            {
+                aux.tp_vlan_tpid
+            }
+            else {
+                ETH_P_8021Q
+            };
+            let [t0, t1] = tpid.to_be_bytes();
+            let [c0, c1] = aux.tp_vlan_tci.to_be_bytes();
+
+            return Some([t0, t1, c0, c1]);
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+
+    None
+}
+
+/// Read a frame, outgoing ones of the host are looped back to the socket.
+///
+/// The 802.1Q tag is put back at its place in the frame as tcpdump does,
+/// `buf` keeps room for it
+fn recv_frame(sd: &OwnedFd, buf: &mut [u8]) -> io::Result<(usize, Direction)> {
+    let mut from = MaybeUninit::<libc::sockaddr_ll>::zeroed();
+    // u64 for alignment of cmsghdr
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut _,
+        iov_len: buf.len() - VLAN_HLEN,
+    };
+
+    let mut msg: libc::msghdr = unsafe { MaybeUninit::zeroed().assume_init() };
+
+    msg.msg_name = from.as_mut_ptr() as *mut _;
+    msg.msg_namelen = size_of::<libc::sockaddr_ll>() as u32;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut _;
+    msg.msg_controllen = size_of_val(&control);
+
+    let n = unsafe { libc::recvmsg(sd.as_raw_fd(), &mut msg, 0) };
+
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut n = n as usize;
+
+    if n >= 2 * size_of::<Mac>()
+        && let Some(tag) = cmsg_vlan(&msg)
+    {
+        let off = 2 * size_of::<Mac>();
+
+        buf.copy_within(off..n, off + VLAN_HLEN);
+        buf[off..off + VLAN_HLEN].copy_from_slice(&tag);
+        n += VLAN_HLEN;
+    }
+
+    let pkttype = unsafe { from.assume_init() }.sll_pkttype;
+    let dir = if pkttype == libc::PACKET_OUTGOING {
+        Direction::Out
+    }
+    else {
+        Direction::In
+    };
+
+    Ok((n, dir))
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let ifname = match cli.ifname {
+        Some(ifname) => ifname,
+        None => get_available_ipv4_ifname()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No available ifname"))?,
+    };
+
+    let filter = if cli.filter.is_empty() {
+        None
+    }
+    else {
+        Some(cli.filter.join(" ").parse::<Filter>()?)
+    };
+
+    let (sd, to, _hwa) = packet_open(&ifname)?;
+
+    if let Some(filter) = &filter {
+        bpf_attach(sd.as_fd(), &bpf_compile(filter)?)?;
+    }
+
+    if cli.promisc {
+        set_promisc(&sd, to.ifindex)?;
+    }
+
+    set_sockopt(&sd, libc::PACKET_AUXDATA, &1i32)?;
+
+    let mut pcap = match &cli.write {
+        Some(path) => {
+            let mut w = PcapngWriter::new(
+                BufWriter::new(File::create(path)?),
+                "sniff",
+            )?;
+
+            w.add_interface(&ifname, LINKTYPE_ETHERNET, SNAPLEN as u32)?;
+
+            Some(w)
+        }
+        None => None,
+    };
+
+    match &filter {
+        Some(filter) => eprintln!("listening on {ifname}, filter `{filter}`"),
+        None => eprintln!("listening on {ifname}"),
+    }
+
+    let mut buf = [0u8; SNAPLEN + VLAN_HLEN];
+    let mut cnt = 0;
+
+    while cli.count.is_none_or(|count| cnt < count) {
+        let (n, dir) = recv_frame(&sd, &mut buf)?;
+        let ts = SystemTime::now();
+        let frame = &buf[..n];
+
+        // frames queued before the filter is attached
+        if let Some(filter) = &filter
+            && !filter.matches(frame)
+        {
+            continue;
+        }
+
+        cnt += 1;
+
+        let since = ts.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let dir_s = match dir {
+            Direction::In => "In ",
+            Direction::Out => "Out",
+        };
+
+        println!(
+            "{}.{:06} {ifname} {dir_s} {}",
+            since.as_secs(),
+            since.subsec_micros(),
+            decode(frame)
+        );
+
+        if let Some(pcap) = &mut pcap {
+            pcap.write_packet(0, ts, Some(dir), frame, None)?;
+            pcap.flush()?;
+        }
+    }
+
+    eprintln!("{cnt} packets captured");
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! TCP and UDP echo servers over the stack, `scripts/test-tcp.sh` runs it
+//! on a veth peered with a netns
+
+use std::{
+    env, io,
+    net::{Ipv4Addr, SocketAddrV4},
+    thread,
+};
+
+use anyhow::anyhow;
+use clap::Parser;
+use log::{info, warn};
+use sip::{
+    config::{LOG_CONFIG_DEFAULT, SipConf},
+    dev::NetDevice,
+    socket::{TcpListener, UdpSocket},
+};
+
+/// Echo servers over SIP
+#[derive(Parser)]
+#[clap(name = "echo")]
+struct Cli {
+    /// Stack configuration file in YAML
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// If name, repeat it for multiple interfaces
+    #[arg(short)]
+    ifname: Vec<String>,
+
+    /// Port of both servers
+    #[arg(short, long, default_value_t = 7)]
+    port: u16,
+}
+
+fn setup_logger(conf: &SipConf) -> anyhow::Result<()> {
+    let path = conf.log.config.as_deref().unwrap_or(LOG_CONFIG_DEFAULT);
+    let mut logconf =
+        log4rs::config::load_config_file(path, Default::default())?;
+
+    if let Some(level) = conf.log.level {
+        logconf.root_mut().set_level(level);
+    }
+
+    if let Ok(level) = env::var("RUST_LOG") {
+        logconf.root_mut().set_level(level.parse()?);
+    }
+
+    log4rs::init_config(logconf)?;
+
+    Ok(())
+}
+
+fn spawn_udp(udp: UdpSocket) -> io::Result<()> {
+    thread::Builder::new().name("echo-udp".to_owned()).spawn(move || {
+        let mut buf = [0; 65535];
+
+        loop {
+            match udp.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    if let Err(err) = udp.send_to(&buf[..n], from) {
+                        warn!("echo-udp: {err}");
+                    }
+                }
+                Err(err) => warn!("echo-udp: {err}"),
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let conf = match cli.config.as_deref() {
+        Some(path) => SipConf::load(path)?,
+        None => SipConf::default(),
+    };
+
+    setup_logger(&conf)?;
+
+    conf.apply_tunables();
+
+    let mut devs = conf.init_devices()?;
+
+    for ifname in cli.ifname.iter() {
+        devs.push(NetDevice::init(ifname)?.register());
+    }
+
+    if devs.is_empty() {
+        Err(anyhow!("No interface, give -i or --config"))?
+    }
+
+    for dev in devs.iter() {
+        dev.spawn()?;
+    }
+
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, cli.port);
+    let listener = TcpListener::bind(addr)?;
+
+    spawn_udp(UdpSocket::bind(addr)?)?;
+
+    info!("echo on {addr}");
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("echo-tcp: {err}");
+                continue;
+            }
+        };
+
+        thread::spawn(move || {
+            if let Err(err) = io::copy(&mut &stream, &mut &stream) {
+                warn!("echo-tcp: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
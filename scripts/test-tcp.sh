@@ -0,0 +1,49 @@
+#!/bin/sh
+# Run the echo example of sip on a veth whose peer is in a netns, then
+# `sudo ip netns exec sip-peer nc 10.7.0.1 7` from another terminal.
+#
+# Kernel owns the same address, its RSTs are dropped while the test runs.
+
+set -eu
+
+NS=sip-peer
+DEV=veth0
+PEER=veth0p
+ADDR=10.7.0.1
+PEER_ADDR=10.7.0.2
+CONF=$(mktemp --suffix=.yaml)
+RULE="OUTPUT -o $DEV -p tcp --tcp-flags RST RST -j DROP"
+
+cleanup() {
+    sudo iptables -D $RULE 2>/dev/null || true
+    sudo ip link del $DEV 2>/dev/null || true
+    sudo ip netns del $NS 2>/dev/null || true
+    rm -f "$CONF"
+}
+
+trap cleanup EXIT
+trap 'exit 130' INT TERM
+
+cleanup
+
+sudo ip netns add $NS
+sudo ip link add $DEV type veth peer name $PEER
+sudo ip link set $PEER netns $NS
+sudo ip addr add $ADDR/24 dev $DEV
+sudo ip link set $DEV up
+sudo ip -n $NS addr add $PEER_ADDR/24 dev $PEER
+sudo ip -n $NS link set $PEER up
+sudo ip -n $NS link set lo up
+
+sudo iptables -A $RULE
+
+# no router on the link, the peer stands in as the gateway
+cat > "$CONF" <<EOF
+interfaces:
+  - name: $DEV
+    address: $ADDR/24
+    gateway: $PEER_ADDR
+EOF
+
+sudo setcap cap_net_raw+eip ./target/debug/examples/echo
+RUST_LOG=${RUST_LOG:-trace} ./target/debug/examples/echo --config "$CONF" --port 7
@@ -1,29 +1,56 @@
-use std::net::Ipv4Addr;
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Write},
+    net::Ipv4Addr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 
 use anyhow::anyhow;
 use derive_more::derive::{Deref, DerefMut};
 use log::trace;
 use m6ptr::{LazyStatic, OwnedPtr};
+use m6tobytes::{as_raw_slice, from_raw_slice};
 use osimodel::datalink::{
     Eth, EthTypeKind, Mac,
     arp::{ARP, ARPOpKind, HTypeKind},
 };
-use time::{Duration, UtcDateTime};
-
-use crate::{dev::NetDevice, skbuff::SkBuff};
+use crate::{
+    clock::{CLOCK, Clock, clock_now, clock_wall},
+    dev::{NetDevice, dev_get_by_name},
+    skbuff::SkBuff,
+    snmp::{SNMP_ARP, SNMP_IP},
+    timer::{TimerId, timer_add, timer_cancel},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Constant Variables
 
+/* Defaults of tunables */
+
 pub const ARP_TBL_SZ: usize = 10;
-pub const ARPLIVE: Duration = Duration::minutes(10);
+pub const ARPLIVE: Duration = Duration::from_secs(10 * 60);
+/// Requests sent before datagrams pending on resolution are dropped
+pub const ARP_RETRIES: usize = 3;
+pub const ARP_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Max datagrams waiting for address resolution
+pub const ARP_QUEUE_SZ: usize = 64;
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Static Variables
 
+pub static ARP_MAX_RETRIES: AtomicUsize = AtomicUsize::new(ARP_RETRIES);
+pub static ARP_QUEUE_MAX: AtomicUsize = AtomicUsize::new(ARP_QUEUE_SZ);
+
 pub static ARP_TBL: LazyStatic<ARPRecTbl> =
     LazyStatic::new(|| ARPRecTbl::new());
 
+pub static ARP_QUEUE: LazyStatic<VecDeque<ARPQueueEntry>> =
+    LazyStatic::new(|| VecDeque::with_capacity(ARP_QUEUE_SZ));
+
 ////////////////////////////////////////////////////////////////////////////////
 //// Structures
 
@@ -33,13 +60,30 @@ pub struct ARPRecord {
     pub age: u16,
     pub ip: Ipv4Addr,
     pub mac: Mac,
-    pub ctime: UtcDateTime,
+    pub ctime: Instant,
+    /// Deadline of the record, `live` of the table when it's inserted
+    /// applies
+    pub expires: Instant,
+    /// Expiry after the record lives out
+    pub timer: Option<TimerId>,
+}
+
+/// IPv4 datagram pending on resolution of `ip`
+#[derive(Debug)]
+pub struct ARPQueueEntry {
+    pub ifname: String,
+    pub ip: Ipv4Addr,
+    pub pkt: Vec<u8>,
 }
 
 /// Using TRLU replace policy
-#[derive(Debug, Deref, DerefMut, Clone, Copy)]
+#[derive(Debug, Deref, DerefMut, Clone)]
 pub struct ARPRecTbl {
-    value: [ARPRecord; ARP_TBL_SZ],
+    #[deref]
+    #[deref_mut]
+    value: Vec<ARPRecord>,
+    live: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -52,26 +96,71 @@ impl Default for ARPRecord {
             age: Default::default(),
             ip: Ipv4Addr::from_bits(0),
             mac: Default::default(),
-            ctime: UtcDateTime::from_unix_timestamp(0).unwrap(),
+            ctime: clock_now(),
+            expires: clock_now(),
+            timer: None,
         }
     }
 }
 
 impl ARPRecTbl {
     pub fn new() -> Self {
+        Self::with_clock(CLOCK.read().unwrap().clone())
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
-            value: Default::default(),
+            value: vec![ARPRecord::default(); ARP_TBL_SZ],
+            live: ARPLIVE,
+            clock,
         }
     }
 
+    pub fn live(&self) -> Duration {
+        self.live
+    }
+
+    /// Resize table keeping the most recently used records, new `live`
+    /// applies to records inserted after it
+    pub fn configure(&mut self, size: usize, live: Duration) {
+        self.value.sort_by_key(|rec| (!rec.is_valid, rec.age));
+
+        for rec in self.value.iter_mut().skip(size) {
+            if let Some(id) = rec.timer.take() {
+                timer_cancel(id);
+            }
+        }
+
+        self.value.resize(size, ARPRecord::default());
+        self.live = live;
+    }
+
+    /// Invalidate all records, returns how many were valid
+    pub fn flush(&mut self) -> usize {
+        let mut n = 0;
+
+        for rec in self.value.iter_mut().filter(|rec| rec.is_valid) {
+            if let Some(id) = rec.timer.take() {
+                timer_cancel(id);
+            }
+
+            rec.is_valid = false;
+            n += 1;
+        }
+
+        n
+    }
+
     pub fn get_mut_and_update(
         &mut self,
         ip: Ipv4Addr,
     ) -> Option<&mut ARPRecord> {
-        let now = UtcDateTime::now();
+        let now = self.clock.now();
+
+        for rec in self.value.iter_mut().filter(|rec| rec.is_valid) {
+            /* the expiry timer may not have fired yet */
 
-        for rec in self.iter_mut().filter(|rec| rec.is_valid) {
-            if rec.ctime + ARPLIVE < now {
+            if rec.expires <= now {
                 rec.is_valid = false;
                 continue;
             }
@@ -90,27 +179,36 @@ impl ARPRecTbl {
     }
 
     pub fn insert(&mut self, ip: Ipv4Addr, mac: Mac) {
-        let now = UtcDateTime::now();
+        self.insert_for(ip, mac, self.live);
+    }
 
-        if let Some(rec) = self.get_mut_and_update(ip) {
-            rec.mac = mac;
-            rec.ctime = now;
-            return;
-        }
+    /// Insert record expiring after `live` which is at most the live of
+    /// the table
+    pub fn insert_for(&mut self, ip: Ipv4Addr, mac: Mac, live: Duration) {
+        let live = live.min(self.live);
+        let now = self.clock.now();
 
-        let rec = if let Some(rec) = self.iter_mut().find(|rec| !rec.is_valid)
-        {
+        let rec = if let Some(rec) = self.get_mut_and_update(ip) {
+            rec
+        }
+        else if let Some(rec) = self.iter_mut().find(|rec| !rec.is_valid) {
             rec
         }
         else {
             self.iter_mut().max_by_key(|rec| rec.age).unwrap()
         };
 
+        if let Some(id) = rec.timer.take() {
+            timer_cancel(id);
+        }
+
         rec.is_valid = true;
         rec.age = 0;
         rec.ip = ip;
         rec.ctime = now;
+        rec.expires = now + live;
         rec.mac = mac;
+        rec.timer = Some(timer_add(live, move || arp_expire(ip, now)));
     }
 }
 
@@ -126,21 +224,46 @@ impl NetDevice {
         let arp_ref = arpbuf.consume::<ARP>();
         let arph = arp_ref.read_unaligned();
 
-        if arph.tpa == self.ip.into() {
-            trace!("Incomming Network ARP handled {}\t\n{arph:#?}", arph.tpa);
+        let tpa: Ipv4Addr = arph.tpa.into();
+
+        if self.has_addr(tpa) {
+            trace!(
+                "Incomming Network ARP handled {:?} {} ({}) -> {}",
+                arph.op.to_kind(),
+                arph.spa,
+                arph.sha,
+                arph.tpa
+            );
         }
         else {
             trace!("Filter Network ARP Package from {}", arph.tpa)
         }
 
         match arph.op.to_kind() {
-            ARPOpKind::Request => self.arp_request(arph.tpa.into())?,
+            ARPOpKind::Request => SNMP_ARP.in_requests.inc(),
+            ARPOpKind::Reply => SNMP_ARP.in_replies.inc(),
+            _ => (),
+        }
+
+        match arph.op.to_kind() {
+            ARPOpKind::Request if self.has_addr(tpa) => self
+                .arp_output(
+                    ARPOpKind::Reply,
+                    tpa,
+                    arph.spa.into(),
+                    self.hwa,
+                    arph.sha,
+                    arph.sha,
+                )?,
+            ARPOpKind::Request => (),
             ARPOpKind::Reply => (),
             _ => (),
         }
 
         ARP_TBL.write().unwrap().insert(arph.spa.into(), arph.sha);
 
+        self.arp_queue_flush(arph.spa.into(), arph.sha)?;
+
 
         Ok(())
     }
@@ -167,6 +290,12 @@ impl NetDevice {
         rawbuf.consume::<Eth>().write_unaligned(ethh);
         skb.nh.set(rawbuf).unwrap();
 
+        match op {
+            ARPOpKind::Request => SNMP_ARP.out_requests.inc(),
+            ARPOpKind::Reply => SNMP_ARP.out_replies.inc(),
+            _ => (),
+        }
+
         let arph = ARP {
             htype: HTypeKind::Ethernet10Mb.into(),
             // yes it's not ARP
@@ -181,7 +310,7 @@ impl NetDevice {
         };
         rawbuf.consume::<ARP>().write_unaligned(arph);
 
-        trace!("Output: {arph:#?}");
+        trace!("Output: ARP {op:?} {src_ip} ({src_mac}) -> {dst_ip}");
 
         let owned = OwnedPtr::new(skb);
 
@@ -193,8 +322,8 @@ impl NetDevice {
     pub fn arp_request(&self, mut tip: Ipv4Addr) -> anyhow::Result<()> {
         /* query if it's in same subnet */
 
-        if tip & self.netmask != self.ip & self.netmask {
-            tip = self.netmask;
+        if !self.is_on_link(tip) {
+            tip = self.gateway;
         }
 
         self.arp_output(
@@ -202,10 +331,315 @@ impl NetDevice {
             self.ip,
             tip,
             self.hwa,
-            Mac::ZERO,
+            Mac::BROADCAST,
             Mac::ZERO,
         )?;
 
         Ok(())
     }
+
+    /// Send IPv4 datagrams to on-link `nexthop`, queue them and resolve it
+    /// if it's unknown
+    pub fn neigh_output(
+        &self,
+        nexthop: Ipv4Addr,
+        pkts: Vec<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let mac = if nexthop.is_broadcast() || nexthop == self.broadcast() {
+            Some(Mac::BROADCAST)
+        }
+        else {
+            ARP_TBL
+                .write()
+                .unwrap()
+                .get_mut_and_update(nexthop)
+                .map(|rec| rec.mac)
+        };
+
+        if let Some(mac) = mac {
+            return self.eth_output(mac, EthTypeKind::IPv4, pkts);
+        }
+
+        let pending = {
+            let mut queue = ARP_QUEUE.write().unwrap();
+            let pending = queue
+                .iter()
+                .any(|ent| ent.ip == nexthop && ent.ifname == self.name);
+
+            let max = ARP_QUEUE_MAX.load(Ordering::Relaxed).max(1);
+
+            for pkt in pkts {
+                while queue.len() >= max {
+                    let dropped = queue.pop_front().unwrap();
+                    trace!("ARP queue full, drop datagram to {}", dropped.ip);
+                    SNMP_IP.out_discards.inc();
+                }
+
+                queue.push_back(ARPQueueEntry {
+                    ifname: self.name.clone(),
+                    ip: nexthop,
+                    pkt,
+                });
+            }
+
+            pending
+        };
+
+        /* request is in flight and retried by timer */
+
+        if pending {
+            return Ok(());
+        }
+
+        let ifname = self.name.clone();
+
+        timer_add(ARP_RETRY_INTERVAL, move || arp_retry(ifname, nexthop, 1));
+
+        self.arp_request(nexthop)
+    }
+
+    /// Send datagrams waiting for `ip` which has been resolved as `mac`
+    pub fn arp_queue_flush(
+        &self,
+        ip: Ipv4Addr,
+        mac: Mac,
+    ) -> anyhow::Result<()> {
+        let pkts = {
+            let mut queue = ARP_QUEUE.write().unwrap();
+            let mut pkts = vec![];
+
+            queue.retain_mut(|ent| {
+                if ent.ip == ip && ent.ifname == self.name {
+                    pkts.push(std::mem::take(&mut ent.pkt));
+                    false
+                }
+                else {
+                    true
+                }
+            });
+
+            pkts
+        };
+
+        if pkts.is_empty() {
+            return Ok(());
+        }
+
+        self.eth_output(mac, EthTypeKind::IPv4, pkts)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Save valid records as lines of `IP MAC EXPIRES`, `EXPIRES` is in UNIX
+/// seconds
+pub fn arp_save<W: Write>(mut w: W) -> io::Result<()> {
+    let tbl = ARP_TBL.read().unwrap();
+    let now = clock_now();
+    let wall = clock_wall();
+
+    for rec in tbl.iter().filter(|rec| rec.is_valid) {
+        let Some(live) = rec.expires.checked_duration_since(now)
+        else {
+            continue;
+        };
+
+        let expires = (wall + live)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mac = as_raw_slice(&rec.mac)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(":");
+
+        writeln!(w, "{} {mac} {expires}", rec.ip)?;
+    }
+
+    Ok(())
+}
+
+/// Load records saved by `arp_save` skipping expired ones, returns number
+/// of records loaded
+pub fn arp_load<R: BufRead>(r: R) -> anyhow::Result<usize> {
+    let now = clock_wall()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut tbl = ARP_TBL.write().unwrap();
+    let mut n = 0;
+
+    for line in r.lines() {
+        let line = line?;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        let &[ip, mac, expires] = &fields[..]
+        else {
+            Err(anyhow!("Malformed ARP record {line:?}"))?
+        };
+
+        let ip: Ipv4Addr = ip.parse()?;
+        let expires: u64 = expires.parse()?;
+        let mac = mac
+            .split(':')
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+
+        if mac.len() != size_of::<Mac>() {
+            Err(anyhow!("Malformed MAC address of {ip}"))?
+        }
+
+        if expires <= now {
+            continue;
+        }
+
+        tbl.insert_for(
+            ip,
+            from_raw_slice::<Mac>(&mac),
+            Duration::from_secs(expires - now),
+        );
+        n += 1;
+    }
+
+    Ok(n)
+}
+
+/// Invalidate record of `ip` created at `ctime` unless it's refreshed
+fn arp_expire(ip: Ipv4Addr, ctime: Instant) {
+    let mut tbl = ARP_TBL.write().unwrap();
+
+    if let Some(rec) = tbl
+        .iter_mut()
+        .find(|rec| rec.is_valid && rec.ip == ip && rec.ctime == ctime)
+    {
+        trace!("ARP record of {ip} expired");
+
+        rec.is_valid = false;
+        rec.timer = None;
+    }
+}
+
+/// Request `ip` again while datagrams are pending on it, drop them after
+/// `ARP_MAX_RETRIES` requests
+fn arp_retry(ifname: String, ip: Ipv4Addr, tries: usize) {
+    let pending = ARP_QUEUE
+        .read()
+        .unwrap()
+        .iter()
+        .any(|ent| ent.ip == ip && ent.ifname == ifname);
+
+    if !pending {
+        return;
+    }
+
+    let dev = dev_get_by_name(&ifname);
+
+    if tries >= ARP_MAX_RETRIES.load(Ordering::Relaxed) || dev.is_none()
+    {
+        trace!("ARP resolution of {ip} failed on {ifname}");
+
+        let mut queue = ARP_QUEUE.write().unwrap();
+        let n = queue.len();
+
+        queue.retain(|ent| !(ent.ip == ip && ent.ifname == ifname));
+        SNMP_IP.out_discards.add((n - queue.len()) as u64);
+
+        return;
+    }
+
+    if let Err(err) = dev.unwrap().arp_request(ip) {
+        trace!("ARP request of {ip} failed: {err:#}");
+    }
+
+    timer_add(ARP_RETRY_INTERVAL, move || arp_retry(ifname, ip, tries + 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, dev::tests::dev_hwa};
+
+    #[test]
+    fn test_arp_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let mut tbl = ARPRecTbl::with_clock(clock.clone());
+        let ip = [10, 0, 0, 2].into();
+
+        tbl.insert(ip, Mac::BROADCAST);
+        clock.advance(ARPLIVE - Duration::from_secs(1));
+
+        assert!(tbl.get_mut_and_update(ip).is_some());
+
+        clock.advance(Duration::from_secs(1));
+
+        assert!(tbl.get_mut_and_update(ip).is_none());
+    }
+
+    #[test]
+    fn test_arp_save_load() {
+        let (ip, old) = ([10, 250, 0, 1].into(), [10, 250, 0, 2].into());
+        let mac = dev_hwa(250, 1);
+
+        ARP_TBL.write().unwrap().insert(ip, mac);
+
+        let mut buf = vec![];
+
+        arp_save(&mut buf).unwrap();
+
+        let saved = String::from_utf8(buf).unwrap();
+        let line = saved
+            .lines()
+            .find(|line| line.starts_with("10.250.0.1 "))
+            .unwrap();
+
+        assert!(line.contains(" 02:00:00:00:fa:01 "));
+
+        // expired records are skipped
+        let now = clock_wall().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let text = format!(
+            "10.250.0.1 02:00:00:00:fa:03 {}\n\
+             10.250.0.2 02:00:00:00:fa:02 {}\n",
+            now + 100,
+            now - 1
+        );
+
+        assert_eq!(arp_load(text.as_bytes()).unwrap(), 1);
+
+        let mut tbl = ARP_TBL.write().unwrap();
+
+        assert_eq!(
+            tbl.get_mut_and_update(ip).map(|rec| rec.mac),
+            Some(dev_hwa(250, 3))
+        );
+        assert!(tbl.get_mut_and_update(old).is_none());
+
+        drop(tbl);
+
+        assert!(arp_load(&b"10.250.0.3 02:00:00:00:fa\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_arp_configure() {
+        let clock = Arc::new(ManualClock::new());
+        let mut tbl = ARPRecTbl::with_clock(clock.clone());
+        let (old, new) = ([10, 0, 0, 2].into(), [10, 0, 0, 3].into());
+
+        tbl.insert(old, Mac::BROADCAST);
+        tbl.configure(4, ARPLIVE / 2);
+        tbl.insert(new, Mac::BROADCAST);
+
+        assert_eq!(tbl.len(), 4);
+
+        // records keep the live when they are inserted
+        clock.advance(ARPLIVE / 2);
+
+        assert!(tbl.get_mut_and_update(old).is_some());
+        assert!(tbl.get_mut_and_update(new).is_none());
+
+        clock.advance(ARPLIVE / 2);
+
+        assert!(tbl.get_mut_and_update(old).is_none());
+    }
 }
@@ -0,0 +1,576 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd},
+};
+
+use anyhow::anyhow;
+
+use crate::filter::{
+    ETH_P_8021Q, ETH_P_ARP, ETH_P_IP, Filter, FilterProto, IPPROTO_TCP,
+    IPPROTO_UDP,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/* Instruction classes */
+
+pub const BPF_LD: u16 = 0x00;
+pub const BPF_LDX: u16 = 0x01;
+pub const BPF_ST: u16 = 0x02;
+pub const BPF_STX: u16 = 0x03;
+pub const BPF_ALU: u16 = 0x04;
+pub const BPF_JMP: u16 = 0x05;
+pub const BPF_RET: u16 = 0x06;
+pub const BPF_MISC: u16 = 0x07;
+
+/* Size of ld */
+
+pub const BPF_W: u16 = 0x00;
+pub const BPF_H: u16 = 0x08;
+pub const BPF_B: u16 = 0x10;
+
+/* Mode of ld */
+
+pub const BPF_IMM: u16 = 0x00;
+pub const BPF_ABS: u16 = 0x20;
+pub const BPF_IND: u16 = 0x40;
+pub const BPF_MEM: u16 = 0x60;
+pub const BPF_LEN: u16 = 0x80;
+pub const BPF_MSH: u16 = 0xa0;
+
+/* Operation of alu and jmp */
+
+pub const BPF_ADD: u16 = 0x00;
+pub const BPF_SUB: u16 = 0x10;
+pub const BPF_MUL: u16 = 0x20;
+pub const BPF_DIV: u16 = 0x30;
+pub const BPF_OR: u16 = 0x40;
+pub const BPF_AND: u16 = 0x50;
+pub const BPF_LSH: u16 = 0x60;
+pub const BPF_RSH: u16 = 0x70;
+pub const BPF_NEG: u16 = 0x80;
+pub const BPF_MOD: u16 = 0x90;
+pub const BPF_XOR: u16 = 0xa0;
+
+pub const BPF_JA: u16 = 0x00;
+pub const BPF_JEQ: u16 = 0x10;
+pub const BPF_JGT: u16 = 0x20;
+pub const BPF_JGE: u16 = 0x30;
+pub const BPF_JSET: u16 = 0x40;
+
+/* Source of operand */
+
+pub const BPF_K: u16 = 0x00;
+pub const BPF_X: u16 = 0x08;
+pub const BPF_A: u16 = 0x10;
+
+/* Operation of misc */
+
+pub const BPF_TAX: u16 = 0x00;
+pub const BPF_TXA: u16 = 0x80;
+
+/// Words of scratch memory `M[]`
+pub const BPF_MEMWORDS: usize = 16;
+
+/// Longest program accepted by Linux
+pub const BPF_MAXINSNS: usize = 4096;
+
+/// Bytes of accepted frames to be kept, like tcpdump
+pub const BPF_SNAPLEN: u32 = 262144;
+
+/// Ancillary data of the kernel is loaded from negative offsets
+pub const SKF_AD_OFF: u32 = libc::SKF_AD_OFF as u32;
+/// 1 if 802.1Q tag was stripped off from the frame into skb metadata
+pub const SKF_AD_VLAN_TAG_PRESENT: u32 = libc::SKF_AD_VLAN_TAG_PRESENT as u32;
+
+/// `M[]` of offset of network header over Ethernet header, it's 4 for
+/// 802.1Q tagged frame
+const MEM_VLAN_OFF: u32 = 0;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// `struct sock_filter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BpfInsn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// Branch target which is resolved after code generation
+#[derive(Debug, Clone, Copy)]
+struct Label(usize);
+
+/// Instruction with unresolved branch targets, `None` is the next one
+struct AsmInsn {
+    code: u16,
+    k: u32,
+    jt: Option<Label>,
+    jf: Option<Label>,
+}
+
+#[derive(Default)]
+struct Asm {
+    insns: Vec<AsmInsn>,
+    /// Position of labels
+    labels: Vec<Option<usize>>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl BpfInsn {
+    pub const fn stmt(code: u16, k: u32) -> Self {
+        Self {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    pub const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+impl Asm {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.insns.len());
+    }
+
+    fn stmt(&mut self, code: u16, k: u32) {
+        self.insns.push(AsmInsn {
+            code,
+            k,
+            jt: None,
+            jf: None,
+        });
+    }
+
+    fn jump(
+        &mut self,
+        code: u16,
+        k: u32,
+        jt: Option<Label>,
+        jf: Option<Label>,
+    ) {
+        self.insns.push(AsmInsn { code, k, jt, jf });
+    }
+
+    /// Jump to `t` if `A == k` else `f`
+    fn jeq(&mut self, k: u32, t: Option<Label>, f: Option<Label>) {
+        self.jump(BPF_JMP | BPF_JEQ | BPF_K, k, t, f);
+    }
+
+    /// `X` is set to offset of network header over Ethernet header
+    fn ldx_vlan(&mut self) {
+        self.stmt(BPF_LDX | BPF_MEM, MEM_VLAN_OFF);
+    }
+
+    /// Ethernet type after 802.1Q tag is loaded into `A`, also set `X`
+    fn ld_ethertype(&mut self) {
+        self.ldx_vlan();
+        self.stmt(BPF_LD | BPF_H | BPF_IND, 12);
+    }
+
+    /// Jump to `t` if it's IPv4 of `proto`, or any IPv4 if it's none,
+    /// else `f`
+    fn ip_proto(&mut self, proto: Option<u8>, t: Option<Label>, f: Label) {
+        self.ld_ethertype();
+
+        let Some(proto) = proto
+        else {
+            self.jeq(ETH_P_IP as u32, t, Some(f));
+            return;
+        };
+
+        self.jeq(ETH_P_IP as u32, None, Some(f));
+        self.stmt(BPF_LD | BPF_B | BPF_IND, 14 + 9);
+        self.jeq(proto as u32, t, Some(f));
+    }
+
+    fn filter(&mut self, filter: &Filter, t: Label, f: Label) {
+        match filter {
+            Filter::Not(filter) => self.filter(filter, f, t),
+            Filter::And(lhs, rhs) => {
+                let next = self.label();
+
+                self.filter(lhs, next, f);
+                self.bind(next);
+                self.filter(rhs, t, f);
+            }
+            Filter::Or(lhs, rhs) => {
+                let next = self.label();
+
+                self.filter(lhs, t, next);
+                self.bind(next);
+                self.filter(rhs, t, f);
+            }
+            Filter::Proto(FilterProto::Vlan) => {
+                // the tag is stripped off by the kernel with acceleration
+                self.stmt(
+                    BPF_LD | BPF_B | BPF_ABS,
+                    SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT,
+                );
+                self.jeq(1, Some(t), None);
+                self.stmt(BPF_LD | BPF_H | BPF_ABS, 12);
+                self.jeq(ETH_P_8021Q as u32, Some(t), Some(f));
+            }
+            Filter::Proto(FilterProto::Arp) => {
+                self.ld_ethertype();
+                self.jeq(ETH_P_ARP as u32, Some(t), Some(f));
+            }
+            Filter::Proto(proto) => {
+                self.ip_proto(proto.ip_proto(), Some(t), f);
+            }
+            Filter::EtherDst(mac) => {
+                let hi = u16::from_be_bytes([mac[0], mac[1]]);
+                let lo = u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+
+                self.stmt(BPF_LD | BPF_W | BPF_ABS, 2);
+                self.jeq(lo, None, Some(f));
+                self.stmt(BPF_LD | BPF_H | BPF_ABS, 0);
+                self.jeq(hi as u32, Some(t), Some(f));
+            }
+            Filter::Host(host) => {
+                let host = host.to_bits();
+                let arp = self.label();
+                let ip = self.label();
+
+                self.ld_ethertype();
+                self.jeq(ETH_P_IP as u32, Some(ip), None);
+                self.jeq(ETH_P_ARP as u32, Some(arp), Some(f));
+
+                // (source, destination) of IPv4 then ARP
+                for (label, offs) in [(ip, [12, 16]), (arp, [14, 24])] {
+                    self.bind(label);
+                    self.stmt(BPF_LD | BPF_W | BPF_IND, 14 + offs[0]);
+                    self.jeq(host, Some(t), None);
+                    self.stmt(BPF_LD | BPF_W | BPF_IND, 14 + offs[1]);
+                    self.jeq(host, Some(t), Some(f));
+                }
+            }
+            Filter::Port(port) => {
+                let l4 = self.label();
+
+                self.ip_proto(None, None, f);
+                self.stmt(BPF_LD | BPF_B | BPF_IND, 14 + 9);
+                self.jeq(IPPROTO_TCP as u32, Some(l4), None);
+                self.jeq(IPPROTO_UDP as u32, Some(l4), Some(f));
+
+                // not the first fragment
+                self.bind(l4);
+                self.stmt(BPF_LD | BPF_H | BPF_IND, 14 + 6);
+                self.jump(BPF_JMP | BPF_JSET | BPF_K, 0x1fff, Some(f), None);
+
+                // X += IHL * 4
+                self.stmt(BPF_LD | BPF_B | BPF_IND, 14);
+                self.stmt(BPF_ALU | BPF_AND | BPF_K, 0x0f);
+                self.stmt(BPF_ALU | BPF_LSH | BPF_K, 2);
+                self.stmt(BPF_ALU | BPF_ADD | BPF_X, 0);
+                self.stmt(BPF_MISC | BPF_TAX, 0);
+
+                self.stmt(BPF_LD | BPF_H | BPF_IND, 14);
+                self.jeq(*port as u32, Some(t), None);
+                self.stmt(BPF_LD | BPF_H | BPF_IND, 14 + 2);
+                self.jeq(*port as u32, Some(t), Some(f));
+            }
+        }
+    }
+
+    fn assemble(self) -> anyhow::Result<Vec<BpfInsn>> {
+        if self.insns.len() > BPF_MAXINSNS {
+            Err(anyhow!("{} instructions is too long", self.insns.len()))?
+        }
+
+        let offset = |pc: usize, label: Option<Label>| {
+            let Some(label) = label
+            else {
+                return Ok(0);
+            };
+
+            let target = self.labels[label.0].unwrap();
+
+            u8::try_from(target - pc - 1).map_err(|_| {
+                anyhow!("branch from {pc} to {target} is too far")
+            })
+        };
+
+        self.insns
+            .iter()
+            .enumerate()
+            .map(|(pc, insn)| {
+                Ok(BpfInsn::jump(
+                    insn.code,
+                    insn.k,
+                    offset(pc, insn.jt)?,
+                    offset(pc, insn.jf)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Compile `filter` into classic BPF which returns `BPF_SNAPLEN` for
+/// accepted frames and 0 for others
+pub fn bpf_compile(filter: &Filter) -> anyhow::Result<Vec<BpfInsn>> {
+    let mut asm = Asm::default();
+    let body = asm.label();
+    let t = asm.label();
+    let f = asm.label();
+
+    /* M[MEM_VLAN_OFF] = 4 if 802.1Q tagged else 0 */
+
+    asm.stmt(BPF_LD | BPF_IMM, 0);
+    asm.stmt(BPF_ST, MEM_VLAN_OFF);
+    asm.stmt(BPF_LD | BPF_H | BPF_ABS, 12);
+    asm.jeq(ETH_P_8021Q as u32, None, Some(body));
+    asm.stmt(BPF_LD | BPF_IMM, 4);
+    asm.stmt(BPF_ST, MEM_VLAN_OFF);
+    asm.bind(body);
+
+    asm.filter(filter, t, f);
+
+    asm.bind(t);
+    asm.stmt(BPF_RET | BPF_K, BPF_SNAPLEN);
+    asm.bind(f);
+    asm.stmt(BPF_RET | BPF_K, 0);
+
+    asm.assemble()
+}
+
+/// Run `prog` on `pkt` like the kernel, loading beyond the packet, division
+/// by zero and falling off the end return 0.
+///
+/// It isn't faithful to the kernel for ancillary data as there is no skb,
+/// `SKF_AD_VLAN_TAG_PRESENT` is 0 for the tag is kept in `pkt`, and others
+/// return 0
+pub fn bpf_run(prog: &[BpfInsn], pkt: &[u8]) -> u32 {
+    let mut a = 0u32;
+    let mut x = 0u32;
+    let mut mem = [0u32; BPF_MEMWORDS];
+    let mut pc = 0;
+
+    let load = |off: u32, size: usize| -> Option<u32> {
+        if off == SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT {
+            return Some(0);
+        }
+
+        let off = off as usize;
+        let bytes = pkt.get(off..off.checked_add(size)?)?;
+
+        Some(bytes.iter().fold(0, |acc, b| acc << 8 | *b as u32))
+    };
+
+    while let Some(insn) = prog.get(pc) {
+        pc += 1;
+
+        let code = insn.code;
+        let k = insn.k;
+
+        match code & 0x07 {
+            BPF_LD | BPF_LDX => {
+                let size = match code & 0x18 {
+                    BPF_W => 4,
+                    BPF_H => 2,
+                    BPF_B => 1,
+                    _ => return 0,
+                };
+
+                let val = match code & 0xe0 {
+                    BPF_IMM => Some(k),
+                    BPF_ABS => load(k, size),
+                    BPF_IND => load(x.wrapping_add(k), size),
+                    BPF_MEM => mem.get(k as usize).copied(),
+                    BPF_LEN => Some(pkt.len() as u32),
+                    BPF_MSH if code & 0x07 == BPF_LDX => {
+                        load(k, 1).map(|b| (b & 0x0f) * 4)
+                    }
+                    _ => None,
+                };
+
+                let Some(val) = val
+                else {
+                    return 0;
+                };
+
+                if code & 0x07 == BPF_LD {
+                    a = val;
+                }
+                else {
+                    x = val;
+                }
+            }
+            BPF_ST | BPF_STX => {
+                let Some(slot) = mem.get_mut(k as usize)
+                else {
+                    return 0;
+                };
+
+                *slot = if code & 0x07 == BPF_ST { a } else { x };
+            }
+            BPF_ALU => {
+                let src = if code & BPF_X != 0 { x } else { k };
+
+                a = match code & 0xf0 {
+                    BPF_ADD => a.wrapping_add(src),
+                    BPF_SUB => a.wrapping_sub(src),
+                    BPF_MUL => a.wrapping_mul(src),
+                    BPF_DIV | BPF_MOD if src == 0 => return 0,
+                    BPF_DIV => a / src,
+                    BPF_MOD => a % src,
+                    BPF_OR => a | src,
+                    BPF_AND => a & src,
+                    BPF_XOR => a ^ src,
+                    BPF_LSH => a.checked_shl(src).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(src).unwrap_or(0),
+                    BPF_NEG => a.wrapping_neg(),
+                    _ => return 0,
+                };
+            }
+            BPF_JMP => {
+                let src = if code & BPF_X != 0 { x } else { k };
+
+                let cond = match code & 0xf0 {
+                    BPF_JA => {
+                        pc += k as usize;
+                        continue;
+                    }
+                    BPF_JEQ => a == src,
+                    BPF_JGT => a > src,
+                    BPF_JGE => a >= src,
+                    BPF_JSET => a & src != 0,
+                    _ => return 0,
+                };
+
+                pc += if cond { insn.jt } else { insn.jf } as usize;
+            }
+            BPF_RET => {
+                return match code & 0x18 {
+                    BPF_A => a,
+                    _ => k,
+                };
+            }
+            BPF_MISC => match code & 0xf8 {
+                BPF_TAX => x = a,
+                BPF_TXA => a = x,
+                _ => return 0,
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    0
+}
+
+/// Attach `prog` to socket by `SO_ATTACH_FILTER`, the old one is replaced
+pub fn bpf_attach(sd: BorrowedFd, prog: &[BpfInsn]) -> io::Result<()> {
+    let fprog = libc::sock_fprog {
+        len: prog.len() as u16,
+        filter: prog.as_ptr() as *mut libc::sock_filter,
+    };
+
+    let ret = unsafe {
+        libc::setsockopt(
+            sd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog as *const _ as *const _,
+            size_of::<libc::sock_fprog>() as u32,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn bpf_detach(sd: BorrowedFd) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            sd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_DETACH_FILTER,
+            std::ptr::null(),
+            0,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ethernet frame of IPv4 `proto` from 10.0.0.1:53 to 10.0.0.2:80
+    fn ipv4_frame(vlan: bool, proto: u8, frag_off: u16) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+
+        frame.extend([0x02, 0, 0, 0, 0, 1]);
+
+        if vlan {
+            frame.extend([0x81, 0x00, 0x00, 0x0a]);
+        }
+
+        frame.extend([0x08, 0x00, 0x45, 0, 0, 40, 0, 1]);
+        frame.extend(frag_off.to_be_bytes());
+        frame.extend([64, proto, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend([0, 53, 0, 80]);
+        frame.resize(frame.len() + 16, 0);
+
+        frame
+    }
+
+    #[test]
+    fn test_bpf_filter() {
+        let frames = [
+            ipv4_frame(false, 6, 0),
+            ipv4_frame(true, 17, 0),
+            ipv4_frame(false, 17, 0x0001),
+            ipv4_frame(true, 1, 0),
+            ipv4_frame(false, 6, 0)[..20].to_vec(),
+        ];
+        let cases = [
+            ("tcp", [true, false, false, false, false]),
+            ("vlan and port 53", [false, true, false, false, false]),
+            (
+                "host 10.0.0.2 and not port 80",
+                [false, false, true, true, false],
+            ),
+            (
+                "ether dst ff:ff:ff:ff:ff:ff and (udp or icmp)",
+                [false, true, true, true, false],
+            ),
+        ];
+
+        for (expr, expected) in cases {
+            let filter = expr.parse::<Filter>().unwrap();
+            let prog = bpf_compile(&filter).unwrap();
+
+            for (frame, expected) in frames.iter().zip(expected) {
+                assert_eq!(filter.matches(frame), expected, "{expr}");
+                assert_eq!(bpf_run(&prog, frame) != 0, expected, "{expr}");
+            }
+        }
+    }
+}
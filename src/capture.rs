@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use m6ptr::LazyStatic;
+
+use crate::{
+    config::CaptureConf,
+    dev::DEV_TBL,
+    pcapng::{Direction, LINKTYPE_ETHERNET, PcapngWriter},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Rotated files kept by default
+pub const CAPTURE_KEEP: usize = 4;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/// Checked before locking `CAPTURE` on each frame
+static CAPTURE_ON: AtomicBool = AtomicBool::new(false);
+
+static CAPTURE: LazyStatic<Option<Capture>> = LazyStatic::new(|| None);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Frames of all devices captured into a pcapng file
+struct Capture {
+    conf: CaptureConf,
+    writer: PcapngWriter<BufWriter<File>>,
+    /// Interface ID of devices in the current file
+    ifids: HashMap<String, u32>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl Capture {
+    fn create(conf: CaptureConf) -> io::Result<Self> {
+        Ok(Self {
+            writer: pcapng_create(&conf.path)?,
+            conf,
+            ifids: HashMap::new(),
+        })
+    }
+
+    fn write(
+        &mut self,
+        ifname: &str,
+        dir: Direction,
+        ts: SystemTime,
+        frame: &[u8],
+        reason: Option<&str>,
+    ) -> io::Result<()> {
+        let ifid = match self.ifids.get(ifname) {
+            Some(ifid) => *ifid,
+            None => {
+                let ifid =
+                    self.writer.add_interface(ifname, LINKTYPE_ETHERNET, 0)?;
+
+                self.ifids.insert(ifname.to_owned(), ifid);
+                ifid
+            }
+        };
+
+        self.writer
+            .write_packet(ifid, ts, Some(dir), frame, reason)?;
+        // so that the file can be followed by Wireshark
+        self.writer.flush()?;
+
+        if let Some(max_size) = self.conf.max_size
+            && self.writer.written() >= max_size
+        {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Shift `PATH.N` to `PATH.N+1` like logrotate, the ones beyond `keep`
+    /// are overwritten, then start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.conf.path;
+
+        if self.conf.keep > 0 {
+            for i in (1..self.conf.keep).rev() {
+                let from = format!("{path}.{i}");
+
+                if Path::new(&from).exists() {
+                    fs::rename(&from, format!("{path}.{}", i + 1))?;
+                }
+            }
+
+            fs::rename(path, format!("{path}.1"))?;
+        }
+
+        self.writer = pcapng_create(path)?;
+        self.ifids.clear();
+
+        info!("capture rotated {path}");
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn pcapng_create(path: &str) -> io::Result<PcapngWriter<BufWriter<File>>> {
+    PcapngWriter::new(BufWriter::new(File::create(path)?), "sip")
+}
+
+/// Start capturing into a new file, the running capture is stopped
+pub fn capture_start(conf: &CaptureConf) -> anyhow::Result<()> {
+    conf.validate()?;
+
+    let capture = Capture::create(conf.clone())
+        .with_context(|| format!("create {}", conf.path))?;
+
+    *CAPTURE.write().unwrap() = Some(capture);
+
+    // frames filtered by the kernel are captured too
+    if !CAPTURE_ON.swap(true, Ordering::Relaxed) {
+        for dev in DEV_TBL.read().unwrap().iter() {
+            if let Err(err) = dev.filter_detach() {
+                warn!("{}: detach filter: {err:#}", dev.name);
+            }
+        }
+    }
+
+    info!("capture into {}", conf.path);
+
+    Ok(())
+}
+
+/// Return false if it isn't capturing
+pub fn capture_stop() -> bool {
+    if CAPTURE_ON.swap(false, Ordering::Relaxed) {
+        filter_attach_all();
+    }
+
+    let Some(capture) = CAPTURE.write().unwrap().take()
+    else {
+        return false;
+    };
+
+    info!("capture into {} stopped", capture.conf.path);
+
+    true
+}
+
+pub fn capture_enabled() -> bool {
+    CAPTURE_ON.load(Ordering::Relaxed)
+}
+
+/// Configuration of the running capture
+pub fn capture_conf() -> Option<CaptureConf> {
+    CAPTURE
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|capture| capture.conf.clone())
+}
+
+/// Record `frame` of device `ifname`, `reason` is why it's dropped.
+/// The capture is stopped if it fails to write.
+pub fn capture_frame(
+    ifname: &str,
+    dir: Direction,
+    ts: SystemTime,
+    frame: &[u8],
+    reason: Option<&str>,
+) {
+    if !capture_enabled() {
+        return;
+    }
+
+    let mut capture = CAPTURE.write().unwrap();
+
+    let Some(cap) = capture.as_mut()
+    else {
+        return;
+    };
+
+    if let Err(err) = cap.write(ifname, dir, ts, frame, reason) {
+        warn!("capture into {}: {err}, stopped", cap.conf.path);
+
+        *capture = None;
+        CAPTURE_ON.store(false, Ordering::Relaxed);
+        drop(capture);
+
+        filter_attach_all();
+    }
+}
+
+fn filter_attach_all() {
+    for dev in DEV_TBL.read().unwrap().iter() {
+        if let Err(err) = dev.filter_attach() {
+            warn!("{}: attach filter: {err:#}", dev.name);
+        }
+    }
+}
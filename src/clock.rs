@@ -0,0 +1,109 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use m6ptr::LazyStatic;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/// Clock of the stack, replaced by `ManualClock` in tests
+pub static CLOCK: LazyStatic<Arc<dyn Clock>> =
+    LazyStatic::new(|| Arc::new(MonotonicClock));
+
+static CLOCK_EPOCH: LazyStatic<Instant> = LazyStatic::new(|| clock_now());
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+pub trait Clock: Debug + Send + Sync {
+    /// Monotonic time for timers and expiry
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, only for timestamps on the wire
+    fn wall(&self) -> SystemTime;
+}
+
+/// Real time of the OS
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MonotonicClock;
+
+/// Time only moves on `advance`, starts at the time it's created
+#[derive(Debug)]
+pub struct ManualClock {
+    mono: Instant,
+    wall: SystemTime,
+    offset: Mutex<Duration>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            mono: Instant::now(),
+            wall: SystemTime::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, dur: Duration) {
+        *self.offset.lock().unwrap() += dur;
+    }
+
+    /// Time passed since it's created
+    pub fn elapsed(&self) -> Duration {
+        *self.offset.lock().unwrap()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.mono + self.elapsed()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall + self.elapsed()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Replace clock of the stack, it should be done before any time is taken
+/// as instants of different clocks aren't comparable
+pub fn clock_set(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = clock;
+}
+
+pub fn clock_now() -> Instant {
+    CLOCK.read().unwrap().now()
+}
+
+pub fn clock_wall() -> SystemTime {
+    CLOCK.read().unwrap().wall()
+}
+
+/// Monotonic time since the clock is first read
+pub fn clock_uptime() -> Duration {
+    clock_now().saturating_duration_since(*CLOCK_EPOCH.read().unwrap())
+}
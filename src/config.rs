@@ -0,0 +1,681 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    arp::{
+        ARP_MAX_RETRIES, ARP_QUEUE_MAX, ARP_QUEUE_SZ, ARP_RETRIES, ARP_TBL,
+        ARP_TBL_SZ, ARPLIVE,
+    },
+    capture::CAPTURE_KEEP,
+    dev::{DevIoKind, NetDevice},
+    ip::IP_FORWARD,
+    ring::{
+        RING_BLOCK_NR, RING_BLOCK_SZ, RING_BLOCK_TOV, RING_FRAME_SZ,
+        RING_MTU_MAX, RING_TX_FRAME_NR,
+    },
+    route::{ROUTE_TBL, RouteEntry},
+    tcp::{
+        TCP_BACKLOG, TCP_DEFAULT_MSS, TCP_RCV_WSCALE, TCP_RCVBUF_SZ,
+        TCP_RETRIES, TCP_RETRIES2, TCP_RMEM, TCP_SNDBUF_SZ, TCP_SOMAXCONN,
+        TCP_SYN_RETRIES, TCP_SYNCNT, TCP_WMEM,
+    },
+    tcpcc::{CCKind, TCP_CONGESTION},
+    udp::{UDP_RXQ_MAX, UDP_RXQ_SZ},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+pub const LOG_CONFIG_DEFAULT: &str = "log4rs.default.yaml";
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Stack configuration file in YAML, all sections are optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SipConf {
+    pub interfaces: Vec<IfaceConf>,
+    pub routes: Vec<RouteConf>,
+    pub ip: IPConf,
+    pub arp: ARPConf,
+    pub tcp: TCPConf,
+    pub udp: UDPConf,
+    pub log: LogConf,
+    pub capture: Option<CaptureConf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IfaceConf {
+    pub name: String,
+    /// Static address, it's discovered from the host if it's none
+    #[serde(default)]
+    pub address: Option<Ipv4Net>,
+    /// It's discovered from the host if it's none
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    /// `plain`, `ring` or `mmsg`
+    #[serde(default, deserialize_with = "de_from_str")]
+    pub io: DevIoKind,
+    /// Used by `ring` I/O
+    #[serde(default)]
+    pub ring: RingConf,
+}
+
+/// TPACKET_V3 rings of an interface
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RingConf {
+    /// Bytes of each block, multiple of page size
+    pub block_size: usize,
+    /// RX blocks
+    pub blocks: usize,
+    /// A block which isn't full is handed over after it
+    #[serde(deserialize_with = "de_duration")]
+    pub block_timeout: Duration,
+    /// TX frame slots
+    pub tx_frames: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConf {
+    pub dst: Ipv4Net,
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    pub dev: String,
+    #[serde(default)]
+    pub metric: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IPConf {
+    pub forward: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ARPConf {
+    pub table_size: usize,
+    #[serde(deserialize_with = "de_duration")]
+    pub live: Duration,
+    pub retries: usize,
+    pub queue_size: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TCPConf {
+    #[serde(deserialize_with = "de_from_str")]
+    pub congestion: CCKind,
+    pub sndbuf: usize,
+    pub rcvbuf: usize,
+    pub syn_retries: u32,
+    pub retries: u32,
+    pub somaxconn: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UDPConf {
+    pub rxq_size: usize,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConf {
+    /// log4rs configuration file, `LOG_CONFIG_DEFAULT` if it's none
+    pub config: Option<String>,
+    /// Level of root logger, `RUST_LOG` overrides it
+    #[serde(deserialize_with = "de_from_str_opt")]
+    pub level: Option<log::LevelFilter>,
+}
+
+/// Capture frames of all devices into a pcapng file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConf {
+    pub path: String,
+    /// Rotate the file when it reaches the size in bytes, never if it's
+    /// none
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Rotated files kept as `PATH.1` to `PATH.N`, the newest first
+    #[serde(default = "capture_keep_default")]
+    pub keep: usize,
+}
+
+/// `ADDR/PREFIX` or `default`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Net {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl SipConf {
+    /// Read and validate configuration file
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("read config {path}"))?;
+
+        let conf: Self = serde_yaml::from_str(&text)
+            .with_context(|| format!("parse config {path}"))?;
+
+        conf.validate()
+            .with_context(|| format!("invalid config {path}"))?;
+
+        Ok(conf)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+
+        for iface in self.interfaces.iter() {
+            if iface.name.is_empty() {
+                Err(anyhow!("interfaces: empty name"))?
+            }
+
+            if !names.insert(iface.name.as_str()) {
+                Err(anyhow!("interfaces: duplicate {}", iface.name))?
+            }
+
+            iface
+                .validate()
+                .with_context(|| format!("interfaces: {}", iface.name))?;
+        }
+
+        for route in self.routes.iter() {
+            route
+                .validate(&names)
+                .with_context(|| format!("routes: {}", route.dst))?;
+        }
+
+        self.arp.validate().context("arp")?;
+        self.tcp.validate().context("tcp")?;
+        self.udp.validate().context("udp")?;
+
+        if let Some(capture) = &self.capture {
+            capture.validate().context("capture")?;
+        }
+
+        Ok(())
+    }
+
+    /// Set tunables of protocols, it's done again on reload
+    pub fn apply_tunables(&self) {
+        IP_FORWARD.store(self.ip.forward, Ordering::Relaxed);
+
+        ARP_TBL
+            .write()
+            .unwrap()
+            .configure(self.arp.table_size, self.arp.live);
+        ARP_MAX_RETRIES.store(self.arp.retries, Ordering::Relaxed);
+        ARP_QUEUE_MAX.store(self.arp.queue_size, Ordering::Relaxed);
+
+        *TCP_CONGESTION.write().unwrap() = self.tcp.congestion;
+        TCP_WMEM.store(self.tcp.sndbuf, Ordering::Relaxed);
+        TCP_RMEM.store(self.tcp.rcvbuf, Ordering::Relaxed);
+        TCP_SYNCNT.store(self.tcp.syn_retries, Ordering::Relaxed);
+        TCP_RETRIES.store(self.tcp.retries, Ordering::Relaxed);
+        TCP_SOMAXCONN.store(self.tcp.somaxconn, Ordering::Relaxed);
+
+        UDP_RXQ_MAX.store(self.udp.rxq_size, Ordering::Relaxed);
+    }
+
+    /// Register configured interfaces then add static routes
+    pub fn init_devices(&self) -> anyhow::Result<Vec<Arc<NetDevice>>> {
+        let mut devs = vec![];
+
+        for iface in self.interfaces.iter() {
+            let inet = iface.address.map(|net| (net.addr, net.netmask()));
+            let mut dev =
+                NetDevice::init_with(&iface.name, inet, iface.gateway)?;
+
+            if let Some(mtu) = iface.mtu {
+                // frames larger than the link are dropped by the driver
+                if mtu > dev.mtu {
+                    Err(anyhow!(
+                        "interfaces: {}: mtu {mtu} exceeds {} of the link",
+                        iface.name,
+                        dev.mtu
+                    ))?
+                }
+
+                dev.mtu = mtu;
+            }
+
+            if iface.io == DevIoKind::Ring && dev.mtu as usize > RING_MTU_MAX {
+                Err(anyhow!(
+                    "interfaces: {}: mtu {} exceeds {RING_MTU_MAX} of ring \
+                     frames",
+                    iface.name,
+                    dev.mtu
+                ))?
+            }
+
+            dev.set_io(iface.io, &iface.ring)?;
+
+            devs.push(dev.register());
+        }
+
+        let mut rttbl = ROUTE_TBL.write().unwrap();
+
+        for route in self.routes.iter() {
+            rttbl.insert(route.into());
+        }
+
+        Ok(devs)
+    }
+}
+
+impl IfaceConf {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(net) = self.address {
+            if net.addr.is_unspecified() || net.prefix == 0 {
+                Err(anyhow!("address {net} isn't a host address"))?
+            }
+
+            if let Some(gateway) = self.gateway
+                && !net.contains(gateway)
+            {
+                Err(anyhow!("gateway {gateway} isn't on link of {net}"))?
+            }
+        }
+
+        if let Some(mtu) = self.mtu
+            && mtu < 68
+        {
+            Err(anyhow!("mtu {mtu} is less than 68 (RFC 791)"))?
+        }
+
+        if self.io == DevIoKind::Ring {
+            self.ring.validate().context("ring")?;
+
+            if let Some(mtu) = self.mtu
+                && mtu as usize > RING_MTU_MAX
+            {
+                Err(anyhow!(
+                    "mtu {mtu} exceeds {RING_MTU_MAX} of ring frames of \
+                     {RING_FRAME_SZ} bytes"
+                ))?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl RouteConf {
+    pub(crate) fn validate(
+        &self,
+        ifnames: &HashSet<&str>,
+    ) -> anyhow::Result<()> {
+        if !ifnames.contains(self.dev.as_str()) {
+            Err(anyhow!("dev {} isn't in interfaces", self.dev))?
+        }
+
+        if self.dst.addr != self.dst.network() {
+            Err(anyhow!("host bits are set, use {}", self.dst.network()))?
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&RouteConf> for RouteEntry {
+    fn from(route: &RouteConf) -> Self {
+        Self {
+            dst: route.dst.addr,
+            mask: route.dst.netmask(),
+            gateway: route.gateway,
+            ifname: route.dev.clone(),
+            metric: route.metric,
+        }
+    }
+}
+
+impl From<&RouteEntry> for RouteConf {
+    fn from(ent: &RouteEntry) -> Self {
+        Self {
+            dst: Ipv4Net::with_netmask(ent.dst, ent.mask),
+            gateway: ent.gateway,
+            dev: ent.ifname.clone(),
+            metric: ent.metric,
+        }
+    }
+}
+
+impl ARPConf {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.table_size == 0 {
+            Err(anyhow!("table_size should be positive"))?
+        }
+
+        if self.live.is_zero() {
+            Err(anyhow!("live should be positive"))?
+        }
+
+        if self.retries == 0 {
+            Err(anyhow!("retries should be positive"))?
+        }
+
+        if self.queue_size == 0 {
+            Err(anyhow!("queue_size should be positive"))?
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for ARPConf {
+    fn default() -> Self {
+        Self {
+            table_size: ARP_TBL_SZ,
+            live: ARPLIVE,
+            retries: ARP_RETRIES,
+            queue_size: ARP_QUEUE_SZ,
+        }
+    }
+}
+
+impl TCPConf {
+    fn validate(&self) -> anyhow::Result<()> {
+        let min = TCP_DEFAULT_MSS as usize;
+        let max = (u16::MAX as usize) << TCP_RCV_WSCALE;
+
+        if self.sndbuf < min {
+            Err(anyhow!("sndbuf should be at least {min}"))?
+        }
+
+        if !(min..=max).contains(&self.rcvbuf) {
+            Err(anyhow!("rcvbuf should be in {min}..={max}"))?
+        }
+
+        if self.syn_retries == 0 || self.retries == 0 {
+            Err(anyhow!("syn_retries and retries should be positive"))?
+        }
+
+        if self.somaxconn == 0 {
+            Err(anyhow!("somaxconn should be positive"))?
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for TCPConf {
+    fn default() -> Self {
+        Self {
+            congestion: CCKind::Cubic,
+            sndbuf: TCP_SNDBUF_SZ,
+            rcvbuf: TCP_RCVBUF_SZ,
+            syn_retries: TCP_SYN_RETRIES,
+            retries: TCP_RETRIES2,
+            somaxconn: TCP_BACKLOG,
+        }
+    }
+}
+
+impl UDPConf {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.rxq_size == 0 {
+            Err(anyhow!("rxq_size should be positive"))?
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for UDPConf {
+    fn default() -> Self {
+        Self {
+            rxq_size: UDP_RXQ_SZ,
+        }
+    }
+}
+
+impl RingConf {
+    fn validate(&self) -> anyhow::Result<()> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        if self.block_size < RING_FRAME_SZ
+            || !self.block_size.is_multiple_of(page_size)
+        {
+            Err(anyhow!(
+                "block_size should be multiple of page size {page_size}, \
+                 at least {RING_FRAME_SZ}"
+            ))?
+        }
+
+        if self.blocks == 0 || self.tx_frames == 0 {
+            Err(anyhow!("blocks and tx_frames should be positive"))?
+        }
+
+        if self.block_timeout.is_zero() {
+            Err(anyhow!("block_timeout should be positive"))?
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RingConf {
+    fn default() -> Self {
+        Self {
+            block_size: RING_BLOCK_SZ,
+            blocks: RING_BLOCK_NR,
+            block_timeout: RING_BLOCK_TOV,
+            tx_frames: RING_TX_FRAME_NR,
+        }
+    }
+}
+
+impl CaptureConf {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            max_size: None,
+            keep: CAPTURE_KEEP,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.path.is_empty() {
+            Err(anyhow!("empty path"))?
+        }
+
+        if self.max_size == Some(0) {
+            Err(anyhow!("max_size should be positive"))?
+        }
+
+        Ok(())
+    }
+}
+
+impl Ipv4Net {
+    pub fn with_netmask(addr: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        Self {
+            addr,
+            prefix: netmask.to_bits().count_ones() as u8,
+        }
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(
+            u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0),
+        )
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.addr & self.netmask()
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        ip & self.netmask() == self.network()
+    }
+}
+
+impl FromStr for Ipv4Net {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "default" {
+            return Ok(Self {
+                addr: Ipv4Addr::UNSPECIFIED,
+                prefix: 0,
+            });
+        }
+
+        let Some((addr, prefix)) = s.split_once('/')
+        else {
+            Err(anyhow!("{s:?} should be ADDR/PREFIX"))?
+        };
+
+        let addr = addr
+            .parse()
+            .with_context(|| format!("{s:?} has invalid address"))?;
+        let prefix = prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= 32)
+            .ok_or_else(|| anyhow!("{s:?} has invalid prefix length"))?;
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Display for Ipv4Net {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.prefix == 0 && self.addr.is_unspecified() {
+            write!(f, "default")
+        }
+        else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl Serialize for Ipv4Net {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Net {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        de_from_str(d)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn de_from_str<'de, D, T>(d: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(d)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn de_from_str_opt<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    de_from_str(d).map(Some)
+}
+
+fn capture_keep_default() -> usize {
+    CAPTURE_KEEP
+}
+
+/// Like `10m` or `200ms`
+fn de_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    humantime::parse_duration(&String::deserialize(d)?)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sip_conf_validate() {
+        let parse = |text: &str| {
+            serde_yaml::from_str::<SipConf>(text)
+                .map(|conf| conf.validate().map_err(|err| format!("{err:#}")))
+        };
+
+        assert_eq!(parse("{}").unwrap(), Ok(()));
+        assert_eq!(
+            parse(include_str!("../sip.example.yaml")).unwrap(),
+            Ok(())
+        );
+
+        let cases = [
+            ("interfaces: [{name: ''}]", "empty name"),
+            ("interfaces: [{name: a}, {name: a}]", "duplicate a"),
+            (
+                "interfaces: [{name: a, address: 10.0.0.0/0}]",
+                "host address",
+            ),
+            (
+                "interfaces: [{name: a, address: 10.0.0.1/24, \
+                 gateway: 10.0.1.1}]",
+                "isn't on link",
+            ),
+            ("interfaces: [{name: a, mtu: 67}]", "less than 68"),
+            (
+                "interfaces: [{name: a, io: ring, ring: {block_size: 1000}}]",
+                "block_size",
+            ),
+            (
+                "interfaces: [{name: a, io: ring, mtu: 9000}]",
+                "exceeds 1986 of ring frames",
+            ),
+            (
+                "routes: [{dst: 10.0.0.0/8, dev: a}]",
+                "dev a isn't in interfaces",
+            ),
+            (
+                "interfaces: [{name: a}]\nroutes: [{dst: 10.0.0.1/8, dev: a}]",
+                "host bits are set",
+            ),
+            ("arp: {live: 0s}", "live should be positive"),
+            ("tcp: {rcvbuf: 1}", "rcvbuf should be in"),
+            ("udp: {rxq_size: 0}", "rxq_size"),
+            ("capture: {path: ''}", "empty path"),
+        ];
+
+        for (text, expected) in cases {
+            let err = parse(text).unwrap().unwrap_err();
+
+            assert!(err.contains(expected), "{text}: {err}");
+        }
+
+        assert!(parse("tcp: {congestion: vegas}").is_err());
+        assert!(parse("unknown: 1").is_err());
+    }
+}
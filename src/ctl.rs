@@ -0,0 +1,657 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddrV4},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use log::{LevelFilter, info, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arp::{ARP_QUEUE, ARP_TBL},
+    capture::{capture_start, capture_stop},
+    clock::clock_now,
+    config::{CaptureConf, Ipv4Net, RouteConf},
+    dev::{DEV_TBL, NetDevice, dev_get_by_name},
+    ip::IP_FORWARD,
+    reactor::{EventSource, Reactor},
+    route::ROUTE_TBL,
+    snmp::snmp_groups,
+    tcp::{TCP_TBL, TCPState},
+    timer::TIMER_WHEEL,
+    udp::UDP_TBL,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Control socket of a daemon run by root, `$XDG_RUNTIME_DIR/sip.sock`
+/// is used instead if it's set
+pub const CTL_SOCK_DEFAULT: &str = "/run/sip.sock";
+
+/// Longest request line, the client is dropped beyond it
+const CTL_LINE_MAX: usize = 64 << 10;
+
+/// A client not reading its responses is dropped after it, as the
+/// reactor is blocked meanwhile
+const CTL_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Request of the control protocol, one JSON value per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum CtlRequest {
+    Interfaces,
+    Neighbours,
+    Routes,
+    Sockets,
+    Counters,
+    AddrAdd {
+        dev: String,
+        addr: Ipv4Net,
+    },
+    AddrDel {
+        dev: String,
+        addr: Ipv4Addr,
+    },
+    RouteAdd(RouteConf),
+    /// Routes of any device if `dev` is none
+    RouteDel {
+        dst: Ipv4Net,
+        dev: Option<String>,
+    },
+    ArpFlush,
+    /// Level of root logger
+    LogLevel {
+        level: String,
+    },
+    /// Capture into pcapng file, the running capture is stopped
+    CaptureStart(CaptureConf),
+    CaptureStop,
+}
+
+/// Response of the control protocol, one JSON value per line for each
+/// request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CtlResponse {
+    Ok,
+    Error(String),
+    Interfaces(Vec<IfaceInfo>),
+    Neighbours(Vec<NeighInfo>),
+    Routes(Vec<RouteConf>),
+    Sockets(Vec<SockInfo>),
+    Counters(BTreeMap<String, u64>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IfaceInfo {
+    pub name: String,
+    pub mac: String,
+    pub mtu: u16,
+    /// Primary address first
+    pub addrs: Vec<Ipv4Net>,
+    pub gateway: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NeighInfo {
+    pub ip: Ipv4Addr,
+    pub mac: String,
+    /// In seconds
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SockInfo {
+    /// `tcp` or `udp`
+    pub proto: String,
+    pub local: SocketAddrV4,
+    pub remote: Option<SocketAddrV4>,
+    pub state: String,
+    /// TCP information like `ss -i`
+    pub info: Option<String>,
+}
+
+pub type CtlLogFn = dyn Fn(LevelFilter) -> anyhow::Result<()> + Send + Sync;
+
+/// Execute requests, things out of the stack like logger are done by
+/// hooks of the daemon
+#[derive(Clone, Default)]
+pub struct CtlHandler {
+    log_level: Option<Arc<CtlLogFn>>,
+}
+
+/// Unix domain control socket polled by the reactor, the socket file is
+/// removed on drop
+pub struct CtlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    handler: CtlHandler,
+}
+
+/// Connected control client
+pub struct CtlConn {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    handler: CtlHandler,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl CtlHandler {
+    pub fn handle(&self, req: &CtlRequest) -> CtlResponse {
+        trace!("ctl: {req:?}");
+
+        match self.handle_inner(req) {
+            Ok(resp) => resp,
+            Err(err) => CtlResponse::Error(format!("{err:#}")),
+        }
+    }
+
+    fn handle_inner(&self, req: &CtlRequest) -> anyhow::Result<CtlResponse> {
+        Ok(match req {
+            CtlRequest::Interfaces => {
+                CtlResponse::Interfaces(ctl_interfaces())
+            }
+            CtlRequest::Neighbours => {
+                CtlResponse::Neighbours(ctl_neighbours())
+            }
+            CtlRequest::Routes => CtlResponse::Routes(
+                ROUTE_TBL.read().unwrap().iter().map(Into::into).collect(),
+            ),
+            CtlRequest::Sockets => CtlResponse::Sockets(ctl_sockets()),
+            CtlRequest::Counters => CtlResponse::Counters(ctl_counters()),
+            CtlRequest::AddrAdd { dev, addr } => {
+                ctl_dev(dev)?.addr_add(addr.addr, addr.netmask())?;
+                info!("ctl: address {addr} added to {dev}");
+                CtlResponse::Ok
+            }
+            CtlRequest::AddrDel { dev, addr } => {
+                ctl_dev(dev)?.addr_del(*addr)?;
+                info!("ctl: address {addr} removed from {dev}");
+                CtlResponse::Ok
+            }
+            CtlRequest::RouteAdd(route) => {
+                ctl_route_add(route)?;
+                info!("ctl: route {} added", route.dst);
+                CtlResponse::Ok
+            }
+            CtlRequest::RouteDel { dst, dev } => {
+                ctl_route_del(dst, dev.as_deref())?;
+                info!("ctl: route {dst} removed");
+                CtlResponse::Ok
+            }
+            CtlRequest::ArpFlush => {
+                let n = ARP_TBL.write().unwrap().flush();
+                info!("ctl: {n} ARP records flushed");
+                CtlResponse::Ok
+            }
+            CtlRequest::LogLevel { level } => {
+                let level = level
+                    .parse::<LevelFilter>()
+                    .with_context(|| format!("log level {level}"))?;
+
+                let Some(hook) = &self.log_level
+                else {
+                    Err(anyhow!("log level isn't supported"))?
+                };
+
+                hook(level)?;
+                info!("ctl: log level {level}");
+                CtlResponse::Ok
+            }
+            CtlRequest::CaptureStart(conf) => {
+                capture_start(conf)?;
+                CtlResponse::Ok
+            }
+            CtlRequest::CaptureStop => {
+                if !capture_stop() {
+                    Err(anyhow!("no capture is running"))?
+                }
+
+                CtlResponse::Ok
+            }
+        })
+    }
+}
+
+impl CtlServer {
+    /// Bind `path`, a stale socket file left by a dead daemon is replaced
+    pub fn bind<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                Err(anyhow!("{} is in use", path.display()))?
+            }
+
+            fs::remove_file(&path)?;
+        }
+
+        /* administration is for the owner only, and the socket file must
+        not be reachable by others before it's restricted */
+        let umask = unsafe { libc::umask(0o077) };
+        let res = UnixListener::bind(&path);
+
+        unsafe { libc::umask(umask) };
+
+        let listener =
+            res.with_context(|| format!("bind {}", path.display()))?;
+
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path,
+            handler: CtlHandler::default(),
+        })
+    }
+
+    /// Hook of `LogLevel` requests
+    pub fn with_log_level<F>(mut self, f: F) -> Self
+    where
+        F: Fn(LevelFilter) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.handler.log_level = Some(Arc::new(f));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CtlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl EventSource for CtlServer {
+    fn fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+
+    fn ready(&mut self, reactor: &mut Reactor) -> anyhow::Result<bool> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => Err(err)?,
+            };
+
+            stream.set_nonblocking(false)?;
+            stream.set_write_timeout(Some(CTL_WRITE_TIMEOUT))?;
+
+            let token = reactor.register(Box::new(CtlConn {
+                stream,
+                buf: Vec::new(),
+                handler: self.handler.clone(),
+            }))?;
+
+            trace!("ctl: client {token} connected");
+        }
+
+        Ok(true)
+    }
+}
+
+impl EventSource for CtlConn {
+    fn fd(&self) -> BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+
+    /// Read once as it's readable, then answer complete lines
+    fn ready(&mut self, _reactor: &mut Reactor) -> anyhow::Result<bool> {
+        let mut chunk = [0u8; 4096];
+        let n = self.stream.read(&mut chunk)?;
+
+        if n == 0 {
+            return Ok(false);
+        }
+
+        self.buf.extend_from_slice(&chunk[..n]);
+
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<u8>>();
+
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            let resp = match serde_json::from_slice::<CtlRequest>(&line) {
+                Ok(req) => self.handler.handle(&req),
+                Err(err) => CtlResponse::Error(format!("bad request: {err}")),
+            };
+
+            let mut out = serde_json::to_vec(&resp)?;
+
+            out.push(b'\n');
+            self.stream.write_all(&out)?;
+        }
+
+        if self.buf.len() > CTL_LINE_MAX {
+            Err(anyhow!("request is longer than {CTL_LINE_MAX}"))?
+        }
+
+        Ok(true)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// `$XDG_RUNTIME_DIR/sip.sock` if it's set, or else `CTL_SOCK_DEFAULT`
+pub fn ctl_sock_default() -> String {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => {
+            Path::new(&dir).join("sip.sock").display().to_string()
+        }
+        _ => CTL_SOCK_DEFAULT.to_owned(),
+    }
+}
+
+fn ctl_dev(name: &str) -> anyhow::Result<Arc<NetDevice>> {
+    dev_get_by_name(name).ok_or_else(|| anyhow!("no device {name}"))
+}
+
+fn ctl_interfaces() -> Vec<IfaceInfo> {
+    DEV_TBL
+        .read()
+        .unwrap()
+        .iter()
+        .map(|dev| IfaceInfo {
+            name: dev.name.clone(),
+            mac: dev.hwa.to_string(),
+            mtu: dev.mtu,
+            addrs: dev
+                .addrs()
+                .into_iter()
+                .map(|(addr, mask)| Ipv4Net::with_netmask(addr, mask))
+                .collect(),
+            gateway: dev.gateway,
+        })
+        .collect()
+}
+
+fn ctl_neighbours() -> Vec<NeighInfo> {
+    let tbl = ARP_TBL.read().unwrap();
+    let now = clock_now();
+
+    tbl.iter()
+        .filter(|rec| rec.is_valid)
+        .map(|rec| NeighInfo {
+            ip: rec.ip,
+            mac: rec.mac.to_string(),
+            expires: rec.expires.saturating_duration_since(now).as_secs(),
+        })
+        .collect()
+}
+
+fn ctl_sockets() -> Vec<SockInfo> {
+    let mut socks = vec![];
+
+    let pcbs = TCP_TBL
+        .read()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for pcb in pcbs {
+        let tcb = pcb.tcb.lock().unwrap();
+
+        socks.push(SockInfo {
+            proto: "tcp".to_owned(),
+            local: tcb.key.local,
+            remote: tcb.key.remote,
+            state: tcb.state.to_string(),
+            info: (tcb.state != TCPState::Listen)
+                .then(|| tcb.info().to_string()),
+        });
+    }
+
+    for key in UDP_TBL.read().unwrap().keys() {
+        socks.push(SockInfo {
+            proto: "udp".to_owned(),
+            local: key.local,
+            remote: key.remote,
+            state: if key.remote.is_some() {
+                "ESTAB"
+            }
+            else {
+                "UNCONN"
+            }
+            .to_owned(),
+            info: None,
+        });
+    }
+
+    socks.sort_by_key(|sock| (sock.proto.clone(), sock.local, sock.remote));
+    socks
+}
+
+/// Counters of the stack by `proto.name`, SNMP ones by `Group.Name` like
+/// `Ip.InReceives`
+pub fn ctl_counters() -> BTreeMap<String, u64> {
+    let mut counters = BTreeMap::new();
+    let mut put = |name: &str, val: u64| {
+        counters.insert(name.to_owned(), val);
+    };
+
+    put("ip.forwarding", IP_FORWARD.load(Ordering::Relaxed) as u64);
+    put(
+        "arp.entries",
+        ARP_TBL
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|rec| rec.is_valid)
+            .count() as u64,
+    );
+    put("arp.queued", ARP_QUEUE.read().unwrap().len() as u64);
+    put("timer.pending", TIMER_WHEEL.read().unwrap().len() as u64);
+
+    let pcbs = TCP_TBL
+        .read()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let (mut estab, mut overflows, mut cookies_sent, mut cookies_recv) =
+        (0, 0, 0, 0);
+    let mut cookies_failed = 0;
+
+    for pcb in pcbs.iter() {
+        let tcb = pcb.tcb.lock().unwrap();
+
+        if tcb.state == TCPState::Established {
+            estab += 1;
+        }
+
+        overflows += tcb.listen_stats.overflows;
+        cookies_sent += tcb.listen_stats.cookies_sent;
+        cookies_recv += tcb.listen_stats.cookies_recv;
+        cookies_failed += tcb.listen_stats.cookies_failed;
+    }
+
+    put("tcp.sockets", pcbs.len() as u64);
+    put("tcp.established", estab);
+    put("tcp.listen_overflows", overflows);
+    put("tcp.syncookies_sent", cookies_sent);
+    put("tcp.syncookies_recv", cookies_recv);
+    put("tcp.syncookies_failed", cookies_failed);
+    put("udp.sockets", UDP_TBL.read().unwrap().len() as u64);
+
+    for (group, values) in snmp_groups() {
+        for (name, val) in values {
+            put(&format!("{group}.{name}"), val);
+        }
+    }
+
+    counters
+}
+
+fn ctl_route_add(route: &RouteConf) -> anyhow::Result<()> {
+    let names = DEV_TBL
+        .read()
+        .unwrap()
+        .iter()
+        .map(|dev| dev.name.clone())
+        .collect::<Vec<_>>();
+
+    route
+        .validate(&names.iter().map(String::as_str).collect::<HashSet<_>>())?;
+
+    if let Some(gateway) = route.gateway
+        && !ctl_dev(&route.dev)?.is_on_link(gateway)
+    {
+        Err(anyhow!("gateway {gateway} isn't on link of {}", route.dev))?
+    }
+
+    ROUTE_TBL.write().unwrap().insert(route.into());
+
+    Ok(())
+}
+
+fn ctl_route_del(dst: &Ipv4Net, dev: Option<&str>) -> anyhow::Result<()> {
+    let mut rttbl = ROUTE_TBL.write().unwrap();
+    let n = rttbl.len();
+
+    rttbl.retain(|ent| {
+        !(ent.dst == dst.addr
+            && ent.mask == dst.netmask()
+            && dev.is_none_or(|dev| ent.ifname == dev))
+    });
+
+    if rttbl.len() == n {
+        Err(anyhow!("no route {dst}"))?
+    }
+
+    Ok(())
+}
+
+/// Send `req` to the daemon listening on `path` and wait its response
+pub fn ctl_request<P: AsRef<Path>>(
+    path: P,
+    req: &CtlRequest,
+) -> anyhow::Result<CtlResponse> {
+    let path = path.as_ref();
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("connect {}", path.display()))?;
+
+    let mut out = serde_json::to_vec(req)?;
+
+    out.push(b'\n');
+    stream.write_all(&out)?;
+
+    let mut line = String::new();
+
+    BufReader::new(stream).read_line(&mut line)?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Shutdown,
+        process,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_ctl_round_trip() {
+        let path = env::temp_dir().join(format!("sip-{}.sock", process::id()));
+        let logged = Arc::new(AtomicBool::new(false));
+        let flag = logged.clone();
+        let server =
+            CtlServer::bind(&path).unwrap().with_log_level(move |_| {
+                flag.store(true, Ordering::Relaxed);
+                Ok(())
+            });
+        let mut reactor = Reactor::new().unwrap();
+        let handle = reactor.handle();
+
+        // a second daemon can't take the socket of the live one
+        assert!(CtlServer::bind(&path).is_err());
+
+        reactor.register(Box::new(server)).unwrap();
+
+        let client = thread::spawn(move || {
+            let resps = [
+                CtlRequest::Counters,
+                CtlRequest::LogLevel {
+                    level: "debug".to_owned(),
+                },
+                CtlRequest::LogLevel {
+                    level: "loud".to_owned(),
+                },
+                CtlRequest::AddrDel {
+                    dev: "nodev0".to_owned(),
+                    addr: [10, 0, 0, 1].into(),
+                },
+            ]
+            .map(|req| ctl_request(&path, &req).unwrap());
+
+            // several requests on one connection, and a malformed one
+            let mut stream = UnixStream::connect(&path).unwrap();
+            let mut lines = String::new();
+
+            stream.write_all(b"\"routes\"\n\n{\"bogus\": 1}\n").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            stream.read_to_string(&mut lines).unwrap();
+
+            handle.stop().unwrap();
+
+            (resps, lines)
+        });
+
+        reactor.run().unwrap();
+
+        let ([counters, level, bad_level, no_dev], lines) =
+            client.join().unwrap();
+
+        assert!(matches!(
+            counters,
+            CtlResponse::Counters(map) if map.contains_key("Ip.InReceives")
+        ));
+        assert!(matches!(level, CtlResponse::Ok));
+        assert!(logged.load(Ordering::Relaxed));
+        assert!(matches!(
+            bad_level,
+            CtlResponse::Error(err) if err.contains("loud")
+        ));
+        assert!(
+            matches!(no_dev, CtlResponse::Error(err) if err.contains("nodev0"))
+        );
+
+        let resps = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<CtlResponse>>();
+
+        assert!(matches!(resps[..], [
+            CtlResponse::Routes(_),
+            CtlResponse::Error(ref err),
+        ] if err.starts_with("bad request")));
+    }
+}
@@ -1,9 +1,15 @@
 use std::{
+    fmt::Display,
+    io,
     net::Ipv4Addr,
     os::fd::{AsFd, OwnedFd},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    thread::{self, JoinHandle},
+    time::SystemTime,
 };
 
-use anyhow::anyhow;
+use anyhow::{Context, anyhow};
 use linuxc::{
     ether::EthTypeKind,
     iface::{HwType, IfAddr, get_ifaddrtbl, get_ifhwaddr, get_ifmtu},
@@ -15,6 +21,8 @@ use linuxc::{
 };
 use log::{trace, warn};
 use m6io::rawbuf::RawBuf;
+use m6ptr::LazyStatic;
+use m6tobytes::as_raw_slice;
 use osimodel::{
     datalink::{
         Eth, EthProtoKind, EthTypeKind as OSIEtHTypeKind, Mac,
@@ -24,10 +32,28 @@ use osimodel::{
 };
 
 use crate::{
-    arp::{ARP_TBL},
+    bpf::{bpf_attach, bpf_compile, bpf_detach},
+    capture::{capture_enabled, capture_frame},
+    clock::clock_wall,
+    config::RingConf,
+    filter::{Filter, FilterProto},
+    ip::inet_init,
+    mmsg::MmsgIo,
+    pcapng::Direction,
+    ring::PacketRing,
+    route::{ROUTE_TBL, RouteEntry},
     skbuff::SkBuff,
+    snmp::{Counter, SNMP_IP},
+    timer::timer_start,
 };
 
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/// Registered devices, in the order of registration
+pub static DEV_TBL: LazyStatic<Vec<Arc<NetDevice>>> =
+    LazyStatic::new(|| Vec::new());
+
 
 #[derive(Debug)]
 pub struct NetDevice {
@@ -38,96 +64,393 @@ pub struct NetDevice {
     pub hwt: HwType,
     pub hwa: Mac,
     pub mtu: u16,
+    /// Secondary (address, netmask) added at runtime
+    pub secondary: RwLock<Vec<(Ipv4Addr, Ipv4Addr)>>,
     /// Sock descriptor
     pub sd: OwnedFd,
     pub to: SockAddrLL,
+    pub stats: DevStats,
+    pub io: DevIo,
 }
 
+/// How frames are read from and sent to the packet socket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DevIoKind {
+    /// One `read` or `sendto` per frame
+    #[default]
+    Plain,
+    /// TPACKET_V3 RX and TX rings
+    Ring,
+    /// Batches of `recvmmsg` and `sendmmsg`
+    Mmsg,
+}
+
+#[derive(Debug)]
+pub enum DevIo {
+    Plain,
+    Ring(PacketRing),
+    Mmsg(MmsgIo),
+}
+
+/// Frames read from and sent to the link like `/proc/net/dev`
+#[derive(Debug, Default)]
+pub struct DevStats {
+    pub rx_packets: Counter,
+    pub rx_bytes: Counter,
+    pub tx_packets: Counter,
+    pub tx_bytes: Counter,
+    pub tx_errors: Counter,
+}
 
 impl NetDevice {
     pub fn init(ifname: &str) -> anyhow::Result<Self> {
-        let sd = socket(
-            AddressFamily::PACKET,
-            SocketType::RAW,
-            Default::default(),
-            EthTypeKind::ALL.into(),
-        )?;
+        Self::init_with(ifname, None, None)
+    }
 
-        let ifaddrtbl = get_ifaddrtbl()?;
+    /// Like `init` with static (address, netmask) and gateway, which are
+    /// discovered from the host if they're none
+    pub fn init_with(
+        ifname: &str,
+        inet: Option<(Ipv4Addr, Ipv4Addr)>,
+        gateway: Option<Ipv4Addr>,
+    ) -> anyhow::Result<Self> {
+        let (sd, to, hwa) = packet_open(ifname)?;
 
-        let Some((ifindex, hwa)) = ifaddrtbl.iter().find_map(|ifaddr| {
-            if let IfAddr::Packet {
-                name,
-                ifindex,
-                addr,
-                ..
-            } = ifaddr
-                && name == ifname
-            {
-                Some((*ifindex, *addr))
-            }
-            else {
-                None
-            }
-        })
-        else {
-            Err(anyhow::anyhow!("no ifname `{ifname}` found"))?
-        };
+        let ifaddrtbl = get_ifaddrtbl()?;
 
-        let Some((ip, netmask)) = ifaddrtbl.iter().find_map(|ifaddr| {
-            if let IfAddr::Inet { name, addr, mask, .. } = ifaddr
-                && name == ifname
-            {
-                Some((*addr, *mask))
-            }
-            else {
-                None
-            }
+        let Some((ip, netmask)) = inet.or_else(|| {
+            ifaddrtbl.iter().find_map(|ifaddr| {
+                if let IfAddr::Inet { name, addr, mask, .. } = ifaddr
+                    && name == ifname
+                {
+                    Some((*addr, *mask))
+                }
+                else {
+                    None
+                }
+            })
         })
         else {
             Err(anyhow::anyhow!("no ifname `{ifname}` found"))?
         };
 
-        let Some(gateway) = get_gateway_ipv4_by_ifname(ifname)?
-        else {
-            Err(anyhow::anyhow!("no gateway found for `{ifname}`"))?
-        };
-
-        let to = SockAddrLL {
-            family: SaFamily::Packet,
-            protocol: EthTypeKind::ARP.into(),
-            ifindex,
-            hatype: HTypeKind::Ethernet10Mb.into(),
-            pkttype: PktType::Host,
-            halen: size_of::<Mac>() as u8,
-            addr: hwa.into(),
+        let gateway = match gateway {
+            Some(gateway) => gateway,
+            None => match get_gateway_ipv4_by_ifname(ifname)? {
+                Some(gateway) => gateway,
+                None => Err(anyhow::anyhow!(
+                    "no gateway found for `{ifname}`"
+                ))?,
+            },
         };
 
         let hwt = get_ifhwaddr(ifname)?.ty;
         let mtu = get_ifmtu(ifname)? as u16;
 
-        bind(sd.as_fd(), to.into())?;
-
-        Ok(Self {
+        let dev = Self {
             name: ifname.to_owned(),
             ip,
             netmask,
             hwt,
             hwa,
             mtu,
+            secondary: RwLock::new(Vec::new()),
             sd,
             to,
+            stats: DevStats::default(),
             gateway,
-        })
+            io: DevIo::Plain,
+        };
+
+        // captures see all frames
+        if !capture_enabled() {
+            dev.filter_attach()?;
+        }
+
+        Ok(dev)
+    }
+
+    /// Let the kernel drop frames which `eth_input` would filter, before
+    /// they are copied to the socket
+    pub fn filter_attach(&self) -> anyhow::Result<()> {
+        let hwa: [u8; 6] = as_raw_slice(&self.hwa).try_into()?;
+
+        let filter = Filter::And(
+            Box::new(Filter::Or(
+                Box::new(Filter::EtherDst(hwa)),
+                Box::new(Filter::EtherDst([0xff; 6])),
+            )),
+            Box::new(Filter::Or(
+                Box::new(Filter::Proto(FilterProto::Arp)),
+                Box::new(Filter::Proto(FilterProto::Ip)),
+            )),
+        );
+
+        bpf_attach(self.sd.as_fd(), &bpf_compile(&filter)?)?;
+
+        Ok(())
+    }
+
+    pub fn filter_detach(&self) -> anyhow::Result<()> {
+        bpf_detach(self.sd.as_fd())?;
+
+        Ok(())
+    }
+
+    /// Switch I/O of the socket before it's registered, it can't be
+    /// switched back from rings
+    pub fn set_io(
+        &mut self,
+        kind: DevIoKind,
+        ring: &RingConf,
+    ) -> anyhow::Result<()> {
+        self.io = match kind {
+            DevIoKind::Plain => DevIo::Plain,
+            DevIoKind::Ring => DevIo::Ring(
+                PacketRing::new(self.sd.as_fd(), ring)
+                    .with_context(|| format!("{}: set up rings", self.name))?,
+            ),
+            DevIoKind::Mmsg => DevIo::Mmsg(
+                MmsgIo::new(self.sd.as_fd())
+                    .with_context(|| format!("{}: set up mmsg", self.name))?,
+            ),
+        };
+
+        Ok(())
+    }
+
+    pub fn ifindex(&self) -> i32 {
+        self.to.ifindex
+    }
+
+    /// Directed broadcast address of the attached network
+    pub fn broadcast(&self) -> Ipv4Addr {
+        self.ip | !self.netmask
     }
 
+    /// Is `ip` in the attached networks
+    pub fn is_on_link(&self, ip: Ipv4Addr) -> bool {
+        self.addrs()
+            .into_iter()
+            .any(|(addr, mask)| ip & mask == addr & mask)
+    }
+
+    /// Primary address first, then secondary ones
+    pub fn addrs(&self) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        let mut addrs = vec![(self.ip, self.netmask)];
+
+        addrs.extend(self.secondary.read().unwrap().iter().copied());
+        addrs
+    }
+
+    pub fn has_addr(&self, ip: Ipv4Addr) -> bool {
+        ip == self.ip
+            || self
+                .secondary
+                .read()
+                .unwrap()
+                .iter()
+                .any(|(addr, _)| *addr == ip)
+    }
+
+    /// Add secondary address with its connected route
+    pub fn addr_add(
+        &self,
+        ip: Ipv4Addr,
+        mask: Ipv4Addr,
+    ) -> anyhow::Result<()> {
+        if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+            Err(anyhow!("invalid address {ip}"))?
+        }
+
+        if self.has_addr(ip) {
+            Err(anyhow!("{ip} exists on {}", self.name))?
+        }
+
+        let connected = self.is_on_link(ip);
+
+        self.secondary.write().unwrap().push((ip, mask));
+
+        if !connected {
+            ROUTE_TBL.write().unwrap().insert(RouteEntry {
+                dst: ip & mask,
+                mask,
+                gateway: None,
+                ifname: self.name.clone(),
+                metric: 0,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Remove secondary address, the connected route is removed if no
+    /// address is left in its network
+    pub fn addr_del(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        if ip == self.ip {
+            Err(anyhow!("primary address {ip} can't be removed"))?
+        }
+
+        let mask = {
+            let mut secondary = self.secondary.write().unwrap();
+
+            let Some(pos) = secondary.iter().position(|(addr, _)| *addr == ip)
+            else {
+                Err(anyhow!("no {ip} on {}", self.name))?
+            };
+
+            secondary.remove(pos).1
+        };
+
+        if !self.is_on_link(ip) {
+            ROUTE_TBL.write().unwrap().retain(|ent| {
+                !(ent.dst == ip & mask
+                    && ent.mask == mask
+                    && ent.gateway.is_none()
+                    && ent.ifname == self.name)
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add device into `DEV_TBL` with its connected route and a default
+    /// route through its gateway
+    pub fn register(self) -> Arc<Self> {
+        inet_init();
+
+        let dev = Arc::new(self);
+        let mut devtbl = DEV_TBL.write().unwrap();
+        let mut rttbl = ROUTE_TBL.write().unwrap();
+
+        rttbl.insert(RouteEntry {
+            dst: dev.ip & dev.netmask,
+            mask: dev.netmask,
+            gateway: None,
+            ifname: dev.name.clone(),
+            metric: 0,
+        });
+
+        // prefer the default route of the first registered device
+        rttbl.insert(RouteEntry {
+            dst: Ipv4Addr::UNSPECIFIED,
+            mask: Ipv4Addr::UNSPECIFIED,
+            gateway: Some(dev.gateway),
+            ifname: dev.name.clone(),
+            metric: devtbl.len() as u32,
+        });
+
+        devtbl.push(dev.clone());
+
+        dev
+    }
+
+    /// Drive the stack by a background input thread, it runs forever. It
+    /// fails if the stack is driven by a `Reactor`.
+    pub fn spawn(self: &Arc<Self>) -> io::Result<JoinHandle<()>> {
+        let dev = self.clone();
+
+        timer_start()?;
+
+        thread::Builder::new()
+            .name(format!("input-{}", dev.name))
+            .spawn(move || {
+                loop {
+                    if let Err(err) = dev.input() {
+                        warn!("{}: {err:#}", dev.name);
+                    }
+                }
+            })
+    }
+
+    /// Handle a frame, or a batch of ready ones by rings or `recvmmsg`.
+    /// It blocks if nothing is ready.
     pub fn input(&self) -> anyhow::Result<()> {
+        self.input_with(true)
+    }
+
+    /// Like `input` on readiness of the socket, rings return at once if
+    /// the ready block has been handled by the last batch
+    pub fn input_ready(&self) -> anyhow::Result<()> {
+        self.input_with(false)
+    }
+
+    fn input_with(&self, wait: bool) -> anyhow::Result<()> {
+        match &self.io {
+            DevIo::Plain => (),
+            DevIo::Ring(ring) => return self.ring_input(ring, wait),
+            DevIo::Mmsg(mmsg) => return self.mmsg_input(mmsg),
+        }
+
         // ethernet frame
         let mut ef: [u8; Eth::FRAME_LEN] = unsafe { core::mem::zeroed() };
 
         let readn = read(self.sd.as_fd(), &mut ef, Eth::FRAME_LEN)?;
 
-        let data = RawBuf::new_from_slice(&ef[..readn]);
+        self.frame_input(&ef[..readn], clock_wall())
+    }
+
+    /// Errors of frames in blocks don't stop the batch, wait for a block
+    /// if none is ready and `wait`
+    fn ring_input(&self, ring: &PacketRing, wait: bool) -> anyhow::Result<()> {
+        let mut handle = |frame: &[u8], ts| {
+            if let Err(err) = self.frame_input(frame, ts) {
+                warn!("{}: {err:#}", self.name);
+            }
+        };
+
+        if ring.rx_batch(&mut handle) == 0 && wait {
+            ring.rx_wait()?;
+            ring.rx_batch(&mut handle);
+        }
+
+        Ok(())
+    }
+
+    fn mmsg_input(&self, mmsg: &MmsgIo) -> anyhow::Result<()> {
+        mmsg.recv_batch(|frame, ts| {
+            if let Err(err) = self.frame_input(frame, ts) {
+                warn!("{}: {err:#}", self.name);
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// `ts` is the time it's received
+    fn frame_input(
+        &self,
+        frame: &[u8],
+        ts: SystemTime,
+    ) -> anyhow::Result<()> {
+        self.stats.rx_packets.inc();
+        self.stats.rx_bytes.add(frame.len() as u64);
+
+        let res = self.eth_input(frame);
+
+        // recorded after handling for the verdict, with time of reading
+        if capture_enabled() {
+            let reason = match &res {
+                Ok(reason) => reason.clone(),
+                Err(err) => Some(format!("{err:#}")),
+            };
+
+            capture_frame(
+                &self.name,
+                Direction::In,
+                ts,
+                frame,
+                reason.as_deref(),
+            );
+        }
+
+        res.map(|_| ())
+    }
+
+    /// Return the reason if the frame is filtered
+    fn eth_input(&self, frame: &[u8]) -> anyhow::Result<Option<String>> {
+        // copied once, `RawBufRef` of m6io only refers to an owned `RawBuf`
+        let data = RawBuf::new_from_slice(frame);
 
         let mut dataref = data.to_ref();
 
@@ -146,46 +469,318 @@ impl NetDevice {
 
         if ethh.dst != Mac::BROADCAST && ethh.dst != self.hwa {
             trace!("Filter Ethernet Frame from {:?}", ethh.dst);
-            return Ok(());
+            return Ok(Some("not addressed to us".to_owned()));
         }
 
         let EthProtoKind::EthType(eth_type_spec) = ethh.proto.into_kind()
         else {
-            match ethh.proto.into_kind() {
+            let reason = match ethh.proto.into_kind() {
                 EthProtoKind::Len(_) => {
                     warn!("Found Legacy 802.3 Ethernet Frame");
+                    "legacy 802.3 frame".to_owned()
                 }
                 EthProtoKind::Undefined(x) => {
                     warn!("Undefined EthProto {x}");
+                    format!("undefined EthProto {x}")
                 }
                 _ => unreachable!(),
-            }
-            return Ok(());
+            };
+            return Ok(Some(reason));
         };
 
         match eth_type_spec {
             OSIEtHTypeKind::IPv4 => {
                 if dataref.rem_len() < size_of::<IPv4>() {
+                    SNMP_IP.in_receives.inc();
+                    SNMP_IP.in_hdr_errors.inc();
                     Err(anyhow!("Uncomplete IPv4 header"))?
                 }
 
                 let iph = dataref.cast::<IPv4>().read_unaligned();
 
-                ARP_TBL.write().unwrap().insert(iph.src.into(), ethh.src);
-
                 trace!("Incomming Network IPv4 handled {:?}", iph.src);
 
                 /* ip input */
+                return self.ip_input(skb);
             }
             OSIEtHTypeKind::ARP => {
                 /* arp input */
                 self.arp_input(skb)?;
             }
             _ => {
-                trace!("Found {eth_type_spec:?} package, skip")
+                trace!("Found {eth_type_spec:?} package, skip");
+                return Ok(Some(format!("unsupported {eth_type_spec:?}")));
             }
         }
 
-        Ok(())
+        Ok(None)
+    }
+}
+
+
+impl FromStr for DevIoKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "plain" => Self::Plain,
+            "ring" => Self::Ring,
+            "mmsg" => Self::Mmsg,
+            _ => Err(anyhow!("Unknown device I/O {s}"))?,
+        })
+    }
+}
+
+impl Display for DevIoKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Plain => "plain",
+                Self::Ring => "ring",
+                Self::Mmsg => "mmsg",
+            }
+        )
+    }
+}
+
+
+/// Raw socket of all protocols bound to `ifname`, with the link address
+/// of it
+pub fn packet_open(
+    ifname: &str,
+) -> anyhow::Result<(OwnedFd, SockAddrLL, Mac)> {
+    let sd = socket(
+        AddressFamily::PACKET,
+        SocketType::RAW,
+        Default::default(),
+        EthTypeKind::ALL.into(),
+    )?;
+
+    let Some((ifindex, hwa)) = get_ifaddrtbl()?.iter().find_map(|ifaddr| {
+        if let IfAddr::Packet {
+            name,
+            ifindex,
+            addr,
+            ..
+        } = ifaddr
+            && name == ifname
+        {
+            Some((*ifindex, *addr))
+        }
+        else {
+            None
+        }
+    })
+    else {
+        Err(anyhow::anyhow!("no ifname `{ifname}` found"))?
+    };
+
+    let to = SockAddrLL {
+        family: SaFamily::Packet,
+        protocol: EthTypeKind::ARP.into(),
+        ifindex,
+        hatype: HTypeKind::Ethernet10Mb.into(),
+        pkttype: PktType::Host,
+        halen: size_of::<Mac>() as u8,
+        addr: hwa.into(),
+    };
+
+    bind(sd.as_fd(), to.into())?;
+
+    Ok((sd, to, hwa))
+}
+
+pub fn dev_get_by_name(name: &str) -> Option<Arc<NetDevice>> {
+    DEV_TBL
+        .read()
+        .unwrap()
+        .iter()
+        .find(|dev| dev.name == name)
+        .cloned()
+}
+
+/// Is `ip` addressed to this host, including broadcast
+pub fn is_local_addr(ip: Ipv4Addr) -> bool {
+    ip.is_broadcast()
+        || DEV_TBL
+            .read()
+            .unwrap()
+            .iter()
+            .any(|dev| dev.has_addr(ip) || dev.broadcast() == ip)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        net::SocketAddrV4,
+        os::unix::net::UnixDatagram,
+        sync::{
+            Once,
+            atomic::{AtomicU8, Ordering},
+        },
+        time::Duration,
+    };
+
+    use m6tobytes::from_raw_slice;
+    use osimodel::network::ip::ProtocolKind;
+
+    use super::*;
+    use crate::{
+        arp::{ARP_TBL, ARPLIVE},
+        ip::tests::ipv4_packet,
+        ring::tests::{ring_anon, ring_fill},
+        udp::{tests::udp_segment, udp_bind, udp_unbind},
+    };
+
+    static DEV_SEQ: AtomicU8 = AtomicU8::new(0);
+
+    static ARP_ONCE: Once = Once::new();
+
+    /// Register device `testN` of 10.N.0.1/24 over a datagram socket pair,
+    /// the returned peer reads frames it sends and writes frames to it
+    pub(crate) fn dev_pair() -> (Arc<NetDevice>, UnixDatagram) {
+        dev_pair_with(DevIo::Plain)
+    }
+
+    /// Like `dev_pair` with frames read and sent by `io`
+    pub(crate) fn dev_pair_with(io: DevIo) -> (Arc<NetDevice>, UnixDatagram) {
+        let n = DEV_SEQ.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sd, peer) = UnixDatagram::pair().unwrap();
+        let hwa = dev_hwa(n, 1);
+
+        // room for neighbours of all tests run at the same time
+        ARP_ONCE
+            .call_once(|| ARP_TBL.write().unwrap().configure(256, ARPLIVE));
+
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let dev = NetDevice {
+            name: format!("test{n}"),
+            ip: Ipv4Addr::new(10, n, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::new(10, n, 0, 254),
+            hwt: get_ifhwaddr("lo").unwrap().ty,
+            hwa,
+            mtu: 1500,
+            secondary: RwLock::new(Vec::new()),
+            sd: sd.into(),
+            to: SockAddrLL {
+                family: SaFamily::Packet,
+                protocol: EthTypeKind::ARP.into(),
+                ifindex: 0,
+                hatype: HTypeKind::Ethernet10Mb.into(),
+                pkttype: PktType::Host,
+                halen: size_of::<Mac>() as u8,
+                addr: hwa.into(),
+            },
+            stats: DevStats::default(),
+            io,
+        };
+
+        (dev.register(), peer)
+    }
+
+    /// Link address of host `host` on the network of device `testN`
+    pub(crate) fn dev_hwa(n: u8, host: u8) -> Mac {
+        from_raw_slice::<Mac>(&[0x02, 0, 0, 0, n, host])
+    }
+
+    /// Host `host` on the network of `dev`, resolved already
+    pub(crate) fn neigh_add(dev: &NetDevice, host: u8) -> (Ipv4Addr, Mac) {
+        let [_, n, ..] = dev.ip.octets();
+        let ip = Ipv4Addr::new(10, n, 0, host);
+        let mac = dev_hwa(n, host);
+
+        ARP_TBL.write().unwrap().insert(ip, mac);
+
+        (ip, mac)
+    }
+
+    /// Frame IPv4 `pkt` from `src` to `dst` through `peer`, then `dev`
+    /// handles it
+    pub(crate) fn frame_input(
+        dev: &NetDevice,
+        peer: &UnixDatagram,
+        src: Mac,
+        dst: Mac,
+        pkt: &[u8],
+    ) -> anyhow::Result<()> {
+        let ethh = Eth {
+            dst,
+            src,
+            proto: OSIEtHTypeKind::IPv4.into(),
+        };
+
+        peer.send(&[as_raw_slice(&ethh), pkt].concat()).unwrap();
+
+        dev.input()
+    }
+
+    /// Next frame sent by device with IPv4 padding stripped off, none if
+    /// it sends nothing in a second
+    pub(crate) fn frame_output(peer: &UnixDatagram) -> Option<(Eth, Vec<u8>)> {
+        let mut frame = [0; Eth::FRAME_LEN];
+        let n = peer.recv(&mut frame).ok()?;
+        let ethh = from_raw_slice::<Eth>(&frame);
+        let mut pkt = frame[size_of::<Eth>()..n].to_vec();
+
+        if matches!(
+            ethh.proto.into_kind(),
+            EthProtoKind::EthType(OSIEtHTypeKind::IPv4)
+        ) {
+            pkt.truncate(u16::from_be_bytes([pkt[2], pkt[3]]) as usize);
+        }
+
+        Some((ethh, pkt))
+    }
+
+    #[test]
+    fn test_dev_ring_input() {
+        let (dev, _peer) = dev_pair_with(DevIo::Ring(ring_anon(2)));
+        let DevIo::Ring(ring) = &dev.io
+        else {
+            unreachable!()
+        };
+        let (host, mac) = neigh_add(&dev, 2);
+        let (key, pcb) = udp_bind(SocketAddrV4::new(dev.ip, 0)).unwrap();
+        let from = SocketAddrV4::new(host, 53);
+        let frame = |dst: Mac, data: &[u8]| {
+            let ethh = Eth {
+                dst,
+                src: mac,
+                proto: OSIEtHTypeKind::IPv4.into(),
+            };
+            let seg = udp_segment(from, key.local, data);
+            let pkt = ipv4_packet(ProtocolKind::UDP, host, dev.ip, &seg);
+
+            [as_raw_slice(&ethh), &pkt].concat()
+        };
+        let frames = [
+            frame(dev.hwa, b"one"),
+            frame(dev_hwa(0, 9), b"lost"),
+            frame(dev.hwa, b"two"),
+        ];
+
+        // nothing is ready and it doesn't wait
+        dev.input_ready().unwrap();
+
+        ring_fill(ring, 0, &[&frames[0], &frames[1]], 1);
+        ring_fill(ring, 1, &[&frames[2]], 1);
+
+        dev.input_ready().unwrap();
+
+        let rxq = pcb.rxq.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+        assert_eq!(rxq, [(from, b"one".to_vec()), (from, b"two".to_vec())]);
+        assert_eq!(dev.stats.rx_packets.get(), 3);
+        assert_eq!(
+            dev.stats.rx_bytes.get(),
+            frames.iter().map(|frame| frame.len() as u64).sum::<u64>()
+        );
+
+        udp_unbind(&key);
     }
 }
@@ -1,27 +1,58 @@
 use std::os::fd::AsFd;
 
+use anyhow::anyhow;
 use linuxc::socket::sendto;
 use log::trace;
-use m6ptr::Ptr;
+use m6ptr::{OwnedPtr, Ptr};
+use m6tobytes::as_raw_slice;
+use osimodel::datalink::{Eth, EthTypeKind, Mac};
 
-use crate::{dev::NetDevice, skbuff::SkBuff};
+use crate::{
+    capture::{capture_enabled, capture_frame},
+    clock::clock_wall,
+    dev::{DevIo, NetDevice},
+    pcapng::Direction,
+    skbuff::SkBuff,
+};
 
 
 
 impl NetDevice {
     pub fn linkoutput(&self, skb: Ptr<SkBuff>) -> anyhow::Result<()> {
+        if !matches!(self.io, DevIo::Plain) {
+            return self.batch_output(skb);
+        }
+
         let mut skb = skb;
 
         loop {
-            let n = sendto(
+            let frame = skb.phy.get().unwrap().cur_slice();
+            let res = sendto(
                 self.sd.as_fd(),
-                skb.phy.get().unwrap().cur_slice(),
+                frame,
                 Default::default(),
                 Default::default(),
-            )?;
+            );
+
+            if capture_enabled() {
+                let reason = res.as_ref().err().map(|err| format!("{err}"));
+
+                capture_frame(
+                    &self.name,
+                    Direction::Out,
+                    clock_wall(),
+                    frame,
+                    reason.as_deref(),
+                );
+            }
+
+            let n = res.inspect_err(|_| self.stats.tx_errors.inc())?;
 
             trace!("linkoutput send {n} bytes");
 
+            self.stats.tx_packets.inc();
+            self.stats.tx_bytes.add(n as u64);
+
             if let Some(next) = skb.next.as_ref() {
                 skb = next.ptr();
             }
@@ -32,4 +63,102 @@ impl NetDevice {
 
         Ok(())
     }
+
+    /// Submit the whole chain by one kick of the TX ring or as few
+    /// `sendmmsg` as possible, frames which don't fit into free ring
+    /// slots are dropped
+    fn batch_output(&self, skb: Ptr<SkBuff>) -> anyhow::Result<()> {
+        let mut skbs = vec![skb];
+
+        while let Some(next) = skbs.last().unwrap().next.as_ref() {
+            let next = next.ptr();
+
+            skbs.push(next);
+        }
+
+        let frames = skbs
+            .iter()
+            .map(|skb| skb.phy.get().unwrap().cur_slice())
+            .collect::<Vec<_>>();
+
+        let (sent, res) = match &self.io {
+            DevIo::Ring(ring) => {
+                let res = ring.tx_batch(&frames);
+
+                // frames of earlier batches
+                self.stats.tx_errors.add(ring.take_tx_rejected());
+
+                match res {
+                    Ok(sent) if sent < frames.len() => {
+                        (sent, Err(anyhow!("TX ring full")))
+                    }
+                    Ok(sent) => (sent, Ok(())),
+                    Err(err) => (0, Err(err.into())),
+                }
+            }
+            DevIo::Mmsg(mmsg) => {
+                let (sent, res) = mmsg.send_batch(&frames);
+
+                (sent, res.map_err(Into::into))
+            }
+            DevIo::Plain => unreachable!(),
+        };
+
+        if capture_enabled() {
+            let ts = clock_wall();
+            let reason = res.as_ref().err().map(|err| format!("{err}"));
+
+            for (i, frame) in frames.iter().enumerate() {
+                // frames after the sent ones are dropped by the error
+                let reason = reason.as_deref().filter(|_| i >= sent);
+
+                capture_frame(&self.name, Direction::Out, ts, frame, reason);
+            }
+        }
+
+        for frame in &frames[..sent] {
+            self.stats.tx_packets.inc();
+            self.stats.tx_bytes.add(frame.len() as u64);
+        }
+
+        self.stats.tx_errors.add((frames.len() - sent) as u64);
+
+        trace!("linkoutput send {sent}/{} frames", frames.len());
+
+        res
+    }
+
+    /// Frame each of `pkts` to `dst` and send them as one `SkBuff` chain
+    pub fn eth_output(
+        &self,
+        dst: Mac,
+        proto: EthTypeKind,
+        pkts: Vec<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let ethh = Eth {
+            dst,
+            src: self.hwa,
+            proto: proto.into(),
+        };
+
+        let Some(skb) = SkBuff::from_chain(pkts.into_iter().map(|pkt| {
+            let mut frame = Vec::with_capacity(size_of::<Eth>() + pkt.len());
+
+            frame.extend_from_slice(as_raw_slice(&ethh));
+            frame.extend_from_slice(&pkt);
+
+            if frame.len() < Eth::ZLEN {
+                frame.resize(Eth::ZLEN, 0);
+            }
+
+            SkBuff::new_from_slice(&frame)
+        }))
+        else {
+            return Ok(());
+        };
+
+        let owned = OwnedPtr::new(skb);
+
+        self.linkoutput(owned.ptr())
+    }
 }
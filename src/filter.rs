@@ -0,0 +1,367 @@
+use std::{fmt::Display, net::Ipv4Addr, str::FromStr};
+
+use anyhow::anyhow;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+pub const ETH_P_8021Q: u16 = 0x8100;
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+/// Ethernet header
+const ETH_HLEN: usize = 14;
+/// 802.1Q tag
+const VLAN_HLEN: usize = 4;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Filter expression like a small subset of pcap-filter(7):
+///
+/// ```text
+/// expr  := term ("or" term)*
+/// term  := factor ("and" factor)*
+/// factor := "not" factor | "(" expr ")" | "host" ADDR | "port" PORT
+///     | "ether" "dst" MAC | "vlan" | "arp" | "ip" | "icmp" | "udp" | "tcp"
+/// ```
+///
+/// One 802.1Q tag is skipped, `host` matches addresses of IPv4 and ARP,
+/// `port` matches the first fragment of TCP and UDP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Proto(FilterProto),
+    EtherDst([u8; 6]),
+    Host(Ipv4Addr),
+    Port(u16),
+    Not(Box<Self>),
+    And(Box<Self>, Box<Self>),
+    Or(Box<Self>, Box<Self>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterProto {
+    Vlan,
+    Arp,
+    Ip,
+    Icmp,
+    Udp,
+    Tcp,
+}
+
+/// Headers of Ethernet frame located for matching
+struct FrameView<'a> {
+    dst: &'a [u8],
+    vlan: bool,
+    ethertype: u16,
+    /// From network header
+    l3: &'a [u8],
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl Filter {
+    /// Does Ethernet `frame` match it, truncated headers don't match
+    pub fn matches(&self, frame: &[u8]) -> bool {
+        match FrameView::new(frame) {
+            Some(view) => self.eval(&view),
+            None => false,
+        }
+    }
+
+    fn eval(&self, view: &FrameView) -> bool {
+        match self {
+            Self::Proto(proto) => view.is_proto(*proto),
+            Self::EtherDst(mac) => view.dst == mac,
+            Self::Host(host) => view.has_host(*host),
+            Self::Port(port) => view.has_port(*port),
+            Self::Not(filter) => !filter.eval(view),
+            Self::And(lhs, rhs) => lhs.eval(view) && rhs.eval(view),
+            Self::Or(lhs, rhs) => lhs.eval(view) || rhs.eval(view),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spaced = s.replace('(', " ( ").replace(')', " ) ");
+        let mut parser = Parser {
+            tokens: spaced.split_whitespace().collect(),
+            pos: 0,
+        };
+
+        let filter = parser.expr()?;
+
+        if let Some(token) = parser.peek() {
+            Err(anyhow!("unexpected `{token}`"))?
+        }
+
+        Ok(filter)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Proto(proto) => write!(f, "{proto}"),
+            Self::EtherDst(mac) => {
+                let mac = mac.map(|b| format!("{b:02x}")).join(":");
+
+                write!(f, "ether dst {mac}")
+            }
+            Self::Host(host) => write!(f, "host {host}"),
+            Self::Port(port) => write!(f, "port {port}"),
+            Self::Not(filter) => match **filter {
+                Self::And(..) | Self::Or(..) => write!(f, "not ({filter})"),
+                _ => write!(f, "not {filter}"),
+            },
+            Self::And(lhs, rhs) => {
+                for (i, side) in [lhs, rhs].into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " and ")?;
+                    }
+
+                    match **side {
+                        Self::Or(..) => write!(f, "({side})")?,
+                        _ => write!(f, "{side}")?,
+                    }
+                }
+
+                Ok(())
+            }
+            Self::Or(lhs, rhs) => write!(f, "{lhs} or {rhs}"),
+        }
+    }
+}
+
+impl FromStr for FilterProto {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "vlan" => Self::Vlan,
+            "arp" => Self::Arp,
+            "ip" => Self::Ip,
+            "icmp" => Self::Icmp,
+            "udp" => Self::Udp,
+            "tcp" => Self::Tcp,
+            _ => Err(anyhow!("unknown protocol `{s}`"))?,
+        })
+    }
+}
+
+impl Display for FilterProto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Vlan => "vlan",
+                Self::Arp => "arp",
+                Self::Ip => "ip",
+                Self::Icmp => "icmp",
+                Self::Udp => "udp",
+                Self::Tcp => "tcp",
+            }
+        )
+    }
+}
+
+impl FilterProto {
+    /// Protocol number of IPv4
+    pub fn ip_proto(&self) -> Option<u8> {
+        match self {
+            Self::Icmp => Some(IPPROTO_ICMP),
+            Self::Udp => Some(IPPROTO_UDP),
+            Self::Tcp => Some(IPPROTO_TCP),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FrameView<'a> {
+    fn new(frame: &'a [u8]) -> Option<Self> {
+        let mut ethertype = be16(frame, ETH_HLEN - 2)?;
+        let mut off = ETH_HLEN;
+        let vlan = ethertype == ETH_P_8021Q;
+
+        if vlan {
+            ethertype = be16(frame, off + 2)?;
+            off += VLAN_HLEN;
+        }
+
+        Some(Self {
+            dst: &frame[..6],
+            vlan,
+            ethertype,
+            l3: &frame[off..],
+        })
+    }
+
+    fn is_proto(&self, proto: FilterProto) -> bool {
+        match proto {
+            FilterProto::Vlan => self.vlan,
+            FilterProto::Arp => self.ethertype == ETH_P_ARP,
+            FilterProto::Ip => self.ip_proto().is_some(),
+            _ => self.ip_proto() == proto.ip_proto(),
+        }
+    }
+
+    fn ip_proto(&self) -> Option<u8> {
+        if self.ethertype != ETH_P_IP {
+            return None;
+        }
+
+        self.l3.get(9).copied()
+    }
+
+    fn has_host(&self, host: Ipv4Addr) -> bool {
+        // (source, destination) of IPv4 and ARP
+        let offs = match self.ethertype {
+            ETH_P_IP => [12, 16],
+            ETH_P_ARP => [14, 24],
+            _ => return false,
+        };
+
+        offs.into_iter()
+            .any(|off| be32(self.l3, off) == Some(host.to_bits()))
+    }
+
+    fn has_port(&self, port: u16) -> bool {
+        if !matches!(self.ip_proto(), Some(IPPROTO_TCP | IPPROTO_UDP)) {
+            return false;
+        }
+
+        // not the first fragment
+        if be16(self.l3, 6).is_none_or(|off| off & 0x1fff != 0) {
+            return false;
+        }
+
+        let l4 = (self.l3[0] & 0x0f) as usize * 4;
+
+        [l4, l4 + 2]
+            .into_iter()
+            .any(|off| be16(self.l3, off) == Some(port))
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> anyhow::Result<&'a str> {
+        let token = self
+            .peek()
+            .ok_or_else(|| anyhow!("unexpected end of filter"))?;
+
+        self.pos += 1;
+
+        Ok(token)
+    }
+
+    fn expr(&mut self) -> anyhow::Result<Filter> {
+        let mut lhs = self.term()?;
+
+        while self.peek() == Some("or") {
+            self.pos += 1;
+            lhs = Filter::Or(Box::new(lhs), Box::new(self.term()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> anyhow::Result<Filter> {
+        let mut lhs = self.factor()?;
+
+        while self.peek() == Some("and") {
+            self.pos += 1;
+            lhs = Filter::And(Box::new(lhs), Box::new(self.factor()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn factor(&mut self) -> anyhow::Result<Filter> {
+        Ok(match self.next()? {
+            "not" => Filter::Not(Box::new(self.factor()?)),
+            "(" => {
+                let filter = self.expr()?;
+
+                if self.next()? != ")" {
+                    Err(anyhow!("`(` isn't closed"))?
+                }
+
+                filter
+            }
+            "host" => {
+                let host = self.next()?;
+
+                Filter::Host(
+                    host.parse()
+                        .map_err(|_| anyhow!("invalid host `{host}`"))?,
+                )
+            }
+            "ether" => {
+                if self.next()? != "dst" {
+                    Err(anyhow!("`ether` should be followed by `dst`"))?
+                }
+
+                let mac = self.next()?;
+
+                Filter::EtherDst(
+                    parse_mac(mac)
+                        .ok_or_else(|| anyhow!("invalid MAC `{mac}`"))?,
+                )
+            }
+            "port" => {
+                let port = self.next()?;
+
+                Filter::Port(
+                    port.parse()
+                        .map_err(|_| anyhow!("invalid port `{port}`"))?,
+                )
+            }
+            token => Filter::Proto(token.parse()?),
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Like `aa:bb:cc:dd:ee:ff`
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut parts = s.split(':');
+
+    for b in mac.iter_mut() {
+        *b = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    parts.next().is_none().then_some(mac)
+}
+
+fn be16(buf: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        buf.get(off..off + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn be32(buf: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        buf.get(off..off + 4)?.try_into().unwrap(),
+    ))
+}
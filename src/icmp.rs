@@ -0,0 +1,667 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use libc::{
+    ECONNREFUSED, EHOSTUNREACH, EMSGSIZE, ENETUNREACH, ENOPROTOOPT,
+    EOPNOTSUPP, EPROTO,
+};
+use log::trace;
+use m6ptr::LazyStatic;
+use m6tobytes::{as_raw_slice, from_raw_slice};
+use osimodel::network::{
+    InetCkSum,
+    icmp::{ICMP, ICMPCode, ICMPTypeKind},
+    inet_cksum,
+    ip::{IPv4, ProtocolKind},
+};
+
+use crate::{
+    clock::clock_now,
+    dev::{NetDevice, is_local_addr},
+    ip::{IP_OFFMASK, ip_hdrlen, ip_output, ip_split},
+    route::ip_rt_update_pmtu,
+    snmp::SNMP_ICMP,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/* Codes for Destination Unreachable */
+
+pub const ICMP_NET_UNREACH: u8 = 0;
+pub const ICMP_HOST_UNREACH: u8 = 1;
+pub const ICMP_PROT_UNREACH: u8 = 2;
+pub const ICMP_PORT_UNREACH: u8 = 3;
+pub const ICMP_FRAG_NEEDED: u8 = 4;
+pub const ICMP_SR_FAILED: u8 = 5;
+
+/* Codes for Redirect */
+
+pub const ICMP_REDIR_NET: u8 = 0;
+pub const ICMP_REDIR_HOST: u8 = 1;
+
+/* Codes for Time Exceeded */
+
+pub const ICMP_EXC_TTL: u8 = 0;
+pub const ICMP_EXC_FRAGTIME: u8 = 1;
+
+/// Quote as much of the original datagram as fits (RFC 1812 4.3.2.3)
+pub const ICMP_ERR_MAX_LEN: usize = 576;
+
+/// Quoted bytes of the original datagram beyond its IP header at least
+pub const ICMP_QUOTE_LEN: usize = 8;
+
+/// Interval of error messages to the same destination
+pub const ICMP_RATELIMIT: Duration = Duration::from_millis(1000);
+/// Burst of error messages in unit of `ICMP_RATELIMIT`
+pub const ICMP_RATE_BURST: u32 = 6;
+pub const ICMP_RATE_TBL_SZ: usize = 256;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+pub static ICMP_RATE_TBL: LazyStatic<HashMap<Ipv4Addr, ICMPRateLimit>> =
+    LazyStatic::new(|| HashMap::with_capacity(ICMP_RATE_TBL_SZ));
+
+/// Transport protocols who want to know errors of their datagrams, they
+/// register themselves by `icmp_register_err_handler`
+pub static ICMP_ERR_TBL: LazyStatic<Vec<(ProtocolKind, ICMPErrHandler)>> =
+    LazyStatic::new(Vec::new);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Token bucket measured in time, refilled as time goes
+#[derive(Debug, Clone, Copy)]
+pub struct ICMPRateLimit {
+    pub tokens: Duration,
+    pub last: Instant,
+}
+
+/// ICMP error about a datagram we sent, for transport protocol
+#[derive(Debug, Clone)]
+pub struct ICMPErr {
+    pub ty: ICMPTypeKind,
+    pub code: u8,
+    /// Linux errno reported to socket
+    pub errno: i32,
+    /// Next-hop MTU of Fragmentation Needed
+    pub mtu: Option<u16>,
+    pub proto: ProtocolKind,
+    /// Addresses of the original datagram, `src` is local
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    /// Quoted transport header, 8 bytes at least (ports of UDP/TCP)
+    pub quote: Vec<u8>,
+}
+
+pub type ICMPErrHandler = fn(&ICMPErr);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl ICMPErr {
+    pub fn to_io_error(&self) -> io::Error {
+        io::Error::from_raw_os_error(self.errno)
+    }
+
+    /// Parameter Problem and Time Exceeded aren't fatal to connection
+    pub fn is_fatal(&self) -> bool {
+        self.ty == ICMPTypeKind::DestinationUnreachable
+            && self.code != ICMP_FRAG_NEEDED
+    }
+}
+
+impl ICMPRateLimit {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            tokens: ICMP_RATELIMIT * ICMP_RATE_BURST,
+            last: now,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        self.tokens = (self.tokens + now.duration_since(self.last))
+            .min(ICMP_RATELIMIT * ICMP_RATE_BURST);
+        self.last = now;
+
+        if self.tokens >= ICMP_RATELIMIT {
+            self.tokens -= ICMP_RATELIMIT;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    pub fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.last)
+            >= ICMP_RATELIMIT * ICMP_RATE_BURST
+    }
+}
+
+impl NetDevice {
+    /// Return the reason if the message is dropped
+    pub fn icmp_input(&self, pkt: &[u8]) -> anyhow::Result<Option<String>> {
+        let (iph, msg) = ip_split(pkt);
+
+        if msg.len() < size_of::<ICMP>() {
+            trace!("Uncomplete ICMP message from {:?}", iph.src);
+            SNMP_ICMP.in_errors.inc();
+            return Ok(Some("uncomplete ICMP message".to_owned()));
+        }
+
+        if inet_cksum(msg) != 0 {
+            trace!("ICMP verify cksum failed from {:?}", iph.src);
+            SNMP_ICMP.in_errors.inc();
+            SNMP_ICMP.in_csum_errors.inc();
+            return Ok(Some("bad ICMP cksum".to_owned()));
+        }
+
+        let icmph = from_raw_slice::<ICMP>(msg);
+        let ty: ICMPTypeKind = icmph.ty.into();
+
+        SNMP_ICMP.count_in(msg[0]);
+
+        trace!("Incomming ICMP {ty:?}/{:?} from {:?}", icmph.code, iph.src);
+
+        match ty {
+            ICMPTypeKind::EchoRequest => self.icmp_echo(pkt)?,
+            ICMPTypeKind::DestinationUnreachable
+            | ICMPTypeKind::TimeExceeded
+            | ICMPTypeKind::BadParam => self.icmp_unreach(pkt)?,
+            _ => (),
+        }
+
+        Ok(None)
+    }
+
+    /// Match the quoted datagram back to transport protocol
+    fn icmp_unreach(&self, pkt: &[u8]) -> anyhow::Result<()> {
+        let (_iph, msg) = ip_split(pkt);
+        let icmph = from_raw_slice::<ICMP>(msg);
+        let ty: ICMPTypeKind = icmph.ty.into();
+        let code = icmph.code.to_bits();
+        let quoted = &msg[size_of::<ICMP>()..];
+
+        if quoted.len() < size_of::<IPv4>() {
+            trace!("ICMP {ty:?} quotes uncomplete IPv4 header");
+            return Ok(());
+        }
+
+        let qiph = from_raw_slice::<IPv4>(quoted);
+        let qhlen = ip_hdrlen(&qiph);
+
+        if qhlen < size_of::<IPv4>() || quoted.len() < qhlen + ICMP_QUOTE_LEN {
+            trace!("ICMP {ty:?} quotes too short");
+            return Ok(());
+        }
+
+        let src: Ipv4Addr = qiph.src.into();
+        let dst: Ipv4Addr = qiph.dst.into();
+
+        if !is_local_addr(src) {
+            trace!("ICMP {ty:?} about {src} -> {dst} isn't ours");
+            return Ok(());
+        }
+
+        let Some(errno) = icmp_err_convert(ty, code)
+        else {
+            return Ok(());
+        };
+
+        let mut mtu = None;
+
+        if ty == ICMPTypeKind::DestinationUnreachable
+            && code == ICMP_FRAG_NEEDED
+        {
+            let pmtu = ip_rt_update_pmtu(
+                dst,
+                icmp_un_to_mtu(icmph.un),
+                qiph.totlen.tot_len(),
+            );
+
+            trace!("PMTU of {dst} is {pmtu}");
+
+            mtu = Some(pmtu);
+        }
+
+        let err = ICMPErr {
+            ty,
+            code,
+            errno,
+            mtu,
+            proto: qiph.proto.into(),
+            src,
+            dst,
+            quote: quoted[qhlen..].to_vec(),
+        };
+
+        trace!("Deliver ICMP error {err:?}");
+
+        let handler = ICMP_ERR_TBL
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(proto, _)| *proto == err.proto)
+            .map(|(_, handler)| *handler);
+
+        if let Some(handler) = handler {
+            handler(&err);
+        }
+
+        Ok(())
+    }
+
+    /// Reply Echo Request with the same identifier, sequence and data
+    fn icmp_echo(&self, pkt: &[u8]) -> anyhow::Result<()> {
+        let (iph, msg) = ip_split(pkt);
+        let src: Ipv4Addr = iph.src.into();
+        let dst: Ipv4Addr = iph.dst.into();
+
+        if dst.is_broadcast() || dst == self.broadcast() {
+            trace!("Ignore broadcast Echo Request from {src}");
+            return Ok(());
+        }
+
+        let icmph = from_raw_slice::<ICMP>(msg);
+        let reply = icmp_pack(
+            ICMPTypeKind::EchoReply,
+            0,
+            icmph.un,
+            &msg[size_of::<ICMP>()..],
+        );
+
+        icmp_output(&reply, dst, src)
+    }
+
+    /// Send ICMP error about `orig` datagram received from this device.
+    ///
+    /// `un` is the second word of ICMP header, e.g. the gateway of Redirect.
+    pub fn icmp_send(
+        &self,
+        orig: &[u8],
+        ty: ICMPTypeKind,
+        code: u8,
+        un: u32,
+    ) -> anyhow::Result<()> {
+        let Some(msg) = self.icmp_error(orig, ty, code, un)
+        else {
+            return Ok(());
+        };
+
+        let src: Ipv4Addr = from_raw_slice::<IPv4>(orig).src.into();
+
+        trace!("Output ICMP {ty:?}/{code} to {src}");
+
+        icmp_output(&msg, self.ip, src)
+    }
+
+    /// ICMP error message quoting `orig`, none if it's suppressed (RFC 1812
+    /// 4.3.2.7) or rate limited
+    fn icmp_error(
+        &self,
+        orig: &[u8],
+        ty: ICMPTypeKind,
+        code: u8,
+        un: u32,
+    ) -> Option<Vec<u8>> {
+        let iph = from_raw_slice::<IPv4>(orig);
+        let hlen = ip_hdrlen(&iph);
+        let src: Ipv4Addr = iph.src.into();
+        let dst: Ipv4Addr = iph.dst.into();
+
+        /* RFC 1812 4.3.2.7 */
+
+        if iph.flags_off.to_bits() & IP_OFFMASK != 0 {
+            trace!("No ICMP error for non-first fragment from {src}");
+            return None;
+        }
+
+        if src.is_unspecified()
+            || src.is_broadcast()
+            || src.is_multicast()
+            || dst.is_broadcast()
+            || dst.is_multicast()
+            || dst == self.broadcast()
+        {
+            trace!("No ICMP error for {src} -> {dst}");
+            return None;
+        }
+
+        if ProtocolKind::ICMP == iph.proto.into()
+            && orig.len() >= hlen + size_of::<ICMP>()
+            && icmp_is_err(from_raw_slice::<ICMP>(&orig[hlen..]).ty.into())
+        {
+            trace!("No ICMP error for ICMP error from {src}");
+            return None;
+        }
+
+        // PMTU discovery depends on it
+        let is_frag_needed = ty == ICMPTypeKind::DestinationUnreachable
+            && code == ICMP_FRAG_NEEDED;
+
+        if !is_frag_needed && !icmp_xrlim_allow(src) {
+            trace!("ICMP rate limited to {src}");
+            return None;
+        }
+
+        let quote_len =
+            (ICMP_ERR_MAX_LEN - size_of::<IPv4>() - size_of::<ICMP>())
+                .max(hlen + ICMP_QUOTE_LEN);
+
+        let quote = &orig[..orig.len().min(quote_len)];
+
+        Some(icmp_pack(ty, code, un, quote))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Send packed ICMP `msg`
+fn icmp_output(
+    msg: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+) -> anyhow::Result<()> {
+    SNMP_ICMP.count_out(msg[0]);
+
+    ip_output(msg, ProtocolKind::ICMP, Some(src), dst).inspect_err(|_| {
+        SNMP_ICMP.out_errors.inc();
+    })
+}
+
+/// Per-destination rate limit of error messages (RFC 1812 4.3.2.8)
+pub fn icmp_xrlim_allow(dst: Ipv4Addr) -> bool {
+    let now = clock_now();
+    let mut tbl = ICMP_RATE_TBL.write().unwrap();
+
+    if tbl.len() >= ICMP_RATE_TBL_SZ && !tbl.contains_key(&dst) {
+        tbl.retain(|_, rate| !rate.is_full(now));
+
+        if tbl.len() >= ICMP_RATE_TBL_SZ {
+            return false;
+        }
+    }
+
+    tbl.entry(dst)
+        .or_insert_with(|| ICMPRateLimit::new(now))
+        .allow(now)
+}
+
+/// Replace the error handler of `proto` if there is
+pub fn icmp_register_err_handler(
+    proto: ProtocolKind,
+    handler: ICMPErrHandler,
+) {
+    let mut handlers = ICMP_ERR_TBL.write().unwrap();
+
+    handlers.retain(|(old, _)| *old != proto);
+    handlers.push((proto, handler));
+}
+
+/// Map ICMP error to errno like Linux `icmp_err_convert`
+pub fn icmp_err_convert(ty: ICMPTypeKind, code: u8) -> Option<i32> {
+    Some(match ty {
+        ICMPTypeKind::DestinationUnreachable => match code {
+            ICMP_NET_UNREACH => ENETUNREACH,
+            ICMP_PROT_UNREACH => ENOPROTOOPT,
+            ICMP_PORT_UNREACH => ECONNREFUSED,
+            ICMP_FRAG_NEEDED => EMSGSIZE,
+            ICMP_SR_FAILED => EOPNOTSUPP,
+            _ => EHOSTUNREACH,
+        },
+        ICMPTypeKind::TimeExceeded => EHOSTUNREACH,
+        ICMPTypeKind::BadParam => EPROTO,
+        _ => None?,
+    })
+}
+
+/// Build ICMP message with checksum over header and `data`
+pub fn icmp_pack(ty: ICMPTypeKind, code: u8, un: u32, data: &[u8]) -> Vec<u8> {
+    let mut hdr = ICMP {
+        ty: ty.into(),
+        code: ICMPCode::new(code),
+        cksum: InetCkSum::default(),
+        un,
+    };
+
+    let mut buf = Vec::with_capacity(size_of::<ICMP>() + data.len());
+
+    buf.extend_from_slice(as_raw_slice(&hdr));
+    buf.extend_from_slice(data);
+
+    hdr.cksum = inet_cksum(&buf).into();
+    buf[..size_of::<ICMP>()].copy_from_slice(as_raw_slice(&hdr));
+
+    buf
+}
+
+/// Second word of Fragmentation Needed, next-hop MTU at low-order 16 bits
+pub fn icmp_un_mtu(mtu: u16) -> u32 {
+    let [hi, lo] = mtu.to_be_bytes();
+
+    u32::from_ne_bytes([0, 0, hi, lo])
+}
+
+pub fn icmp_un_to_mtu(un: u32) -> u16 {
+    let [_, _, hi, lo] = un.to_ne_bytes();
+
+    u16::from_be_bytes([hi, lo])
+}
+
+/// Second word of Parameter Problem, the octet where error was detected
+pub fn icmp_un_pointer(pointer: u8) -> u32 {
+    u32::from_ne_bytes([pointer, 0, 0, 0])
+}
+
+/// Second word of Redirect, the gateway address
+pub fn icmp_un_gateway(gateway: Ipv4Addr) -> u32 {
+    u32::from_ne_bytes(gateway.octets())
+}
+
+/// Is ICMP type an error message (not a query)
+pub fn icmp_is_err(ty: ICMPTypeKind) -> bool {
+    use ICMPTypeKind::*;
+
+    matches!(
+        ty,
+        DestinationUnreachable
+            | SourceQuench
+            | Redirect
+            | TimeExceeded
+            | BadParam
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use osimodel::datalink::Mac;
+
+    use super::*;
+    use crate::{
+        dev::tests::{dev_pair, frame_input, frame_output, neigh_add},
+        ip::{IP_MF, ip_send_check, tests::ipv4_packet},
+    };
+
+    #[test]
+    fn test_icmp_echo() {
+        let (dev, peer) = dev_pair();
+        let (host, hwa) = neigh_add(&dev, 2);
+        let echo = |seq: u8| {
+            icmp_pack(
+                ICMPTypeKind::EchoRequest,
+                0,
+                u32::from_ne_bytes([0x12, 0x34, 0, seq]),
+                b"ping",
+            )
+        };
+
+        // broadcast is ignored, so the first reply is of the second one
+        let bcast =
+            ipv4_packet(ProtocolKind::ICMP, host, dev.broadcast(), &echo(1));
+        let pkt = ipv4_packet(ProtocolKind::ICMP, host, dev.ip, &echo(2));
+
+        frame_input(&dev, &peer, hwa, Mac::BROADCAST, &bcast).unwrap();
+        frame_input(&dev, &peer, hwa, dev.hwa, &pkt).unwrap();
+
+        let (ethh, reply) = frame_output(&peer).unwrap();
+
+        assert_eq!(ethh.dst, hwa);
+        assert_eq!(reply[9], 1);
+        assert_eq!(reply[12..20], [dev.ip.octets(), host.octets()].concat());
+        assert_eq!(
+            reply[20..],
+            icmp_pack(
+                ICMPTypeKind::EchoReply,
+                0,
+                u32::from_ne_bytes([0x12, 0x34, 0, 2]),
+                b"ping"
+            )
+        );
+        assert_eq!(inet_cksum(&reply[20..]), 0);
+    }
+
+    #[test]
+    fn test_icmp_error_quote() {
+        let (dev, _peer) = dev_pair();
+        let [_, n, ..] = dev.ip.octets();
+        let udp = (0..600).map(|i| i as u8).collect::<Vec<u8>>();
+        let cases = [
+            (
+                ICMPTypeKind::DestinationUnreachable,
+                3,
+                ICMP_PORT_UNREACH,
+                0,
+            ),
+            (ICMPTypeKind::TimeExceeded, 11, ICMP_EXC_TTL, 0),
+            (ICMPTypeKind::BadParam, 12, 0, icmp_un_pointer(20)),
+        ];
+
+        for (host, (ty, ty_bits, code, un)) in (2..).zip(cases) {
+            let src = Ipv4Addr::new(10, n, 0, host);
+
+            for len in [8, udp.len()] {
+                let orig =
+                    ipv4_packet(ProtocolKind::UDP, src, dev.ip, &udp[..len]);
+                let msg = dev.icmp_error(&orig, ty, code, un).unwrap();
+
+                assert_eq!(msg[..2], [ty_bits, code], "{ty:?}");
+                assert_eq!(msg[4..8], un.to_ne_bytes());
+                assert_eq!(inet_cksum(&msg), 0);
+                // at most 576 bytes with IP and ICMP headers
+                assert_eq!(
+                    msg.len(),
+                    (8 + orig.len()).min(ICMP_ERR_MAX_LEN - 20)
+                );
+                assert_eq!(msg[8..], orig[..msg.len() - 8]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_icmp_error_suppressed() {
+        let (dev, _peer) = dev_pair();
+        let [_, n, ..] = dev.ip.octets();
+        let host = Ipv4Addr::new(10, n, 0, 2);
+        let udp = [0, 53, 0, 53, 0, 8, 0, 0];
+        let error = |orig: &[u8]| {
+            dev.icmp_error(
+                orig,
+                ICMPTypeKind::DestinationUnreachable,
+                ICMP_PORT_UNREACH,
+                0,
+            )
+        };
+        let with_frag = |flags_off: u16| {
+            let mut pkt = ipv4_packet(ProtocolKind::UDP, host, dev.ip, &udp);
+
+            pkt[6..8].copy_from_slice(&flags_off.to_be_bytes());
+            ip_send_check(&mut pkt);
+            pkt
+        };
+
+        for (src, dst) in [
+            (Ipv4Addr::UNSPECIFIED, dev.ip),
+            (Ipv4Addr::BROADCAST, dev.ip),
+            (Ipv4Addr::new(224, 0, 0, 1), dev.ip),
+            (host, Ipv4Addr::BROADCAST),
+            (host, Ipv4Addr::new(224, 0, 0, 1)),
+            (host, dev.broadcast()),
+        ] {
+            let orig = ipv4_packet(ProtocolKind::UDP, src, dst, &udp);
+
+            assert!(error(&orig).is_none(), "{src} -> {dst}");
+        }
+
+        // only the first fragment
+        assert!(error(&with_frag(1)).is_none());
+        assert!(error(&with_frag(IP_MF)).is_some());
+
+        // errors of errors, but queries
+        let unreach = icmp_pack(
+            ICMPTypeKind::DestinationUnreachable,
+            ICMP_PORT_UNREACH,
+            0,
+            &[0; 28],
+        );
+        let echo = icmp_pack(ICMPTypeKind::EchoRequest, 0, 0, &[]);
+
+        assert!(
+            error(&ipv4_packet(ProtocolKind::ICMP, host, dev.ip, &unreach))
+                .is_none()
+        );
+        assert!(
+            error(&ipv4_packet(ProtocolKind::ICMP, host, dev.ip, &echo))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_icmp_rate_limit() {
+        let now = Instant::now();
+        let mut rate = ICMPRateLimit::new(now);
+
+        for _ in 0..ICMP_RATE_BURST {
+            assert!(rate.allow(now));
+        }
+
+        assert!(!rate.allow(now));
+
+        // refilled by one token each interval up to the burst
+        let now = now + ICMP_RATELIMIT;
+
+        assert!(rate.allow(now));
+        assert!(!rate.allow(now));
+        assert!(!rate.is_full(now + ICMP_RATELIMIT * (ICMP_RATE_BURST - 1)));
+        assert!(rate.is_full(now + ICMP_RATELIMIT * ICMP_RATE_BURST));
+
+        /* per destination, Fragmentation Needed isn't limited */
+
+        let (dev, _peer) = dev_pair();
+        let [_, n, ..] = dev.ip.octets();
+        let udp = [0, 53, 0, 53, 0, 8, 0, 0];
+        let orig = |host| {
+            let src = Ipv4Addr::new(10, n, 0, host);
+
+            ipv4_packet(ProtocolKind::UDP, src, dev.ip, &udp)
+        };
+        let error = |orig: &[u8], code| {
+            dev.icmp_error(orig, ICMPTypeKind::DestinationUnreachable, code, 0)
+        };
+
+        for _ in 0..ICMP_RATE_BURST {
+            assert!(error(&orig(2), ICMP_PORT_UNREACH).is_some());
+        }
+
+        assert!(error(&orig(2), ICMP_PORT_UNREACH).is_none());
+        assert!(error(&orig(2), ICMP_FRAG_NEEDED).is_some());
+        assert!(error(&orig(3), ICMP_PORT_UNREACH).is_some());
+    }
+}
@@ -1,44 +1,779 @@
-use std::net::Ipv4Addr;
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        Once,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+    },
+};
 
-use m6ptr::Ptr;
+use anyhow::anyhow;
+use log::trace;
+use m6tobytes::{as_raw_slice, from_raw_slice};
 use osimodel::network::{
     InetCkSum,
-    ip::{FlagsAndOff, IHLAndVer, IPv4, Id, ProtocolKind, TTL, ToS, TotLen},
+    icmp::ICMPTypeKind,
+    inet_cksum,
+    ip::{
+        FlagsAndOff, IHLAndVer, IPv4, Id, Protocol, ProtocolKind, TTL, ToS,
+        TotLen,
+    },
 };
 
-use crate::{dev::NetDevice, skbuff::SkBuff};
+use crate::{
+    dev::{NetDevice, is_local_addr},
+    icmp::{
+        ICMP_EXC_TTL, ICMP_FRAG_NEEDED, ICMP_NET_UNREACH, ICMP_PROT_UNREACH,
+        ICMP_REDIR_HOST, ICMP_SR_FAILED, icmp_un_gateway, icmp_un_mtu,
+        icmp_un_pointer,
+    },
+    ipopt::{
+        IPOption, IPOptions, ip_options_build, ip_options_compile,
+        ip_options_forward, ip_options_fragment, ip_options_srr_peek,
+        ip_options_srr_pending, ip_options_srr_record,
+    },
+    ipq::{ip_defrag, ip_is_fragment},
+    route::{ip_route_output, ip_rt_get_pmtu},
+    skbuff::SkBuff,
+    snmp::SNMP_IP,
+    tcp::tcp_init,
+    udp::udp_init,
+};
 
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
 
+/// Don't Fragment
+pub const IP_DF: u16 = 0x4000;
+/// More Fragments
+pub const IP_MF: u16 = 0x2000;
+/// Fragment offset in 8 bytes
+pub const IP_OFFMASK: u16 = 0x1FFF;
 
 ////////////////////////////////////////////////////////////////////////////////
-//// Implementations
+//// Static Variables
 
+/// Router mode, datagrams not addressed to local are routed to the next hop
+pub static IP_FORWARD: AtomicBool = AtomicBool::new(false);
+
+static IP_ID: AtomicU16 = AtomicU16::new(0);
+
+static INET_ONCE: Once = Once::new();
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
 
 impl NetDevice {
-    /// TDDO
-    pub fn ip_output(
+    /// Return the reason if the datagram is dropped
+    pub fn ip_input(&self, skb: SkBuff) -> anyhow::Result<Option<String>> {
+        let nh = *skb.nh.get().unwrap();
+
+        SNMP_IP.in_receives.inc();
+
+        if nh.rem_len() < size_of::<IPv4>() {
+            SNMP_IP.in_hdr_errors.inc();
+            Err(anyhow!("Uncomplete IPv4 header"))?
+        }
+
+        let iph = nh.cast::<IPv4>().read_unaligned();
+        let hlen = ip_hdrlen(&iph);
+        let totlen = iph.totlen.tot_len() as usize;
+
+        if nh.cur_slice()[0] >> 4 != 4
+            || hlen < size_of::<IPv4>()
+            || totlen < hlen
+            || totlen > nh.rem_len()
+        {
+            SNMP_IP.in_hdr_errors.inc();
+            Err(anyhow!("Malformed IPv4 header {iph:#?}"))?
+        }
+
+        // strip off Ethernet padding
+        let pkt = &nh.cur_slice()[..totlen];
+
+        if inet_cksum(&pkt[..hlen]) != 0 {
+            SNMP_IP.in_hdr_errors.inc();
+            Err(anyhow!("IPv4 header verify cksum failed {iph:#?}"))?
+        }
+
+        let opt = match ip_options_compile(&pkt[..hlen]) {
+            Ok(opt) => opt,
+            Err(pointer) => {
+                trace!("Malformed IPv4 options at {pointer}");
+                SNMP_IP.in_hdr_errors.inc();
+
+                self.icmp_send(
+                    pkt,
+                    ICMPTypeKind::BadParam,
+                    0,
+                    icmp_un_pointer(pointer),
+                )?;
+
+                return Ok(Some(format!("bad IPv4 option at {pointer}")));
+            }
+        };
+
+        let dst: Ipv4Addr = iph.dst.into();
+        let is_forward = IP_FORWARD.load(Ordering::Relaxed);
+        // group bit of Ethernet destination (RFC 1812 5.3.4)
+        let is_link_bcast = skb
+            .phy
+            .get()
+            .is_some_and(|phy| phy.cur_slice()[0] & 0x01 != 0);
+
+        if is_local_addr(dst) {
+            if !ip_options_srr_pending(pkt, &opt) {
+                return self.ip_local_deliver(pkt);
+            }
+
+            if !is_forward {
+                trace!("Filter source routed datagram to {dst}");
+                SNMP_IP.in_discards.inc();
+                return Ok(Some("source routed".to_owned()));
+            }
+
+            return self.ip_options_rcv_srr(pkt, &opt, is_link_bcast);
+        }
+
+        if !is_forward {
+            trace!("Filter IPv4 datagram to {dst}");
+            SNMP_IP.in_addr_errors.inc();
+            return Ok(Some(format!("{dst} isn't local")));
+        }
+
+        self.ip_forward(pkt, &opt, is_link_bcast)
+    }
+
+    /// We are the current hop of source route, continue with the next one
+    fn ip_options_rcv_srr(
+        &self,
+        pkt: &[u8],
+        opt: &IPOptions,
+        is_link_bcast: bool,
+    ) -> anyhow::Result<Option<String>> {
+        let mut fwd = pkt.to_vec();
+        let mut iph = from_raw_slice::<IPv4>(&fwd);
+        let hlen = ip_hdrlen(&iph);
+
+        let Some(next) = ip_options_srr_peek(&fwd[..hlen], opt)
+        else {
+            return Ok(Some("source route without next hop".to_owned()));
+        };
+
+        let Some(rt) = ip_route_output(next)
+        else {
+            self.icmp_send(
+                pkt,
+                ICMPTypeKind::DestinationUnreachable,
+                ICMP_SR_FAILED,
+                0,
+            )?;
+
+            return Ok(Some(format!("no route to source route {next}")));
+        };
+
+        ip_options_srr_record(&mut fwd[..hlen], opt, rt.dev.ip);
+
+        iph.dst = next.into();
+        fwd[..size_of::<IPv4>()].copy_from_slice(as_raw_slice(&iph));
+        ip_send_check(&mut fwd);
+
+        trace!("Source route {:?} -> {next}", iph.src);
+
+        self.ip_forward(&fwd, opt, is_link_bcast)
+    }
+
+    /// Return the reason if the datagram is dropped, a fragment kept for
+    /// reassembly isn't
+    pub fn ip_local_deliver(
+        &self,
+        pkt: &[u8],
+    ) -> anyhow::Result<Option<String>> {
+        if ip_is_fragment(pkt) {
+            let Some(pkt) = ip_defrag(pkt, &self.name)
+            else {
+                return Ok(None);
+            };
+
+            return self.ip_local_deliver(&pkt);
+        }
+
+        let iph = from_raw_slice::<IPv4>(pkt);
+        let proto: ProtocolKind = iph.proto.into();
+
+        let input = match proto {
+            ProtocolKind::ICMP => Self::icmp_input,
+            ProtocolKind::UDP => Self::udp_input,
+            ProtocolKind::TCP => Self::tcp_input,
+            _ => {
+                trace!("Found {proto:?} datagram from {:?}", iph.src);
+                SNMP_IP.in_unknown_protos.inc();
+
+                self.icmp_send(
+                    pkt,
+                    ICMPTypeKind::DestinationUnreachable,
+                    ICMP_PROT_UNREACH,
+                    0,
+                )?;
+
+                return Ok(Some(format!("unknown protocol {proto:?}")));
+            }
+        };
+
+        SNMP_IP.in_delivers.inc();
+
+        input(self, pkt)
+    }
+
+    /// RFC 1812 5.2, return the reason if the datagram is dropped.
+    ///
+    /// `is_link_bcast` is if it's received as link-layer broadcast.
+    pub fn ip_forward(
         &self,
-        skb: Ptr<SkBuff>,
+        pkt: &[u8],
+        opt: &IPOptions,
+        is_link_bcast: bool,
+    ) -> anyhow::Result<Option<String>> {
+        let iph = from_raw_slice::<IPv4>(pkt);
+        let src: Ipv4Addr = iph.src.into();
+        let dst: Ipv4Addr = iph.dst.into();
+
+        /* RFC 1812 5.3.4 and 4.2.2.11, only unicast is routed */
+
+        if is_link_bcast {
+            trace!("Filter link-layer broadcast {src} -> {dst}");
+            SNMP_IP.in_addr_errors.inc();
+            return Ok(Some("link-layer broadcast".to_owned()));
+        }
+
+        if !ip_is_unicast(dst) || !ip_is_unicast(src) {
+            trace!("Filter forwarding {src} -> {dst}");
+            SNMP_IP.in_addr_errors.inc();
+            return Ok(Some(format!("{src} -> {dst} isn't routable")));
+        }
+
+        if iph.ttl.to_bits() <= 1 {
+            trace!("TTL exceeded {src} -> {dst}");
+            SNMP_IP.in_hdr_errors.inc();
+
+            self.icmp_send(pkt, ICMPTypeKind::TimeExceeded, ICMP_EXC_TTL, 0)?;
+
+            return Ok(Some("TTL exceeded".to_owned()));
+        }
+
+        let Some(rt) = ip_route_output(dst)
+        else {
+            trace!("No route to {dst}");
+            SNMP_IP.out_no_routes.inc();
+
+            self.icmp_send(
+                pkt,
+                ICMPTypeKind::DestinationUnreachable,
+                ICMP_NET_UNREACH,
+                0,
+            )?;
+
+            return Ok(Some(format!("no route to {dst}")));
+        };
+
+        if opt.is_strictroute && rt.nexthop != dst {
+            trace!("Strict source route to {dst} isn't on link");
+
+            self.icmp_send(
+                pkt,
+                ICMPTypeKind::DestinationUnreachable,
+                ICMP_SR_FAILED,
+                0,
+            )?;
+
+            return Ok(Some(format!("strict source route to {dst}")));
+        }
+
+        if opt.router_alert {
+            trace!("Router Alert {src} -> {dst}");
+        }
+
+        if pkt.len() > rt.dev.mtu as usize
+            && iph.flags_off.to_bits() & IP_DF != 0
+        {
+            trace!("Fragmentation needed for {dst} on {}", rt.dev.name);
+            SNMP_IP.frag_fails.inc();
+
+            self.icmp_send(
+                pkt,
+                ICMPTypeKind::DestinationUnreachable,
+                ICMP_FRAG_NEEDED,
+                icmp_un_mtu(rt.dev.mtu),
+            )?;
+
+            return Ok(Some(format!("DF set over mtu {}", rt.dev.mtu)));
+        }
+
+        /* RFC 1812 5.2.7.2, the source could reach next hop directly */
+
+        if rt.dev.name == self.name
+            && self.is_on_link(src)
+            && opt.srr.is_none()
+        {
+            self.icmp_send(
+                pkt,
+                ICMPTypeKind::Redirect,
+                ICMP_REDIR_HOST,
+                icmp_un_gateway(rt.nexthop),
+            )?;
+        }
+
+        let mut pkt = pkt.to_vec();
+        let hlen = ip_hdrlen(&iph);
+
+        ip_decrease_ttl(&mut pkt);
+
+        if ip_options_forward(&mut pkt[..hlen], opt, rt.dev.ip) {
+            ip_send_check(&mut pkt);
+        }
+
+        trace!("Forward {src} -> {dst} via {} {}", rt.nexthop, rt.dev.name);
+        SNMP_IP.forw_datagrams.inc();
+
+        rt.dev.ip_finish_output(pkt, rt.nexthop)?;
+
+        Ok(None)
+    }
+
+    /// Fragment datagram if it exceeds MTU then send it to `nexthop`
+    pub fn ip_finish_output(
+        &self,
+        pkt: Vec<u8>,
+        nexthop: Ipv4Addr,
+    ) -> anyhow::Result<()> {
+        let pkts = if pkt.len() > self.mtu as usize {
+            let Some(pkts) = ip_fragment(&pkt, self.mtu as usize)
+            else {
+                SNMP_IP.frag_fails.inc();
+                Err(anyhow!("IPv4 header doesn't fit in mtu {}", self.mtu))?
+            };
+
+            pkts
+        }
+        else {
+            vec![pkt]
+        };
+
+        SNMP_IP.count_frags(pkts.len());
+
+        self.neigh_output(nexthop, pkts)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Register the transport protocols to IPv4 once like Linux `inet_init`
+pub fn inet_init() {
+    INET_ONCE.call_once(|| {
+        udp_init();
+        tcp_init();
+    });
+}
+
+/// Neither broadcast, multicast, reserved (class E), unspecified nor
+/// loopback (RFC 1812 4.2.2.11 and 5.3.7)
+pub fn ip_is_unicast(ip: Ipv4Addr) -> bool {
+    !(ip.is_unspecified() || ip.is_loopback() || ip.octets()[0] >= 224)
+}
+
+pub fn ip_hdrlen(iph: &IPv4) -> usize {
+    iph.ihl_v.ihl() as usize * 4
+}
+
+/// -> (header, payload)
+pub fn ip_split(pkt: &[u8]) -> (IPv4, &[u8]) {
+    let iph = from_raw_slice::<IPv4>(pkt);
+
+    (iph, &pkt[ip_hdrlen(&iph)..])
+}
+
+/// Route datagram of `payload` to `dst`, source defaults to the address of
+/// output device
+pub fn ip_output(
+    payload: &[u8],
+    proto: ProtocolKind,
+    src: Option<Ipv4Addr>,
+    dst: Ipv4Addr,
+) -> anyhow::Result<()> {
+    ip_output_opts(payload, proto, src, dst, &[])
+}
+
+/// `ip_output` with options, the datagram is sent to the first hop if there
+/// is source route
+pub fn ip_output_opts(
+    payload: &[u8],
+    proto: ProtocolKind,
+    src: Option<Ipv4Addr>,
+    dst: Ipv4Addr,
+    opts: &[IPOption],
+) -> anyhow::Result<()> {
+    SNMP_IP.out_requests.inc();
+
+    let optbuf = ip_options_build(opts, dst)?;
+
+    let srr = opts.iter().find_map(|opt| {
+        opt.first_hop()
+            .map(|hop| (hop, matches!(opt, IPOption::StrictSourceRoute(_))))
+    });
+
+    let hdr_dst = srr.map(|(hop, _)| hop).unwrap_or(dst);
+
+    let Some(rt) = ip_route_output(hdr_dst)
+    else {
+        SNMP_IP.out_no_routes.inc();
+        Err(anyhow!("Network is unreachable {hdr_dst}"))?
+    };
+
+    if let Some((hop, true)) = srr
+        && rt.nexthop != hop
+    {
+        Err(anyhow!("Strict source route first hop {hop} isn't on link"))?
+    }
+
+    let src = src.unwrap_or(rt.dev.ip);
+    let hlen = size_of::<IPv4>() + optbuf.len();
+
+    let iph = IPv4 {
+        ihl_v: IHLAndVer::with_options_bytes(optbuf.len()),
+        tos: ToS::default(),
+        totlen: TotLen::new_with_tot_len((hlen + payload.len()) as u16),
+        id: Id::new(IP_ID.fetch_add(1, Ordering::Relaxed)),
+        flags_off: FlagsAndOff::default(),
+        ttl: TTL::default(),
+        proto: proto.into(),
+        cksum: InetCkSum::default(),
+        src: src.into(),
+        dst: hdr_dst.into(),
+    };
+
+    let mut pkt = Vec::with_capacity(hlen + payload.len());
+
+    pkt.extend_from_slice(as_raw_slice(&iph));
+    pkt.extend_from_slice(&optbuf);
+    pkt.extend_from_slice(payload);
+
+    ip_send_check(&mut pkt);
+
+    let mtu = ip_rt_get_pmtu(dst)
+        .map(|pmtu| pmtu.min(rt.dev.mtu))
+        .unwrap_or(rt.dev.mtu) as usize;
+
+    let pkts = if pkt.len() > mtu {
+        let Some(pkts) = ip_fragment(&pkt, mtu)
+        else {
+            SNMP_IP.frag_fails.inc();
+            Err(anyhow!("IPv4 header doesn't fit in mtu {mtu}"))?
+        };
+
+        pkts
+    }
+    else {
+        vec![pkt]
+    };
+
+    SNMP_IP.count_frags(pkts.len());
+
+    rt.dev.neigh_output(rt.nexthop, pkts)
+}
+
+/// Recompute header checksum over options included
+pub fn ip_send_check(pkt: &mut [u8]) {
+    let mut iph = from_raw_slice::<IPv4>(pkt);
+    let hlen = ip_hdrlen(&iph);
+
+    iph.cksum = InetCkSum::default();
+    pkt[..size_of::<IPv4>()].copy_from_slice(as_raw_slice(&iph));
+
+    iph.cksum = inet_cksum(&pkt[..hlen]).into();
+    pkt[..size_of::<IPv4>()].copy_from_slice(as_raw_slice(&iph));
+}
+
+/// Checksum of transport `seg` over pseudo header (RFC 768, RFC 793)
+pub fn ip_pseudo_cksum(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    proto: ProtocolKind,
+    seg: &[u8],
+) -> u16 {
+    let mut buf = Vec::with_capacity(12 + seg.len());
+    let proto: Protocol = proto.into();
+
+    buf.extend_from_slice(&src.octets());
+    buf.extend_from_slice(&dst.octets());
+    buf.push(0);
+    buf.push(proto.to_bits());
+    buf.extend_from_slice(&(seg.len() as u16).to_be_bytes());
+    buf.extend_from_slice(seg);
+
+    inet_cksum(&buf)
+}
+
+/// Decrease TTL and update checksum incrementally (RFC 1624)
+pub fn ip_decrease_ttl(pkt: &mut [u8]) {
+    let mut iph = from_raw_slice::<IPv4>(pkt);
+
+    // TTL is the high-order byte of its 16-bit word
+    let check = iph.cksum.to_bits() as u32 + 0x0100u16.to_be() as u32;
+
+    iph.cksum = ((check + (check >= 0xFFFF) as u32) as u16).into();
+    iph.ttl = TTL::from_bits(iph.ttl.to_bits() - 1);
+
+    pkt[..size_of::<IPv4>()].copy_from_slice(as_raw_slice(&iph));
+}
+
+/// Split datagram into fragments fit in `mtu` (RFC 791), none if the
+/// header leaves no room for 8 bytes of data
+pub fn ip_fragment(pkt: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let (iph, data) = ip_split(pkt);
+    let hdr = &pkt[..ip_hdrlen(&iph)];
+    let flags_off = iph.flags_off.to_bits();
+    let off0 = (flags_off & IP_OFFMASK) as usize;
+
+    // payload of each fragment except the last is multiple of 8 bytes
+    let maxlen = mtu.checked_sub(hdr.len())? & !7;
+
+    if maxlen == 0 {
+        return None;
+    }
+
+    let frags = data
+        .chunks(maxlen)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let is_last = (i + 1) * maxlen >= data.len();
+            let mut frag_flags_off =
+                (off0 + i * maxlen / 8) as u16 & IP_OFFMASK;
+
+            if !is_last || flags_off & IP_MF != 0 {
+                frag_flags_off |= IP_MF;
+            }
+
+            let mut fiph = iph;
+
+            fiph.totlen =
+                TotLen::new_with_tot_len((hdr.len() + chunk.len()) as u16);
+            fiph.flags_off = FlagsAndOff::from_bits(frag_flags_off);
+
+            let mut frag = Vec::with_capacity(hdr.len() + chunk.len());
+
+            frag.extend_from_slice(as_raw_slice(&fiph));
+            frag.extend_from_slice(&hdr[size_of::<IPv4>()..]);
+
+            if i > 0 {
+                ip_options_fragment(&mut frag[size_of::<IPv4>()..]);
+            }
+
+            frag.extend_from_slice(chunk);
+
+            ip_send_check(&mut frag);
+
+            frag
+        })
+        .collect();
+
+    Some(frags)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::dev::tests::{dev_pair, frame_input, frame_output, neigh_add};
+
+    /// IPv4 datagram of UDP from 10.0.0.1 to 10.0.0.2 with `opts`
+    pub(crate) fn ipv4_datagram(
+        ttl: u8,
+        opts: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let hlen = 20 + opts.len();
+        let totlen = (hlen + payload.len()) as u16;
+        let mut pkt = vec![0x40 | (hlen / 4) as u8, 0];
+
+        pkt.extend(totlen.to_be_bytes());
+        pkt.extend([0x12, 0x34, 0, 0, ttl, 17, 0, 0]);
+        pkt.extend([10, 0, 0, 1, 10, 0, 0, 2]);
+        pkt.extend(opts);
+        pkt.extend(payload);
+
+        ip_send_check(&mut pkt);
+
+        pkt
+    }
+
+    /// IPv4 datagram of `proto` from `src` to `dst` without options
+    pub(crate) fn ipv4_packet(
+        proto: ProtocolKind,
         src: Ipv4Addr,
         dst: Ipv4Addr,
-    ) -> anyhow::Result<()> {
-        let _rawbuf = *skb.nh.get().unwrap();
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut pkt = ipv4_datagram(64, &[], payload);
+        let proto: Protocol = proto.into();
+
+        pkt[9] = proto.to_bits();
+        pkt[12..16].copy_from_slice(&src.octets());
+        pkt[16..20].copy_from_slice(&dst.octets());
+        ip_send_check(&mut pkt);
+
+        pkt
+    }
+
+    /// UDP datagram of no data from `src` to `dst` with `ttl`
+    fn ipv4_between(src: Ipv4Addr, dst: Ipv4Addr, ttl: u8) -> Vec<u8> {
+        let udp = [0, 53, 0, 53, 0, 8, 0, 0];
+        let mut pkt = ipv4_packet(ProtocolKind::UDP, src, dst, &udp);
+
+        pkt[8] = ttl;
+        ip_send_check(&mut pkt);
+
+        pkt
+    }
+
+    #[test]
+    fn test_ip_fragment() {
+        // Record Route isn't copied, Router Alert is
+        let opts = [7, 7, 4, 0, 0, 0, 0, 0x94, 4, 0, 0, 0];
+        let payload = (0..100).collect::<Vec<u8>>();
+        let pkt = ipv4_datagram(64, &opts, &payload);
+        let frags = ip_fragment(&pkt, 80).unwrap();
+        let mut data = vec![];
+
+        assert_eq!(frags.len(), 3);
+
+        for (i, frag) in frags.iter().enumerate() {
+            let flags_off = u16::from_be_bytes([frag[6], frag[7]]);
+            let totlen = u16::from_be_bytes([frag[2], frag[3]]) as usize;
+
+            assert!(frag.len() <= 80);
+            assert_eq!(totlen, frag.len());
+            assert_eq!(inet_cksum(&frag[..32]), 0);
+            assert_eq!((flags_off & IP_OFFMASK) as usize * 8, data.len());
+            assert_eq!(flags_off & IP_MF != 0, i < 2);
+
+            if i == 0 {
+                assert_eq!(frag[20..32], opts);
+            }
+            else {
+                assert_eq!(frag[20..27], [1; 7]);
+                assert_eq!(frag[27..32], opts[7..]);
+            }
+
+            data.extend_from_slice(&frag[32..]);
+        }
+
+        assert_eq!(data, payload);
+
+        // no room for 8 bytes of data after the header
+        assert!(ip_fragment(&pkt, 39).is_none());
+        assert!(ip_fragment(&pkt, 24).is_none());
+    }
+
+    #[test]
+    fn test_ip_decrease_ttl() {
+        for ttl in 2..=255 {
+            let mut pkt = ipv4_datagram(ttl, &[], &[0; 8]);
 
-        let _iph = IPv4 {
-            ihl_v: IHLAndVer::with_options_bytes(0),
-            tos: ToS::default(),
-            totlen: TotLen::new_with_tot_len(20),
-            id: Id::new(0),
-            flags_off: FlagsAndOff::default(),
-            ttl: TTL::default(),
-            proto: ProtocolKind::UDP.into(),
-            cksum: InetCkSum::default(),
-            src: src.into(),
-            dst: dst.into(),
+            ip_decrease_ttl(&mut pkt);
+
+            assert_eq!(pkt[8], ttl - 1);
+            assert_eq!(inet_cksum(&pkt[..20]), 0, "ttl {ttl}");
         }
-        .checksummed();
+    }
+
+    #[test]
+    fn test_ip_forward() {
+        let (a, peer_a) = dev_pair();
+        let (b, peer_b) = dev_pair();
+        let (host, host_hwa) = neigh_add(&a, 2);
+        let (near, near_hwa) = neigh_add(&a, 3);
+        let (dst, dst_hwa) = neigh_add(&b, 2);
+
+        IP_FORWARD.store(true, Ordering::Relaxed);
+
+        /* TTL is decreased with the checksum updated incrementally */
+
+        let pkt = ipv4_between(host, dst, 64);
+
+        frame_input(&a, &peer_a, host_hwa, a.hwa, &pkt).unwrap();
+
+        let (ethh, fwd) = frame_output(&peer_b).unwrap();
+        let mut expected = pkt.clone();
+
+        expected[8] = 63;
+        ip_send_check(&mut expected);
+
+        assert_eq!((ethh.src, ethh.dst), (b.hwa, dst_hwa));
+        assert_eq!(fwd, expected);
+
+        /* TTL 1 is answered with Time Exceeded quoting the datagram */
+
+        let pkt = ipv4_between(host, dst, 1);
+
+        frame_input(&a, &peer_a, host_hwa, a.hwa, &pkt).unwrap();
 
+        let (ethh, err) = frame_output(&peer_a).unwrap();
+
+        assert_eq!(ethh.dst, host_hwa);
+        assert_eq!(err[9], 1);
+        assert_eq!(err[12..20], [a.ip.octets(), host.octets()].concat());
+        assert_eq!(err[20..22], [11, ICMP_EXC_TTL]);
+        assert_eq!(inet_cksum(&err[20..]), 0);
+        assert_eq!(err[28..], pkt);
+
+        /* back to the ingress, the source is redirected to the next hop */
+
+        let pkt = ipv4_between(host, near, 64);
+
+        frame_input(&a, &peer_a, host_hwa, a.hwa, &pkt).unwrap();
+
+        let (ethh, err) = frame_output(&peer_a).unwrap();
+
+        assert_eq!(ethh.dst, host_hwa);
+        assert_eq!(err[20..22], [5, ICMP_REDIR_HOST]);
+        assert_eq!(err[24..28], near.octets());
+        assert_eq!(inet_cksum(&err[20..]), 0);
+        assert_eq!(err[28..], pkt);
+
+        let (ethh, fwd) = frame_output(&peer_a).unwrap();
+
+        assert_eq!((ethh.src, ethh.dst), (a.hwa, near_hwa));
+        assert_eq!(fwd[8], 63);
+
+        // the datagram of TTL 1 wasn't forwarded
+        assert!(frame_output(&peer_b).is_none());
+    }
+
+    #[test]
+    fn test_ip_forward_unroutable() {
+        let (a, _peer_a) = dev_pair();
+        let (b, _peer_b) = dev_pair();
+        let (host, _) = neigh_add(&a, 2);
+        let (dst, _) = neigh_add(&b, 2);
+        let opt = IPOptions::default();
+        let forward = |src, dst, is_link_bcast| {
+            a.ip_forward(&ipv4_between(src, dst, 64), &opt, is_link_bcast)
+                .unwrap()
+        };
+
+        for bad in [
+            Ipv4Addr::BROADCAST,
+            Ipv4Addr::new(224, 0, 0, 9),
+            Ipv4Addr::new(239, 1, 2, 3),
+            Ipv4Addr::new(240, 0, 0, 1),
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::UNSPECIFIED,
+        ] {
+            assert!(forward(host, bad, false).is_some(), "to {bad}");
+            assert!(forward(bad, dst, false).is_some(), "from {bad}");
+        }
 
-        Ok(())
+        assert!(forward(host, dst, true).is_some());
+        assert!(forward(host, dst, false).is_none());
     }
 }
@@ -0,0 +1,546 @@
+use std::net::Ipv4Addr;
+
+use anyhow::anyhow;
+use time::UtcDateTime;
+
+use crate::{clock::clock_wall, dev::is_local_addr};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+pub const IPOPT_END: u8 = 0;
+pub const IPOPT_NOOP: u8 = 1;
+/// Record Route
+pub const IPOPT_RR: u8 = 7;
+/// Internet Timestamp
+pub const IPOPT_TS: u8 = 68;
+/// Loose Source and Record Route
+pub const IPOPT_LSRR: u8 = 131;
+/// Strict Source and Record Route
+pub const IPOPT_SSRR: u8 = 137;
+/// Router Alert
+pub const IPOPT_RA: u8 = 148;
+
+/// Option is copied into all fragments
+pub const IPOPT_COPY: u8 = 0x80;
+
+/* Timestamp flags */
+
+pub const IPOPT_TS_TSONLY: u8 = 0;
+pub const IPOPT_TS_TSANDADDR: u8 = 1;
+pub const IPOPT_TS_PRESPEC: u8 = 3;
+
+/// Max bytes of options, limited by 4 bits IHL
+pub const IPOPT_MAX_LEN: usize = 40;
+
+/// Min value of pointer, the first slot follows type, length and pointer
+const IPOPT_MINOFF: u8 = 4;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Options found in a received header (RFC 791, RFC 2113), offsets are
+/// from the header start
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IPOptions {
+    pub rr: Option<usize>,
+    pub ts: Option<usize>,
+    /// Loose or strict source route
+    pub srr: Option<usize>,
+    pub is_strictroute: bool,
+    pub router_alert: bool,
+}
+
+/// Options for output
+#[derive(Debug, Clone)]
+pub enum IPOption {
+    /// Reserve `slots` addresses
+    RecordRoute {
+        slots: u8,
+    },
+    /// Reserve `slots` entries, with `flag` one of `IPOPT_TS_TSONLY` and
+    /// `IPOPT_TS_TSANDADDR`
+    Timestamp {
+        flag: u8,
+        slots: u8,
+    },
+    /// Timestamps of prespecified addresses
+    TimestampPrespec(Vec<Ipv4Addr>),
+    /// Hops before the destination
+    LooseSourceRoute(Vec<Ipv4Addr>),
+    /// Hops before the destination
+    StrictSourceRoute(Vec<Ipv4Addr>),
+    RouterAlert,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl IPOption {
+    /// First hop if it's a source route
+    pub fn first_hop(&self) -> Option<Ipv4Addr> {
+        match self {
+            Self::LooseSourceRoute(hops) | Self::StrictSourceRoute(hops) => {
+                hops.first().copied()
+            }
+            _ => None,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Validate options of `hdr`, the whole IPv4 header.
+///
+/// Err is the octet pointer for ICMP Parameter Problem.
+pub fn ip_options_compile(hdr: &[u8]) -> Result<IPOptions, u8> {
+    let mut opt = IPOptions::default();
+    let mut off = 20;
+
+    while off < hdr.len() {
+        let ty = hdr[off];
+
+        if ty == IPOPT_END {
+            break;
+        }
+
+        if ty == IPOPT_NOOP {
+            off += 1;
+            continue;
+        }
+
+        if off + 1 >= hdr.len() {
+            return Err(off as u8);
+        }
+
+        let optlen = hdr[off + 1] as usize;
+
+        if optlen < 2 || off + optlen > hdr.len() {
+            return Err(off as u8 + 1);
+        }
+
+        let optptr = &hdr[off..off + optlen];
+
+        match ty {
+            IPOPT_RR | IPOPT_LSRR | IPOPT_SSRR => {
+                if optlen < 3 {
+                    return Err(off as u8 + 1);
+                }
+
+                if optptr[2] < IPOPT_MINOFF
+                    || optptr[2] as usize <= optlen
+                        && optptr[2] as usize + 3 > optlen
+                {
+                    return Err(off as u8 + 2);
+                }
+
+                if ty == IPOPT_RR {
+                    if opt.rr.is_some() {
+                        return Err(off as u8);
+                    }
+
+                    opt.rr = Some(off);
+                }
+                else {
+                    if opt.srr.is_some() {
+                        return Err(off as u8);
+                    }
+
+                    opt.srr = Some(off);
+                    opt.is_strictroute = ty == IPOPT_SSRR;
+                }
+            }
+            IPOPT_TS => {
+                if opt.ts.is_some() {
+                    return Err(off as u8);
+                }
+
+                if optlen < 4 {
+                    return Err(off as u8 + 1);
+                }
+
+                if optptr[2] < IPOPT_MINOFF + 1 {
+                    return Err(off as u8 + 2);
+                }
+
+                let slotlen = match optptr[3] & 0xF {
+                    IPOPT_TS_TSONLY => 4,
+                    IPOPT_TS_TSANDADDR | IPOPT_TS_PRESPEC => 8,
+                    _ => return Err(off as u8 + 3),
+                };
+
+                if optptr[2] as usize <= optlen
+                    && optptr[2] as usize + slotlen - 1 > optlen
+                {
+                    return Err(off as u8 + 2);
+                }
+
+                // overflow counter is about to be increased
+                if optptr[2] as usize > optlen && optptr[3] >> 4 == 0xF {
+                    return Err(off as u8 + 3);
+                }
+
+                opt.ts = Some(off);
+            }
+            IPOPT_RA => {
+                if optlen != 4 {
+                    return Err(off as u8 + 1);
+                }
+
+                // other values are reserved
+                if optptr[2] == 0 && optptr[3] == 0 {
+                    opt.router_alert = true;
+                }
+            }
+            _ => (),
+        }
+
+        off += optlen;
+    }
+
+    Ok(opt)
+}
+
+/// Is there address left in the source route
+pub fn ip_options_srr_pending(hdr: &[u8], opt: &IPOptions) -> bool {
+    opt.srr
+        .is_some_and(|srr| hdr[srr + 2] as usize <= hdr[srr + 1] as usize)
+}
+
+/// Next address of the source route
+pub fn ip_options_srr_peek(hdr: &[u8], opt: &IPOptions) -> Option<Ipv4Addr> {
+    if !ip_options_srr_pending(hdr, opt) {
+        return None;
+    }
+
+    let srr = opt.srr.unwrap();
+    let slot = srr + hdr[srr + 2] as usize - 1;
+
+    Some(Ipv4Addr::from(
+        <[u8; 4]>::try_from(&hdr[slot..slot + 4]).unwrap(),
+    ))
+}
+
+/// Replace the next address of the source route with `addr` and advance
+pub fn ip_options_srr_record(hdr: &mut [u8], opt: &IPOptions, addr: Ipv4Addr) {
+    if !ip_options_srr_pending(hdr, opt) {
+        return;
+    }
+
+    let srr = opt.srr.unwrap();
+    let slot = srr + hdr[srr + 2] as usize - 1;
+
+    hdr[slot..slot + 4].copy_from_slice(&addr.octets());
+    hdr[srr + 2] += 4;
+}
+
+/// Update Record Route and Timestamp as a hop leaving from `addr`,
+/// -> if header is changed
+pub fn ip_options_forward(
+    hdr: &mut [u8],
+    opt: &IPOptions,
+    addr: Ipv4Addr,
+) -> bool {
+    let mut changed = false;
+
+    if let Some(rr) = opt.rr {
+        let ptr = hdr[rr + 2] as usize;
+
+        if ptr + 3 <= hdr[rr + 1] as usize {
+            let slot = rr + ptr - 1;
+
+            hdr[slot..slot + 4].copy_from_slice(&addr.octets());
+            hdr[rr + 2] += 4;
+            changed = true;
+        }
+    }
+
+    if let Some(ts) = opt.ts {
+        changed |= ip_options_stamp(hdr, ts, addr);
+    }
+
+    changed
+}
+
+/// Fill the next Timestamp entry, -> if header is changed
+pub fn ip_options_stamp(hdr: &mut [u8], ts: usize, addr: Ipv4Addr) -> bool {
+    let optlen = hdr[ts + 1] as usize;
+    let ptr = hdr[ts + 2] as usize;
+    let flag = hdr[ts + 3] & 0xF;
+    let slot = ts + ptr - 1;
+
+    if ptr > optlen {
+        let overflow = (hdr[ts + 3] >> 4).saturating_add(1).min(0xF);
+
+        hdr[ts + 3] = overflow << 4 | flag;
+
+        return true;
+    }
+
+    let stamp = ip_options_timestamp().to_be_bytes();
+
+    match flag {
+        IPOPT_TS_TSONLY => {
+            hdr[slot..slot + 4].copy_from_slice(&stamp);
+            hdr[ts + 2] += 4;
+        }
+        IPOPT_TS_TSANDADDR => {
+            hdr[slot..slot + 4].copy_from_slice(&addr.octets());
+            hdr[slot + 4..slot + 8].copy_from_slice(&stamp);
+            hdr[ts + 2] += 8;
+        }
+        IPOPT_TS_PRESPEC => {
+            let prespec = Ipv4Addr::from(
+                <[u8; 4]>::try_from(&hdr[slot..slot + 4]).unwrap(),
+            );
+
+            if !is_local_addr(prespec) {
+                return false;
+            }
+
+            hdr[slot + 4..slot + 8].copy_from_slice(&stamp);
+            hdr[ts + 2] += 8;
+        }
+        _ => return false,
+    }
+
+    true
+}
+
+/// Milliseconds since midnight UT
+pub fn ip_options_timestamp() -> u32 {
+    let now = UtcDateTime::from(clock_wall());
+
+    ((now.hour() as u32 * 60 + now.minute() as u32) * 60 + now.second() as u32)
+        * 1000
+        + now.millisecond() as u32
+}
+
+/// Replace options not copied into non-first fragments with NOOP
+pub fn ip_options_fragment(opts: &mut [u8]) {
+    let mut off = 0;
+
+    while off < opts.len() {
+        let ty = opts[off];
+
+        if ty == IPOPT_END {
+            break;
+        }
+
+        if ty == IPOPT_NOOP {
+            off += 1;
+            continue;
+        }
+
+        let Some(&optlen) = opts.get(off + 1)
+        else {
+            break;
+        };
+
+        let optlen = optlen as usize;
+
+        if optlen < 2 || off + optlen > opts.len() {
+            break;
+        }
+
+        if ty & IPOPT_COPY == 0 {
+            opts[off..off + optlen].fill(IPOPT_NOOP);
+        }
+
+        off += optlen;
+    }
+}
+
+/// Encode options padded to 32 bits, `dst` is appended to source route.
+pub fn ip_options_build(
+    opts: &[IPOption],
+    dst: Ipv4Addr,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(IPOPT_MAX_LEN);
+
+    for opt in opts {
+        let optlen = match opt {
+            IPOption::RecordRoute { slots } => 3 + *slots as usize * 4,
+            IPOption::Timestamp { flag, slots } => match *flag {
+                IPOPT_TS_TSONLY => 4 + *slots as usize * 4,
+                IPOPT_TS_TSANDADDR => 4 + *slots as usize * 8,
+                _ => Err(anyhow!("Unsupported timestamp flag {flag}"))?,
+            },
+            IPOption::TimestampPrespec(addrs) => 4 + addrs.len() * 8,
+            IPOption::LooseSourceRoute(hops)
+            | IPOption::StrictSourceRoute(hops) => {
+                if hops.is_empty() {
+                    Err(anyhow!("Empty source route"))?
+                }
+
+                3 + hops.len() * 4
+            }
+            IPOption::RouterAlert => 4,
+        };
+
+        if buf.len() + optlen > IPOPT_MAX_LEN {
+            Err(anyhow!("IPv4 options exceed {IPOPT_MAX_LEN} bytes"))?
+        }
+
+        let start = buf.len();
+
+        match opt {
+            IPOption::RecordRoute { .. } => {
+                buf.extend_from_slice(&[IPOPT_RR, optlen as u8, 4]);
+            }
+            IPOption::Timestamp { flag, .. } => {
+                buf.extend_from_slice(&[IPOPT_TS, optlen as u8, 5, *flag]);
+            }
+            IPOption::TimestampPrespec(addrs) => {
+                buf.extend_from_slice(&[
+                    IPOPT_TS,
+                    optlen as u8,
+                    5,
+                    IPOPT_TS_PRESPEC,
+                ]);
+
+                for addr in addrs {
+                    buf.extend_from_slice(&addr.octets());
+                    buf.extend_from_slice(&[0; 4]);
+                }
+            }
+            IPOption::LooseSourceRoute(hops)
+            | IPOption::StrictSourceRoute(hops) => {
+                let ty = if matches!(opt, IPOption::LooseSourceRoute(_)) {
+                    IPOPT_LSRR
+                }
+                else {
+                    IPOPT_SSRR
+                };
+
+                buf.extend_from_slice(&[ty, optlen as u8, 4]);
+
+                // the first hop goes to destination field of header
+                for hop in hops[1..].iter().chain([&dst]) {
+                    buf.extend_from_slice(&hop.octets());
+                }
+            }
+            IPOption::RouterAlert => {
+                buf.extend_from_slice(&[IPOPT_RA, 4, 0, 0]);
+            }
+        }
+
+        // reserved slots are zero
+        buf.resize(start + optlen, 0);
+    }
+
+    buf.resize(buf.len().next_multiple_of(4), IPOPT_END);
+
+    Ok(buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_options_compile() {
+        let hdr = |opts: &[u8]| [&[0; 20], opts].concat();
+
+        // length less than 2, beyond the header, or missing
+        assert_eq!(ip_options_compile(&hdr(&[7, 1, 4, 0])).err(), Some(21));
+        assert_eq!(ip_options_compile(&hdr(&[7, 9, 4, 0])).err(), Some(21));
+        assert_eq!(ip_options_compile(&hdr(&[1, 1, 1, 7])).err(), Some(23));
+        // duplicate Record Route
+        assert_eq!(
+            ip_options_compile(&hdr(&[7, 3, 4, 7, 3, 4, 0, 0])).err(),
+            Some(23)
+        );
+        // pointer before the first slot, or into a partial slot
+        assert_eq!(
+            ip_options_compile(&hdr(&[131, 7, 3, 0, 0, 0, 0, 0])).err(),
+            Some(22)
+        );
+        assert_eq!(
+            ip_options_compile(&hdr(&[
+                137, 11, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]))
+            .err(),
+            Some(22)
+        );
+        // Timestamp overflow counter can't be increased
+        assert_eq!(
+            ip_options_compile(&hdr(&[68, 8, 9, 0xf0, 0, 0, 0, 0])).err(),
+            Some(23)
+        );
+
+        let mut hdr = hdr(&[137, 11, 4, 10, 0, 0, 3, 10, 0, 0, 2, 0]);
+        let opt = ip_options_compile(&hdr).unwrap();
+
+        assert_eq!(opt.srr, Some(20));
+        assert!(opt.is_strictroute);
+
+        for next in [[10, 0, 0, 3], [10, 0, 0, 2]] {
+            assert!(ip_options_srr_pending(&hdr, &opt));
+            assert_eq!(ip_options_srr_peek(&hdr, &opt), Some(next.into()));
+
+            ip_options_srr_record(&mut hdr, &opt, [10, 0, 0, 9].into());
+        }
+
+        assert!(!ip_options_srr_pending(&hdr, &opt));
+        assert_eq!(hdr[27..31], [10, 0, 0, 9]);
+    }
+
+    #[test]
+    fn test_ip_options_overflow() {
+        let addr = [10, 0, 0, 9].into();
+        let mut hdr = [&[0; 20][..], &[7, 7, 4, 0, 0, 0, 0, 0]].concat();
+        let opt = ip_options_compile(&hdr).unwrap();
+
+        assert!(ip_options_forward(&mut hdr, &opt, addr));
+        assert_eq!(hdr[23..28], [10, 0, 0, 9, 0]);
+        // Record Route is full
+        assert!(!ip_options_forward(&mut hdr, &opt, addr));
+        assert_eq!(hdr[22], 8);
+
+        // full Timestamp counts the hop in its overflow
+        let mut hdr = [&[0; 20][..], &[68, 8, 9, 0x01, 0, 0, 0, 0]].concat();
+
+        assert!(ip_options_stamp(&mut hdr, 20, addr));
+        assert_eq!(hdr[23], 0x11);
+    }
+
+    #[test]
+    fn test_ip_options_build() {
+        let hops = vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()];
+        let opts = ip_options_build(
+            &[
+                IPOption::LooseSourceRoute(hops),
+                IPOption::RecordRoute { slots: 2 },
+            ],
+            [10, 0, 0, 3].into(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            opts,
+            [
+                131, 11, 4, 10, 0, 0, 2, 10, 0, 0, 3, 7, 11, 4, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0
+            ]
+        );
+
+        let mut hdr = [&[0; 20][..], &opts].concat();
+        let opt = ip_options_compile(&hdr).unwrap();
+
+        assert_eq!((opt.srr, opt.rr), (Some(20), Some(31)));
+
+        // LSRR is copied into all fragments, RR is not
+        ip_options_fragment(&mut hdr[20..]);
+
+        assert_eq!(hdr[20..31], opts[..11]);
+        assert_eq!(hdr[31..42], [1; 11]);
+
+        let too_long =
+            [IPOption::RecordRoute { slots: 9 }, IPOption::RouterAlert];
+
+        assert!(ip_options_build(&too_long, [10, 0, 0, 3].into()).is_err());
+    }
+}
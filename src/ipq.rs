@@ -0,0 +1,380 @@
+use std::{collections::HashMap, net::Ipv4Addr, time::Duration};
+
+use derive_more::derive::Deref;
+use log::trace;
+use m6ptr::LazyStatic;
+use m6tobytes::as_raw_slice;
+use osimodel::network::{
+    icmp::ICMPTypeKind,
+    ip::{FlagsAndOff, IPv4, TotLen},
+};
+
+use crate::{
+    dev::dev_get_by_name,
+    icmp::ICMP_EXC_FRAGTIME,
+    ip::{IP_DF, IP_MF, IP_OFFMASK, ip_send_check, ip_split},
+    snmp::SNMP_IP,
+    timer::{TIMER_WHEEL, TimerId, TimerWheel},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Incomplete datagrams are dropped after it like Linux `ipfrag_time`
+pub const IPQ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of queued fragments, new fragments are dropped beyond it like
+/// Linux `ipfrag_high_thresh`
+pub const IPQ_HIGH_THRESH: usize = 4 << 20;
+
+const IP_MAXPACKET: usize = 0xFFFF;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+pub static IPQ_TBL: LazyStatic<IPQTbl> = LazyStatic::new(IPQTbl::new);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Fragments of the same datagram (RFC 791 3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IPQKey {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub id: u16,
+    pub proto: u8,
+}
+
+/// Datagram being reassembled
+#[derive(Debug)]
+pub struct IPQ {
+    /// Header of the first fragment, it becomes header of the datagram
+    hdr: Option<Vec<u8>>,
+    /// (offset, data) in order without overlap
+    frags: Vec<(usize, Vec<u8>)>,
+    /// Payload length known from the last fragment
+    len: Option<usize>,
+    /// Bytes received
+    meat: usize,
+    /// Interface of the first fragment, Time Exceeded is sent on it
+    ifname: String,
+    timer: TimerId,
+}
+
+/// Datagrams being reassembled, they expire by timers on `wheel`
+#[derive(Deref)]
+pub struct IPQTbl {
+    #[deref]
+    value: HashMap<IPQKey, IPQ>,
+    /// Bytes of all fragments
+    mem: usize,
+    wheel: &'static LazyStatic<TimerWheel>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl IPQ {
+    /// Insert the fragment of `off` unless it's a duplicate, returns false
+    /// if it overlaps others like Linux since 4.19
+    fn insert(&mut self, off: usize, data: &[u8]) -> bool {
+        let end = off + data.len();
+        let pos = self.frags.partition_point(|(o, _)| *o < off);
+
+        if let Some((o, d)) = self.frags.get(pos)
+            && *o == off
+            && d.len() == data.len()
+        {
+            return true;
+        }
+
+        if pos > 0 {
+            let (o, d) = &self.frags[pos - 1];
+
+            if o + d.len() > off {
+                return false;
+            }
+        }
+
+        if let Some((o, _)) = self.frags.get(pos)
+            && *o < end
+        {
+            return false;
+        }
+
+        self.frags.insert(pos, (off, data.to_vec()));
+        self.meat += data.len();
+
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.hdr.is_some() && Some(self.meat) == self.len
+    }
+
+    /// The datagram of header of the first fragment with all data
+    fn reassemble(&self) -> Vec<u8> {
+        let hdr = self.hdr.as_ref().unwrap();
+        let mut pkt = Vec::with_capacity(hdr.len() + self.meat);
+
+        pkt.extend_from_slice(hdr);
+
+        for (_, data) in self.frags.iter() {
+            pkt.extend_from_slice(data);
+        }
+
+        let (mut iph, _) = ip_split(&pkt);
+
+        iph.totlen = TotLen::new_with_tot_len(pkt.len() as u16);
+        iph.flags_off =
+            FlagsAndOff::from_bits(iph.flags_off.to_bits() & IP_DF);
+
+        pkt[..size_of::<IPv4>()].copy_from_slice(as_raw_slice(&iph));
+        ip_send_check(&mut pkt);
+
+        pkt
+    }
+
+    /// The first fragment as it's received for the quote of ICMP error
+    fn first_frag(&self) -> Option<Vec<u8>> {
+        let hdr = self.hdr.as_ref()?;
+        let (0, data) = self.frags.first()?
+        else {
+            return None;
+        };
+
+        Some([&hdr[..], data].concat())
+    }
+}
+
+impl IPQTbl {
+    pub fn new() -> Self {
+        Self::with_wheel(&TIMER_WHEEL)
+    }
+
+    pub fn with_wheel(wheel: &'static LazyStatic<TimerWheel>) -> Self {
+        Self {
+            value: HashMap::new(),
+            mem: 0,
+            wheel,
+        }
+    }
+
+    /// Remove the queue of `key` with its timer
+    fn kill(&mut self, key: IPQKey) -> Option<IPQ> {
+        let q = self.value.remove(&key)?;
+
+        self.wheel.write().unwrap().cancel(q.timer);
+        self.mem -= q.meat;
+
+        Some(q)
+    }
+}
+
+impl Default for IPQTbl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+pub fn ip_is_fragment(pkt: &[u8]) -> bool {
+    let (iph, _) = ip_split(pkt);
+
+    iph.flags_off.to_bits() & (IP_MF | IP_OFFMASK) != 0
+}
+
+/// Queue the fragment received on `ifname`, returns the datagram once all
+/// fragments have arrived (RFC 815)
+pub fn ip_defrag(pkt: &[u8], ifname: &str) -> Option<Vec<u8>> {
+    ipq_defrag(&IPQ_TBL, pkt, ifname)
+}
+
+/// `ip_defrag` on the queues of `tbl`
+fn ipq_defrag(
+    tbl: &'static LazyStatic<IPQTbl>,
+    pkt: &[u8],
+    ifname: &str,
+) -> Option<Vec<u8>> {
+    let (iph, data) = ip_split(pkt);
+    let hlen = pkt.len() - data.len();
+    let flags_off = iph.flags_off.to_bits();
+    let off = (flags_off & IP_OFFMASK) as usize * 8;
+    let end = off + data.len();
+    let is_last = flags_off & IP_MF == 0;
+
+    let key = IPQKey {
+        src: iph.src.into(),
+        dst: iph.dst.into(),
+        id: iph.id.to_bits(),
+        proto: iph.proto.to_bits(),
+    };
+
+    SNMP_IP.reasm_reqds.inc();
+
+    if hlen + end > IP_MAXPACKET || !is_last && data.len() % 8 != 0 {
+        trace!("Malformed fragment {key:?} at {off}");
+        SNMP_IP.reasm_fails.inc();
+        return None;
+    }
+
+    if data.is_empty() {
+        return None;
+    }
+
+    let mut ipqtbl = tbl.write().unwrap();
+    let qs = &mut *ipqtbl;
+
+    if qs.mem + data.len() > IPQ_HIGH_THRESH {
+        trace!("Reassembly queue is full, drop fragment {key:?}");
+        SNMP_IP.reasm_fails.inc();
+        return None;
+    }
+
+    let q = qs.value.entry(key).or_insert_with(|| IPQ {
+        hdr: None,
+        frags: Vec::new(),
+        len: None,
+        meat: 0,
+        ifname: ifname.to_owned(),
+        timer: qs
+            .wheel
+            .write()
+            .unwrap()
+            .add(IPQ_TIMEOUT, Box::new(move || ipq_expire(tbl, key))),
+    });
+
+    let meat = q.meat;
+
+    // data beyond the end, or another end
+    let is_corrupt = match q.len {
+        Some(len) => end > len || is_last && end != len,
+        None => {
+            is_last && q.frags.last().is_some_and(|(o, d)| o + d.len() > end)
+        }
+    };
+
+    if is_corrupt || !q.insert(off, data) {
+        trace!("Overlapped or corrupt fragment {key:?} at {off}");
+        qs.kill(key);
+        SNMP_IP.reasm_fails.inc();
+        return None;
+    }
+
+    qs.mem += q.meat - meat;
+
+    if is_last {
+        q.len = Some(end);
+    }
+
+    if off == 0 {
+        q.hdr = Some(pkt[..hlen].to_vec());
+    }
+
+    if !q.is_complete() {
+        return None;
+    }
+
+    let pkt = q.reassemble();
+
+    qs.kill(key);
+    SNMP_IP.reasm_oks.inc();
+
+    Some(pkt)
+}
+
+/// Drop the incomplete datagram, the sender is told if the first fragment
+/// is there (RFC 792)
+fn ipq_expire(tbl: &LazyStatic<IPQTbl>, key: IPQKey) {
+    let Some(q) = tbl.write().unwrap().kill(key)
+    else {
+        return;
+    };
+
+    trace!("Reassembly of {key:?} timed out");
+    SNMP_IP.reasm_timeout.inc();
+    SNMP_IP.reasm_fails.inc();
+
+    let (Some(frag), Some(dev)) = (q.first_frag(), dev_get_by_name(&q.ifname))
+    else {
+        return;
+    };
+
+    if let Err(err) =
+        dev.icmp_send(&frag, ICMPTypeKind::TimeExceeded, ICMP_EXC_FRAGTIME, 0)
+    {
+        trace!("ICMP Time Exceeded of {key:?} failed: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ip::{ip_fragment, tests::ipv4_datagram},
+        timer::TIMER_TICK,
+    };
+
+    static WHEEL: LazyStatic<TimerWheel> = LazyStatic::new(TimerWheel::new);
+
+    static IPQS: LazyStatic<IPQTbl> =
+        LazyStatic::new(|| IPQTbl::with_wheel(&WHEEL));
+
+    #[test]
+    fn test_ip_defrag() {
+        let opts = [7, 7, 4, 0, 0, 0, 0, 0x94, 4, 0, 0, 0];
+        let payload = (0..100).collect::<Vec<u8>>();
+        let pkt = ipv4_datagram(64, &opts, &payload);
+        let frags = ip_fragment(&pkt, 80).unwrap();
+        let defrag = |frag: &[u8]| ipq_defrag(&IPQS, frag, "lo");
+
+        assert!(!ip_is_fragment(&pkt));
+        assert!(frags.iter().all(|frag| ip_is_fragment(frag)));
+
+        // out of order with a duplicate
+        assert_eq!(defrag(&frags[2]), None);
+        assert_eq!(defrag(&frags[0]), None);
+        assert_eq!(defrag(&frags[2]), None);
+        assert_eq!(defrag(&frags[1]), Some(pkt.clone()));
+        assert!(IPQS.read().unwrap().is_empty());
+        assert!(WHEEL.read().unwrap().is_empty());
+
+        // overlapped fragment drops the whole datagram
+        let mut overlap = frags[1].clone();
+
+        overlap[7] -= 1;
+        ip_send_check(&mut overlap);
+
+        assert_eq!(defrag(&frags[0]), None);
+        assert_eq!(defrag(&overlap), None);
+        assert_eq!(defrag(&frags[1]), None);
+        assert_eq!(defrag(&frags[2]), None);
+        assert_eq!(
+            IPQS.read().unwrap().mem,
+            frags[1..]
+                .iter()
+                .map(|frag| ip_split(frag).1.len())
+                .sum::<usize>()
+        );
+
+        /* incomplete datagram expires on the last tick */
+
+        let ticks = IPQ_TIMEOUT.as_millis() / TIMER_TICK.as_millis();
+
+        for _ in 1..ticks {
+            assert!(WHEEL.write().unwrap().tick().is_empty());
+        }
+
+        let expired = WHEEL.write().unwrap().tick();
+
+        for f in expired {
+            f();
+        }
+
+        assert!(IPQS.read().unwrap().is_empty());
+        assert_eq!(IPQS.read().unwrap().mem, 0);
+        assert!(WHEEL.read().unwrap().is_empty());
+    }
+}
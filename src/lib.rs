@@ -4,10 +4,25 @@ pub mod arp;
 pub mod skbuff;
 pub mod dev;
 pub mod ip;
-
-
-#[cfg(test)]
-mod tests {
-
-}
-
+pub mod ipopt;
+pub mod ipq;
+pub mod icmp;
+pub mod route;
+pub mod udp;
+pub mod tcp;
+pub mod tcpcc;
+pub mod syncookie;
+pub mod snmp;
+pub mod clock;
+pub mod timer;
+pub mod reactor;
+pub mod config;
+pub mod ctl;
+pub mod metrics;
+pub mod pcapng;
+pub mod capture;
+pub mod filter;
+pub mod bpf;
+pub mod ring;
+pub mod mmsg;
+pub mod socket;
@@ -0,0 +1,546 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write as _},
+    fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use log::trace;
+
+use crate::{
+    arp::{ARP_QUEUE, ARP_TBL},
+    dev::{DEV_TBL, DevStats},
+    ip::IP_FORWARD,
+    reactor::{EventSource, Reactor},
+    snmp::{Counter, snmp_groups},
+    tcp::TCP_TBL,
+    timer::TIMER_WHEEL,
+    udp::UDP_TBL,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Longest request head, the client is dropped beyond it
+const METRICS_HEAD_MAX: usize = 8 << 10;
+
+const METRICS_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// `HOST:PORT` or `unix:PATH`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// HTTP endpoint of `/metrics` polled by the reactor, a Unix socket file
+/// is removed on drop
+pub struct MetricsServer {
+    listener: MetricsListener,
+}
+
+enum MetricsListener {
+    Inet(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+enum MetricsStream {
+    Inet(TcpStream),
+    Unix(UnixStream),
+}
+
+/// One request is answered then the connection is closed
+struct MetricsConn {
+    stream: MetricsStream,
+    buf: Vec<u8>,
+}
+
+/// Counter of interfaces to be exported
+type DevCounterFn = fn(&DevStats) -> &Counter;
+
+/// Builder of the text format, samples of a family follow its `TYPE`
+#[derive(Default)]
+struct MetricsText {
+    text: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl FromStr for MetricsAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+
+        Ok(Self::Inet(
+            s.parse().with_context(|| format!("metrics address {s}"))?,
+        ))
+    }
+}
+
+impl Display for MetricsAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "http://{addr}/metrics"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl MetricsServer {
+    /// A stale Unix socket file is replaced
+    pub fn bind(addr: &MetricsAddr) -> anyhow::Result<Self> {
+        let listener = match addr {
+            MetricsAddr::Inet(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("bind {addr}"))?;
+
+                listener.set_nonblocking(true)?;
+                MetricsListener::Inet(listener)
+            }
+            MetricsAddr::Unix(path) => {
+                if path.exists() {
+                    if UnixStream::connect(path).is_ok() {
+                        Err(anyhow!("{} is in use", path.display()))?
+                    }
+
+                    fs::remove_file(path)?;
+                }
+
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("bind {}", path.display()))?;
+
+                listener.set_nonblocking(true)?;
+                MetricsListener::Unix(listener, path.clone())
+            }
+        };
+
+        Ok(Self { listener })
+    }
+
+    fn accept(&self) -> io::Result<MetricsStream> {
+        let stream = match &self.listener {
+            MetricsListener::Inet(listener) => {
+                MetricsStream::Inet(listener.accept()?.0)
+            }
+            MetricsListener::Unix(listener, _) => {
+                MetricsStream::Unix(listener.accept()?.0)
+            }
+        };
+
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(METRICS_WRITE_TIMEOUT))?;
+
+        Ok(stream)
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        if let MetricsListener::Unix(_, path) = &self.listener {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl EventSource for MetricsServer {
+    fn fd(&self) -> BorrowedFd<'_> {
+        match &self.listener {
+            MetricsListener::Inet(listener) => listener.as_fd(),
+            MetricsListener::Unix(listener, _) => listener.as_fd(),
+        }
+    }
+
+    fn ready(&mut self, reactor: &mut Reactor) -> anyhow::Result<bool> {
+        loop {
+            let stream = match self.accept() {
+                Ok(stream) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => Err(err)?,
+            };
+
+            reactor.register(Box::new(MetricsConn {
+                stream,
+                buf: Vec::new(),
+            }))?;
+        }
+
+        Ok(true)
+    }
+}
+
+impl MetricsStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Inet(stream) => stream.set_nonblocking(nonblocking),
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Inet(stream) => stream.set_write_timeout(dur),
+            Self::Unix(stream) => stream.set_write_timeout(dur),
+        }
+    }
+}
+
+impl Read for MetricsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Inet(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MetricsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Inet(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Inet(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl EventSource for MetricsConn {
+    fn fd(&self) -> BorrowedFd<'_> {
+        match &self.stream {
+            MetricsStream::Inet(stream) => stream.as_fd(),
+            MetricsStream::Unix(stream) => stream.as_fd(),
+        }
+    }
+
+    fn ready(&mut self, _reactor: &mut Reactor) -> anyhow::Result<bool> {
+        let mut chunk = [0u8; 1024];
+        let n = self.stream.read(&mut chunk)?;
+
+        if n == 0 {
+            return Ok(false);
+        }
+
+        self.buf.extend_from_slice(&chunk[..n]);
+
+        if !self.buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            if self.buf.len() > METRICS_HEAD_MAX {
+                Err(anyhow!("request head is longer than {METRICS_HEAD_MAX}"))?
+            }
+
+            return Ok(true);
+        }
+
+        let head = String::from_utf8_lossy(&self.buf);
+        let mut parts = head.split_ascii_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+
+        trace!("metrics: {method} {path}");
+
+        let (status, body) = match (method, path) {
+            ("GET", "/metrics" | "/") => ("200 OK", metrics_render()),
+            ("GET", _) => ("404 Not Found", "Not Found\n".to_owned()),
+            _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned()),
+        };
+
+        write!(
+            self.stream,
+            "HTTP/1.1 {status}\r\n\
+             Content-Type: {METRICS_CONTENT_TYPE}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+
+        Ok(false)
+    }
+}
+
+impl MetricsText {
+    fn family(&mut self, name: &str, ty: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {ty}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], val: u64) {
+        self.text.push_str(name);
+
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(key, val)| format!("{key}=\"{}\"", metrics_escape(val)))
+                .collect::<Vec<_>>();
+
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+
+        let _ = writeln!(self.text, " {val}");
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn metrics_escape(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Metrics of the stack in Prometheus text format, SNMP counters are named
+/// like `netstat` of node exporter
+pub fn metrics_render() -> String {
+    let mut m = MetricsText::default();
+
+    for (group, values) in snmp_groups() {
+        for (name, val) in values {
+            let metric = format!("sip_netstat_{group}_{name}");
+
+            m.family(&metric, "counter", &format!("{group} {name} of SNMP"));
+            m.sample(&metric, &[], val);
+        }
+    }
+
+    /* interfaces */
+
+    let devs = DEV_TBL.read().unwrap().clone();
+
+    let families: [(&str, &str, DevCounterFn); 5] = [
+        (
+            "sip_network_receive_bytes_total",
+            "Bytes of frames read from the link",
+            |stats| &stats.rx_bytes,
+        ),
+        (
+            "sip_network_receive_packets_total",
+            "Frames read from the link",
+            |stats| &stats.rx_packets,
+        ),
+        (
+            "sip_network_transmit_bytes_total",
+            "Bytes of frames sent to the link",
+            |stats| &stats.tx_bytes,
+        ),
+        (
+            "sip_network_transmit_packets_total",
+            "Frames sent to the link",
+            |stats| &stats.tx_packets,
+        ),
+        (
+            "sip_network_transmit_errs_total",
+            "Frames failed to be sent",
+            |stats| &stats.tx_errors,
+        ),
+    ];
+
+    for (metric, help, counter) in families {
+        m.family(metric, "counter", help);
+
+        for dev in devs.iter() {
+            m.sample(
+                metric,
+                &[("device", &dev.name)],
+                counter(&dev.stats).get(),
+            );
+        }
+    }
+
+    /* tables */
+
+    m.family("sip_ip_forwarding", "gauge", "IPv4 forwarding is enabled");
+    m.sample(
+        "sip_ip_forwarding",
+        &[],
+        IP_FORWARD.load(Ordering::Relaxed) as u64,
+    );
+
+    m.family("sip_arp_entries", "gauge", "Valid records of ARP cache");
+    m.sample(
+        "sip_arp_entries",
+        &[],
+        ARP_TBL
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|rec| rec.is_valid)
+            .count() as u64,
+    );
+
+    m.family(
+        "sip_arp_queue_length",
+        "gauge",
+        "Datagrams waiting on ARP resolution",
+    );
+    m.sample(
+        "sip_arp_queue_length",
+        &[],
+        ARP_QUEUE.read().unwrap().len() as u64,
+    );
+
+    m.family("sip_timers_pending", "gauge", "Pending timers of the stack");
+    m.sample(
+        "sip_timers_pending",
+        &[],
+        TIMER_WHEEL.read().unwrap().len() as u64,
+    );
+
+    /* sockets by state */
+
+    let mut socks = BTreeMap::<(&str, String), u64>::new();
+    let pcbs = TCP_TBL
+        .read()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for pcb in pcbs {
+        let state = pcb.tcb.lock().unwrap().state.to_string();
+
+        *socks.entry(("tcp", state)).or_default() += 1;
+    }
+
+    for key in UDP_TBL.read().unwrap().keys() {
+        let state = if key.remote.is_some() {
+            "ESTAB"
+        }
+        else {
+            "UNCONN"
+        };
+
+        *socks.entry(("udp", state.to_owned())).or_default() += 1;
+    }
+
+    m.family("sip_sockets", "gauge", "Sockets by protocol and state");
+
+    for ((proto, state), n) in socks {
+        m.sample("sip_sockets", &[("proto", proto), ("state", &state)], n);
+    }
+
+    m.text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet, env, net::Shutdown, path::Path, process, thread,
+    };
+
+    use super::*;
+
+    /// Send `req` to the endpoint on `path` and split the response into
+    /// the status line, the headers and the body
+    fn metrics_get(path: &Path, req: &str) -> (String, String, String) {
+        let mut stream = UnixStream::connect(path).unwrap();
+        let mut resp = String::new();
+
+        stream.write_all(req.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        stream.read_to_string(&mut resp).unwrap();
+
+        let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+        let (status, headers) = head.split_once("\r\n").unwrap();
+
+        (status.to_owned(), headers.to_owned(), body.to_owned())
+    }
+
+    #[test]
+    fn test_metrics_scrape() {
+        let path = env::temp_dir()
+            .join(format!("sip-metrics-{}.sock", process::id()));
+        let addr = format!("unix:{}", path.display()).parse().unwrap();
+        let server = MetricsServer::bind(&addr).unwrap();
+        let mut reactor = Reactor::new().unwrap();
+        let handle = reactor.handle();
+
+        reactor.register(Box::new(server)).unwrap();
+
+        let client = thread::spawn(move || {
+            let resps = [
+                "GET /metrics HTTP/1.1\r\nHost: sip\r\n\r\n",
+                "GET /nothing HTTP/1.1\r\n\r\n",
+                "POST /metrics HTTP/1.1\r\n\r\n",
+            ]
+            .map(|req| metrics_get(&path, req));
+
+            handle.stop().unwrap();
+
+            resps
+        });
+
+        reactor.run().unwrap();
+
+        let [(status, headers, body), not_found, not_allowed] =
+            client.join().unwrap();
+
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(headers.contains(METRICS_CONTENT_TYPE));
+        assert!(headers.contains(&format!("Content-Length: {}", body.len())));
+        assert!(not_found.0.ends_with("404 Not Found"));
+        assert!(not_allowed.0.ends_with("405 Method Not Allowed"));
+
+        // each family is described once, before its samples
+        let mut helps = HashSet::new();
+        let mut types = HashSet::new();
+
+        for line in body.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                assert!(
+                    helps.insert(help.split(' ').next().unwrap()),
+                    "{line}"
+                );
+                continue;
+            }
+
+            if let Some(ty) = line.strip_prefix("# TYPE ") {
+                let (name, ty) = ty.split_once(' ').unwrap();
+
+                assert!(helps.contains(name), "{line}");
+                assert!(matches!(ty, "counter" | "gauge"), "{line}");
+                assert!(types.insert(name), "{line}");
+                continue;
+            }
+
+            let (sample, val) = line.rsplit_once(' ').unwrap();
+            let name = sample.split('{').next().unwrap();
+
+            assert!(types.contains(name), "{line}");
+            assert!(
+                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{line}"
+            );
+            assert!(val.parse::<u64>().is_ok(), "{line}");
+        }
+
+        assert!(types.contains("sip_netstat_Ip_InReceives"));
+        assert!(types.contains("sip_arp_entries"));
+        assert_eq!(metrics_escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
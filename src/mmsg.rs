@@ -0,0 +1,266 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+    ptr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::trace;
+use osimodel::datalink::Eth;
+
+use crate::clock::clock_wall;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Frames of each `recvmmsg` or `sendmmsg` at most
+pub const MMSG_VLEN: usize = 32;
+
+/// Control buffer of each message, room for `SCM_TIMESTAMPNS`
+const MMSG_CMSG_LEN: usize = 8;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Batch I/O of a packet socket by `recvmmsg` and `sendmmsg`
+#[derive(Debug)]
+pub struct MmsgIo {
+    fd: RawFd,
+    /// `MMSG_VLEN` frames received by one call
+    rx_buf: Mutex<Box<[u8]>>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl MmsgIo {
+    /// Frames are stamped by the kernel on arrival (`SO_TIMESTAMPNS`)
+    pub fn new(sd: BorrowedFd) -> io::Result<Self> {
+        let fd = sd.as_raw_fd();
+        let on: libc::c_int = 1;
+
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPNS,
+                &on as *const _ as *const _,
+                size_of::<libc::c_int>() as u32,
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            rx_buf: Mutex::new(
+                vec![0; MMSG_VLEN * Eth::FRAME_LEN].into_boxed_slice(),
+            ),
+        })
+    }
+
+    /// Wait for a frame, then call `f` with it and the ones already
+    /// queued, frames truncated by the buffer are dropped. Return the
+    /// number of frames.
+    pub fn recv_batch<F>(&self, mut f: F) -> io::Result<usize>
+    where
+        F: FnMut(&[u8], SystemTime),
+    {
+        let mut rx_buf = self.rx_buf.lock().unwrap();
+        let mut iovs: [libc::iovec; MMSG_VLEN] = unsafe { std::mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MMSG_VLEN] =
+            unsafe { std::mem::zeroed() };
+        // u64 for alignment of cmsghdr
+        let mut cmsgs = [[0u64; MMSG_CMSG_LEN]; MMSG_VLEN];
+
+        for (i, ((iov, msg), cmsg)) in iovs
+            .iter_mut()
+            .zip(msgs.iter_mut())
+            .zip(cmsgs.iter_mut())
+            .enumerate()
+        {
+            iov.iov_base = rx_buf[i * Eth::FRAME_LEN..].as_mut_ptr() as *mut _;
+            iov.iov_len = Eth::FRAME_LEN;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = cmsg.as_mut_ptr() as *mut _;
+            msg.msg_hdr.msg_controllen = size_of_val(cmsg);
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                self.fd,
+                msgs.as_mut_ptr(),
+                MMSG_VLEN as u32,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let now = clock_wall();
+
+        for (i, msg) in msgs[..n as usize].iter().enumerate() {
+            let off = i * Eth::FRAME_LEN;
+
+            if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                trace!("Drop frame truncated to {} bytes", msg.msg_len);
+                continue;
+            }
+
+            let ts = cmsg_timestamp(&msg.msg_hdr).unwrap_or(now);
+
+            f(&rx_buf[off..off + msg.msg_len as usize], ts);
+        }
+
+        Ok(n as usize)
+    }
+
+    /// Send `frames` by as few `sendmmsg` as the kernel takes, return the
+    /// number of frames sent before an error
+    pub fn send_batch(&self, frames: &[&[u8]]) -> (usize, io::Result<()>) {
+        let mut sent = 0;
+
+        for chunk in frames.chunks(MMSG_VLEN) {
+            let mut iovs: [libc::iovec; MMSG_VLEN] =
+                unsafe { std::mem::zeroed() };
+            let mut msgs: [libc::mmsghdr; MMSG_VLEN] =
+                unsafe { std::mem::zeroed() };
+
+            for ((frame, iov), msg) in
+                chunk.iter().zip(iovs.iter_mut()).zip(msgs.iter_mut())
+            {
+                iov.iov_base = frame.as_ptr() as *mut _;
+                iov.iov_len = frame.len();
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+            }
+
+            let mut off = 0;
+
+            // part of them may be taken
+            while off < chunk.len() {
+                let n = unsafe {
+                    libc::sendmmsg(
+                        self.fd,
+                        msgs[off..].as_mut_ptr(),
+                        (chunk.len() - off) as u32,
+                        0,
+                    )
+                };
+
+                if n < 0 {
+                    return (sent, Err(io::Error::last_os_error()));
+                }
+
+                off += n as usize;
+                sent += n as usize;
+            }
+        }
+
+        (sent, Ok(()))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Arrival time in `SCM_TIMESTAMPNS` of the message
+fn cmsg_timestamp(msg: &libc::msghdr) -> Option<SystemTime> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+
+        if hdr.cmsg_level == libc::SOL_SOCKET
+            && hdr.cmsg_type == libc::SCM_TIMESTAMPNS
+        {
+            let ts = unsafe {
+                libc::CMSG_DATA(cmsg)
+                    .cast::<libc::timespec>()
+                    .read_unaligned()
+            };
+
+            return Some(
+                UNIX_EPOCH
+                    + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32),
+            );
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::{fd::AsFd, unix::net::UnixDatagram};
+
+    use super::*;
+
+    /// Frames queued on `peer` so far
+    fn mmsg_drain(peer: &UnixDatagram) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        let mut buf = [0; 64];
+
+        while let Ok(n) = peer.recv(&mut buf) {
+            frames.push(buf[..n].to_vec());
+        }
+
+        frames
+    }
+
+    #[test]
+    fn test_mmsg_send_partial() {
+        let (sd, peer) = UnixDatagram::pair().unwrap();
+        let io = MmsgIo::new(sd.as_fd()).unwrap();
+        let frames = (0..MMSG_VLEN as u8 * 3)
+            .map(|i| vec![i; 60])
+            .collect::<Vec<_>>();
+        let refs = frames.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+        sd.set_nonblocking(true).unwrap();
+        peer.set_nonblocking(true).unwrap();
+
+        // the send buffer takes a few frames only, as low as the kernel
+        // allows
+        let sndbuf: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                sd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &sndbuf as *const _ as *const _,
+                size_of::<libc::c_int>() as u32,
+            )
+        };
+
+        assert_eq!(ret, 0);
+
+        let (sent, res) = io.send_batch(&refs);
+
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(sent > 0 && sent < frames.len());
+        assert_eq!(mmsg_drain(&peer), frames[..sent]);
+
+        // the rest goes on with each batch counting what it has sent
+        let mut off = sent;
+
+        while off < frames.len() {
+            let (sent, res) = io.send_batch(&refs[off..]);
+            let recv = mmsg_drain(&peer);
+
+            assert_eq!(recv, frames[off..off + sent]);
+            assert_eq!(res.is_ok(), off + sent == frames.len());
+
+            off += sent;
+        }
+    }
+}
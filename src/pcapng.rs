@@ -0,0 +1,298 @@
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+pub const LINKTYPE_ETHERNET: u16 = 1;
+
+/* Block types */
+
+const BT_SHB: u32 = 0x0A0D_0D0A;
+const BT_IDB: u32 = 0x0000_0001;
+const BT_EPB: u32 = 0x0000_0006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/* Option codes */
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+/* Direction of epb_flags */
+
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Writer of pcapng (draft-ietf-opsawg-pcapng) in little endian with one
+/// section, timestamps are in microseconds
+#[derive(Debug)]
+pub struct PcapngWriter<W> {
+    w: W,
+    ifaces: u32,
+    /// Bytes written since the Section Header Block
+    written: u64,
+}
+
+/// Body of block being built
+#[derive(Default)]
+struct Block(Vec<u8>);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start the section, `appl` is the application writing it
+    pub fn new(w: W, appl: &str) -> io::Result<Self> {
+        let mut it = Self {
+            w,
+            ifaces: 0,
+            written: 0,
+        };
+        let mut block = Block::default();
+
+        block.u32(BYTE_ORDER_MAGIC);
+        block.u16(1);
+        block.u16(0);
+        // section length is unspecified
+        block.bytes(&(-1i64).to_le_bytes());
+        block.opt(SHB_USERAPPL, appl.as_bytes());
+        block.opt_end();
+
+        it.write_block(BT_SHB, block)?;
+
+        Ok(it)
+    }
+
+    /// Describe an interface, return its ID referred by packets
+    pub fn add_interface(
+        &mut self,
+        name: &str,
+        linktype: u16,
+        snaplen: u32,
+    ) -> io::Result<u32> {
+        let mut block = Block::default();
+
+        block.u16(linktype);
+        block.u16(0);
+        block.u32(snaplen);
+        block.opt(IF_NAME, name.as_bytes());
+        block.opt_end();
+
+        self.write_block(BT_IDB, block)?;
+        self.ifaces += 1;
+
+        Ok(self.ifaces - 1)
+    }
+
+    /// Write Enhanced Packet Block of `data` captured at `ts`
+    pub fn write_packet(
+        &mut self,
+        ifid: u32,
+        ts: SystemTime,
+        dir: Option<Direction>,
+        data: &[u8],
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        if ifid >= self.ifaces {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no interface {ifid}"),
+            ));
+        }
+
+        let micros = ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut block = Block::default();
+
+        block.u32(ifid);
+        block.u32((micros >> 32) as u32);
+        block.u32(micros as u32);
+        block.u32(data.len() as u32);
+        block.u32(data.len() as u32);
+        block.bytes(data);
+
+        if let Some(dir) = dir {
+            let flags = match dir {
+                Direction::In => EPB_FLAGS_INBOUND,
+                Direction::Out => EPB_FLAGS_OUTBOUND,
+            };
+
+            block.opt(EPB_FLAGS, &flags.to_le_bytes());
+        }
+
+        if let Some(comment) = comment {
+            block.opt(OPT_COMMENT, comment.as_bytes());
+        }
+
+        block.opt_end();
+
+        self.write_block(BT_EPB, block)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+
+    fn write_block(&mut self, ty: u32, block: Block) -> io::Result<()> {
+        // type, total length, body, total length
+        let total = (block.0.len() + 12) as u32;
+
+        self.w.write_all(&ty.to_le_bytes())?;
+        self.w.write_all(&total.to_le_bytes())?;
+        self.w.write_all(&block.0)?;
+        self.w.write_all(&total.to_le_bytes())?;
+        self.written += total as u64;
+
+        Ok(())
+    }
+}
+
+impl Block {
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Padded to 32 bits
+    fn bytes(&mut self, v: &[u8]) {
+        self.0.extend_from_slice(v);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+    }
+
+    fn opt(&mut self, code: u16, v: &[u8]) {
+        self.u16(code);
+        self.u16(v.len().min(u16::MAX as usize) as u16);
+        self.bytes(&v[..v.len().min(u16::MAX as usize)]);
+    }
+
+    fn opt_end(&mut self) {
+        self.u16(OPT_ENDOFOPT);
+        self.u16(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// (type, body) of each block, checking both total lengths
+    fn pcapng_blocks(mut buf: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let u32_at = |b: &[u8], i: usize| {
+            u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+        };
+        let mut blocks = Vec::new();
+
+        while !buf.is_empty() {
+            let total = u32_at(buf, 4) as usize;
+
+            assert_eq!(total % 4, 0);
+            assert_eq!(u32_at(buf, total - 4) as usize, total);
+
+            blocks.push((u32_at(buf, 0), buf[8..total - 4].to_vec()));
+            buf = &buf[total..];
+        }
+
+        blocks
+    }
+
+    /// (code, value) of options, up to `opt_endofopt`
+    fn pcapng_opts(mut buf: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut opts = Vec::new();
+
+        loop {
+            let code = u16::from_le_bytes([buf[0], buf[1]]);
+            let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+
+            if code == 0 {
+                assert_eq!((len, buf.len()), (0, 4));
+                break;
+            }
+
+            opts.push((code, buf[4..4 + len].to_vec()));
+            buf = &buf[4 + len.next_multiple_of(4)..];
+        }
+
+        opts
+    }
+
+    #[test]
+    fn test_pcapng_writer() {
+        let mut out = Vec::new();
+        let ts = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+        let frame = (0..61).collect::<Vec<u8>>();
+
+        let mut w = PcapngWriter::new(&mut out, "sip").unwrap();
+        let ifid = w.add_interface("eth0", LINKTYPE_ETHERNET, 1514).unwrap();
+
+        w.write_packet(ifid, ts, Some(Direction::Out), &frame, Some("x"))
+            .unwrap();
+        w.write_packet(ifid, ts, None, &frame[..4], None).unwrap();
+
+        assert!(w.write_packet(1, ts, None, &frame, None).is_err());
+
+        let written = w.written();
+
+        assert_eq!(written, out.len() as u64);
+
+        let blocks = pcapng_blocks(&out);
+        let types = blocks.iter().map(|(ty, _)| *ty).collect::<Vec<_>>();
+
+        assert_eq!(types, [0x0A0D_0D0A, 1, 6, 6]);
+
+        // SHB: magic, version 1.0, section length -1, shb_userappl
+        let shb = &blocks[0].1;
+
+        assert_eq!(shb[..8], [0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0]);
+        assert_eq!(shb[8..16], [0xFF; 8]);
+        assert_eq!(pcapng_opts(&shb[16..]), [(4, b"sip".to_vec())]);
+
+        // IDB: linktype, reserved, snaplen, if_name
+        let idb = &blocks[1].1;
+
+        assert_eq!(idb[..8], [1, 0, 0, 0, 0xEA, 0x05, 0, 0]);
+        assert_eq!(pcapng_opts(&idb[8..]), [(2, b"eth0".to_vec())]);
+
+        // EPB: interface, timestamp high and low, lengths, padded data
+        let epb = &blocks[2].1;
+
+        assert_eq!(epb[..12], [0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(epb[12..20], [61, 0, 0, 0, 61, 0, 0, 0]);
+        assert_eq!(epb[20..81], frame);
+        assert_eq!(epb[81..84], [0; 3]);
+        assert_eq!(
+            pcapng_opts(&epb[84..]),
+            [(2, 2u32.to_le_bytes().to_vec()), (1, b"x".to_vec())]
+        );
+
+        let epb = &blocks[3].1;
+
+        assert_eq!(epb[12..20], [4, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(pcapng_opts(&epb[24..]), []);
+    }
+}
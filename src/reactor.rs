@@ -0,0 +1,446 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::{Arc, Mutex},
+};
+
+use linuxc::epoll::{Epoll, EpollData, EpollEvent, EpollFlag};
+use log::{trace, warn};
+
+use crate::{
+    dev::NetDevice,
+    timer::{TIMER_TICK, TimerDriver, timer_claim, timer_run},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Max events handled in one wait
+const REACTOR_EVENTS: usize = 64;
+
+/* Tokens of internal fds, sources start after them */
+
+const TOKEN_TIMER: u64 = 0;
+const TOKEN_WAKE: u64 = 1;
+const TOKEN_START: u64 = 2;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Readable fd polled by the reactor
+pub trait EventSource: Send {
+    fn fd(&self) -> BorrowedFd<'_>;
+
+    /// Handle readiness of `fd`, returns false to be deregistered
+    fn ready(&mut self, reactor: &mut Reactor) -> anyhow::Result<bool>;
+}
+
+pub type ReactorFn = Box<dyn FnOnce(&mut Reactor) + Send>;
+
+/// Single threaded event loop of the stack, it multiplexes devices, the
+/// timer wheel, wakeups from other threads and sources like control
+/// clients
+pub struct Reactor {
+    epoll: Epoll,
+    timerfd: OwnedFd,
+    handle: ReactorHandle,
+    sources: HashMap<u64, Box<dyn EventSource>>,
+    next_token: u64,
+    stopped: bool,
+}
+
+/// Wake the reactor from other threads and run closures on it
+#[derive(Clone)]
+pub struct ReactorHandle {
+    eventfd: Arc<OwnedFd>,
+    posted: Arc<Mutex<Vec<ReactorFn>>>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl Reactor {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut epoll = Epoll::create()?;
+        let timerfd = timerfd_create()?;
+        let eventfd = eventfd_create()?;
+
+        epoll.insert(timerfd.as_fd(), reactor_event(TOKEN_TIMER))?;
+        epoll.insert(eventfd.as_fd(), reactor_event(TOKEN_WAKE))?;
+
+        Ok(Self {
+            epoll,
+            timerfd,
+            handle: ReactorHandle {
+                eventfd: Arc::new(eventfd),
+                posted: Default::default(),
+            },
+            sources: HashMap::new(),
+            next_token: TOKEN_START,
+            stopped: false,
+        })
+    }
+
+    pub fn handle(&self) -> ReactorHandle {
+        self.handle.clone()
+    }
+
+    /// Poll `src` until its `ready` returns false or fails, returns its
+    /// token
+    pub fn register(
+        &mut self,
+        src: Box<dyn EventSource>,
+    ) -> anyhow::Result<u64> {
+        let token = self.next_token;
+
+        self.epoll.insert(src.fd(), reactor_event(token))?;
+        self.sources.insert(token, src);
+        self.next_token += 1;
+
+        Ok(token)
+    }
+
+    pub fn deregister(
+        &mut self,
+        token: u64,
+    ) -> anyhow::Result<Option<Box<dyn EventSource>>> {
+        let Some(src) = self.sources.remove(&token)
+        else {
+            return Ok(None);
+        };
+
+        self.epoll.remove(src.fd())?;
+
+        Ok(Some(src))
+    }
+
+    /// `run` returns after current events are handled
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let mut events = [EpollEvent::default(); REACTOR_EVENTS];
+
+        // the first reactor drives timers of the stack
+        if timer_claim(TimerDriver::Reactor)? {
+            timerfd_settime(self.timerfd.as_fd())?;
+        }
+
+        while !self.stopped {
+            let tokens = self
+                .epoll
+                .pwait(&mut events, -1, None)?
+                .iter()
+                .map(|ev| unsafe { ev.data.u64 })
+                .collect::<Vec<_>>();
+
+            for token in tokens {
+                self.dispatch(token)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&mut self, token: u64) -> anyhow::Result<()> {
+        match token {
+            TOKEN_TIMER => {
+                fd_read_u64(self.timerfd.as_fd())?;
+                timer_run();
+            }
+            TOKEN_WAKE => {
+                fd_read_u64(self.handle.eventfd.as_fd())?;
+
+                let posted =
+                    std::mem::take(&mut *self.handle.posted.lock().unwrap());
+
+                for f in posted {
+                    f(self);
+                }
+            }
+            _ => {
+                // it may be deregistered by previous event
+                let Some(mut src) = self.sources.remove(&token)
+                else {
+                    return Ok(());
+                };
+
+                let keep = src.ready(self).unwrap_or_else(|err| {
+                    warn!("reactor source {token}: {err:#}");
+                    false
+                });
+
+                if keep {
+                    self.sources.insert(token, src);
+                }
+                else {
+                    trace!("reactor source {token} removed");
+                    self.epoll.remove(src.fd())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for Reactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reactor")
+            .field("sources", &self.sources.len())
+            .field("stopped", &self.stopped)
+            .finish()
+    }
+}
+
+impl ReactorHandle {
+    pub fn wake(&self) -> io::Result<()> {
+        let val = 1u64.to_ne_bytes();
+        let ret = unsafe {
+            libc::write(
+                self.eventfd.as_raw_fd(),
+                val.as_ptr() as *const _,
+                val.len(),
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Run `f` on the reactor thread, e.g. to register new sources
+    pub fn post<F: FnOnce(&mut Reactor) + Send + 'static>(
+        &self,
+        f: F,
+    ) -> io::Result<()> {
+        self.posted.lock().unwrap().push(Box::new(f));
+        self.wake()
+    }
+
+    pub fn stop(&self) -> io::Result<()> {
+        self.post(|reactor| reactor.stop())
+    }
+}
+
+impl Debug for ReactorHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReactorHandle")
+            .field("eventfd", &self.eventfd)
+            .field("posted", &self.posted.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Device is read one frame for each readiness, errors of frames are
+/// only logged
+impl EventSource for Arc<NetDevice> {
+    fn fd(&self) -> BorrowedFd<'_> {
+        self.sd.as_fd()
+    }
+
+    fn ready(&mut self, _reactor: &mut Reactor) -> anyhow::Result<bool> {
+        if let Err(err) = self.input_ready() {
+            warn!("{}: {err:#}", self.name);
+        }
+
+        Ok(true)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn reactor_event(token: u64) -> EpollEvent {
+    EpollEvent {
+        events: EpollFlag::In,
+        data: EpollData { u64: token },
+    }
+}
+
+fn timerfd_create() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::timerfd_create(
+            libc::CLOCK_MONOTONIC,
+            libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+        )
+    };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Expire every `TIMER_TICK`
+fn timerfd_settime(fd: BorrowedFd) -> io::Result<()> {
+    let tick = libc::timespec {
+        tv_sec: TIMER_TICK.as_secs() as _,
+        tv_nsec: TIMER_TICK.subsec_nanos() as _,
+    };
+    let spec = libc::itimerspec {
+        it_interval: tick,
+        it_value: tick,
+    };
+
+    let ret = unsafe {
+        libc::timerfd_settime(fd.as_raw_fd(), 0, &spec, std::ptr::null_mut())
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn eventfd_create() -> io::Result<OwnedFd> {
+    let fd =
+        unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Read counter of timerfd or eventfd, it's zero if it's drained by
+/// previous read
+fn fd_read_u64(fd: BorrowedFd) -> io::Result<u64> {
+    let mut val = [0u8; 8];
+    let ret = unsafe {
+        libc::read(fd.as_raw_fd(), val.as_mut_ptr() as *mut _, val.len())
+    };
+
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(0);
+        }
+
+        return Err(err);
+    }
+
+    Ok(u64::from_ne_bytes(val))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::net::UnixDatagram,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::timer::timer_add;
+
+    /// Datagram socket which is read once
+    struct OneShot {
+        sd: UnixDatagram,
+        recv: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl EventSource for OneShot {
+        fn fd(&self) -> BorrowedFd<'_> {
+            self.sd.as_fd()
+        }
+
+        fn ready(&mut self, reactor: &mut Reactor) -> anyhow::Result<bool> {
+            let mut buf = [0; 64];
+            let n = self.sd.recv(&mut buf)?;
+
+            self.recv.lock().unwrap().push(buf[..n].to_vec());
+            reactor.stop();
+
+            Ok(false)
+        }
+    }
+
+    /// Stop `reactor` after a while in case the event never comes
+    fn watchdog(reactor: &Reactor) {
+        let handle = reactor.handle();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(5));
+            handle.stop().ok();
+        });
+    }
+
+    #[test]
+    fn test_reactor_wake() {
+        let mut reactor = Reactor::new().unwrap();
+        let handle = reactor.handle();
+        let posted = Arc::new(AtomicBool::new(false));
+        let flag = posted.clone();
+
+        watchdog(&reactor);
+
+        thread::spawn(move || {
+            handle
+                .post(move |reactor| {
+                    flag.store(true, Ordering::Relaxed);
+                    reactor.stop();
+                })
+                .unwrap();
+        });
+
+        reactor.run().unwrap();
+
+        assert!(posted.load(Ordering::Relaxed));
+        assert!(reactor.handle.posted.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reactor_source() {
+        let mut reactor = Reactor::new().unwrap();
+        let (sd, peer) = UnixDatagram::pair().unwrap();
+        let recv = Arc::new(Mutex::new(vec![]));
+        let src = OneShot {
+            sd,
+            recv: recv.clone(),
+        };
+
+        watchdog(&reactor);
+
+        let token = reactor.register(Box::new(src)).unwrap();
+
+        peer.send(b"ping").unwrap();
+        reactor.run().unwrap();
+
+        // it's removed as it returns false
+        assert_eq!(*recv.lock().unwrap(), [b"ping"]);
+        assert!(reactor.deregister(token).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reactor_timer() {
+        let mut reactor = Reactor::new().unwrap();
+        let handle = reactor.handle();
+        let fired = Arc::new(AtomicBool::new(false));
+        let flag = fired.clone();
+
+        watchdog(&reactor);
+
+        // the wheel may be claimed by the reactor of another test
+        timerfd_settime(reactor.timerfd.as_fd()).unwrap();
+
+        timer_add(TIMER_TICK * 3, move || {
+            flag.store(true, Ordering::Relaxed);
+            handle.stop().unwrap();
+        });
+
+        reactor.run().unwrap();
+
+        assert!(fired.load(Ordering::Relaxed));
+    }
+}
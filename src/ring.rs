@@ -0,0 +1,448 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+    ptr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use osimodel::datalink::Eth;
+
+use crate::config::RingConf;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Size of each RX block, it's multiple of page size
+pub const RING_BLOCK_SZ: usize = 1 << 18;
+pub const RING_BLOCK_NR: usize = 64;
+/// A block is retired to user after it even if it isn't full
+pub const RING_BLOCK_TOV: Duration = Duration::from_millis(1);
+
+/// Slot of TX frames, it holds `tpacket3_hdr` and an Ethernet frame
+pub const RING_FRAME_SZ: usize = 2048;
+pub const RING_TX_FRAME_NR: usize = 256;
+/// Offset of the frame in a TX slot
+pub const RING_DATA_OFF: usize =
+    size_of::<libc::tpacket3_hdr>().next_multiple_of(libc::TPACKET_ALIGNMENT);
+/// Largest MTU of which frames fit in a TX slot
+pub const RING_MTU_MAX: usize =
+    RING_FRAME_SZ - RING_DATA_OFF - size_of::<Eth>();
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// RX and TX rings of TPACKET_V3 (PACKET_MMAP) mapped from a packet socket,
+/// the RX ring is first then the TX ring in the mapping.
+///
+/// Frames of RX ring are delivered block by block, TX frames are queued in
+/// slots then sent by one `send`.
+///
+/// It saves syscalls but not copies, each RX frame is still copied once into
+/// `SkBuff` as `RawBuf` can't borrow the mapping, and each TX frame once
+/// into its slot.
+#[derive(Debug)]
+pub struct PacketRing {
+    fd: RawFd,
+    map: *mut u8,
+    map_len: usize,
+    block_sz: usize,
+    block_nr: usize,
+    frame_nr: usize,
+    /// Next RX block to be handled
+    rx_next: Mutex<usize>,
+    /// Next TX slot to be filled
+    tx_next: Mutex<usize>,
+    /// TX frames rejected by the kernel
+    tx_rejected: AtomicU64,
+}
+
+unsafe impl Send for PacketRing {}
+unsafe impl Sync for PacketRing {}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl PacketRing {
+    /// Set up rings on `sd`, frames are read or written through the rings
+    /// only since then
+    pub fn new(sd: BorrowedFd, conf: &RingConf) -> io::Result<Self> {
+        let fd = sd.as_raw_fd();
+
+        setsockopt(
+            fd,
+            libc::PACKET_VERSION,
+            &(libc::tpacket_versions::TPACKET_V3 as libc::c_int),
+        )?;
+
+        let rx = libc::tpacket_req3 {
+            tp_block_size: conf.block_size as u32,
+            tp_block_nr: conf.blocks as u32,
+            // frames of RX blocks are variable sized
+            tp_frame_size: RING_FRAME_SZ as u32,
+            tp_frame_nr: (conf.block_size / RING_FRAME_SZ * conf.blocks)
+                as u32,
+            tp_retire_blk_tov: conf.block_timeout.as_millis().max(1) as u32,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+
+        setsockopt(fd, libc::PACKET_RX_RING, &rx)?;
+
+        let frames_per_block = conf.block_size / RING_FRAME_SZ;
+        let tx_blocks = conf.tx_frames.div_ceil(frames_per_block);
+        let tx = libc::tpacket_req3 {
+            tp_block_size: conf.block_size as u32,
+            tp_block_nr: tx_blocks as u32,
+            tp_frame_size: RING_FRAME_SZ as u32,
+            tp_frame_nr: (tx_blocks * frames_per_block) as u32,
+            tp_retire_blk_tov: 0,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+
+        setsockopt(fd, libc::PACKET_TX_RING, &tx)?;
+
+        let map_len = conf.block_size * (conf.blocks + tx_blocks);
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            map: map as *mut u8,
+            map_len,
+            block_sz: conf.block_size,
+            block_nr: conf.blocks,
+            frame_nr: tx_blocks * frames_per_block,
+            rx_next: Mutex::new(0),
+            tx_next: Mutex::new(0),
+            tx_rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Call `f` with each frame and its timestamp of ready RX blocks, then
+    /// return them to the kernel, so `f` can't keep the frame. Return the
+    /// number of frames.
+    pub fn rx_batch<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&[u8], SystemTime),
+    {
+        let mut rx_next = self.rx_next.lock().unwrap();
+        let mut n = 0;
+
+        loop {
+            let block = unsafe { self.map.add(*rx_next * self.block_sz) };
+            let desc = block as *mut libc::tpacket_block_desc;
+            let status = unsafe {
+                AtomicU32::from_ptr(&raw mut (*desc).hdr.bh1.block_status)
+            };
+
+            if status.load(Ordering::Acquire) & libc::TP_STATUS_USER == 0 {
+                break;
+            }
+
+            let (num_pkts, mut off) = unsafe {
+                let bh1 = &(*desc).hdr.bh1;
+
+                (bh1.num_pkts, bh1.offset_to_first_pkt as usize)
+            };
+
+            for _ in 0..num_pkts {
+                let hdr = unsafe {
+                    block.add(off).cast::<libc::tpacket3_hdr>().read()
+                };
+                let frame = unsafe {
+                    std::slice::from_raw_parts(
+                        block.add(off + hdr.tp_mac as usize),
+                        hdr.tp_snaplen as usize,
+                    )
+                };
+                let ts =
+                    UNIX_EPOCH + Duration::new(hdr.tp_sec as u64, hdr.tp_nsec);
+
+                f(frame, ts);
+
+                off += hdr.tp_next_offset as usize;
+            }
+
+            status.store(libc::TP_STATUS_KERNEL, Ordering::Release);
+
+            *rx_next = (*rx_next + 1) % self.block_nr;
+            n += num_pkts as usize;
+        }
+
+        n
+    }
+
+    /// Block until an RX block is ready
+    pub fn rx_wait(&self) -> io::Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN | libc::POLLERR,
+            revents: 0,
+        };
+
+        loop {
+            if unsafe { libc::poll(&mut pfd, 1, -1) } >= 0 {
+                return Ok(());
+            }
+
+            let err = io::Error::last_os_error();
+
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Queue `frames` into TX slots then kick the kernel to send them by one
+    /// `send`, frames beyond free slots are left. Return the number of
+    /// frames queued.
+    pub fn tx_batch(&self, frames: &[&[u8]]) -> io::Result<usize> {
+        let tx_base = unsafe { self.map.add(self.block_sz * self.block_nr) };
+
+        if frames
+            .iter()
+            .any(|frame| RING_DATA_OFF + frame.len() > RING_FRAME_SZ)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+        }
+
+        let mut tx_next = self.tx_next.lock().unwrap();
+        let mut n = 0;
+
+        for frame in frames {
+            let slot = unsafe { tx_base.add(*tx_next * RING_FRAME_SZ) };
+            let hdr = slot as *mut libc::tpacket3_hdr;
+            let status =
+                unsafe { AtomicU32::from_ptr(&raw mut (*hdr).tp_status) };
+
+            match status.load(Ordering::Acquire) {
+                libc::TP_STATUS_AVAILABLE => (),
+                // the kernel stops at it until the slot is released
+                libc::TP_STATUS_WRONG_FORMAT => {
+                    self.tx_rejected.fetch_add(1, Ordering::Relaxed);
+                    status.store(libc::TP_STATUS_AVAILABLE, Ordering::Release);
+                }
+                _ => break,
+            }
+
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    frame.as_ptr(),
+                    slot.add(RING_DATA_OFF),
+                    frame.len(),
+                );
+                (*hdr).tp_len = frame.len() as u32;
+                (*hdr).tp_snaplen = frame.len() as u32;
+                (*hdr).tp_next_offset = 0;
+            }
+
+            status.store(libc::TP_STATUS_SEND_REQUEST, Ordering::Release);
+
+            *tx_next = (*tx_next + 1) % self.frame_nr;
+            n += 1;
+        }
+
+        if n > 0 {
+            let ret = unsafe {
+                libc::sendto(self.fd, ptr::null(), 0, 0, ptr::null(), 0)
+            };
+
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(n)
+    }
+
+    /// Number of TX frames rejected by the kernel since the last call
+    pub fn take_tx_rejected(&self) -> u64 {
+        self.tx_rejected.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Drop for PacketRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut _, self.map_len);
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, val: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            opt,
+            val as *const T as *const _,
+            size_of::<T>() as u32,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const BLOCK_SZ: usize = 4096;
+
+    /// Offset of the first frame header in a block
+    const BLOCK_HDR_LEN: usize = size_of::<libc::tpacket_block_desc>()
+        .next_multiple_of(libc::TPACKET_ALIGNMENT);
+
+    /// RX ring of `blocks` over an anonymous mapping instead of a packet
+    /// socket, all blocks are owned by the kernel
+    pub(crate) fn ring_anon(blocks: usize) -> PacketRing {
+        let map_len = BLOCK_SZ * blocks;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        assert_ne!(map, libc::MAP_FAILED);
+
+        PacketRing {
+            fd: -1,
+            map: map as *mut u8,
+            map_len,
+            block_sz: BLOCK_SZ,
+            block_nr: blocks,
+            frame_nr: 0,
+            rx_next: Mutex::new(0),
+            tx_next: Mutex::new(0),
+            tx_rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Fill RX block `i` with `frames` received in second `sec`, then
+    /// retire it to user like the kernel
+    pub(crate) fn ring_fill(
+        ring: &PacketRing,
+        i: usize,
+        frames: &[&[u8]],
+        sec: u32,
+    ) {
+        let block = unsafe { ring.map.add(i * ring.block_sz) };
+        let mut off = BLOCK_HDR_LEN;
+
+        for (j, frame) in frames.iter().enumerate() {
+            let len = (RING_DATA_OFF + frame.len())
+                .next_multiple_of(libc::TPACKET_ALIGNMENT);
+            let mut hdr: libc::tpacket3_hdr = unsafe { std::mem::zeroed() };
+
+            assert!(off + len <= ring.block_sz);
+
+            hdr.tp_next_offset =
+                if j + 1 < frames.len() { len as u32 } else { 0 };
+            hdr.tp_sec = sec;
+            hdr.tp_nsec = j as u32;
+            hdr.tp_snaplen = frame.len() as u32;
+            hdr.tp_len = frame.len() as u32;
+            hdr.tp_mac = RING_DATA_OFF as u16;
+
+            unsafe {
+                block.add(off).cast::<libc::tpacket3_hdr>().write(hdr);
+                ptr::copy_nonoverlapping(
+                    frame.as_ptr(),
+                    block.add(off + RING_DATA_OFF),
+                    frame.len(),
+                );
+            }
+
+            off += len;
+        }
+
+        let desc = block as *mut libc::tpacket_block_desc;
+
+        unsafe {
+            (*desc).hdr.bh1.num_pkts = frames.len() as u32;
+            (*desc).hdr.bh1.offset_to_first_pkt = BLOCK_HDR_LEN as u32;
+        }
+
+        ring_status(ring, i).store(libc::TP_STATUS_USER, Ordering::Release);
+    }
+
+    fn ring_status(ring: &PacketRing, i: usize) -> &AtomicU32 {
+        let desc = unsafe { ring.map.add(i * ring.block_sz) }
+            as *mut libc::tpacket_block_desc;
+
+        unsafe { AtomicU32::from_ptr(&raw mut (*desc).hdr.bh1.block_status) }
+    }
+
+    #[test]
+    fn test_ring_rx_blocks() {
+        let ring = ring_anon(3);
+        let mut frames = vec![];
+        let mut rx = |ring: &PacketRing| {
+            ring.rx_batch(|frame, ts| frames.push((frame.to_vec(), ts)))
+        };
+        let at = |sec, nsec| UNIX_EPOCH + Duration::new(sec, nsec);
+
+        assert_eq!(rx(&ring), 0);
+
+        ring_fill(&ring, 0, &[&[1; 60], &[2; 99]], 1);
+        ring_fill(&ring, 1, &[&[3; 1514]], 2);
+
+        assert_eq!(rx(&ring), 3);
+
+        // blocks are returned to the kernel as they are walked
+        for i in 0..3 {
+            assert_eq!(
+                ring_status(&ring, i).load(Ordering::Acquire),
+                libc::TP_STATUS_KERNEL
+            );
+        }
+
+        // the walk goes on from the next block and wraps around
+        ring_fill(&ring, 0, &[&[5; 64]], 4);
+        ring_fill(&ring, 2, &[&[4; 64]], 3);
+
+        assert_eq!(rx(&ring), 2);
+        assert_eq!(rx(&ring), 0);
+        assert_eq!(*ring.rx_next.lock().unwrap(), 1);
+        assert_eq!(
+            frames,
+            [
+                (vec![1; 60], at(1, 0)),
+                (vec![2; 99], at(1, 1)),
+                (vec![3; 1514], at(2, 0)),
+                (vec![4; 64], at(3, 0)),
+                (vec![5; 64], at(4, 0)),
+            ]
+        );
+    }
+}
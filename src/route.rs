@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use derive_more::derive::{Deref, DerefMut};
+use m6ptr::LazyStatic;
+
+use crate::{
+    clock::clock_now,
+    dev::{NetDevice, dev_get_by_name},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Learned path MTU is forgotten after it (RFC 1191 6.3)
+pub const IP_RT_MTU_EXPIRES: Duration = Duration::from_secs(600);
+/// Lower bound of learned path MTU, the same as Linux `min_pmtu`
+pub const IP_RT_MIN_PMTU: u16 = 552;
+
+/// RFC 1191 7.1, guess MTU when router doesn't report it
+const MTU_PLATEAUS: [u16; 9] =
+    [32000, 17914, 8166, 4352, 2002, 1492, 576, 296, 68];
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+pub static ROUTE_TBL: LazyStatic<RouteTbl> =
+    LazyStatic::new(|| RouteTbl::new());
+
+pub static PMTU_TBL: LazyStatic<HashMap<Ipv4Addr, PMTUEntry>> =
+    LazyStatic::new(|| HashMap::new());
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub dst: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    /// `None` for directly connected network
+    pub gateway: Option<Ipv4Addr>,
+    pub ifname: String,
+    /// Lower is preferred among entries with same prefix length
+    pub metric: u32,
+}
+
+/// Longest prefix match on a plain list, it's enough for lab topologies
+#[derive(Debug, Default, Deref, DerefMut)]
+pub struct RouteTbl {
+    value: Vec<RouteEntry>,
+}
+
+/// Path MTU learned from Fragmentation Needed
+#[derive(Debug, Clone, Copy)]
+pub struct PMTUEntry {
+    pub mtu: u16,
+    pub expires: Instant,
+}
+
+/// Output decision of a route lookup
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub dev: Arc<NetDevice>,
+    pub nexthop: Ipv4Addr,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl RouteEntry {
+    pub fn prefix_len(&self) -> u32 {
+        self.mask.to_bits().count_ones()
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        ip & self.mask == self.dst & self.mask
+    }
+}
+
+impl Display for RouteEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.prefix_len() == 0 {
+            write!(f, "default")?;
+        }
+        else {
+            write!(f, "{}/{}", self.dst, self.prefix_len())?;
+        }
+
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+
+        write!(f, " dev {} metric {}", self.ifname, self.metric)
+    }
+}
+
+impl RouteTbl {
+    pub fn new() -> Self {
+        Self { value: Vec::new() }
+    }
+
+    /// Replace the entry with same destination and metric if there is
+    pub fn insert(&mut self, ent: RouteEntry) {
+        self.retain(|old| {
+            !(old.dst == ent.dst
+                && old.mask == ent.mask
+                && old.metric == ent.metric)
+        });
+
+        self.push(ent);
+    }
+
+    pub fn remove(
+        &mut self,
+        dst: Ipv4Addr,
+        mask: Ipv4Addr,
+    ) -> Option<RouteEntry> {
+        let pos = self
+            .iter()
+            .position(|ent| ent.dst == dst && ent.mask == mask)?;
+
+        Some(self.value.remove(pos))
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<&RouteEntry> {
+        self.iter().filter(|ent| ent.contains(ip)).max_by(|a, b| {
+            a.prefix_len()
+                .cmp(&b.prefix_len())
+                .then(b.metric.cmp(&a.metric))
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+pub fn ip_route_output(dst: Ipv4Addr) -> Option<Route> {
+    let tbl = ROUTE_TBL.read().unwrap();
+
+    /* limited broadcast never leaves through a gateway */
+
+    if dst.is_broadcast() {
+        let ent = tbl.iter().find(|ent| ent.gateway.is_none())?;
+
+        return Some(Route {
+            dev: dev_get_by_name(&ent.ifname)?,
+            nexthop: dst,
+        });
+    }
+
+    let ent = tbl.lookup(dst)?;
+
+    Some(Route {
+        dev: dev_get_by_name(&ent.ifname)?,
+        nexthop: ent.gateway.unwrap_or(dst),
+    })
+}
+
+/// Learn path MTU to `dst`, `mtu` is zero if router doesn't report it then
+/// guess it from the length of the original datagram
+pub fn ip_rt_update_pmtu(dst: Ipv4Addr, mtu: u16, orig_len: u16) -> u16 {
+    let mtu = if mtu == 0 || mtu >= orig_len {
+        MTU_PLATEAUS
+            .into_iter()
+            .find(|plateau| *plateau < orig_len)
+            .unwrap_or(IP_RT_MIN_PMTU)
+    }
+    else {
+        mtu
+    }
+    .max(IP_RT_MIN_PMTU);
+
+    let mut tbl = PMTU_TBL.write().unwrap();
+    let now = clock_now();
+
+    tbl.retain(|_, ent| ent.expires > now);
+
+    let ent = tbl.entry(dst).or_insert(PMTUEntry {
+        mtu,
+        expires: now + IP_RT_MTU_EXPIRES,
+    });
+
+    // PMTU only decreases until it expires
+    if mtu <= ent.mtu {
+        ent.mtu = mtu;
+        ent.expires = now + IP_RT_MTU_EXPIRES;
+    }
+
+    ent.mtu
+}
+
+pub fn ip_rt_get_pmtu(dst: Ipv4Addr) -> Option<u16> {
+    PMTU_TBL
+        .read()
+        .unwrap()
+        .get(&dst)
+        .filter(|ent| ent.expires > clock_now())
+        .map(|ent| ent.mtu)
+}
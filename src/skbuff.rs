@@ -46,6 +46,28 @@ impl SkBuff {
 
         it
     }
+
+    pub fn new_from_slice(src: &[u8]) -> Self {
+        let data = RawBuf::new_from_slice(src);
+        let it = Self::default();
+
+        it.phy.set(data.to_ref()).unwrap();
+        it.data.set(data).unwrap();
+
+        it
+    }
+
+    /// Link `skbs` through `next` in order, return the head
+    pub fn from_chain<I>(skbs: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = Self>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        skbs.into_iter().rev().fold(None, |next, mut skb| {
+            skb.next = next.map(OwnedPtr::new);
+            Some(skb)
+        })
+    }
 }
 
 impl NetDevice {
@@ -0,0 +1,386 @@
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+pub static SNMP_IP: IPMib = IPMib::new();
+pub static SNMP_ICMP: ICMPMib = ICMPMib::new();
+pub static SNMP_ARP: ARPMib = ARPMib::new();
+pub static SNMP_UDP: UDPMib = UDPMib::new();
+pub static SNMP_TCP: TCPMib = TCPMib::new();
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Counter of MIB, it only goes up
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+/// Group of counters in the order of `/proc/net/snmp`
+pub type SnmpGroup = (&'static str, Vec<(String, u64)>);
+
+/// IP group of RFC 1213 MIB-II
+#[derive(Debug, Default)]
+pub struct IPMib {
+    pub in_receives: Counter,
+    /// Malformed header, bad checksum or options, TTL exceeded
+    pub in_hdr_errors: Counter,
+    /// Not addressed to local and not forwarded
+    pub in_addr_errors: Counter,
+    pub forw_datagrams: Counter,
+    pub in_unknown_protos: Counter,
+    /// Dropped for no problem of themselves, e.g. source routed ones when
+    /// not forwarding
+    pub in_discards: Counter,
+    pub in_delivers: Counter,
+    /// Datagrams of local protocols, forwarded ones aren't included
+    pub out_requests: Counter,
+    /// Dropped while waiting on ARP
+    pub out_discards: Counter,
+    pub out_no_routes: Counter,
+    pub reasm_timeout: Counter,
+    /// Fragments received
+    pub reasm_reqds: Counter,
+    pub reasm_oks: Counter,
+    /// Failures of any kind, not datagrams as fragments are dropped
+    pub reasm_fails: Counter,
+    pub frag_oks: Counter,
+    /// DF is set but it needs fragmentation
+    pub frag_fails: Counter,
+    pub frag_creates: Counter,
+}
+
+/// ICMP group of RFC 1213 MIB-II, messages of each type are counted like
+/// `IcmpMsg` of Linux
+#[derive(Debug)]
+pub struct ICMPMib {
+    pub in_msgs: Counter,
+    pub in_errors: Counter,
+    pub in_csum_errors: Counter,
+    pub out_msgs: Counter,
+    pub out_errors: Counter,
+    pub in_types: [Counter; 256],
+    pub out_types: [Counter; 256],
+}
+
+/// ARP isn't in MIB-II, it's like `Arp` of lwIP stats
+#[derive(Debug, Default)]
+pub struct ARPMib {
+    pub in_requests: Counter,
+    pub in_replies: Counter,
+    pub out_requests: Counter,
+    pub out_replies: Counter,
+}
+
+#[derive(Debug, Default)]
+pub struct UDPMib {
+    pub in_datagrams: Counter,
+    pub no_ports: Counter,
+    pub in_errors: Counter,
+    pub out_datagrams: Counter,
+    /// Receive queue is full, they're counted in `in_errors` too
+    pub rcvbuf_errors: Counter,
+    pub in_csum_errors: Counter,
+}
+
+#[derive(Debug, Default)]
+pub struct TCPMib {
+    pub active_opens: Counter,
+    pub passive_opens: Counter,
+    pub in_segs: Counter,
+    /// Retransmitted ones are included
+    pub out_segs: Counter,
+    pub retrans_segs: Counter,
+    pub in_errs: Counter,
+    pub out_rsts: Counter,
+    pub in_csum_errors: Counter,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl IPMib {
+    pub const fn new() -> Self {
+        Self {
+            in_receives: Counter::new(),
+            in_hdr_errors: Counter::new(),
+            in_addr_errors: Counter::new(),
+            forw_datagrams: Counter::new(),
+            in_unknown_protos: Counter::new(),
+            in_discards: Counter::new(),
+            in_delivers: Counter::new(),
+            out_requests: Counter::new(),
+            out_discards: Counter::new(),
+            out_no_routes: Counter::new(),
+            reasm_timeout: Counter::new(),
+            reasm_reqds: Counter::new(),
+            reasm_oks: Counter::new(),
+            reasm_fails: Counter::new(),
+            frag_oks: Counter::new(),
+            frag_fails: Counter::new(),
+            frag_creates: Counter::new(),
+        }
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        snmp_values(&[
+            ("InReceives", &self.in_receives),
+            ("InHdrErrors", &self.in_hdr_errors),
+            ("InAddrErrors", &self.in_addr_errors),
+            ("ForwDatagrams", &self.forw_datagrams),
+            ("InUnknownProtos", &self.in_unknown_protos),
+            ("InDiscards", &self.in_discards),
+            ("InDelivers", &self.in_delivers),
+            ("OutRequests", &self.out_requests),
+            ("OutDiscards", &self.out_discards),
+            ("OutNoRoutes", &self.out_no_routes),
+            ("ReasmTimeout", &self.reasm_timeout),
+            ("ReasmReqds", &self.reasm_reqds),
+            ("ReasmOKs", &self.reasm_oks),
+            ("ReasmFails", &self.reasm_fails),
+            ("FragOKs", &self.frag_oks),
+            ("FragFails", &self.frag_fails),
+            ("FragCreates", &self.frag_creates),
+        ])
+    }
+
+    /// `pkt` is split into `n` fragments
+    pub fn count_frags(&self, n: usize) {
+        if n > 1 {
+            self.frag_oks.inc();
+            self.frag_creates.add(n as u64);
+        }
+    }
+}
+
+impl ICMPMib {
+    pub const fn new() -> Self {
+        Self {
+            in_msgs: Counter::new(),
+            in_errors: Counter::new(),
+            in_csum_errors: Counter::new(),
+            out_msgs: Counter::new(),
+            out_errors: Counter::new(),
+            in_types: [const { Counter::new() }; 256],
+            out_types: [const { Counter::new() }; 256],
+        }
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        snmp_values(&[
+            ("InMsgs", &self.in_msgs),
+            ("InErrors", &self.in_errors),
+            ("InCsumErrors", &self.in_csum_errors),
+            ("OutMsgs", &self.out_msgs),
+            ("OutErrors", &self.out_errors),
+        ])
+    }
+
+    /// Types which have been seen only like `IcmpMsg` of Linux
+    pub fn msg_values(&self) -> Vec<(String, u64)> {
+        let mut values = vec![];
+
+        for (dir, types) in [("In", &self.in_types), ("Out", &self.out_types)]
+        {
+            for (ty, counter) in types.iter().enumerate() {
+                if counter.get() > 0 {
+                    values.push((format!("{dir}Type{ty}"), counter.get()));
+                }
+            }
+        }
+
+        values
+    }
+
+    pub fn count_in(&self, ty: u8) {
+        self.in_msgs.inc();
+        self.in_types[ty as usize].inc();
+    }
+
+    pub fn count_out(&self, ty: u8) {
+        self.out_msgs.inc();
+        self.out_types[ty as usize].inc();
+    }
+}
+
+impl Default for ICMPMib {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ARPMib {
+    pub const fn new() -> Self {
+        Self {
+            in_requests: Counter::new(),
+            in_replies: Counter::new(),
+            out_requests: Counter::new(),
+            out_replies: Counter::new(),
+        }
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        snmp_values(&[
+            ("InRequests", &self.in_requests),
+            ("InReplies", &self.in_replies),
+            ("OutRequests", &self.out_requests),
+            ("OutReplies", &self.out_replies),
+        ])
+    }
+}
+
+impl UDPMib {
+    pub const fn new() -> Self {
+        Self {
+            in_datagrams: Counter::new(),
+            no_ports: Counter::new(),
+            in_errors: Counter::new(),
+            out_datagrams: Counter::new(),
+            rcvbuf_errors: Counter::new(),
+            in_csum_errors: Counter::new(),
+        }
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        snmp_values(&[
+            ("InDatagrams", &self.in_datagrams),
+            ("NoPorts", &self.no_ports),
+            ("InErrors", &self.in_errors),
+            ("OutDatagrams", &self.out_datagrams),
+            ("RcvbufErrors", &self.rcvbuf_errors),
+            ("InCsumErrors", &self.in_csum_errors),
+        ])
+    }
+}
+
+impl TCPMib {
+    pub const fn new() -> Self {
+        Self {
+            active_opens: Counter::new(),
+            passive_opens: Counter::new(),
+            in_segs: Counter::new(),
+            out_segs: Counter::new(),
+            retrans_segs: Counter::new(),
+            in_errs: Counter::new(),
+            out_rsts: Counter::new(),
+            in_csum_errors: Counter::new(),
+        }
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        snmp_values(&[
+            ("ActiveOpens", &self.active_opens),
+            ("PassiveOpens", &self.passive_opens),
+            ("InSegs", &self.in_segs),
+            ("OutSegs", &self.out_segs),
+            ("RetransSegs", &self.retrans_segs),
+            ("InErrs", &self.in_errs),
+            ("OutRsts", &self.out_rsts),
+            ("InCsumErrors", &self.in_csum_errors),
+        ])
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn snmp_values(counters: &[(&str, &Counter)]) -> Vec<(String, u64)> {
+    counters
+        .iter()
+        .map(|(name, counter)| (name.to_string(), counter.get()))
+        .collect()
+}
+
+/// All groups in the order of `/proc/net/snmp`
+pub fn snmp_groups() -> Vec<SnmpGroup> {
+    vec![
+        ("Ip", SNMP_IP.values()),
+        ("Icmp", SNMP_ICMP.values()),
+        ("IcmpMsg", SNMP_ICMP.msg_values()),
+        ("Tcp", SNMP_TCP.values()),
+        ("Udp", SNMP_UDP.values()),
+        ("Arp", SNMP_ARP.values()),
+    ]
+}
+
+/// Format of `/proc/net/snmp`, a line of names then a line of values for
+/// each group
+pub fn snmp_write<W: Write>(mut w: W) -> io::Result<()> {
+    for (group, values) in snmp_groups() {
+        if values.is_empty() {
+            continue;
+        }
+
+        let (names, vals): (Vec<_>, Vec<_>) = values
+            .into_iter()
+            .map(|(name, val)| (name, val.to_string()))
+            .unzip();
+
+        writeln!(w, "{group}: {}", names.join(" "))?;
+        writeln!(w, "{group}: {}", vals.join(" "))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snmp_write() {
+        let mut out = Vec::new();
+
+        snmp_write(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        let groups = snmp_groups()
+            .into_iter()
+            .filter(|(_, values)| !values.is_empty())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), groups.len() * 2);
+
+        for (pair, (group, values)) in lines.chunks(2).zip(groups) {
+            let (head, names) = pair[0].split_once(": ").unwrap();
+            let (head2, vals) = pair[1].split_once(": ").unwrap();
+            let names = names.split(' ').collect::<Vec<_>>();
+            let vals = vals.split(' ').collect::<Vec<_>>();
+
+            assert_eq!((head, head2), (group, group));
+            assert_eq!(names.len(), vals.len(), "{group}");
+            assert_eq!(
+                names,
+                values
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+            );
+            assert!(vals.iter().all(|v| v.parse::<i64>().is_ok()), "{group}");
+        }
+
+        assert!(lines[0].starts_with("Ip: InReceives InHdrErrors"));
+    }
+}
@@ -0,0 +1,1235 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libc::{EACCES, ECONNRESET, ENOTCONN};
+
+use crate::{
+    clock::clock_now,
+    dev::DEV_TBL,
+    tcp::{
+        TCB, TCP_SOMAXCONN, TCPInfo, TCPKeepalive, TCPListenStats, TCPPcb,
+        TCPState, tcp_close, tcp_connect, tcp_listen, tcp_update,
+    },
+    tcpcc::CCKind,
+    udp::{UDPKey, UDPPcb, udp_bind, udp_connect, udp_output, udp_unbind},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// Blocking UDP socket mirroring `std::net::UdpSocket`, it works after
+/// devices are registered and driven by `NetDevice::spawn` or a `Reactor`
+#[derive(Debug)]
+pub struct UdpSocket {
+    key: Mutex<UDPKey>,
+    pcb: Arc<UDPPcb>,
+    read_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+    broadcast: AtomicBool,
+}
+
+/// Async UDP socket woken by the stack, it's runtime agnostic as only
+/// `std::task` is used. Adapters of `tokio` and `futures-io` traits behind
+/// the features of the same name read or write one datagram per call on a
+/// connected socket.
+#[derive(Debug)]
+pub struct AsyncUdpSocket {
+    inner: UdpSocket,
+}
+
+/// Blocking TCP stream mirroring `std::net::TcpStream`
+#[derive(Debug)]
+pub struct TcpStream {
+    pcb: Arc<TCPPcb>,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+}
+
+/// Blocking TCP listener mirroring `std::net::TcpListener`
+#[derive(Debug)]
+pub struct TcpListener {
+    pcb: Arc<TCPPcb>,
+    nonblocking: AtomicBool,
+}
+
+/// Async TCP stream woken by the stack, adapters of `tokio` and
+/// `futures-io` traits are behind the features of the same name
+#[derive(Debug)]
+pub struct AsyncTcpStream {
+    inner: TcpStream,
+}
+
+#[derive(Debug)]
+pub struct AsyncTcpListener {
+    inner: TcpListener,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl UdpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        each_addr(addr, |addr| {
+            let (key, pcb) = udp_bind(addr)?;
+
+            Ok(Self {
+                key: Mutex::new(key),
+                pcb,
+                read_timeout: Mutex::new(None),
+                nonblocking: AtomicBool::new(false),
+                broadcast: AtomicBool::new(false),
+            })
+        })
+    }
+
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        each_addr(addr, |addr| {
+            let mut key = self.key.lock().unwrap();
+
+            *key = udp_connect(*key, addr)?;
+
+            Ok(())
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.key.lock().unwrap().local.into())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.key
+            .lock()
+            .unwrap()
+            .remote
+            .map(Into::into)
+            .ok_or_else(|| io::Error::from_raw_os_error(ENOTCONN))
+    }
+
+    pub fn send_to<A: ToSocketAddrs>(
+        &self,
+        buf: &[u8],
+        addr: A,
+    ) -> io::Result<usize> {
+        each_addr(addr, |addr| self.send_to_v4(buf, addr))
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let Some(remote) = self.key.lock().unwrap().remote
+        else {
+            Err(io::Error::from_raw_os_error(ENOTCONN))?
+        };
+
+        self.send_to_v4(buf, remote)
+    }
+
+    /// Excess bytes of the datagram are discarded like `std::net`
+    pub fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        self.recv_inner(buf, false)
+    }
+
+    pub fn peek_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        self.recv_inner(buf, true)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.peek_from(buf).map(|(n, _)| n)
+    }
+
+    /// `None` blocks forever, zero duration is invalid like `std::net`
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = check_timeout(dur)?;
+
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.broadcast.store(broadcast, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        Ok(self.broadcast.load(Ordering::Relaxed))
+    }
+
+    /// Pending error reported by ICMP, it's cleared
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(self.pcb.take_err())
+    }
+
+    fn send_to_v4(&self, buf: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        if let Some(err) = self.pcb.take_err() {
+            Err(err)?
+        }
+
+        if !self.broadcast.load(Ordering::Relaxed) && is_broadcast(*dst.ip()) {
+            Err(io::Error::from_raw_os_error(EACCES))?
+        }
+
+        let src = self.key.lock().unwrap().local;
+
+        udp_output(src, dst, buf).map_err(into_io_error)?;
+
+        Ok(buf.len())
+    }
+
+    fn recv_inner(
+        &self,
+        buf: &mut [u8],
+        peek: bool,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let nonblocking = self.nonblocking.load(Ordering::Relaxed);
+
+        let mut rxq = self.pcb.rxq.lock().unwrap();
+
+        if nonblocking {
+            if rxq.is_empty() && !self.has_err() {
+                Err(io::Error::from(io::ErrorKind::WouldBlock))?
+            }
+        }
+        else if let Some(dur) = timeout {
+            let (guard, res) = self
+                .pcb
+                .rx_cond
+                .wait_timeout_while(rxq, dur, |rxq| {
+                    rxq.is_empty() && !self.has_err()
+                })
+                .unwrap();
+
+            if res.timed_out() {
+                Err(io::Error::from(io::ErrorKind::WouldBlock))?
+            }
+
+            rxq = guard;
+        }
+        else {
+            rxq = self
+                .pcb
+                .rx_cond
+                .wait_while(rxq, |rxq| rxq.is_empty() && !self.has_err())
+                .unwrap();
+        }
+
+        self.recv_front(&mut rxq, buf, peek)
+    }
+
+    /// Register the waker of `cx` if there is nothing to receive
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut rxq = self.pcb.rxq.lock().unwrap();
+
+        if rxq.is_empty() && !self.has_err() {
+            self.pcb.register_rx_waker(cx.waker());
+
+            return Poll::Pending;
+        }
+
+        Poll::Ready(self.recv_front(&mut rxq, buf, false))
+    }
+
+    /// Take the pending error or the front datagram, `rxq` must be ready
+    fn recv_front(
+        &self,
+        rxq: &mut VecDeque<(SocketAddrV4, Vec<u8>)>,
+        buf: &mut [u8],
+        peek: bool,
+    ) -> io::Result<(usize, SocketAddr)> {
+        if let Some(err) = self.pcb.take_err() {
+            Err(err)?
+        }
+
+        let (from, n) = {
+            let (from, data) = rxq.front().unwrap();
+            let n = data.len().min(buf.len());
+
+            buf[..n].copy_from_slice(&data[..n]);
+
+            (*from, n)
+        };
+
+        if !peek {
+            rxq.pop_front();
+        }
+
+        Ok((n, from.into()))
+    }
+
+    fn has_err(&self) -> bool {
+        self.pcb.err.lock().unwrap().is_some()
+    }
+}
+
+impl AsyncUdpSocket {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        UdpSocket::bind(addr).map(Self::from)
+    }
+
+    pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Never pending, datagrams wait for ARP in the stack
+    pub async fn send_to<A: ToSocketAddrs>(
+        &self,
+        buf: &[u8],
+        addr: A,
+    ) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|cx| self.inner.poll_recv_from(cx, buf)).await
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).await.map(|(n, _)| n)
+    }
+
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    pub fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_recv_from(cx, buf).map_ok(|(n, _)| n)
+    }
+
+    /// Never pending like `send_to`
+    pub fn poll_send_to(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.inner.send_to(buf, target))
+    }
+
+    pub fn poll_send(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.inner.send(buf))
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    pub fn into_inner(self) -> UdpSocket {
+        self.inner
+    }
+}
+
+impl From<UdpSocket> for AsyncUdpSocket {
+    fn from(inner: UdpSocket) -> Self {
+        Self { inner }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        udp_unbind(&self.key.lock().unwrap());
+    }
+}
+
+impl TcpStream {
+    fn from_pcb(pcb: Arc<TCPPcb>) -> Self {
+        Self {
+            pcb,
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+            nonblocking: AtomicBool::new(false),
+        }
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        each_addr(addr, |addr| {
+            let stream = Self::from_pcb(tcp_connect(addr)?);
+
+            tcb_wait(&stream.pcb, None, false, connect_ready)?;
+
+            Ok(stream)
+        })
+    }
+
+    pub fn connect_timeout(
+        addr: &SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let SocketAddr::V4(addr) = addr
+        else {
+            Err(no_v4_addr())?
+        };
+
+        let stream = Self::from_pcb(tcp_connect(*addr)?);
+
+        tcb_wait(&stream.pcb, Some(timeout), false, connect_ready).map_err(
+            |err| match err.kind() {
+                io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut.into(),
+                _ => err,
+            },
+        )?;
+
+        Ok(stream)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.pcb.tcb.lock().unwrap().local().into())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.pcb.tcb.lock().unwrap().remote().into())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut tcb = self.pcb.tcb.lock().unwrap();
+
+        if !tcb.state.is_synchronized() {
+            Err(io::Error::from_raw_os_error(ENOTCONN))?
+        }
+
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            tcb.rd_shut = true;
+        }
+
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            tcb.shutdown_write();
+        }
+
+        tcp_update(&self.pcb, &mut tcb);
+
+        Ok(())
+    }
+
+    /// `None` blocks forever, zero duration is invalid like `std::net`
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = check_timeout(dur)?;
+
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.write_timeout.lock().unwrap() = check_timeout(dur)?;
+
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(self.pcb.tcb.lock().unwrap().take_err())
+    }
+
+    /// Like `TCP_INFO` socket option
+    pub fn info(&self) -> TCPInfo {
+        self.pcb.tcb.lock().unwrap().info()
+    }
+
+    /// Like `TCP_CONGESTION` socket option
+    pub fn set_congestion(&self, kind: CCKind) {
+        self.pcb.tcb.lock().unwrap().set_cc(kind);
+    }
+
+    /// Disable Nagle algorithm like `TCP_NODELAY`, queued small data is
+    /// sent at once
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        let mut tcb = self.pcb.tcb.lock().unwrap();
+
+        tcb.nodelay = nodelay;
+        tcp_update(&self.pcb, &mut tcb);
+
+        Ok(())
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        Ok(self.pcb.tcb.lock().unwrap().nodelay)
+    }
+
+    /// Disable delayed ACK like `TCP_QUICKACK`, pending ACK is sent at once
+    pub fn set_quickack(&self, quickack: bool) -> io::Result<()> {
+        let mut tcb = self.pcb.tcb.lock().unwrap();
+
+        tcb.quickack = quickack;
+        tcb.ack_now |= quickack && tcb.delack_deadline.is_some();
+        tcp_update(&self.pcb, &mut tcb);
+
+        Ok(())
+    }
+
+    pub fn quickack(&self) -> io::Result<bool> {
+        Ok(self.pcb.tcb.lock().unwrap().quickack)
+    }
+
+    /// `SO_KEEPALIVE` with `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and
+    /// `TCP_KEEPCNT`, `None` disables it
+    pub fn set_keepalive(
+        &self,
+        keepalive: Option<TCPKeepalive>,
+    ) -> io::Result<()> {
+        if keepalive.is_some_and(|keepalive| {
+            keepalive.idle.is_zero()
+                || keepalive.interval.is_zero()
+                || keepalive.count == 0
+        }) {
+            Err(io::Error::from(io::ErrorKind::InvalidInput))?
+        }
+
+        let mut tcb = self.pcb.tcb.lock().unwrap();
+
+        tcb.keepalive = keepalive;
+        tcb.keep_probes = 0;
+
+        Ok(())
+    }
+
+    pub fn keepalive(&self) -> io::Result<Option<TCPKeepalive>> {
+        Ok(self.pcb.tcb.lock().unwrap().keepalive)
+    }
+
+    /// Like `TCP_USER_TIMEOUT`, `None` means the default retries
+    pub fn set_user_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.pcb.tcb.lock().unwrap().user_timeout = check_timeout(dur)?;
+
+        Ok(())
+    }
+
+    pub fn user_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.pcb.tcb.lock().unwrap().user_timeout)
+    }
+
+    pub fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        tcb_poll(&self.pcb, cx, |tcb| read_ready(tcb, buf))
+    }
+
+    pub fn poll_write(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tcb_poll(&self.pcb, cx, |tcb| write_ready(tcb, buf))
+    }
+}
+
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let nonblocking = self.nonblocking.load(Ordering::Relaxed);
+
+        tcb_wait(&self.pcb, timeout, nonblocking, |tcb| read_ready(tcb, buf))
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = *self.write_timeout.lock().unwrap();
+        let nonblocking = self.nonblocking.load(Ordering::Relaxed);
+
+        tcb_wait(&self.pcb, timeout, nonblocking, |tcb| write_ready(tcb, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        tcp_close(&self.pcb);
+    }
+}
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        each_addr(addr, |addr| {
+            Ok(Self {
+                pcb: tcp_listen(addr, TCP_SOMAXCONN.load(Ordering::Relaxed))?,
+                nonblocking: AtomicBool::new(false),
+            })
+        })
+    }
+
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let nonblocking = self.nonblocking.load(Ordering::Relaxed);
+
+        tcb_wait(&self.pcb, None, nonblocking, accept_ready)
+    }
+
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<TcpStream>> {
+        std::iter::repeat_with(|| self.accept().map(|(stream, _)| stream))
+    }
+
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        tcb_poll(&self.pcb, cx, accept_ready)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.pcb.tcb.lock().unwrap().local().into())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(self.pcb.tcb.lock().unwrap().take_err())
+    }
+
+    /// Dropped SYNs and SYN cookies
+    pub fn stats(&self) -> TCPListenStats {
+        self.pcb.tcb.lock().unwrap().listen_stats
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        tcp_close(&self.pcb);
+    }
+}
+
+impl AsyncTcpStream {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let mut last_err = None;
+
+        for addr in addr.to_socket_addrs()? {
+            let SocketAddr::V4(addr) = addr
+            else {
+                continue;
+            };
+
+            let stream = TcpStream::from_pcb(tcp_connect(addr)?);
+
+            match poll_fn(|cx| tcb_poll(&stream.pcb, cx, connect_ready)).await
+            {
+                Ok(()) => return Ok(stream.into()),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(no_v4_addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.inner.poll_read(cx, buf)).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.inner.poll_write(cx, buf)).await
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+
+            buf = &buf[n..];
+        }
+
+        Ok(())
+    }
+
+    pub fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_read(cx, buf)
+    }
+
+    pub fn poll_write(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_write(cx, buf)
+    }
+
+    /// FIN is queued, it never blocks
+    pub fn poll_shutdown(
+        &self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.shutdown(Shutdown::Write))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Socket options are set on the blocking stream
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> TcpStream {
+        self.inner
+    }
+}
+
+impl From<TcpStream> for AsyncTcpStream {
+    fn from(inner: TcpStream) -> Self {
+        Self { inner }
+    }
+}
+
+impl AsyncTcpListener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        TcpListener::bind(addr).map(Self::from)
+    }
+
+    pub async fn accept(&self) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        let (stream, addr) = poll_fn(|cx| self.inner.poll_accept(cx)).await?;
+
+        Ok((stream.into(), addr))
+    }
+
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(AsyncTcpStream, SocketAddr)>> {
+        self.inner
+            .poll_accept(cx)
+            .map_ok(|(stream, addr)| (stream.into(), addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> TcpListener {
+        self.inner
+    }
+}
+
+impl From<TcpListener> for AsyncTcpListener {
+    fn from(inner: TcpListener) -> Self {
+        Self { inner }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for AsyncUdpSocket {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = std::task::ready!(AsyncUdpSocket::poll_recv(
+            self.get_mut(),
+            cx,
+            buf.initialize_unfilled()
+        ))?;
+
+        buf.advance(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for AsyncUdpSocket {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncUdpSocket::poll_send(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncRead for AsyncUdpSocket {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncUdpSocket::poll_recv(self.get_mut(), cx, buf)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for AsyncUdpSocket {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncUdpSocket::poll_send(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = std::task::ready!(AsyncTcpStream::poll_read(
+            self.get_mut(),
+            cx,
+            buf.initialize_unfilled()
+        ))?;
+
+        buf.advance(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_write(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncTcpStream::poll_shutdown(self.get_mut(), cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_read(self.get_mut(), cx, buf)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_write(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncTcpStream::poll_shutdown(self.get_mut(), cx)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Try each IPv4 address like `std::net`, return the last error
+fn each_addr<A: ToSocketAddrs, T>(
+    addr: A,
+    mut f: impl FnMut(SocketAddrV4) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut last_err = None;
+
+    for addr in addr.to_socket_addrs()? {
+        let SocketAddr::V4(addr) = addr
+        else {
+            continue;
+        };
+
+        match f(addr) {
+            Ok(res) => return Ok(res),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_v4_addr))
+}
+
+fn no_v4_addr() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "could not resolve to any IPv4 addresses",
+    )
+}
+
+fn check_timeout(dur: Option<Duration>) -> io::Result<Option<Duration>> {
+    if dur == Some(Duration::ZERO) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ))?
+    }
+
+    Ok(dur)
+}
+
+/// Block until `f` gives result, the connection is updated then
+fn tcb_wait<T>(
+    pcb: &Arc<TCPPcb>,
+    timeout: Option<Duration>,
+    nonblocking: bool,
+    mut f: impl FnMut(&mut TCB) -> Option<io::Result<T>>,
+) -> io::Result<T> {
+    let deadline = timeout.map(|dur| clock_now() + dur);
+    let mut tcb = pcb.tcb.lock().unwrap();
+
+    loop {
+        if let Some(res) = f(&mut tcb) {
+            tcp_update(pcb, &mut tcb);
+
+            return res;
+        }
+
+        if nonblocking {
+            Err(io::Error::from(io::ErrorKind::WouldBlock))?
+        }
+
+        tcb = match deadline {
+            Some(deadline) => {
+                let now = clock_now();
+
+                if now >= deadline {
+                    Err(io::Error::from(io::ErrorKind::WouldBlock))?
+                }
+
+                pcb.cond.wait_timeout(tcb, deadline - now).unwrap().0
+            }
+            None => pcb.cond.wait(tcb).unwrap(),
+        };
+    }
+}
+
+/// Async version of `tcb_wait`
+fn tcb_poll<T>(
+    pcb: &Arc<TCPPcb>,
+    cx: &mut Context<'_>,
+    mut f: impl FnMut(&mut TCB) -> Option<io::Result<T>>,
+) -> Poll<io::Result<T>> {
+    let mut tcb = pcb.tcb.lock().unwrap();
+
+    match f(&mut tcb) {
+        Some(res) => {
+            tcp_update(pcb, &mut tcb);
+
+            Poll::Ready(res)
+        }
+        None => {
+            pcb.register_waker(cx.waker());
+
+            Poll::Pending
+        }
+    }
+}
+
+fn connect_ready(tcb: &mut TCB) -> Option<io::Result<()>> {
+    if let Some(err) = tcb.take_err() {
+        return Some(Err(err));
+    }
+
+    match tcb.state {
+        TCPState::Closed => {
+            Some(Err(io::Error::from_raw_os_error(ECONNRESET)))
+        }
+        state if state.is_connecting() => None,
+        _ => Some(Ok(())),
+    }
+}
+
+fn read_ready(tcb: &mut TCB, buf: &mut [u8]) -> Option<io::Result<usize>> {
+    if buf.is_empty() {
+        return Some(Ok(0));
+    }
+
+    tcb.is_readable().then(|| tcb.recv(buf))
+}
+
+fn write_ready(tcb: &mut TCB, buf: &[u8]) -> Option<io::Result<usize>> {
+    if buf.is_empty() {
+        return Some(Ok(0));
+    }
+
+    tcb.is_writable().then(|| tcb.send(buf))
+}
+
+fn accept_ready(tcb: &mut TCB) -> Option<io::Result<(TcpStream, SocketAddr)>> {
+    if let Some(err) = tcb.take_err() {
+        return Some(Err(err));
+    }
+
+    let (pcb, remote) = tcb.accept_q.pop_front()?;
+
+    Some(Ok((TcpStream::from_pcb(pcb), remote.into())))
+}
+
+fn is_broadcast(ip: Ipv4Addr) -> bool {
+    ip.is_broadcast()
+        || DEV_TBL
+            .read()
+            .unwrap()
+            .iter()
+            .any(|dev| dev.broadcast() == ip)
+}
+
+/// Keep errno raised by the stack
+pub(crate) fn into_io_error(err: anyhow::Error) -> io::Error {
+    err.downcast::<io::Error>().unwrap_or_else(io::Error::other)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Wake, Waker};
+
+    use osimodel::network::ip::ProtocolKind;
+
+    use super::*;
+    use crate::{
+        dev::tests::{dev_pair, frame_input, frame_output, neigh_add},
+        ip::{ip_split, tests::ipv4_packet},
+        udp::tests::udp_segment,
+    };
+
+    /// Waker which records that it's woken
+    #[derive(Default)]
+    struct WakeFlag(AtomicBool);
+
+    impl Wake for WakeFlag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_udp_socket() {
+        let (dev, peer) = dev_pair();
+        let (host, hwa) = neigh_add(&dev, 2);
+        let sock = UdpSocket::bind((dev.ip, 0)).unwrap();
+        let SocketAddr::V4(local) = sock.local_addr().unwrap()
+        else {
+            unreachable!()
+        };
+        let remote = SocketAddrV4::new(host, 7);
+        let mut buf = [0; 16];
+
+        assert_eq!(sock.send_to(b"echo", remote).unwrap(), 4);
+
+        let (_, pkt) = frame_output(&peer).unwrap();
+        let (_, seg) = ip_split(&pkt);
+
+        assert_eq!(seg[..2], local.port().to_be_bytes());
+        assert_eq!(seg[2..4], [0, 7]);
+        assert_eq!(seg[8..], *b"echo");
+
+        // the peer echoes it back
+        let echo = udp_segment(remote, local, &seg[8..]);
+        let pkt = ipv4_packet(ProtocolKind::UDP, host, dev.ip, &echo);
+
+        frame_input(&dev, &peer, hwa, dev.hwa, &pkt).unwrap();
+
+        assert_eq!(sock.recv_from(&mut buf).unwrap(), (4, remote.into()));
+        assert_eq!(buf[..4], *b"echo");
+
+        sock.set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+
+        assert_eq!(
+            sock.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // broadcast needs SO_BROADCAST
+        assert_eq!(
+            sock.send_to(b"echo", (dev.broadcast(), 7))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(EACCES)
+        );
+    }
+
+    #[test]
+    fn test_async_udp_wake() {
+        let (dev, peer) = dev_pair();
+        let (host, hwa) = neigh_add(&dev, 2);
+        let sock = AsyncUdpSocket::from(UdpSocket::bind((dev.ip, 0)).unwrap());
+        let SocketAddr::V4(local) = sock.local_addr().unwrap()
+        else {
+            unreachable!()
+        };
+        let remote = SocketAddrV4::new(host, 7);
+        let flag = Arc::new(WakeFlag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = [0; 16];
+
+        assert!(sock.poll_recv_from(&mut cx, &mut buf).is_pending());
+        assert!(!flag.0.load(Ordering::Relaxed));
+
+        let seg = udp_segment(remote, local, b"wake");
+        let pkt = ipv4_packet(ProtocolKind::UDP, host, dev.ip, &seg);
+
+        frame_input(&dev, &peer, hwa, dev.hwa, &pkt).unwrap();
+
+        assert!(flag.0.load(Ordering::Relaxed));
+        assert!(matches!(
+            sock.poll_recv_from(&mut cx, &mut buf),
+            Poll::Ready(Ok((4, from))) if from == remote.into()
+        ));
+        assert_eq!(buf[..4], *b"wake");
+    }
+}
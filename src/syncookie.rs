@@ -0,0 +1,159 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::SocketAddrV4,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use m6ptr::LazyStatic;
+
+use crate::clock::Clock;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Low bits of cookie keep hash and MSS index, high 8 bits keep the time
+const COOKIE_BITS: u32 = 24;
+const COOKIE_MASK: u32 = (1 << COOKIE_BITS) - 1;
+
+/// Cookie time counts in it
+const COOKIE_PERIOD: Duration = Duration::from_secs(60);
+/// Cookie older than it in periods is rejected
+const COOKIE_MAX_AGE: u32 = 2;
+
+/// Cookies are only checked for a while after SYN queue overflows
+pub const SYNCOOKIE_VALID: Duration =
+    Duration::from_secs(COOKIE_PERIOD.as_secs() * COOKIE_MAX_AGE as u64);
+
+/// MSS encodable, chosen like Linux `msstab`
+pub const SYNCOOKIE_MSS: [u16; 4] = [536, 1300, 1440, 1460];
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+static COOKIE_SECRET: LazyStatic<[RandomState; 2]> =
+    LazyStatic::new(|| [RandomState::new(), RandomState::new()]);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// ISN of SYN-ACK encoding `mss` which is rounded down to the table
+/// (RFC 4987 3.6), returns (cookie, encoded MSS)
+pub fn syncookie_isn(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    seq: u32,
+    mss: u16,
+    clock: &dyn Clock,
+) -> (u32, u16) {
+    let idx = SYNCOOKIE_MSS
+        .iter()
+        .rposition(|&val| val <= mss)
+        .unwrap_or_default();
+    let t = cookie_time(clock);
+    let secret = COOKIE_SECRET.read().unwrap();
+
+    let h1 = secret[0].hash_one((local, remote)) as u32;
+    let h2 = secret[1].hash_one((local, remote, t)) as u32;
+
+    let cookie = h1
+        .wrapping_add(seq)
+        .wrapping_add(t << COOKIE_BITS)
+        .wrapping_add(h2.wrapping_add(idx as u32) & COOKIE_MASK);
+
+    (cookie, SYNCOOKIE_MSS[idx])
+}
+
+/// Check `cookie` of the SYN with `seq`, returns the encoded MSS
+pub fn syncookie_check(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    seq: u32,
+    cookie: u32,
+    clock: &dyn Clock,
+) -> Option<u16> {
+    let secret = COOKIE_SECRET.read().unwrap();
+
+    let h1 = secret[0].hash_one((local, remote)) as u32;
+    let val = cookie.wrapping_sub(h1).wrapping_sub(seq);
+
+    let t = val >> COOKIE_BITS;
+
+    if cookie_time(clock).wrapping_sub(t) & 0xFF >= COOKIE_MAX_AGE {
+        return None;
+    }
+
+    let h2 = secret[1].hash_one((local, remote, t)) as u32;
+    let idx = val.wrapping_sub(h2) & COOKIE_MASK;
+
+    SYNCOOKIE_MSS.get(idx as usize).copied()
+}
+
+/// 8 bits counter of `COOKIE_PERIOD`
+fn cookie_time(clock: &dyn Clock) -> u32 {
+    let now = clock.wall().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    (now.as_secs() / COOKIE_PERIOD.as_secs()) as u32 & 0xFF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_syncookie() {
+        let local = "10.0.0.1:80".parse().unwrap();
+        let remote = "10.0.0.2:40000".parse().unwrap();
+        let other = "10.0.0.2:40001".parse().unwrap();
+        let seq = 0xdead_beef_u32;
+        let clock = ManualClock::new();
+
+        // MSS is rounded down to the table, smaller than it takes the least
+        for (mss, encoded) in [
+            (9000, 1460),
+            (1460, 1460),
+            (1400, 1300),
+            (537, 536),
+            (100, 536),
+        ] {
+            let (cookie, mss) = syncookie_isn(local, remote, seq, mss, &clock);
+
+            assert_eq!(mss, encoded);
+            assert_eq!(
+                syncookie_check(local, remote, seq, cookie, &clock),
+                Some(mss)
+            );
+            assert_eq!(
+                syncookie_check(local, other, seq, cookie, &clock),
+                None
+            );
+
+            // another SYN sequence moves the time counter 128 periods away
+            let bad = seq ^ 1 << 31;
+
+            assert_eq!(
+                syncookie_check(local, remote, bad, cookie, &clock),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_syncookie_expiry() {
+        let local = "10.0.0.1:80".parse().unwrap();
+        let remote = "10.0.0.2:40000".parse().unwrap();
+        let clock = ManualClock::new();
+        let (cookie, mss) = syncookie_isn(local, remote, 1, 1460, &clock);
+
+        clock.advance(SYNCOOKIE_VALID / 2);
+
+        assert_eq!(
+            syncookie_check(local, remote, 1, cookie, &clock),
+            Some(mss)
+        );
+
+        clock.advance(SYNCOOKIE_VALID / 2);
+
+        assert_eq!(syncookie_check(local, remote, 1, cookie, &clock), None);
+    }
+}
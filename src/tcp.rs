@@ -0,0 +1,3494 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    hash::{BuildHasher, RandomState},
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    ops::RangeInclusive,
+    sync::{
+        Arc, Condvar, Mutex, Once, Weak,
+        atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering},
+    },
+    task::Waker,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libc::{
+    EADDRINUSE, EADDRNOTAVAIL, ECONNREFUSED, ECONNRESET, ENETUNREACH, EPIPE,
+    ETIMEDOUT,
+};
+use log::trace;
+use m6ptr::LazyStatic;
+use m6tobytes::{as_raw_slice, from_raw_slice};
+use osimodel::network::ip::ProtocolKind;
+
+use crate::{
+    clock::{CLOCK, clock_now, clock_wall},
+    dev::{NetDevice, is_local_addr},
+    icmp::{ICMPErr, icmp_register_err_handler},
+    ip::{ip_output, ip_pseudo_cksum, ip_split},
+    route::ip_route_output,
+    snmp::SNMP_TCP,
+    syncookie::{SYNCOOKIE_VALID, syncookie_check, syncookie_isn},
+    tcpcc::{CCKind, CongestionControl, TCP_CONGESTION},
+    timer::timer_add,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/* Control bits */
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+/* Option kinds */
+
+pub const TCPOPT_EOL: u8 = 0;
+pub const TCPOPT_NOP: u8 = 1;
+pub const TCPOPT_MSS: u8 = 2;
+pub const TCPOPT_WSCALE: u8 = 3;
+pub const TCPOPT_SACK_PERM: u8 = 4;
+pub const TCPOPT_SACK: u8 = 5;
+pub const TCPOPT_TS: u8 = 8;
+
+/// Timestamps option with 2 NOPs
+pub const TCPOLEN_TS_ALIGNED: usize = 12;
+
+/// IANA dynamic ports (RFC 6335)
+pub const TCP_EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Send MSS if peer doesn't tell it (RFC 9293 3.7.1)
+pub const TCP_DEFAULT_MSS: u16 = 536;
+/// Floor of send MSS like Linux, data still fits after 40 bytes of options
+pub const TCP_MIN_MSS: u16 = 88;
+
+pub const TCP_SNDBUF_SZ: usize = 4 << 20;
+pub const TCP_RCVBUF_SZ: usize = 4 << 20;
+
+/// Receive window scale, receive buffer fits 16 bits after shift
+pub const TCP_RCV_WSCALE: u8 = 7;
+/// RFC 7323 2.3
+pub const TCP_MAX_WSCALE: u8 = 14;
+
+/// `ts_recent` is invalid after idle of it (RFC 7323 5.5)
+pub const TCP_PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// Maximum Segment Lifetime, TIME-WAIT lasts 2MSL like Linux
+pub const TCP_MSL: Duration = Duration::from_secs(30);
+/// FIN-WAIT-2 of orphaned connection is aborted after it
+pub const TCP_FIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// RFC 6298 2.1
+pub const TCP_RTO_INIT: Duration = Duration::from_secs(1);
+/// Lower than 1 second of RFC 6298 2.4 like Linux
+pub const TCP_RTO_MIN: Duration = Duration::from_millis(200);
+pub const TCP_RTO_MAX: Duration = Duration::from_secs(120);
+pub const TCP_SYN_RETRIES: u32 = 6;
+pub const TCP_RETRIES2: u32 = 15;
+
+pub const TCP_TMR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ACK is delayed at most it (RFC 9293 3.8.6.3)
+pub const TCP_DELACK_TIMEOUT: Duration = Duration::from_millis(200);
+
+/* Keepalive defaults of Linux */
+
+pub const TCP_KEEPIDLE: Duration = Duration::from_secs(7200);
+pub const TCP_KEEPINTVL: Duration = Duration::from_secs(75);
+pub const TCP_KEEPCNT: u32 = 9;
+
+/// Fast retransmit threshold (RFC 5681 3.2)
+pub const TCP_DUPACK_THRESH: u32 = 3;
+/// Initial window in segments (RFC 6928)
+pub const TCP_INIT_CWND: u32 = 10;
+
+pub const TCP_BACKLOG: usize = 128;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/* Tunables, they're taken by connections when created */
+
+/// Send buffer size like Linux `net.ipv4.tcp_wmem`
+pub static TCP_WMEM: AtomicUsize = AtomicUsize::new(TCP_SNDBUF_SZ);
+/// Receive buffer size like Linux `net.ipv4.tcp_rmem`
+pub static TCP_RMEM: AtomicUsize = AtomicUsize::new(TCP_RCVBUF_SZ);
+pub static TCP_SYNCNT: AtomicU32 = AtomicU32::new(TCP_SYN_RETRIES);
+pub static TCP_RETRIES: AtomicU32 = AtomicU32::new(TCP_RETRIES2);
+/// Backlog of listeners created by socket API, like Linux `somaxconn`
+pub static TCP_SOMAXCONN: AtomicUsize = AtomicUsize::new(TCP_BACKLOG);
+
+/// Connections with `remote` and listeners without it
+pub static TCP_TBL: LazyStatic<HashMap<TCPKey, Arc<TCPPcb>>> =
+    LazyStatic::new(|| HashMap::new());
+
+static TCP_PORT_NEXT: AtomicU16 = AtomicU16::new(*TCP_EPHEMERAL_PORTS.start());
+
+/// Keyed SipHash as the PRF of ISN (RFC 6528)
+static TCP_ISN_SECRET: LazyStatic<RandomState> =
+    LazyStatic::new(|| RandomState::new());
+
+static TCP_TMR_ONCE: Once = Once::new();
+
+/// Base of timestamp clock
+static TCP_TS_EPOCH: LazyStatic<Instant> = LazyStatic::new(|| clock_now());
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// TCP header, all fields are in network byte order (RFC 9293 3.1)
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct TCP {
+    pub src: u16,
+    pub dst: u16,
+    pub seq: u32,
+    pub ack: u32,
+    /// Data offset at high 4 bits and control bits at low 8 bits
+    pub off_flags: u16,
+    pub wnd: u16,
+    pub cksum: u16,
+    pub urp: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TCPKey {
+    pub local: SocketAddrV4,
+    pub remote: Option<SocketAddrV4>,
+}
+
+/// RFC 9293 3.3.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TCPState {
+    Closed,
+    Listen,
+    SynSent,
+    SynRcvd,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TCPOpts {
+    pub mss: Option<u16>,
+    pub wscale: Option<u8>,
+    pub sack_perm: bool,
+    /// (TSval, TSecr)
+    pub ts: Option<(u32, u32)>,
+    /// SACK blocks (left edge, right edge)
+    pub sack: Vec<(u32, u32)>,
+}
+
+/// Keepalive probes after the connection is idle (RFC 1122 4.2.3.6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TCPKeepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped
+    pub count: u32,
+}
+
+/// Incoming segment
+#[derive(Debug)]
+pub struct TCPSeg<'a> {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub wnd: u32,
+    pub opts: TCPOpts,
+    pub data: &'a [u8],
+}
+
+/// Transmission Control Block
+#[derive(Debug)]
+pub struct TCB {
+    pub key: TCPKey,
+    pub state: TCPState,
+
+    /* Send sequence variables */
+    pub iss: u32,
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    /// Highest sequence sent, `snd_nxt` goes back to `snd_una` on timeout
+    pub snd_max: u32,
+    pub snd_wnd: u32,
+    pub snd_wl1: u32,
+    pub snd_wl2: u32,
+    /// Send MSS, options are not excluded (RFC 6691)
+    pub mss: u16,
+    pub snd_wscale: u8,
+
+    /* Receive sequence variables */
+    pub irs: u32,
+    pub rcv_nxt: u32,
+    /// Advertised MSS
+    pub rcv_mss: u16,
+    pub rcv_wscale: u8,
+
+    /* Options offered by us and then agreed by peer */
+    pub wscale_ok: bool,
+    pub sack_ok: bool,
+    pub ts_ok: bool,
+
+    /* Timestamps (RFC 7323 4) */
+    /// Random offset of our TSval
+    pub ts_off: u32,
+    pub ts_recent: u32,
+    pub ts_recent_stamp: Instant,
+
+    /// Unacknowledged and unsent data starting at `snd_una`
+    pub sndbuf: VecDeque<u8>,
+    pub rcvbuf: VecDeque<u8>,
+    pub sndbuf_sz: usize,
+    pub rcvbuf_sz: usize,
+    /// Out-of-order segments (seq, data)
+    pub ooo: Vec<(u32, Vec<u8>)>,
+    /// The latest out-of-order segment, its block is reported first
+    pub ooo_last: Option<u32>,
+
+    pub syn_acked: bool,
+    /// User has closed the sending side
+    pub fin_queued: bool,
+    pub fin_acked: bool,
+    pub rcv_fin: bool,
+    pub rd_shut: bool,
+    /// User has gone
+    pub orphan: bool,
+    pub ack_now: bool,
+
+    /* User options */
+    /// Disable Nagle algorithm
+    pub nodelay: bool,
+    /// Disable delayed ACK, it's kept unlike Linux
+    pub quickack: bool,
+    pub keepalive: Option<TCPKeepalive>,
+    /// Unacknowledged data longer than it aborts the connection (RFC 5482)
+    pub user_timeout: Option<Duration>,
+
+    /// Full-sized segments received but not acknowledged
+    pub delack_segs: u32,
+    /// The largest segment received as the full size
+    pub rcv_seg_max: usize,
+    pub delack_deadline: Option<Instant>,
+    /// Time of the last segment received, for keepalive
+    pub rcv_stamp: Instant,
+    pub keep_probes: u32,
+    /// Since when `snd_una` is waiting for acknowledgment
+    pub una_stamp: Option<Instant>,
+
+    pub err: Option<i32>,
+    /// Reported by ICMP, it becomes `err` on timeout (RFC 1122 4.2.3.9)
+    pub soft_err: Option<i32>,
+
+    pub rto: Duration,
+    pub rtx_deadline: Option<Instant>,
+    /// Retransmissions of current timeout, the backoff exponent
+    pub rtx_count: u32,
+
+    /* RTT estimation (RFC 6298) */
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    /// The timed segment (end sequence, sent time), cleared on
+    /// retransmission by Karn's algorithm
+    pub rtt_seq: Option<(u32, Instant)>,
+
+    /* Congestion control (RFC 5681, RFC 6582) */
+    pub cc: Box<dyn CongestionControl>,
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub dupacks: u32,
+    pub in_recovery: bool,
+    /// `snd_max` when loss is detected last time
+    pub recover: u32,
+    /// SACK scoreboard, disjoint ranges above `snd_una` in order
+    pub sacked: Vec<(u32, u32)>,
+    /// Highest sequence retransmitted in SACK recovery (RFC 6675)
+    pub high_rxt: u32,
+
+    /* Statistics */
+    pub total_retrans: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    /// TIME-WAIT or orphaned FIN-WAIT-2
+    pub tw_deadline: Option<Instant>,
+
+    /* Listener */
+    /// Bound of both SYN queue and accept queue
+    pub backlog: usize,
+    /// Established connections with their remote address
+    pub accept_q: VecDeque<(Arc<TCPPcb>, SocketAddrV4)>,
+    /// SYN cookies are accepted for a while after it
+    pub syn_overflow: Option<Instant>,
+    pub listen_stats: TCPListenStats,
+    /// Listener of passive open
+    pub parent: Weak<TCPPcb>,
+    /// Counted in `syn_qlen` of parent
+    pub in_syn_q: bool,
+}
+
+/// Listener counters like Linux `ListenOverflows` and `Syncookies*`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TCPListenStats {
+    /// SYNs and cookie ACKs dropped as accept queue is full
+    pub overflows: u64,
+    pub cookies_sent: u64,
+    /// Connections established by valid cookies
+    pub cookies_recv: u64,
+    pub cookies_failed: u64,
+}
+
+/// Connection information like `ss -i` from Linux `struct tcp_info`
+#[derive(Debug, Clone)]
+pub struct TCPInfo {
+    pub state: TCPState,
+    pub cc: &'static str,
+    pub rto: Duration,
+    pub srtt: Duration,
+    pub rttvar: Duration,
+    pub mss: u16,
+    pub rcv_mss: u16,
+    /// (send, receive) shift
+    pub wscale: Option<(u8, u8)>,
+    pub ts: bool,
+    pub sack: bool,
+    /// In segments
+    pub cwnd: u32,
+    /// In segments, `None` before the first loss
+    pub ssthresh: Option<u32>,
+    /// Segments in flight
+    pub unacked: u32,
+    /// SACKed segments
+    pub sacked: u32,
+    pub retrans: u32,
+    pub total_retrans: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+}
+
+#[derive(Debug)]
+pub struct TCPPcb {
+    pub tcb: Mutex<TCB>,
+    /// Notified on any change of `tcb`
+    pub cond: Condvar,
+    /// Registered with `tcb` locked
+    pub wakers: Mutex<Vec<Waker>>,
+    /// SYN-RECEIVED connections of listener, it's changed without locking
+    /// the listener
+    pub syn_qlen: AtomicUsize,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl TCP {
+    pub fn src_port(&self) -> u16 {
+        u16::from_be(self.src)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        u16::from_be(self.dst)
+    }
+
+    pub fn seq(&self) -> u32 {
+        u32::from_be(self.seq)
+    }
+
+    pub fn ack(&self) -> u32 {
+        u32::from_be(self.ack)
+    }
+
+    /// Header length in bytes
+    pub fn doff(&self) -> usize {
+        (u16::from_be(self.off_flags) >> 12) as usize * 4
+    }
+
+    pub fn flags(&self) -> u8 {
+        u16::from_be(self.off_flags) as u8
+    }
+
+    pub fn wnd(&self) -> u16 {
+        u16::from_be(self.wnd)
+    }
+}
+
+impl TCPKey {
+    pub fn port(&self) -> u16 {
+        self.local.port()
+    }
+}
+
+impl Display for TCPState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TCPState::*;
+
+        write!(
+            f,
+            "{}",
+            match self {
+                Closed => "CLOSED",
+                Listen => "LISTEN",
+                SynSent => "SYN-SENT",
+                SynRcvd => "SYN-RECEIVED",
+                Established => "ESTABLISHED",
+                FinWait1 => "FIN-WAIT-1",
+                FinWait2 => "FIN-WAIT-2",
+                CloseWait => "CLOSE-WAIT",
+                Closing => "CLOSING",
+                LastAck => "LAST-ACK",
+                TimeWait => "TIME-WAIT",
+            }
+        )
+    }
+}
+
+/// Format of `ss -i`, times are in milliseconds
+impl Display for TCPInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        if self.ts {
+            write!(f, "ts ")?;
+        }
+
+        if self.sack {
+            write!(f, "sack ")?;
+        }
+
+        write!(f, "{}", self.cc)?;
+
+        if let Some((snd, rcv)) = self.wscale {
+            write!(f, " wscale:{snd},{rcv}")?;
+        }
+
+        write!(
+            f,
+            " rto:{} rtt:{:.3}/{:.3} mss:{} rcvmss:{} cwnd:{}",
+            self.rto.as_millis(),
+            ms(self.srtt),
+            ms(self.rttvar),
+            self.mss,
+            self.rcv_mss,
+            self.cwnd
+        )?;
+
+        if let Some(ssthresh) = self.ssthresh {
+            write!(f, " ssthresh:{ssthresh}")?;
+        }
+
+        write!(
+            f,
+            " bytes_acked:{} bytes_received:{} unacked:{}",
+            self.bytes_acked, self.bytes_received, self.unacked,
+        )?;
+
+        if self.sacked > 0 {
+            write!(f, " sacked:{}", self.sacked)?;
+        }
+
+        write!(
+            f,
+            " retrans:{}/{} snd_wnd:{} rcv_wnd:{}",
+            self.retrans, self.total_retrans, self.snd_wnd, self.rcv_wnd
+        )
+    }
+}
+
+impl TCPState {
+    /// SYN has been exchanged
+    pub fn is_synchronized(&self) -> bool {
+        !matches!(self, Self::Closed | Self::Listen | Self::SynSent)
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        matches!(self, Self::SynSent | Self::SynRcvd)
+    }
+}
+
+impl<'a> TCPSeg<'a> {
+    /// Sequence space it occupies
+    pub fn len(&self) -> u32 {
+        self.data.len() as u32
+            + (self.flags & TCP_SYN != 0) as u32
+            + (self.flags & TCP_FIN != 0) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+impl TCB {
+    pub fn new(key: TCPKey, state: TCPState) -> Self {
+        let iss = key.remote.map(|remote| tcp_isn(key.local, remote));
+
+        Self {
+            key,
+            state,
+            iss: iss.unwrap_or_default(),
+            snd_una: iss.unwrap_or_default(),
+            snd_nxt: iss.unwrap_or_default(),
+            snd_max: iss.unwrap_or_default(),
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            mss: TCP_DEFAULT_MSS,
+            snd_wscale: 0,
+            irs: 0,
+            rcv_nxt: 0,
+            rcv_mss: TCP_DEFAULT_MSS,
+            rcv_wscale: TCP_RCV_WSCALE,
+            wscale_ok: true,
+            sack_ok: true,
+            ts_ok: true,
+            ts_off: key
+                .remote
+                .map(|remote| {
+                    TCP_ISN_SECRET
+                        .read()
+                        .unwrap()
+                        .hash_one((remote, key.local))
+                        as u32
+                })
+                .unwrap_or_default(),
+            ts_recent: 0,
+            ts_recent_stamp: clock_now(),
+            sndbuf: VecDeque::new(),
+            rcvbuf: VecDeque::new(),
+            sndbuf_sz: TCP_WMEM.load(Ordering::Relaxed),
+            rcvbuf_sz: TCP_RMEM.load(Ordering::Relaxed),
+            ooo: Vec::new(),
+            ooo_last: None,
+            syn_acked: false,
+            fin_queued: false,
+            fin_acked: false,
+            rcv_fin: false,
+            rd_shut: false,
+            orphan: false,
+            ack_now: false,
+            nodelay: false,
+            quickack: false,
+            keepalive: None,
+            user_timeout: None,
+            delack_segs: 0,
+            rcv_seg_max: 0,
+            delack_deadline: None,
+            rcv_stamp: clock_now(),
+            keep_probes: 0,
+            una_stamp: None,
+            err: None,
+            soft_err: None,
+            rto: TCP_RTO_INIT,
+            rtx_deadline: None,
+            rtx_count: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rtt_seq: None,
+            cc: TCP_CONGESTION.read().unwrap().build(),
+            cwnd: TCP_INIT_CWND * TCP_DEFAULT_MSS as u32,
+            ssthresh: u32::MAX,
+            dupacks: 0,
+            in_recovery: false,
+            recover: iss.unwrap_or_default(),
+            sacked: Vec::new(),
+            high_rxt: iss.unwrap_or_default(),
+            total_retrans: 0,
+            bytes_acked: 0,
+            bytes_received: 0,
+            tw_deadline: None,
+            backlog: 0,
+            accept_q: VecDeque::new(),
+            syn_overflow: None,
+            listen_stats: TCPListenStats::default(),
+            parent: Weak::new(),
+            in_syn_q: false,
+        }
+    }
+
+    pub fn local(&self) -> SocketAddrV4 {
+        self.key.local
+    }
+
+    pub fn remote(&self) -> SocketAddrV4 {
+        self.key.remote.unwrap()
+    }
+
+    pub fn take_err(&mut self) -> Option<io::Error> {
+        self.err.take().map(io::Error::from_raw_os_error)
+    }
+
+    /// Sequence number of `sndbuf[0]`
+    fn snd_seq0(&self) -> u32 {
+        self.snd_una.wrapping_add(!self.syn_acked as u32)
+    }
+
+    /// Bytes sent but not acknowledged
+    pub fn flight(&self) -> u32 {
+        self.snd_max.wrapping_sub(self.snd_una)
+    }
+
+    pub fn rcv_wnd(&self) -> u32 {
+        self.rcvbuf_sz.saturating_sub(self.rcvbuf.len()) as u32
+    }
+
+    /// Receive window as the peer sees it after scaling down to 16 bits
+    pub fn adv_wnd(&self) -> u32 {
+        (self.rcv_wnd() >> self.rcv_wscale).min(u16::MAX as u32)
+            << self.rcv_wscale
+    }
+
+    pub fn is_readable(&self) -> bool {
+        !self.rcvbuf.is_empty()
+            || self.rcv_fin
+            || self.rd_shut
+            || self.err.is_some()
+            || self.state == TCPState::Closed
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.sndbuf.len() < self.sndbuf_sz
+            || self.err.is_some()
+            || self.state == TCPState::Closed
+    }
+
+    /// Queue `buf` as much as possible
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(err) = self.take_err() {
+            Err(err)?
+        }
+
+        if self.fin_queued
+            || !matches!(
+                self.state,
+                TCPState::SynSent
+                    | TCPState::SynRcvd
+                    | TCPState::Established
+                    | TCPState::CloseWait
+            )
+        {
+            Err(io::Error::from_raw_os_error(EPIPE))?
+        }
+
+        let n = buf
+            .len()
+            .min(self.sndbuf_sz.saturating_sub(self.sndbuf.len()));
+
+        self.sndbuf.extend(&buf[..n]);
+
+        Ok(n)
+    }
+
+    /// Read received data, zero means EOF if it's readable
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rd_shut {
+            return Ok(0);
+        }
+
+        if self.rcvbuf.is_empty()
+            && let Some(err) = self.take_err()
+        {
+            Err(err)?
+        }
+
+        let old_wnd = self.rcv_wnd();
+        let n = buf.len().min(self.rcvbuf.len());
+
+        for (dst, src) in buf.iter_mut().zip(self.rcvbuf.drain(..n)) {
+            *dst = src;
+        }
+
+        // tell peer the reopened window
+        if old_wnd < self.rcv_mss as u32
+            && self.rcv_wnd() >= self.rcv_mss as u32
+            && self.state.is_synchronized()
+        {
+            self.ack_now = true;
+        }
+
+        Ok(n)
+    }
+
+    pub fn set_cc(&mut self, kind: CCKind) {
+        self.cc = kind.build();
+    }
+
+    pub fn info(&self) -> TCPInfo {
+        let mss = self.mss as u32;
+
+        TCPInfo {
+            state: self.state,
+            cc: self.cc.name(),
+            rto: self.rto,
+            srtt: self.srtt.unwrap_or_default(),
+            rttvar: self.rttvar,
+            mss: self.mss,
+            rcv_mss: self.rcv_mss,
+            wscale: self
+                .wscale_ok
+                .then_some((self.snd_wscale, self.rcv_wscale)),
+            ts: self.ts_ok,
+            sack: self.sack_ok,
+            cwnd: self.cwnd / mss,
+            ssthresh: (self.ssthresh != u32::MAX)
+                .then_some(self.ssthresh / mss),
+            unacked: self.flight().div_ceil(mss),
+            sacked: self.sacked_bytes().div_ceil(mss),
+            retrans: self.rtx_count,
+            total_retrans: self.total_retrans,
+            bytes_acked: self.bytes_acked,
+            bytes_received: self.bytes_received,
+            snd_wnd: self.snd_wnd,
+            rcv_wnd: self.rcv_wnd(),
+        }
+    }
+
+    /// User CLOSE call (RFC 9293 3.10.4)
+    pub fn close(&mut self) {
+        use TCPState::*;
+
+        self.orphan = true;
+
+        // RFC 2525 2.17, unread data is lost
+        if !self.rcvbuf.is_empty() && self.state.is_synchronized() {
+            self.abort();
+            return;
+        }
+
+        match self.state {
+            Listen | SynSent => self.state = Closed,
+            SynRcvd | Established | CloseWait => self.shutdown_write(),
+            _ => (),
+        }
+    }
+
+    /// Send FIN after queued data
+    pub fn shutdown_write(&mut self) {
+        use TCPState::*;
+
+        if self.fin_queued {
+            return;
+        }
+
+        match self.state {
+            SynRcvd | Established => self.state = FinWait1,
+            CloseWait => self.state = LastAck,
+            SynSent => {
+                self.state = Closed;
+                return;
+            }
+            _ => return,
+        }
+
+        self.fin_queued = true;
+    }
+
+    /// User ABORT call, RST is sent in synchronized states
+    pub fn abort(&mut self) {
+        if self.state.is_synchronized() && self.state != TCPState::TimeWait {
+            tcp_rst(self.local(), self.remote(), self.snd_nxt, None);
+        }
+
+        self.state = TCPState::Closed;
+    }
+
+    fn reset(&mut self, errno: Option<i32>) {
+        self.err = errno;
+        self.state = TCPState::Closed;
+        self.rtx_deadline = None;
+    }
+
+    /// RFC 9293 3.10.7.3
+    pub fn syn_sent_input(&mut self, seg: &TCPSeg) {
+        if seg.has(TCP_ACK)
+            && (seq_leq(seg.ack, self.iss) || seq_gt(seg.ack, self.snd_max))
+        {
+            if !seg.has(TCP_RST) {
+                tcp_rst(self.local(), self.remote(), seg.ack, None);
+            }
+
+            return;
+        }
+
+        if seg.has(TCP_RST) {
+            if seg.has(TCP_ACK) {
+                trace!("Connection refused {}", self.remote());
+
+                self.reset(Some(ECONNREFUSED));
+            }
+
+            return;
+        }
+
+        if !seg.has(TCP_SYN) {
+            return;
+        }
+
+        self.irs = seg.seq;
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.syn_opts_input(&seg.opts);
+
+        if seg.has(TCP_ACK) {
+            if let Some((_, sent)) = self.rtt_seq.take() {
+                self.rtt_update(clock_now().duration_since(sent));
+            }
+
+            self.syn_acked = true;
+            self.snd_una = seg.ack;
+            self.rtx_count = 0;
+            self.rtx_deadline = None;
+            self.init_cwnd();
+        }
+
+        self.update_wnd(seg);
+        self.ack_now = true;
+
+        if self.syn_acked {
+            self.state = TCPState::Established;
+        }
+        else {
+            /* simultaneous open, SYN will be resent with ACK */
+
+            self.state = TCPState::SynRcvd;
+            self.snd_nxt = self.iss;
+        }
+    }
+
+    /// RFC 9293 3.10.7.4, returns if connection just gets established
+    pub fn synchronized_input(&mut self, seg: &TCPSeg) -> bool {
+        use TCPState::*;
+
+        // our SYN-ACK is lost
+        if self.state == SynRcvd
+            && seg.has(TCP_SYN)
+            && !seg.has(TCP_ACK)
+            && seg.seq == self.irs
+        {
+            self.snd_nxt = self.iss;
+            return false;
+        }
+
+        /* PAWS (RFC 7323 5.3) */
+
+        let tsval = seg.opts.ts.filter(|_| self.ts_ok).map(|(tsval, _)| tsval);
+
+        if let Some(tsval) = tsval
+            && !seg.has(TCP_RST)
+            && seq_lt(tsval, self.ts_recent)
+            && clock_now().duration_since(self.ts_recent_stamp) < TCP_PAWS_IDLE
+        {
+            trace!("PAWS rejects segment {} from {}", seg.seq, self.remote());
+
+            self.ack_now = true;
+            return false;
+        }
+
+        /* check sequence number */
+
+        if !self.is_acceptable(seg) {
+            if !seg.has(TCP_RST) {
+                self.ack_now = true;
+            }
+
+            return false;
+        }
+
+        self.rcv_stamp = clock_now();
+        self.keep_probes = 0;
+
+        if let Some(tsval) = tsval
+            && seq_geq(tsval, self.ts_recent)
+            && seq_leq(seg.seq, self.rcv_nxt)
+        {
+            self.ts_recent = tsval;
+            self.ts_recent_stamp = clock_now();
+        }
+
+        /* check RST bit (RFC 5961 3.2) */
+
+        if seg.has(TCP_RST) {
+            if seg.seq != self.rcv_nxt {
+                self.ack_now = true;
+                return false;
+            }
+
+            trace!("Connection reset {} in {}", self.remote(), self.state);
+
+            match self.state {
+                SynRcvd if self.parent.upgrade().is_some() => self.reset(None),
+                SynRcvd => self.reset(Some(ECONNREFUSED)),
+                Established | FinWait1 | FinWait2 | CloseWait => {
+                    self.reset(Some(ECONNRESET))
+                }
+                _ => self.reset(None),
+            }
+
+            return false;
+        }
+
+        /* check SYN bit (RFC 5961 4.2), challenge ACK */
+
+        if seg.has(TCP_SYN) {
+            self.ack_now = true;
+            return false;
+        }
+
+        /* check ACK field */
+
+        if !seg.has(TCP_ACK) {
+            return false;
+        }
+
+        let mut is_established = false;
+
+        if self.state == SynRcvd {
+            if seq_leq(seg.ack, self.snd_una) || seq_gt(seg.ack, self.snd_max)
+            {
+                tcp_rst(self.local(), self.remote(), seg.ack, None);
+                return false;
+            }
+
+            self.state = Established;
+            self.update_wnd(seg);
+            self.init_cwnd();
+            is_established = true;
+        }
+
+        if seq_gt(seg.ack, self.snd_max) {
+            self.ack_now = true;
+            return is_established;
+        }
+
+        if self.sack_ok {
+            self.sack_input(&seg.opts.sack);
+        }
+
+        if seq_gt(seg.ack, self.snd_una) {
+            self.ack_input(seg);
+        }
+        else if self.is_dupack(seg) {
+            self.dupack_input();
+        }
+
+        if seq_geq(seg.ack, self.snd_una)
+            && (seq_lt(self.snd_wl1, seg.seq)
+                || self.snd_wl1 == seg.seq && seq_leq(self.snd_wl2, seg.ack))
+        {
+            self.update_wnd(seg);
+        }
+
+        match self.state {
+            FinWait1 if self.fin_acked => {
+                self.state = FinWait2;
+
+                if self.orphan {
+                    self.tw_deadline = Some(clock_now() + TCP_FIN_TIMEOUT);
+                }
+            }
+            Closing if self.fin_acked => self.enter_time_wait(),
+            LastAck if self.fin_acked => {
+                self.state = Closed;
+                return is_established;
+            }
+            TimeWait if seg.has(TCP_FIN) => {
+                self.ack_now = true;
+                self.enter_time_wait();
+            }
+            _ => (),
+        }
+
+        /* process segment text */
+
+        if matches!(self.state, Established | FinWait1 | FinWait2)
+            && !seg.data.is_empty()
+        {
+            self.data_input(seg);
+        }
+
+        /* check FIN bit */
+
+        if seg.has(TCP_FIN)
+            && !self.rcv_fin
+            && seg.seq.wrapping_add(seg.data.len() as u32) == self.rcv_nxt
+        {
+            self.rcv_fin = true;
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.ack_now = true;
+
+            match self.state {
+                SynRcvd | Established => self.state = CloseWait,
+                FinWait1 if self.fin_acked => self.enter_time_wait(),
+                FinWait1 => self.state = Closing,
+                FinWait2 => self.enter_time_wait(),
+                _ => (),
+            }
+        }
+
+        is_established
+    }
+
+    /// RFC 9293 3.10.7.4 table of segment acceptability
+    fn is_acceptable(&self, seg: &TCPSeg) -> bool {
+        let wnd = self.adv_wnd();
+        let end = seg.seq.wrapping_add(seg.len()).wrapping_sub(1);
+        let in_wnd = |seq: u32| {
+            seq_geq(seq, self.rcv_nxt)
+                && seq_lt(seq, self.rcv_nxt.wrapping_add(wnd))
+        };
+
+        match (seg.len(), wnd) {
+            (0, 0) => seg.seq == self.rcv_nxt,
+            (0, _) => in_wnd(seg.seq),
+            (_, 0) => false,
+            _ => in_wnd(seg.seq) || in_wnd(end),
+        }
+    }
+
+    fn ack_input(&mut self, seg: &TCPSeg) {
+        let now = clock_now();
+        let ack = seg.ack;
+        let acked_seq = ack.wrapping_sub(self.snd_una);
+        let mut acked = acked_seq as usize;
+
+        if !self.syn_acked {
+            self.syn_acked = true;
+            acked -= 1;
+        }
+
+        let n = acked.min(self.sndbuf.len());
+
+        self.sndbuf.drain(..n);
+
+        if acked > n && self.fin_queued {
+            self.fin_acked = true;
+        }
+
+        self.snd_una = ack;
+
+        if seq_lt(self.snd_nxt, ack) {
+            self.snd_nxt = ack;
+        }
+
+        if seq_lt(self.high_rxt, ack) {
+            self.high_rxt = ack;
+        }
+
+        self.sacked.retain_mut(|(start, end)| {
+            if seq_lt(*start, ack) {
+                *start = ack;
+            }
+
+            seq_lt(*start, *end)
+        });
+
+        /* RTT measurement, timestamps are free from Karn's algorithm
+         * (RFC 7323 4.1) */
+
+        if let Some((_, tsecr)) = seg.opts.ts
+            && self.ts_ok
+            && tsecr != 0
+        {
+            let r = self.ts_now().wrapping_sub(tsecr);
+
+            self.rtt_seq = None;
+            self.rtt_update(Duration::from_millis(r as u64));
+        }
+        else if let Some((seq, sent)) = self.rtt_seq
+            && seq_geq(ack, seq)
+        {
+            self.rtt_seq = None;
+            self.rtt_update(now - sent);
+        }
+
+        self.bytes_acked += acked_seq as u64;
+        self.rtx_count = 0;
+        self.soft_err = None;
+        self.rtx_deadline = if self.snd_una == self.snd_max {
+            None
+        }
+        else {
+            Some(now + self.rto)
+        };
+        self.una_stamp = self.rtx_deadline.map(|_| now);
+
+        self.cong_ack(acked_seq, now);
+    }
+
+    /// RFC 6298 2.2 and 2.3
+    fn rtt_update(&mut self, r: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(r)) / 4;
+                self.srtt = Some((srtt * 7 + r) / 8);
+            }
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+        }
+
+        self.rto = (self.srtt.unwrap()
+            + (self.rttvar * 4).max(TCP_TMR_INTERVAL))
+        .clamp(TCP_RTO_MIN, TCP_RTO_MAX);
+    }
+
+    /// RFC 5681 2 definition of duplicate acknowledgment
+    fn is_dupack(&self, seg: &TCPSeg) -> bool {
+        seg.ack == self.snd_una
+            && self.snd_una != self.snd_max
+            && seg.data.is_empty()
+            && !seg.has(TCP_SYN | TCP_FIN)
+            && self.seg_wnd(seg) == self.snd_wnd
+    }
+
+    /// Fast retransmit and fast recovery (RFC 6582 3.2, RFC 6675 5)
+    fn dupack_input(&mut self) {
+        let mss = self.mss as u32;
+
+        self.dupacks += 1;
+
+        if self.in_recovery {
+            // SACK recovery is clocked by pipe instead of inflation
+            if !self.sack_ok {
+                self.cwnd += mss;
+            }
+
+            return;
+        }
+
+        let is_lost = self.dupacks >= TCP_DUPACK_THRESH
+            || self.sack_ok && self.sack_lost_edge() != self.snd_una;
+
+        // don't enter recovery again for the same loss
+        if !is_lost || !seq_gt(self.snd_una, self.recover) {
+            return;
+        }
+
+        trace!("Fast retransmit {} -> {}", self.local(), self.remote());
+
+        self.ssthresh = self.cc.ssthresh(self.cwnd, self.flight(), mss);
+        self.cwnd = if self.sack_ok {
+            self.ssthresh
+        }
+        else {
+            self.ssthresh + TCP_DUPACK_THRESH * mss
+        };
+        self.recover = self.snd_max;
+        self.in_recovery = true;
+
+        self.rtx_head();
+    }
+
+    /// New data is acknowledged (RFC 6582 3.2)
+    fn cong_ack(&mut self, acked: u32, now: Instant) {
+        let mss = self.mss as u32;
+
+        if !self.in_recovery {
+            self.dupacks = 0;
+            self.cc
+                .cong_avoid(&mut self.cwnd, self.ssthresh, mss, acked, now);
+
+            return;
+        }
+
+        if seq_geq(self.snd_una, self.recover) {
+            /* full acknowledgment, deflate the window */
+
+            self.in_recovery = false;
+            self.dupacks = 0;
+            self.cwnd = if self.sack_ok {
+                self.ssthresh
+            }
+            else {
+                self.ssthresh.min(self.flight().max(mss) + mss)
+            };
+        }
+        else if !self.sack_ok {
+            /* partial acknowledgment */
+
+            self.rtx_head();
+            self.cwnd = self.cwnd.saturating_sub(acked).max(mss);
+
+            if acked >= mss {
+                self.cwnd += mss;
+            }
+        }
+    }
+
+    /// Retransmit the first unacknowledged segment
+    fn rtx_head(&mut self) {
+        let seq0 = self.snd_seq0();
+        let mut n = self.sndbuf.len().min(self.data_mss());
+
+        // don't resend SACKed data
+        if let Some((start, _)) = self.sacked.first() {
+            n = n.min(start.wrapping_sub(seq0) as usize);
+        }
+
+        self.rtt_seq = None;
+        self.total_retrans += 1;
+        SNMP_TCP.retrans_segs.inc();
+
+        if n > 0 {
+            let data: Vec<u8> = self.sndbuf.range(..n).copied().collect();
+
+            self.xmit(seq0, TCP_ACK, &data);
+            self.high_rxt = seq0.wrapping_add(n as u32);
+        }
+        else if self.fin_queued && !self.fin_acked {
+            self.xmit(seq0, TCP_FIN | TCP_ACK, &[]);
+        }
+    }
+
+    /// Merge valid SACK blocks into the scoreboard (RFC 2018)
+    pub(crate) fn sack_input(&mut self, blocks: &[(u32, u32)]) {
+        for &(start, end) in blocks {
+            // D-SACK or bogus
+            if !seq_lt(start, end)
+                || seq_leq(start, self.snd_una)
+                || seq_gt(end, self.snd_max)
+            {
+                continue;
+            }
+
+            let (mut start, mut end) = (start, end);
+
+            self.sacked.retain(|&(s, e)| {
+                if seq_gt(s, end) || seq_lt(e, start) {
+                    return true;
+                }
+
+                if seq_lt(s, start) {
+                    start = s;
+                }
+
+                if seq_gt(e, end) {
+                    end = e;
+                }
+
+                false
+            });
+
+            let pos = self
+                .sacked
+                .iter()
+                .position(|&(s, _)| seq_gt(s, start))
+                .unwrap_or(self.sacked.len());
+
+            self.sacked.insert(pos, (start, end));
+        }
+    }
+
+    pub fn sacked_bytes(&self) -> u32 {
+        self.sacked
+            .iter()
+            .map(|(start, end)| end.wrapping_sub(*start))
+            .sum()
+    }
+
+    /// Holes below it are lost, for more than (DupThresh - 1) * SMSS bytes
+    /// above are SACKed (RFC 6675 4 IsLost)
+    pub(crate) fn sack_lost_edge(&self) -> u32 {
+        let thresh = (TCP_DUPACK_THRESH - 1) * self.mss as u32;
+        let mut above = 0;
+
+        for &(start, end) in self.sacked.iter().rev() {
+            above += end.wrapping_sub(start);
+
+            if above > thresh {
+                return start;
+            }
+        }
+
+        self.snd_una
+    }
+
+    /// RFC 6675 5 SetPipe, bytes thought to be in the network
+    pub(crate) fn sack_pipe(&self) -> u32 {
+        let edge = self.sack_lost_edge();
+        let high = if seq_gt(self.snd_nxt, self.snd_max) {
+            self.snd_nxt
+        }
+        else {
+            self.snd_max
+        };
+
+        let mut pipe = 0;
+        let mut lo = self.snd_una;
+
+        for &(start, end) in self.sacked.iter().chain([(high, high)].iter()) {
+            /* hole [lo, start) */
+
+            let lost_end = if seq_lt(edge, start) { edge } else { start };
+            let rxt_end = if seq_lt(self.high_rxt, start) {
+                self.high_rxt
+            }
+            else {
+                start
+            };
+
+            pipe += start.wrapping_sub(lo);
+            pipe -= seq_span(lo, lost_end);
+            pipe += seq_span(lo, rxt_end);
+
+            lo = end;
+        }
+
+        pipe
+    }
+
+    /// RFC 6675 5 NextSeg rule 1, the first lost bytes not retransmitted
+    pub(crate) fn sack_next_seg(&self) -> Option<(u32, usize)> {
+        let edge = self.sack_lost_edge();
+        let mut lo = self.snd_una;
+
+        for &(start, end) in &self.sacked {
+            let from = if seq_gt(self.high_rxt, lo) {
+                self.high_rxt
+            }
+            else {
+                lo
+            };
+
+            if seq_lt(from, start) && seq_lt(from, edge) {
+                let n = start.wrapping_sub(from) as usize;
+
+                return Some((from, n.min(self.data_mss())));
+            }
+
+            lo = end;
+        }
+
+        None
+    }
+
+    /// Retransmit lost segments in SACK recovery, returns if any is sent
+    fn sack_output(&mut self) -> bool {
+        let mss = self.mss as u32;
+        let seq0 = self.snd_seq0();
+        let mut is_sent = false;
+
+        while self.cwnd.saturating_sub(self.sack_pipe()) >= mss {
+            let Some((seq, n)) = self.sack_next_seg()
+            else {
+                break;
+            };
+
+            let off = seq.wrapping_sub(seq0) as usize;
+            let data: Vec<u8> =
+                self.sndbuf.range(off..off + n).copied().collect();
+
+            self.xmit(seq, TCP_ACK, &data);
+            self.high_rxt = seq.wrapping_add(n as u32);
+            self.total_retrans += 1;
+            SNMP_TCP.retrans_segs.inc();
+            is_sent = true;
+        }
+
+        is_sent
+    }
+
+    /// RFC 6928, one segment if SYN is lost (RFC 5681 3.1)
+    fn init_cwnd(&mut self) {
+        let mss = self.mss as u32;
+
+        self.cwnd = if self.total_retrans > 0 {
+            mss
+        }
+        else {
+            (TCP_INIT_CWND * mss).min((2 * mss).max(14600))
+        };
+    }
+
+    /// Window of SYN is never scaled (RFC 7323 2.2)
+    fn seg_wnd(&self, seg: &TCPSeg) -> u32 {
+        if seg.has(TCP_SYN) {
+            seg.wnd
+        }
+        else {
+            seg.wnd << self.snd_wscale
+        }
+    }
+
+    fn update_wnd(&mut self, seg: &TCPSeg) {
+        self.snd_wnd = self.seg_wnd(seg);
+        self.snd_wl1 = seg.seq;
+        self.snd_wl2 = seg.ack;
+    }
+
+    fn data_input(&mut self, seg: &TCPSeg) {
+        let rcv_nxt = self.rcv_nxt;
+        let mut seq = seg.seq;
+        let mut data = seg.data;
+
+        // trim the part already received
+        if seq_lt(seq, self.rcv_nxt) {
+            let off = self.rcv_nxt.wrapping_sub(seq) as usize;
+
+            data = &data[off.min(data.len())..];
+            seq = self.rcv_nxt;
+        }
+
+        let data = &data[..data.len().min(self.rcv_wnd() as usize)];
+
+        // duplicate, out-of-order or gap filling data is acknowledged
+        // immediately (RFC 5681 4.2)
+        if data.is_empty() || seq != self.rcv_nxt || !self.ooo.is_empty() {
+            self.ack_now = true;
+        }
+
+        if data.is_empty() {
+            return;
+        }
+
+        if seq != self.rcv_nxt {
+            if !self.ooo.iter().any(|(oseq, _)| *oseq == seq) {
+                self.ooo.push((seq, data.to_vec()));
+            }
+
+            self.ooo_last = Some(seq);
+
+            return;
+        }
+
+        self.rcvbuf.extend(data);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(data.len() as u32);
+
+        self.ooo_flush();
+
+        /* delayed ACK, at least every second full-sized segment */
+
+        self.rcv_seg_max = self.rcv_seg_max.max(data.len());
+
+        if data.len() == self.rcv_seg_max {
+            self.delack_segs += 1;
+        }
+
+        if self.quickack || self.delack_segs >= 2 {
+            self.ack_now = true;
+        }
+        else if self.delack_deadline.is_none() {
+            self.delack_deadline = Some(clock_now() + TCP_DELACK_TIMEOUT);
+        }
+
+        self.bytes_received += self.rcv_nxt.wrapping_sub(rcv_nxt) as u64;
+    }
+
+    /// Move the out-of-order segments become in-order into `rcvbuf`
+    fn ooo_flush(&mut self) {
+        loop {
+            let rcv_nxt = self.rcv_nxt;
+
+            self.ooo.retain(|(seq, data)| {
+                seq_gt(seq.wrapping_add(data.len() as u32), rcv_nxt)
+            });
+
+            let Some(pos) =
+                self.ooo.iter().position(|(seq, _)| seq_leq(*seq, rcv_nxt))
+            else {
+                break;
+            };
+
+            let (seq, data) = self.ooo.swap_remove(pos);
+            let off = rcv_nxt.wrapping_sub(seq) as usize;
+            let n = (data.len() - off).min(self.rcv_wnd() as usize);
+
+            self.rcvbuf.extend(&data[off..off + n]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+        }
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = TCPState::TimeWait;
+        self.rtx_deadline = None;
+        self.tw_deadline = Some(clock_now() + TCP_MSL * 2);
+    }
+
+    fn mss_from(&self, opts: &TCPOpts) -> u16 {
+        opts.mss
+            .unwrap_or(TCP_DEFAULT_MSS)
+            .min(self.rcv_mss)
+            .max(TCP_MIN_MSS)
+    }
+
+    /// Options of SYN from peer, those not offered are disabled
+    fn syn_opts_input(&mut self, opts: &TCPOpts) {
+        self.mss = self.mss_from(opts);
+
+        match opts.wscale {
+            Some(wscale) if self.wscale_ok => {
+                self.snd_wscale = wscale.min(TCP_MAX_WSCALE);
+            }
+            _ => {
+                self.wscale_ok = false;
+                self.snd_wscale = 0;
+                self.rcv_wscale = 0;
+            }
+        }
+
+        self.sack_ok &= opts.sack_perm;
+
+        match opts.ts {
+            Some((tsval, _)) if self.ts_ok => {
+                self.ts_recent = tsval;
+                self.ts_recent_stamp = clock_now();
+            }
+            _ => self.ts_ok = false,
+        }
+    }
+
+    /// Timestamp clock of milliseconds (RFC 7323 5.4)
+    fn ts_now(&self) -> u32 {
+        (clock_now()
+            .duration_since(*TCP_TS_EPOCH.read().unwrap())
+            .as_millis() as u32)
+            .wrapping_add(self.ts_off)
+    }
+
+    /// Blocks of out-of-order data, the latest one first (RFC 2018 4)
+    fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        let mut segs: Vec<(u32, u32)> = self
+            .ooo
+            .iter()
+            .map(|(seq, data)| (*seq, seq.wrapping_add(data.len() as u32)))
+            .collect();
+
+        segs.sort_by_key(|(seq, _)| seq.wrapping_sub(self.rcv_nxt));
+
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+
+        for (start, end) in segs {
+            match blocks.last_mut() {
+                Some((_, last)) if seq_leq(start, *last) => {
+                    if seq_gt(end, *last) {
+                        *last = end;
+                    }
+                }
+                _ => blocks.push((start, end)),
+            }
+        }
+
+        if let Some(seq) = self.ooo_last
+            && let Some(pos) = blocks.iter().position(|&(start, end)| {
+                seq_geq(seq, start) && seq_lt(seq, end)
+            })
+        {
+            let block = blocks.remove(pos);
+
+            blocks.insert(0, block);
+        }
+
+        // 40 bytes of option space
+        blocks.truncate(if self.ts_ok { 3 } else { 4 });
+
+        blocks
+    }
+
+    /// Options of segment with `flags`
+    pub(crate) fn opts(&self, flags: u8) -> Vec<u8> {
+        let mut opts = Vec::new();
+
+        if flags & TCP_SYN != 0 {
+            opts.extend_from_slice(&[TCPOPT_MSS, 4]);
+            opts.extend_from_slice(&self.rcv_mss.to_be_bytes());
+
+            if self.wscale_ok {
+                opts.extend_from_slice(&[
+                    TCPOPT_NOP,
+                    TCPOPT_WSCALE,
+                    3,
+                    self.rcv_wscale,
+                ]);
+            }
+
+            if self.sack_ok && !self.ts_ok {
+                opts.extend_from_slice(&[TCPOPT_NOP, TCPOPT_NOP]);
+            }
+
+            if self.sack_ok {
+                opts.extend_from_slice(&[TCPOPT_SACK_PERM, 2]);
+            }
+        }
+
+        if self.ts_ok {
+            let tsecr = if flags & TCP_ACK != 0 {
+                self.ts_recent
+            }
+            else {
+                0
+            };
+
+            if flags & TCP_SYN == 0 || !self.sack_ok {
+                opts.extend_from_slice(&[TCPOPT_NOP, TCPOPT_NOP]);
+            }
+
+            opts.extend_from_slice(&[TCPOPT_TS, 10]);
+            opts.extend_from_slice(&self.ts_now().to_be_bytes());
+            opts.extend_from_slice(&tsecr.to_be_bytes());
+        }
+
+        if self.sack_ok && flags & TCP_SYN == 0 && !self.ooo.is_empty() {
+            let blocks = self.sack_blocks();
+
+            opts.extend_from_slice(&[
+                TCPOPT_NOP,
+                TCPOPT_NOP,
+                TCPOPT_SACK,
+                2 + 8 * blocks.len() as u8,
+            ]);
+
+            for (start, end) in blocks {
+                opts.extend_from_slice(&start.to_be_bytes());
+                opts.extend_from_slice(&end.to_be_bytes());
+            }
+        }
+
+        opts
+    }
+
+    /// Payload size of one segment, MSS doesn't count options (RFC 6691)
+    fn data_mss(&self) -> usize {
+        (self.mss as usize).saturating_sub(self.opts(TCP_ACK).len())
+    }
+
+    /// Send what's allowed, SYN, data, FIN or a pure ACK
+    pub fn output(&mut self) {
+        use TCPState::*;
+
+        let is_sent = match self.state {
+            Closed | Listen => return,
+            SynSent | SynRcvd if self.snd_nxt == self.iss => {
+                let flags = if self.state == SynSent {
+                    TCP_SYN
+                }
+                else {
+                    TCP_SYN | TCP_ACK
+                };
+
+                self.xmit(self.iss, flags, &[]);
+                self.snd_nxt = self.iss.wrapping_add(1);
+
+                if self.rtx_count == 0 {
+                    self.rtt_seq = Some((self.snd_nxt, clock_now()));
+                }
+
+                true
+            }
+            SynSent | SynRcvd | TimeWait => false,
+            _ => self.output_data(),
+        };
+
+        if seq_gt(self.snd_nxt, self.snd_max) {
+            self.snd_max = self.snd_nxt;
+        }
+
+        // every segment sent carries ACK
+        if (self.ack_now || is_sent) && self.state.is_synchronized() {
+            if !is_sent {
+                self.xmit(self.snd_nxt, TCP_ACK, &[]);
+            }
+
+            self.delack_segs = 0;
+            self.delack_deadline = None;
+        }
+
+        self.ack_now = false;
+
+        let has_unsent = self.sndbuf.len()
+            > self.snd_nxt.wrapping_sub(self.snd_seq0()) as usize
+            || self.fin_queued && !self.fin_acked;
+
+        if self.rtx_deadline.is_none()
+            && (self.snd_una != self.snd_max || has_unsent)
+        {
+            self.rtx_deadline = Some(clock_now() + self.rto);
+        }
+
+        if self.una_stamp.is_none() && self.snd_una != self.snd_max {
+            self.una_stamp = Some(clock_now());
+        }
+    }
+
+    /// Returns if any segment is sent
+    fn output_data(&mut self) -> bool {
+        let seq0 = self.snd_seq0();
+        let is_sack_recovery = self.in_recovery && self.sack_ok;
+        let mut is_sent = is_sack_recovery && self.sack_output();
+
+        loop {
+            let off = self.snd_nxt.wrapping_sub(seq0) as usize;
+            let unsent = self.sndbuf.len().saturating_sub(off);
+            let usable = if is_sack_recovery {
+                let wnd =
+                    self.snd_una
+                        .wrapping_add(self.snd_wnd)
+                        .wrapping_sub(self.snd_nxt) as i32;
+
+                wnd.min(self.cwnd as i32 - self.sack_pipe() as i32)
+            }
+            else {
+                self.snd_una
+                    .wrapping_add(self.snd_wnd.min(self.cwnd))
+                    .wrapping_sub(self.snd_nxt) as i32
+            };
+
+            if unsent == 0 {
+                let fin_seq = seq0.wrapping_add(self.sndbuf.len() as u32);
+
+                if self.fin_queued
+                    && !self.fin_acked
+                    && self.snd_nxt == fin_seq
+                {
+                    self.xmit(fin_seq, TCP_FIN | TCP_ACK, &[]);
+                    self.snd_nxt = fin_seq.wrapping_add(1);
+                    is_sent = true;
+                }
+
+                break;
+            }
+
+            if usable <= 0 {
+                break;
+            }
+
+            let mss = self.data_mss();
+            let n = unsent.min(mss).min(usable as usize);
+
+            // Nagle algorithm (RFC 9293 3.7.4), retransmission and the
+            // last data before FIN aren't delayed
+            if n < mss
+                && !self.nodelay
+                && !self.fin_queued
+                && self.snd_nxt != self.snd_una
+                && seq_geq(self.snd_nxt, self.snd_max)
+            {
+                break;
+            }
+
+            let data: Vec<u8> =
+                self.sndbuf.range(off..off + n).copied().collect();
+            let flags = if n == unsent {
+                TCP_ACK | TCP_PSH
+            }
+            else {
+                TCP_ACK
+            };
+
+            self.xmit(self.snd_nxt, flags, &data);
+
+            // only new data is timed
+            if self.rtt_seq.is_none() && seq_geq(self.snd_nxt, self.snd_max) {
+                self.rtt_seq =
+                    Some((self.snd_nxt.wrapping_add(n as u32), clock_now()));
+            }
+
+            self.snd_nxt = self.snd_nxt.wrapping_add(n as u32);
+            is_sent = true;
+        }
+
+        is_sent
+    }
+
+    /// Retransmission timeout or zero window probe
+    fn rtx_timeout(&mut self, now: Instant) {
+        let limit = if self.state.is_connecting() {
+            TCP_SYNCNT.load(Ordering::Relaxed)
+        }
+        else {
+            TCP_RETRIES.load(Ordering::Relaxed)
+        };
+
+        let is_probe = self.snd_wnd == 0 && self.snd_una == self.snd_max;
+
+        let is_expired = match (self.user_timeout, self.una_stamp) {
+            (Some(timeout), Some(stamp)) => {
+                now.duration_since(stamp) >= timeout
+            }
+            (Some(_), None) => false,
+            (None, _) => self.rtx_count >= limit,
+        };
+
+        if !is_probe {
+            if is_expired {
+                trace!("Connection timed out {}", self.remote());
+
+                let err = self.soft_err.unwrap_or(ETIMEDOUT);
+
+                self.reset(Some(err));
+                return;
+            }
+
+            self.rtx_count += 1;
+            self.total_retrans += 1;
+            SNMP_TCP.retrans_segs.inc();
+
+            /* RFC 5681 3.1, ssthresh is kept for the same segment */
+
+            let mss = self.mss as u32;
+
+            if self.rtx_count == 1 {
+                self.ssthresh =
+                    self.cc.ssthresh(self.cwnd, self.flight(), mss);
+            }
+
+            self.cwnd = mss;
+            self.dupacks = 0;
+            self.in_recovery = false;
+            self.recover = self.snd_max;
+            self.rtt_seq = None;
+
+            // SACK is advisory, all are retransmitted (RFC 2018 8)
+            self.sacked.clear();
+            self.high_rxt = self.snd_una;
+        }
+
+        // exponential backoff (RFC 6298 5.5)
+        self.rto = (self.rto * 2).min(TCP_RTO_MAX);
+
+        trace!(
+            "Retransmit {} -> {} #{}",
+            self.local(),
+            self.remote(),
+            self.rtx_count
+        );
+
+        self.rtx_deadline = None;
+        self.snd_nxt = self.snd_una;
+
+        if is_probe {
+            // window probe with one byte beyond the window
+            self.snd_wnd = 1;
+            self.output();
+            self.snd_wnd = 0;
+        }
+        else {
+            self.output();
+        }
+    }
+
+    fn tmr(&mut self, now: Instant) {
+        if let Some(deadline) = self.tw_deadline
+            && now >= deadline
+        {
+            trace!("{} {} expired", self.local(), self.state);
+
+            self.tw_deadline = None;
+            self.state = TCPState::Closed;
+
+            return;
+        }
+
+        if let Some(deadline) = self.rtx_deadline
+            && now >= deadline
+        {
+            self.rtx_timeout(now);
+        }
+
+        if let Some(deadline) = self.delack_deadline
+            && now >= deadline
+        {
+            self.delack_deadline = None;
+            self.ack_now = true;
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            self.keepalive_tmr(keepalive, now);
+        }
+    }
+
+    /// Probe with sequence `snd_una - 1` to elicit an ACK
+    fn keepalive_tmr(&mut self, keepalive: TCPKeepalive, now: Instant) {
+        if !matches!(
+            self.state,
+            TCPState::Established | TCPState::CloseWait | TCPState::FinWait2
+        ) || self.snd_una != self.snd_max
+        {
+            return;
+        }
+
+        let idle = now.duration_since(self.rcv_stamp);
+
+        if idle < keepalive.idle + keepalive.interval * self.keep_probes {
+            return;
+        }
+
+        if self.keep_probes >= keepalive.count {
+            trace!("Keepalive timed out {}", self.remote());
+
+            self.reset(Some(ETIMEDOUT));
+            return;
+        }
+
+        self.keep_probes += 1;
+        self.xmit(self.snd_una.wrapping_sub(1), TCP_ACK, &[]);
+    }
+
+    fn xmit(&self, seq: u32, flags: u8, data: &[u8]) {
+        let opts = self.opts(flags);
+        let ack = if flags & TCP_ACK != 0 {
+            self.rcv_nxt
+        }
+        else {
+            0
+        };
+        let wnd = if flags & TCP_SYN != 0 {
+            self.rcv_wnd()
+        }
+        else {
+            self.rcv_wnd() >> self.rcv_wscale
+        }
+        .min(u16::MAX as u32) as u16;
+
+        tcp_xmit(
+            self.local(),
+            self.remote(),
+            seq,
+            ack,
+            flags,
+            wnd,
+            &opts,
+            data,
+        );
+    }
+}
+
+impl Default for TCPKeepalive {
+    fn default() -> Self {
+        Self {
+            idle: TCP_KEEPIDLE,
+            interval: TCP_KEEPINTVL,
+            count: TCP_KEEPCNT,
+        }
+    }
+}
+
+impl TCPPcb {
+    pub fn new(tcb: TCB) -> Self {
+        Self {
+            tcb: Mutex::new(tcb),
+            cond: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+            syn_qlen: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn register_waker(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+
+        if !wakers.iter().any(|old| old.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    /// Wake both of blocking and async users, call it with `tcb` locked
+    pub fn wake(&self) {
+        self.cond.notify_all();
+
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl NetDevice {
+    /// Return the reason if the segment is dropped
+    pub fn tcp_input(&self, pkt: &[u8]) -> anyhow::Result<Option<String>> {
+        let (iph, seg) = ip_split(pkt);
+        let src: Ipv4Addr = iph.src.into();
+        let dst: Ipv4Addr = iph.dst.into();
+
+        if seg.len() < size_of::<TCP>() {
+            trace!("Uncomplete TCP header from {src}");
+            SNMP_TCP.in_errs.inc();
+            return Ok(Some("uncomplete TCP header".to_owned()));
+        }
+
+        let th = from_raw_slice::<TCP>(seg);
+        let doff = th.doff();
+
+        if doff < size_of::<TCP>() || doff > seg.len() {
+            trace!("Malformed TCP data offset {doff} from {src}");
+            SNMP_TCP.in_errs.inc();
+            return Ok(Some(format!("malformed TCP data offset {doff}")));
+        }
+
+        if ip_pseudo_cksum(src, dst, ProtocolKind::TCP, seg) != 0 {
+            trace!("TCP verify cksum failed from {src}");
+            SNMP_TCP.in_errs.inc();
+            SNMP_TCP.in_csum_errors.inc();
+            return Ok(Some("bad TCP cksum".to_owned()));
+        }
+
+        SNMP_TCP.in_segs.inc();
+
+        if dst.is_broadcast() || dst.is_multicast() || dst == self.broadcast()
+        {
+            return Ok(Some(format!("TCP to {dst}")));
+        }
+
+        let seg = TCPSeg {
+            seq: th.seq(),
+            ack: th.ack(),
+            flags: th.flags(),
+            wnd: th.wnd() as u32,
+            opts: tcp_parse_opts(&seg[size_of::<TCP>()..doff]),
+            data: &seg[doff..],
+        };
+
+        let local = SocketAddrV4::new(dst, th.dst_port());
+        let remote = SocketAddrV4::new(src, th.src_port());
+
+        let Some(pcb) = tcp_lookup(local, remote)
+        else {
+            trace!("No TCP socket on {local} for {remote}");
+
+            tcp_rst_reply(local, remote, &seg);
+
+            return Ok(Some(format!("no TCP socket on {local}")));
+        };
+
+        let mut tcb = pcb.tcb.lock().unwrap();
+
+        match tcb.state {
+            TCPState::Closed => {
+                drop(tcb);
+                tcp_rst_reply(local, remote, &seg);
+
+                return Ok(Some(format!("TCP socket on {local} is closed")));
+            }
+            TCPState::Listen => {
+                return Ok(
+                    self.tcp_listen_input(&pcb, &mut tcb, local, remote, &seg)
+                );
+            }
+            TCPState::SynSent => {
+                tcb.syn_sent_input(&seg);
+                tcp_update(&pcb, &mut tcb);
+            }
+            _ => {
+                if tcb.synchronized_input(&seg) {
+                    tcp_accept_ready(&pcb, &tcb);
+                }
+
+                tcp_update(&pcb, &mut tcb);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// RFC 9293 3.10.7.2, return the reason if the segment is dropped
+    fn tcp_listen_input(
+        &self,
+        pcb: &Arc<TCPPcb>,
+        tcb: &mut TCB,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        seg: &TCPSeg,
+    ) -> Option<String> {
+        if seg.has(TCP_RST) {
+            return Some("RST to listener".to_owned());
+        }
+
+        if seg.has(TCP_ACK) {
+            if seg.has(TCP_SYN)
+                || !self.tcp_cookie_input(pcb, tcb, local, remote, seg)
+            {
+                tcp_rst_reply(local, remote, seg);
+
+                return Some("ACK to listener".to_owned());
+            }
+
+            return None;
+        }
+
+        if !seg.has(TCP_SYN) {
+            return Some("no SYN to listener".to_owned());
+        }
+
+        if tcb.accept_q.len() >= tcb.backlog {
+            trace!("Accept queue of {} is full, drop SYN", tcb.local());
+
+            tcb.listen_stats.overflows += 1;
+            return Some(format!("accept queue of {} is full", tcb.local()));
+        }
+
+        /* SYN flood, reply stateless SYN-ACK (RFC 4987 3.6) */
+
+        if pcb.syn_qlen.load(Ordering::Relaxed) >= tcb.backlog {
+            let mss = seg
+                .opts
+                .mss
+                .unwrap_or(TCP_DEFAULT_MSS)
+                .min(self.mtu - 40)
+                .max(TCP_MIN_MSS);
+            let clock = CLOCK.read().unwrap().clone();
+            let (cookie, mss) =
+                syncookie_isn(local, remote, seg.seq, mss, &*clock);
+
+            let mut opts = vec![TCPOPT_MSS, 4];
+
+            opts.extend_from_slice(&mss.to_be_bytes());
+
+            trace!("SYN queue of {} is full, send cookie", tcb.local());
+
+            tcb.syn_overflow = Some(clock_now());
+            tcb.listen_stats.cookies_sent += 1;
+
+            tcp_xmit(
+                local,
+                remote,
+                cookie,
+                seg.seq.wrapping_add(1),
+                TCP_SYN | TCP_ACK,
+                TCP_RMEM.load(Ordering::Relaxed).min(u16::MAX as usize) as u16,
+                &opts,
+                &[],
+            );
+
+            return None;
+        }
+
+        let key = TCPKey {
+            local,
+            remote: Some(remote),
+        };
+
+        let mut child = TCB::new(key, TCPState::SynRcvd);
+
+        SNMP_TCP.passive_opens.inc();
+
+        child.rcv_mss = self.mtu - 40;
+        child.syn_opts_input(&seg.opts);
+        child.irs = seg.seq;
+        child.rcv_nxt = seg.seq.wrapping_add(1);
+        child.snd_wnd = seg.wnd;
+        child.snd_wl1 = seg.seq;
+        child.parent = Arc::downgrade(pcb);
+        child.in_syn_q = true;
+
+        trace!("SYN {remote} -> {local}");
+
+        child.output();
+
+        pcb.syn_qlen.fetch_add(1, Ordering::Relaxed);
+
+        TCP_TBL
+            .write()
+            .unwrap()
+            .insert(key, Arc::new(TCPPcb::new(child)));
+
+        None
+    }
+
+    /// ACK of a SYN cookie establishes the connection at once, returns if
+    /// the cookie is valid
+    fn tcp_cookie_input(
+        &self,
+        pcb: &Arc<TCPPcb>,
+        tcb: &mut TCB,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        seg: &TCPSeg,
+    ) -> bool {
+        if tcb.syn_overflow.is_none_or(|stamp| {
+            clock_now().duration_since(stamp) >= SYNCOOKIE_VALID
+        }) {
+            return false;
+        }
+
+        let irs = seg.seq.wrapping_sub(1);
+        let iss = seg.ack.wrapping_sub(1);
+
+        let clock = CLOCK.read().unwrap().clone();
+
+        let Some(mss) = syncookie_check(local, remote, irs, iss, &*clock)
+        else {
+            tcb.listen_stats.cookies_failed += 1;
+            return false;
+        };
+
+        tcb.listen_stats.cookies_recv += 1;
+
+        if tcb.accept_q.len() >= tcb.backlog {
+            tcb.listen_stats.overflows += 1;
+            return true;
+        }
+
+        let key = TCPKey {
+            local,
+            remote: Some(remote),
+        };
+
+        let mut child = TCB::new(key, TCPState::Established);
+
+        SNMP_TCP.passive_opens.inc();
+
+        /* only MSS is kept in the cookie */
+
+        child.rcv_mss = self.mtu - 40;
+        child.mss = mss;
+        child.wscale_ok = false;
+        child.sack_ok = false;
+        child.ts_ok = false;
+        child.rcv_wscale = 0;
+
+        child.iss = iss;
+        child.snd_una = seg.ack;
+        child.snd_nxt = seg.ack;
+        child.snd_max = seg.ack;
+        child.recover = iss;
+        child.high_rxt = seg.ack;
+        child.syn_acked = true;
+        child.irs = irs;
+        child.rcv_nxt = seg.seq;
+        child.parent = Arc::downgrade(pcb);
+        child.update_wnd(seg);
+        child.init_cwnd();
+
+        trace!("SYN cookie {remote} -> {local}");
+
+        child.synchronized_input(seg);
+        child.output();
+
+        let child = Arc::new(TCPPcb::new(child));
+
+        TCP_TBL.write().unwrap().insert(key, child.clone());
+
+        tcb.accept_q.push_back((child, remote));
+        pcb.wake();
+
+        true
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_leq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+pub fn seq_gt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+pub fn seq_geq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) >= 0
+}
+
+/// Length of `[a, b)`, zero if it's empty
+pub fn seq_span(a: u32, b: u32) -> u32 {
+    if seq_lt(a, b) { b.wrapping_sub(a) } else { 0 }
+}
+
+/// ISN = M + F(localip, localport, remoteip, remoteport, secretkey), M is
+/// a 4 microseconds timer (RFC 6528 3)
+pub fn tcp_isn(local: SocketAddrV4, remote: SocketAddrV4) -> u32 {
+    tcp_isn_at(local, remote, clock_wall())
+}
+
+fn tcp_isn_at(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    wall: SystemTime,
+) -> u32 {
+    let f = TCP_ISN_SECRET.read().unwrap().hash_one((local, remote));
+    let m = wall
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+        / 4;
+
+    (m as u32).wrapping_add(f as u32)
+}
+
+pub fn tcp_parse_opts(mut buf: &[u8]) -> TCPOpts {
+    let mut opts = TCPOpts::default();
+
+    while let [kind, rem @ ..] = buf {
+        match *kind {
+            TCPOPT_EOL => break,
+            TCPOPT_NOP => {
+                buf = rem;
+                continue;
+            }
+            _ => (),
+        }
+
+        let Some(&len) = rem.first()
+        else {
+            break;
+        };
+
+        if len < 2 || len as usize > buf.len() {
+            break;
+        }
+
+        let val = &buf[2..len as usize];
+
+        match (*kind, val.len()) {
+            (TCPOPT_MSS, 2) => {
+                opts.mss = Some(u16::from_be_bytes([val[0], val[1]]));
+            }
+            (TCPOPT_WSCALE, 1) => opts.wscale = Some(val[0]),
+            (TCPOPT_SACK_PERM, 0) => opts.sack_perm = true,
+            (TCPOPT_TS, 8) => {
+                opts.ts = Some((
+                    u32::from_be_bytes(val[..4].try_into().unwrap()),
+                    u32::from_be_bytes(val[4..].try_into().unwrap()),
+                ));
+            }
+            (TCPOPT_SACK, n) if n % 8 == 0 => {
+                opts.sack = val
+                    .chunks_exact(8)
+                    .map(|block| {
+                        (
+                            u32::from_be_bytes(block[..4].try_into().unwrap()),
+                            u32::from_be_bytes(block[4..].try_into().unwrap()),
+                        )
+                    })
+                    .collect();
+            }
+            _ => (),
+        }
+
+        buf = &buf[len as usize..];
+    }
+
+    opts
+}
+
+/// Build and send a segment, `opts` is padded to 4 bytes
+#[allow(clippy::too_many_arguments)]
+pub fn tcp_xmit(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    wnd: u16,
+    opts: &[u8],
+    data: &[u8],
+) {
+    let optlen = opts.len().next_multiple_of(4);
+    let doff = size_of::<TCP>() + optlen;
+
+    let mut th = TCP {
+        src: local.port().to_be(),
+        dst: remote.port().to_be(),
+        seq: seq.to_be(),
+        ack: ack.to_be(),
+        off_flags: ((doff as u16 / 4) << 12 | flags as u16).to_be(),
+        wnd: wnd.to_be(),
+        cksum: 0,
+        urp: 0,
+    };
+
+    let mut seg = Vec::with_capacity(doff + data.len());
+
+    seg.extend_from_slice(as_raw_slice(&th));
+    seg.extend_from_slice(opts);
+    seg.resize(doff, TCPOPT_EOL);
+    seg.extend_from_slice(data);
+
+    th.cksum =
+        ip_pseudo_cksum(*local.ip(), *remote.ip(), ProtocolKind::TCP, &seg);
+    seg[..size_of::<TCP>()].copy_from_slice(as_raw_slice(&th));
+
+    SNMP_TCP.out_segs.inc();
+
+    if flags & TCP_RST != 0 {
+        SNMP_TCP.out_rsts.inc();
+    }
+
+    if let Err(err) =
+        ip_output(&seg, ProtocolKind::TCP, Some(*local.ip()), *remote.ip())
+    {
+        trace!("TCP output to {remote} failed: {err}");
+    }
+}
+
+/// RST with `seq`, it acknowledges `ack` if there is
+pub fn tcp_rst(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    seq: u32,
+    ack: Option<u32>,
+) {
+    match ack {
+        Some(ack) => {
+            tcp_xmit(local, remote, seq, ack, TCP_RST | TCP_ACK, 0, &[], &[])
+        }
+        None => tcp_xmit(local, remote, seq, 0, TCP_RST, 0, &[], &[]),
+    }
+}
+
+/// Reset for segment doesn't belong to any connection (RFC 9293 3.10.7.1)
+fn tcp_rst_reply(local: SocketAddrV4, remote: SocketAddrV4, seg: &TCPSeg) {
+    if seg.has(TCP_RST) {
+        return;
+    }
+
+    if seg.has(TCP_ACK) {
+        tcp_rst(local, remote, seg.ack, None);
+    }
+    else {
+        tcp_rst(local, remote, 0, Some(seg.seq.wrapping_add(seg.len())));
+    }
+}
+
+/// Connection first, then listener bound to the address and wildcard
+pub fn tcp_lookup(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+) -> Option<Arc<TCPPcb>> {
+    let tbl = TCP_TBL.read().unwrap();
+    let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local.port());
+
+    [(local, Some(remote)), (local, None), (any, None)]
+        .into_iter()
+        .find_map(|(local, remote)| {
+            tbl.get(&TCPKey { local, remote }).cloned()
+        })
+}
+
+/// Output, wake users and remove closed connection
+pub fn tcp_update(pcb: &Arc<TCPPcb>, tcb: &mut TCB) {
+    tcb.output();
+
+    if tcb.in_syn_q && tcb.state != TCPState::SynRcvd {
+        tcb.in_syn_q = false;
+
+        if let Some(parent) = tcb.parent.upgrade() {
+            parent.syn_qlen.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    if tcb.state == TCPState::Closed {
+        let mut tbl = TCP_TBL.write().unwrap();
+
+        if tbl.get(&tcb.key).is_some_and(|old| Arc::ptr_eq(old, pcb)) {
+            tbl.remove(&tcb.key);
+        }
+    }
+
+    pcb.wake();
+}
+
+/// Passive opened connection is established, queue it to the listener
+fn tcp_accept_ready(pcb: &Arc<TCPPcb>, tcb: &TCB) {
+    let Some(parent) = tcb.parent.upgrade()
+    else {
+        return;
+    };
+
+    let mut ptcb = parent.tcb.lock().unwrap();
+
+    if ptcb.state != TCPState::Listen {
+        return;
+    }
+
+    ptcb.accept_q.push_back((pcb.clone(), tcb.remote()));
+    parent.wake();
+}
+
+/// Active open, local address is decided by route
+pub fn tcp_connect(remote: SocketAddrV4) -> io::Result<Arc<TCPPcb>> {
+    let Some(rt) = ip_route_output(*remote.ip())
+    else {
+        Err(io::Error::from_raw_os_error(ENETUNREACH))?
+    };
+
+    let mut tbl = TCP_TBL.write().unwrap();
+
+    let port = tcp_ephemeral_port(&tbl)?;
+
+    let key = TCPKey {
+        local: SocketAddrV4::new(rt.dev.ip, port),
+        remote: Some(remote),
+    };
+
+    let mut tcb = TCB::new(key, TCPState::SynSent);
+
+    tcb.rcv_mss = rt.dev.mtu - 40;
+    tcb.mss = tcb.rcv_mss.min(TCP_DEFAULT_MSS);
+
+    let pcb = Arc::new(TCPPcb::new(tcb));
+
+    tbl.insert(key, pcb.clone());
+    drop(tbl);
+
+    tcp_tmr_start();
+
+    trace!("Connect {} -> {remote}", key.local);
+    SNMP_TCP.active_opens.inc();
+
+    pcb.tcb.lock().unwrap().output();
+
+    Ok(pcb)
+}
+
+/// Passive open on `local`, zero port means an ephemeral one
+pub fn tcp_listen(
+    local: SocketAddrV4,
+    backlog: usize,
+) -> io::Result<Arc<TCPPcb>> {
+    if !local.ip().is_unspecified() && !is_local_addr(*local.ip()) {
+        Err(io::Error::from_raw_os_error(EADDRNOTAVAIL))?
+    }
+
+    let mut tbl = TCP_TBL.write().unwrap();
+
+    let port = if local.port() == 0 {
+        tcp_ephemeral_port(&tbl)?
+    }
+    else {
+        local.port()
+    };
+
+    let key = TCPKey {
+        local: SocketAddrV4::new(*local.ip(), port),
+        remote: None,
+    };
+
+    if tbl.keys().any(|old| {
+        old.remote.is_none()
+            && old.port() == port
+            && (old.local.ip().is_unspecified()
+                || local.ip().is_unspecified()
+                || old.local.ip() == local.ip())
+    }) {
+        Err(io::Error::from_raw_os_error(EADDRINUSE))?
+    }
+
+    let mut tcb = TCB::new(key, TCPState::Listen);
+
+    tcb.backlog = backlog;
+
+    let pcb = Arc::new(TCPPcb::new(tcb));
+
+    tbl.insert(key, pcb.clone());
+    drop(tbl);
+
+    tcp_tmr_start();
+
+    trace!("Listen on {}", key.local);
+
+    Ok(pcb)
+}
+
+/// Close the connection or listener, pending connections of listener are
+/// aborted
+pub fn tcp_close(pcb: &Arc<TCPPcb>) {
+    let mut tcb = pcb.tcb.lock().unwrap();
+    let accept_q = std::mem::take(&mut tcb.accept_q);
+
+    tcb.close();
+    tcp_update(pcb, &mut tcb);
+    drop(tcb);
+
+    for (child, _) in accept_q {
+        tcp_abort(&child);
+    }
+}
+
+pub fn tcp_abort(pcb: &Arc<TCPPcb>) {
+    let mut tcb = pcb.tcb.lock().unwrap();
+
+    tcb.abort();
+    tcp_update(pcb, &mut tcb);
+}
+
+/// Close all listeners and connections with FIN after queued data, e.g.
+/// on shutdown
+pub fn tcp_close_all() {
+    let pcbs: Vec<Arc<TCPPcb>> =
+        TCP_TBL.read().unwrap().values().cloned().collect();
+
+    for pcb in pcbs {
+        tcp_close(&pcb);
+    }
+}
+
+/// Reset all connections which are still open
+pub fn tcp_abort_all() {
+    let pcbs: Vec<Arc<TCPPcb>> =
+        TCP_TBL.read().unwrap().values().cloned().collect();
+
+    for pcb in pcbs {
+        tcp_abort(&pcb);
+    }
+}
+
+/// Connections still exchanging segments, TIME-WAIT ones are done
+pub fn tcp_active_count() -> usize {
+    let pcbs: Vec<Arc<TCPPcb>> =
+        TCP_TBL.read().unwrap().values().cloned().collect();
+
+    pcbs.iter()
+        .filter(|pcb| {
+            !matches!(
+                pcb.tcb.lock().unwrap().state,
+                TCPState::TimeWait | TCPState::Closed
+            )
+        })
+        .count()
+}
+
+fn tcp_ephemeral_port(tbl: &HashMap<TCPKey, Arc<TCPPcb>>) -> io::Result<u16> {
+    let start = *TCP_EPHEMERAL_PORTS.start();
+    let n = TCP_EPHEMERAL_PORTS.len() as u16;
+
+    for _ in 0..n {
+        let off = TCP_PORT_NEXT
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_sub(start)
+            % n;
+        let port = start + off;
+
+        if !tbl.keys().any(|old| old.port() == port) {
+            return Ok(port);
+        }
+    }
+
+    Err(io::Error::from_raw_os_error(EADDRINUSE))
+}
+
+fn tcp_tmr_start() {
+    TCP_TMR_ONCE.call_once(|| {
+        timer_add(TCP_TMR_INTERVAL, tcp_tmr_fire);
+    });
+}
+
+fn tcp_tmr_fire() {
+    tcp_tmr();
+    timer_add(TCP_TMR_INTERVAL, tcp_tmr_fire);
+}
+
+/// Retransmission, TIME-WAIT, FIN-WAIT-2, delayed ACK and keepalive timers
+/// of all connections
+pub fn tcp_tmr() {
+    let pcbs: Vec<Arc<TCPPcb>> =
+        TCP_TBL.read().unwrap().values().cloned().collect();
+    let now = clock_now();
+
+    for pcb in pcbs {
+        let mut tcb = pcb.tcb.lock().unwrap();
+
+        if tcb.rtx_deadline.is_none()
+            && tcb.tw_deadline.is_none()
+            && tcb.delack_deadline.is_none()
+            && tcb.keepalive.is_none()
+        {
+            continue;
+        }
+
+        tcb.tmr(now);
+        tcp_update(&pcb, &mut tcb);
+    }
+}
+
+/// Information of all connections like `ss -ti`
+pub fn tcp_info_all() -> Vec<(TCPKey, TCPInfo)> {
+    let pcbs: Vec<Arc<TCPPcb>> =
+        TCP_TBL.read().unwrap().values().cloned().collect();
+
+    pcbs.into_iter()
+        .map(|pcb| {
+            let tcb = pcb.tcb.lock().unwrap();
+
+            (tcb.key, tcb.info())
+        })
+        .collect()
+}
+
+/// Hook TCP into the lower layers
+pub fn tcp_init() {
+    icmp_register_err_handler(ProtocolKind::TCP, tcp_err);
+}
+
+/// ICMP error handler (RFC 1122 4.2.3.9)
+pub fn tcp_err(err: &ICMPErr) {
+    let th = from_raw_slice::<TCP>(&{
+        let mut quote = err.quote.clone();
+
+        quote.resize(size_of::<TCP>(), 0);
+        quote
+    });
+
+    let local = SocketAddrV4::new(err.src, th.src_port());
+    let remote = SocketAddrV4::new(err.dst, th.dst_port());
+
+    let Some(pcb) = TCP_TBL
+        .read()
+        .unwrap()
+        .get(&TCPKey {
+            local,
+            remote: Some(remote),
+        })
+        .cloned()
+    else {
+        return;
+    };
+
+    let mut tcb = pcb.tcb.lock().unwrap();
+
+    // the quoted segment must be in flight, or it's spoofed (RFC 5927 4.1)
+    if seq_lt(th.seq(), tcb.snd_una) || seq_gt(th.seq(), tcb.snd_nxt) {
+        trace!("ICMP error for {remote} out of window: {}", th.seq());
+        return;
+    }
+
+    if let Some(mtu) = err.mtu {
+        let mss = mtu.saturating_sub(40).max(TCP_MIN_MSS).min(tcb.mss);
+
+        if mss < tcb.mss {
+            trace!("MSS of {remote} decreases to {mss}");
+
+            tcb.mss = mss;
+            tcb.snd_nxt = tcb.snd_una;
+            tcp_update(&pcb, &mut tcb);
+        }
+
+        return;
+    }
+
+    if tcb.state == TCPState::SynSent && err.is_fatal() {
+        tcb.reset(Some(err.errno));
+        tcp_update(&pcb, &mut tcb);
+    }
+    else {
+        tcb.soft_err = Some(err.errno);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        dev::tests::{dev_pair, frame_output, neigh_add},
+        ip::tests::ipv4_packet,
+    };
+
+    /// ISS of the remote end of test connections
+    pub(crate) const PEER_ISS: u32 = 1000;
+
+    /// TCP segment with checksum from `src` to `dst`, `opts` is padded
+    pub(crate) fn tcp_segment(
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        opts: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let doff = size_of::<TCP>() + opts.len().next_multiple_of(4);
+        let th = TCP {
+            src: src.port().to_be(),
+            dst: dst.port().to_be(),
+            seq: seq.to_be(),
+            ack: ack.to_be(),
+            off_flags: ((doff as u16 / 4) << 12 | flags as u16).to_be(),
+            wnd: u16::MAX.to_be(),
+            cksum: 0,
+            urp: 0,
+        };
+        let mut seg = [as_raw_slice(&th), opts].concat();
+
+        seg.resize(doff, TCPOPT_EOL);
+        seg.extend_from_slice(data);
+
+        let cksum =
+            ip_pseudo_cksum(*src.ip(), *dst.ip(), ProtocolKind::TCP, &seg);
+
+        seg[16..18].copy_from_slice(&cksum.to_ne_bytes());
+        seg
+    }
+
+    /// Segment without options from `remote` is handled by `dev`
+    pub(crate) fn seg_input(
+        dev: &NetDevice,
+        remote: SocketAddrV4,
+        local: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        data: &[u8],
+    ) -> Option<String> {
+        let seg = tcp_segment(remote, local, seq, ack, flags, &[], data);
+        let pkt =
+            ipv4_packet(ProtocolKind::TCP, *remote.ip(), *local.ip(), &seg);
+
+        dev.tcp_input(&pkt).unwrap()
+    }
+
+    /// Next segment sent by device as (header, options, data)
+    pub(crate) fn seg_output(
+        peer: &UnixDatagram,
+    ) -> Option<(TCP, TCPOpts, Vec<u8>)> {
+        let (_, pkt) = frame_output(peer)?;
+        let (_, seg) = ip_split(&pkt);
+        let th = from_raw_slice::<TCP>(seg);
+        let opts = tcp_parse_opts(&seg[size_of::<TCP>()..th.doff()]);
+
+        Some((th, opts, seg[th.doff()..].to_vec()))
+    }
+
+    /// Connect to host 2 of `dev` which offers no option, returns the
+    /// connection with its local and remote address
+    pub(crate) fn tcp_open(
+        dev: &NetDevice,
+        peer: &UnixDatagram,
+    ) -> (Arc<TCPPcb>, SocketAddrV4, SocketAddrV4) {
+        let (host, _) = neigh_add(dev, 2);
+        let remote = SocketAddrV4::new(host, 80);
+        let pcb = tcp_connect(remote).unwrap();
+
+        let (th, ..) = seg_output(peer).unwrap();
+        let local = SocketAddrV4::new(dev.ip, th.src_port());
+        let iss = th.seq();
+
+        assert_eq!(th.flags(), TCP_SYN);
+
+        let flags = TCP_SYN | TCP_ACK;
+
+        seg_input(dev, remote, local, PEER_ISS, iss + 1, flags, &[]);
+
+        let (th, ..) = seg_output(peer).unwrap();
+
+        assert_eq!((th.flags(), th.ack()), (TCP_ACK, PEER_ISS + 1));
+        assert_eq!(pcb.tcb.lock().unwrap().state, TCPState::Established);
+
+        (pcb, local, remote)
+    }
+
+    /// Connection brought to synchronized `state` by the peer of no data
+    fn tcp_goto(
+        dev: &NetDevice,
+        peer: &UnixDatagram,
+        state: TCPState,
+    ) -> (Arc<TCPPcb>, SocketAddrV4, SocketAddrV4) {
+        use TCPState::*;
+
+        let (pcb, local, remote) = tcp_open(dev, peer);
+        let snd_nxt = pcb.tcb.lock().unwrap().snd_nxt;
+        let seq = PEER_ISS + 1;
+        let input = |seq, ack, flags| {
+            seg_input(dev, remote, local, seq, ack, flags, &[]);
+        };
+        let output = || seg_output(peer).unwrap().0.flags();
+
+        if matches!(state, FinWait1 | FinWait2 | Closing | TimeWait) {
+            tcp_close(&pcb);
+
+            assert_eq!(output(), TCP_FIN | TCP_ACK);
+        }
+
+        match state {
+            FinWait2 => input(seq, snd_nxt + 1, TCP_ACK),
+            Closing | TimeWait | CloseWait | LastAck => {
+                input(seq, snd_nxt, TCP_FIN | TCP_ACK);
+
+                assert_eq!(output(), TCP_ACK);
+            }
+            _ => (),
+        }
+
+        match state {
+            TimeWait => input(seq + 1, snd_nxt + 1, TCP_ACK),
+            LastAck => {
+                tcp_close(&pcb);
+
+                assert_eq!(output(), TCP_FIN | TCP_ACK);
+            }
+            _ => (),
+        }
+
+        assert_eq!(pcb.tcb.lock().unwrap().state, state);
+
+        (pcb, local, remote)
+    }
+
+    #[test]
+    fn test_tcp_simultaneous_open() {
+        let (dev, peer) = dev_pair();
+        let (host, _) = neigh_add(&dev, 2);
+        let remote = SocketAddrV4::new(host, 80);
+        let pcb = tcp_connect(remote).unwrap();
+
+        let (th, ..) = seg_output(&peer).unwrap();
+        let local = SocketAddrV4::new(dev.ip, th.src_port());
+        let iss = th.seq();
+
+        assert_eq!(th.flags(), TCP_SYN);
+
+        // SYNs cross, ours is resent with ACK
+        seg_input(&dev, remote, local, PEER_ISS, 0, TCP_SYN, &[]);
+
+        let (th, ..) = seg_output(&peer).unwrap();
+
+        assert_eq!(pcb.tcb.lock().unwrap().state, TCPState::SynRcvd);
+        assert_eq!(th.flags(), TCP_SYN | TCP_ACK);
+        assert_eq!((th.seq(), th.ack()), (iss, PEER_ISS + 1));
+
+        seg_input(&dev, remote, local, PEER_ISS + 1, iss + 1, TCP_ACK, &[]);
+
+        let tcb = pcb.tcb.lock().unwrap();
+
+        assert_eq!(tcb.state, TCPState::Established);
+        assert_eq!((tcb.snd_una, tcb.rcv_nxt), (iss + 1, PEER_ISS + 1));
+
+        drop(tcb);
+        tcp_abort(&pcb);
+    }
+
+    #[test]
+    fn test_tcp_simultaneous_close() {
+        let (dev, peer) = dev_pair();
+        let (pcb, local, remote) = tcp_open(&dev, &peer);
+        let snd_nxt = pcb.tcb.lock().unwrap().snd_nxt;
+        let seq = PEER_ISS + 1;
+
+        tcp_close(&pcb);
+
+        let (th, ..) = seg_output(&peer).unwrap();
+
+        assert_eq!(th.flags(), TCP_FIN | TCP_ACK);
+        assert_eq!(th.seq(), snd_nxt);
+        assert_eq!(pcb.tcb.lock().unwrap().state, TCPState::FinWait1);
+
+        // FINs cross, ours isn't acknowledged yet
+        seg_input(&dev, remote, local, seq, snd_nxt, TCP_FIN | TCP_ACK, &[]);
+
+        let (th, ..) = seg_output(&peer).unwrap();
+
+        assert_eq!((th.flags(), th.ack()), (TCP_ACK, seq + 1));
+        assert_eq!(pcb.tcb.lock().unwrap().state, TCPState::Closing);
+
+        let before = clock_now();
+
+        seg_input(&dev, remote, local, seq + 1, snd_nxt + 1, TCP_ACK, &[]);
+
+        let mut tcb = pcb.tcb.lock().unwrap();
+
+        assert_eq!(tcb.state, TCPState::TimeWait);
+
+        /* TIME-WAIT lasts 2MSL */
+
+        tcb.tmr(before + TCP_MSL * 2 - TCP_TMR_INTERVAL);
+
+        assert_eq!(tcb.state, TCPState::TimeWait);
+
+        tcb.tmr(clock_now() + TCP_MSL * 2);
+
+        assert_eq!(tcb.state, TCPState::Closed);
+
+        tcp_update(&pcb, &mut tcb);
+        drop(tcb);
+
+        assert!(tcp_lookup(local, remote).is_none());
+    }
+
+    #[test]
+    fn test_tcp_rst_syn_sent() {
+        let (dev, peer) = dev_pair();
+        let (host, _) = neigh_add(&dev, 2);
+        let remote = SocketAddrV4::new(host, 80);
+        let pcb = tcp_connect(remote).unwrap();
+
+        let (th, ..) = seg_output(&peer).unwrap();
+        let local = SocketAddrV4::new(dev.ip, th.src_port());
+        let iss = th.seq();
+        let state = || pcb.tcb.lock().unwrap().state;
+
+        // RST without ACK or acknowledging something else is ignored
+        seg_input(&dev, remote, local, PEER_ISS, 0, TCP_RST, &[]);
+
+        assert_eq!(state(), TCPState::SynSent);
+
+        let flags = TCP_RST | TCP_ACK;
+
+        seg_input(&dev, remote, local, 0, iss + 2, flags, &[]);
+
+        assert_eq!(state(), TCPState::SynSent);
+
+        seg_input(&dev, remote, local, 0, iss + 1, flags, &[]);
+
+        let mut tcb = pcb.tcb.lock().unwrap();
+
+        assert_eq!(tcb.state, TCPState::Closed);
+        assert_eq!(tcb.take_err().unwrap().raw_os_error(), Some(ECONNREFUSED));
+        assert!(tcp_lookup(local, remote).is_none());
+    }
+
+    #[test]
+    fn test_tcp_rst_syn_rcvd() {
+        let (dev, peer) = dev_pair();
+        let (host, _) = neigh_add(&dev, 2);
+        let remote = SocketAddrV4::new(host, 80);
+        let listener = tcp_listen(SocketAddrV4::new(dev.ip, 0), 8).unwrap();
+        let local = listener.tcb.lock().unwrap().local();
+
+        seg_input(&dev, remote, local, PEER_ISS, 0, TCP_SYN, &[]);
+
+        let (th, ..) = seg_output(&peer).unwrap();
+        let child = tcp_lookup(local, remote).unwrap();
+
+        assert_eq!(th.flags(), TCP_SYN | TCP_ACK);
+        assert_eq!(child.tcb.lock().unwrap().state, TCPState::SynRcvd);
+        assert_eq!(listener.syn_qlen.load(Ordering::Relaxed), 1);
+
+        // connection goes back to the listener quietly
+        seg_input(&dev, remote, local, PEER_ISS + 1, 0, TCP_RST, &[]);
+
+        let mut tcb = child.tcb.lock().unwrap();
+
+        assert_eq!(tcb.state, TCPState::Closed);
+        assert!(tcb.take_err().is_none());
+        assert_eq!(listener.syn_qlen.load(Ordering::Relaxed), 0);
+        assert!(Arc::ptr_eq(&tcp_lookup(local, remote).unwrap(), &listener));
+
+        assert_eq!(
+            seg_input(&dev, remote, local, PEER_ISS, 0, TCP_RST, &[])
+                .as_deref(),
+            Some("RST to listener")
+        );
+
+        tcp_close(&listener);
+    }
+
+    #[test]
+    fn test_tcp_rst_synchronized() {
+        use TCPState::*;
+
+        let (dev, peer) = dev_pair();
+
+        for (state, err) in [
+            (Established, Some(ECONNRESET)),
+            (FinWait1, Some(ECONNRESET)),
+            (FinWait2, Some(ECONNRESET)),
+            (CloseWait, Some(ECONNRESET)),
+            (Closing, None),
+            (LastAck, None),
+            (TimeWait, None),
+        ] {
+            let (pcb, local, remote) = tcp_goto(&dev, &peer, state);
+            let rcv_nxt = pcb.tcb.lock().unwrap().rcv_nxt;
+
+            // not exactly the next sequence, challenge ACK (RFC 5961 3.2)
+            seg_input(&dev, remote, local, rcv_nxt + 1, 0, TCP_RST, &[]);
+
+            let (th, ..) = seg_output(&peer).unwrap();
+
+            assert_eq!((th.flags(), th.ack()), (TCP_ACK, rcv_nxt), "{state}");
+            assert_eq!(pcb.tcb.lock().unwrap().state, state);
+
+            seg_input(&dev, remote, local, rcv_nxt, 0, TCP_RST, &[]);
+
+            let mut tcb = pcb.tcb.lock().unwrap();
+
+            assert_eq!(tcb.state, Closed, "{state}");
+            assert_eq!(
+                tcb.take_err().and_then(|err| err.raw_os_error()),
+                err,
+                "{state}"
+            );
+            assert!(tcp_lookup(local, remote).is_none());
+        }
+    }
+
+    #[test]
+    fn test_tcp_isn() {
+        let clock = ManualClock::new();
+        let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 49152);
+        let other = SocketAddrV4::new(*remote.ip(), 49153);
+        let isn = tcp_isn_at(local, remote, clock.wall());
+
+        // F of RFC 6528 differs by connection
+        assert_ne!(isn, tcp_isn_at(local, other, clock.wall()));
+        assert_ne!(isn, tcp_isn_at(remote, local, clock.wall()));
+
+        // M ticks every 4 microseconds
+        clock.advance(Duration::from_millis(4));
+
+        assert_eq!(
+            tcp_isn_at(local, remote, clock.wall()).wrapping_sub(isn),
+            1000
+        );
+    }
+
+    /// Established TCB of 1000 bytes MSS and 10 segments in flight, the
+    /// sequence wraps in the flight
+    fn tcb_in_flight() -> (TCB, u32) {
+        let key = TCPKey {
+            local: "10.0.0.1:80".parse().unwrap(),
+            remote: Some("10.0.0.2:40000".parse().unwrap()),
+        };
+        let mut tcb = TCB::new(key, TCPState::Established);
+        let una = 0u32.wrapping_sub(3500);
+
+        tcb.mss = 1000;
+        tcb.ts_ok = false;
+        tcb.syn_acked = true;
+        tcb.snd_una = una;
+        tcb.snd_nxt = una.wrapping_add(10_000);
+        tcb.snd_max = tcb.snd_nxt;
+        tcb.high_rxt = una;
+
+        (tcb, una)
+    }
+
+    #[test]
+    fn test_tcp_sack_scoreboard() {
+        let (mut tcb, una) = tcb_in_flight();
+        let seq = |off: u32| una.wrapping_add(off);
+
+        tcb.sack_input(&[
+            (seq(1000), seq(2000)),
+            (seq(3000), seq(4000)),
+            // bridges the two blocks
+            (seq(1500), seq(3000)),
+            // D-SACK, beyond snd_max and empty ones are ignored
+            (seq(0).wrapping_sub(100), seq(500)),
+            (seq(9000), seq(11_000)),
+            (seq(6000), seq(6000)),
+        ]);
+
+        assert_eq!(tcb.sacked, [(seq(1000), seq(4000))]);
+
+        tcb.sack_input(&[(seq(5000), seq(6000))]);
+
+        assert_eq!(
+            tcb.sacked,
+            [(seq(1000), seq(4000)), (seq(5000), seq(6000))]
+        );
+        assert_eq!(tcb.sacked_bytes(), 4000);
+
+        // more than 2 segments are SACKed above only the first hole
+        assert_eq!(tcb.sack_lost_edge(), seq(1000));
+
+        // holes of 1000 and 4000 bytes, the first is lost
+        assert_eq!(tcb.sack_pipe(), 5000);
+        assert_eq!(tcb.sack_next_seg(), Some((seq(0), 1000)));
+
+        // retransmitted bytes are in the network again
+        tcb.high_rxt = seq(1000);
+
+        assert_eq!(tcb.sack_pipe(), 6000);
+        assert_eq!(tcb.sack_next_seg(), None);
+
+        // the second hole is lost too after more SACKs above
+        tcb.sack_input(&[(seq(7000), seq(9000))]);
+
+        assert_eq!(tcb.sack_lost_edge(), seq(5000));
+        assert_eq!(tcb.sack_pipe(), 3000);
+        assert_eq!(tcb.sack_next_seg(), Some((seq(4000), 1000)));
+    }
+
+    #[test]
+    fn test_tcp_parse_opts() {
+        let (mut tcb, _) = tcb_in_flight();
+
+        tcb.ts_ok = true;
+
+        let opts = tcp_parse_opts(&tcb.opts(TCP_SYN));
+
+        assert_eq!(opts.mss, Some(tcb.rcv_mss));
+        assert_eq!(opts.wscale, Some(tcb.rcv_wscale));
+        assert!(opts.sack_perm);
+        assert!(opts.ts.is_some());
+
+        // the latest out-of-order segment is reported first
+        tcb.rcv_nxt = 1000;
+        tcb.ooo = vec![(2000, vec![0; 500]), (3000, vec![0; 100])];
+        tcb.ooo_last = Some(3000);
+
+        let opts = tcp_parse_opts(&tcb.opts(TCP_ACK));
+
+        assert_eq!(opts.mss, None);
+        assert_eq!(opts.sack, [(3000, 3100), (2000, 2500)]);
+
+        // unknown kinds are skipped, bad length stops parsing
+        let opts = tcp_parse_opts(&[
+            1, 30, 4, 0xaa, 0xbb, 3, 3, 7, 2, 4, 0x05, 0xb4, 4, 1,
+        ]);
+
+        assert_eq!(opts.wscale, Some(7));
+        assert_eq!(opts.mss, Some(1460));
+        assert!(!opts.sack_perm);
+
+        let opts = tcp_parse_opts(&[4, 2, 0, 2, 4, 0x05, 0xb4]);
+
+        assert!(opts.sack_perm);
+        assert_eq!(opts.mss, None);
+    }
+
+    #[test]
+    fn test_tcp_mss_floor() {
+        let (dev, peer) = dev_pair();
+        let (host, _) = neigh_add(&dev, 2);
+        let listener = tcp_listen(SocketAddrV4::new(dev.ip, 0), 1).unwrap();
+        let local = listener.tcb.lock().unwrap().local();
+        let syn = |remote: SocketAddrV4, mss: u16| {
+            let opts = [&[TCPOPT_MSS, 4][..], &mss.to_be_bytes()].concat();
+            let seg =
+                tcp_segment(remote, local, PEER_ISS, 0, TCP_SYN, &opts, &[]);
+
+            dev.tcp_input(&ipv4_packet(ProtocolKind::TCP, host, dev.ip, &seg))
+                .unwrap()
+        };
+
+        for (port, mss) in [(40000, 0), (40001, 1)] {
+            let remote = SocketAddrV4::new(host, port);
+
+            assert_eq!(syn(remote, mss), None);
+
+            let (th, ..) = seg_output(&peer).unwrap();
+            let child = tcp_lookup(local, remote).unwrap();
+            let mut tcb = child.tcb.lock().unwrap();
+
+            assert_eq!(th.flags(), TCP_SYN | TCP_ACK);
+            assert_eq!(tcb.mss, TCP_MIN_MSS);
+            assert!(tcb.data_mss() > 0);
+            assert_eq!(tcb.info().mss, TCP_MIN_MSS);
+
+            tcb.abort();
+            tcp_update(&child, &mut tcb);
+
+            assert_eq!(seg_output(&peer).unwrap().0.flags(), TCP_RST);
+        }
+
+        /* SYN cookie encodes the MSS of its table */
+
+        listener.syn_qlen.store(1, Ordering::Relaxed);
+
+        let remote = SocketAddrV4::new(host, 40002);
+
+        assert_eq!(syn(remote, 0), None);
+
+        let (th, opts, _) = seg_output(&peer).unwrap();
+
+        assert_eq!(th.flags(), TCP_SYN | TCP_ACK);
+        assert_eq!(opts.mss, Some(TCP_DEFAULT_MSS));
+
+        let flags = TCP_ACK;
+
+        seg_input(&dev, remote, local, PEER_ISS + 1, th.seq() + 1, flags, &[]);
+
+        let child = tcp_lookup(local, remote).unwrap();
+        let tcb = child.tcb.lock().unwrap();
+
+        assert_eq!(tcb.state, TCPState::Established);
+        assert_eq!(tcb.info().mss, TCP_DEFAULT_MSS);
+
+        drop(tcb);
+        listener.syn_qlen.store(0, Ordering::Relaxed);
+        tcp_close(&listener);
+    }
+
+    #[test]
+    fn test_tcp_nagle() {
+        let (dev, peer) = dev_pair();
+        let (pcb, local, remote) = tcp_open(&dev, &peer);
+        let send = |buf: &[u8]| {
+            let mut tcb = pcb.tcb.lock().unwrap();
+
+            tcb.send(buf).unwrap();
+            tcp_update(&pcb, &mut tcb);
+        };
+
+        send(b"a");
+
+        let (th, _, data) = seg_output(&peer).unwrap();
+        let una = th.seq();
+
+        assert_eq!(data, b"a");
+
+        // small segment waits while data is unacknowledged
+        send(b"b");
+
+        assert_eq!(pcb.tcb.lock().unwrap().snd_nxt, una + 1);
+
+        seg_input(&dev, remote, local, PEER_ISS + 1, una + 1, TCP_ACK, &[]);
+
+        let (th, _, data) = seg_output(&peer).unwrap();
+
+        assert_eq!((th.seq(), data.as_slice()), (una + 1, &b"b"[..]));
+
+        // TCP_NODELAY sends it at once
+        pcb.tcb.lock().unwrap().nodelay = true;
+        send(b"c");
+
+        let (th, _, data) = seg_output(&peer).unwrap();
+
+        assert_eq!((th.seq(), data.as_slice()), (una + 2, &b"c"[..]));
+
+        tcp_abort(&pcb);
+    }
+
+    #[test]
+    fn test_tcp_delayed_ack() {
+        let (dev, peer) = dev_pair();
+        let (pcb, local, remote) = tcp_open(&dev, &peer);
+        let ack = pcb.tcb.lock().unwrap().snd_nxt;
+        let mut seq = PEER_ISS + 1;
+        let mut input = |flags| {
+            seg_input(&dev, remote, local, seq, ack, flags, &[0; 100]);
+            seq += 100;
+            seq
+        };
+
+        let rcv_nxt = input(TCP_ACK | TCP_PSH);
+        let mut tcb = pcb.tcb.lock().unwrap();
+        let deadline = tcb.delack_deadline.unwrap();
+
+        assert_eq!(tcb.rcv_nxt, rcv_nxt);
+
+        tcb.tmr(deadline - TCP_TMR_INTERVAL);
+
+        assert!(!tcb.ack_now);
+
+        tcb.tmr(deadline);
+        tcp_update(&pcb, &mut tcb);
+        drop(tcb);
+
+        let (th, ..) = seg_output(&peer).unwrap();
+
+        assert_eq!((th.flags(), th.ack()), (TCP_ACK, rcv_nxt));
+
+        // every second full-sized segment is acknowledged at once
+        input(TCP_ACK);
+
+        assert!(pcb.tcb.lock().unwrap().delack_deadline.is_some());
+
+        let rcv_nxt = input(TCP_ACK);
+        let (th, ..) = seg_output(&peer).unwrap();
+
+        assert_eq!(th.ack(), rcv_nxt);
+        assert!(pcb.tcb.lock().unwrap().delack_deadline.is_none());
+
+        // TCP_QUICKACK doesn't delay any
+        pcb.tcb.lock().unwrap().quickack = true;
+
+        let rcv_nxt = input(TCP_ACK);
+        let (th, ..) = seg_output(&peer).unwrap();
+
+        assert_eq!(th.ack(), rcv_nxt);
+        assert!(pcb.tcb.lock().unwrap().delack_deadline.is_none());
+
+        tcp_abort(&pcb);
+    }
+
+    #[test]
+    fn test_tcp_keepalive() {
+        let (dev, peer) = dev_pair();
+        let (pcb, local, remote) = tcp_open(&dev, &peer);
+        let secs = Duration::from_secs;
+        let mut tcb = pcb.tcb.lock().unwrap();
+        let una = tcb.snd_una;
+
+        tcb.keepalive = Some(TCPKeepalive {
+            idle: secs(10),
+            interval: secs(2),
+            count: 2,
+        });
+
+        let stamp = tcb.rcv_stamp;
+
+        tcb.tmr(stamp + secs(10) - TCP_TMR_INTERVAL);
+
+        assert_eq!(tcb.keep_probes, 0);
+
+        for (i, off) in [(1, 10), (1, 11), (2, 12)] {
+            tcb.tmr(stamp + secs(off));
+
+            assert_eq!(tcb.keep_probes, i);
+        }
+
+        for _ in 0..2 {
+            let (th, _, data) = seg_output(&peer).unwrap();
+
+            assert_eq!((th.flags(), th.seq()), (TCP_ACK, una - 1));
+            assert!(data.is_empty());
+        }
+
+        drop(tcb);
+
+        // the answer makes it idle again
+        seg_input(&dev, remote, local, PEER_ISS + 1, una, TCP_ACK, &[]);
+
+        let mut tcb = pcb.tcb.lock().unwrap();
+        let stamp = tcb.rcv_stamp;
+
+        assert_eq!(tcb.keep_probes, 0);
+
+        tcb.tmr(stamp + secs(10));
+        tcb.tmr(stamp + secs(12));
+
+        assert_eq!(tcb.state, TCPState::Established);
+
+        tcb.tmr(stamp + secs(14));
+
+        assert_eq!(tcb.state, TCPState::Closed);
+        assert_eq!(tcb.take_err().unwrap().raw_os_error(), Some(ETIMEDOUT));
+
+        tcp_update(&pcb, &mut tcb);
+    }
+
+    #[test]
+    fn test_tcp_user_timeout() {
+        let (dev, peer) = dev_pair();
+        let (pcb, ..) = tcp_open(&dev, &peer);
+        let mut tcb = pcb.tcb.lock().unwrap();
+
+        tcb.user_timeout = Some(Duration::from_secs(5));
+        tcb.send(&[0; 100]).unwrap();
+        tcp_update(&pcb, &mut tcb);
+
+        let (th, _, data) = seg_output(&peer).unwrap();
+        let stamp = tcb.una_stamp.unwrap();
+
+        assert_eq!(data.len(), 100);
+
+        // retransmitted before the timeout
+        let deadline = tcb.rtx_deadline.unwrap();
+
+        tcb.tmr(deadline);
+
+        let (rtx, _, data) = seg_output(&peer).unwrap();
+
+        assert_eq!((rtx.seq(), data.len()), (th.seq(), 100));
+        assert_eq!(tcb.rtx_count, 1);
+
+        tcb.tmr(stamp + Duration::from_secs(5));
+
+        assert_eq!(tcb.state, TCPState::Closed);
+        assert_eq!(tcb.take_err().unwrap().raw_os_error(), Some(ETIMEDOUT));
+
+        tcp_update(&pcb, &mut tcb);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{fmt::Debug, str::FromStr, time::Instant};
+
+use anyhow::anyhow;
+use m6ptr::LazyStatic;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/* CUBIC (RFC 9438 4.1) */
+
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/// Congestion controller of new connections, like Linux
+/// `net.ipv4.tcp_congestion_control`
+pub static TCP_CONGESTION: LazyStatic<CCKind> =
+    LazyStatic::new(|| CCKind::Cubic);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CCKind {
+    NewReno,
+    Cubic,
+}
+
+/// Congestion control with fast retransmit and fast recovery (RFC 5681,
+/// RFC 6582) done by TCP itself, controller only decides window
+pub trait CongestionControl: Debug + Send {
+    fn name(&self) -> &'static str;
+
+    /// Grow `cwnd` when `acked` bytes of new data are acknowledged out of
+    /// recovery
+    fn cong_avoid(
+        &mut self,
+        cwnd: &mut u32,
+        ssthresh: u32,
+        mss: u32,
+        acked: u32,
+        now: Instant,
+    );
+
+    /// Slow start threshold after loss is detected by duplicate ACKs or
+    /// retransmission timeout
+    fn ssthresh(&mut self, cwnd: u32, flight: u32, mss: u32) -> u32;
+}
+
+#[derive(Debug, Default)]
+pub struct NewReno {
+    /// Acknowledged bytes in congestion avoidance (RFC 5681 3.1)
+    bytes_acked: u32,
+}
+
+/// RFC 9438, window is computed in segments
+#[derive(Debug, Default)]
+pub struct Cubic {
+    w_max: f64,
+    /// `w_max` of the last congestion event for fast convergence
+    w_last_max: f64,
+    k: f64,
+    /// Start of current congestion avoidance
+    epoch: Option<Instant>,
+    origin: f64,
+    /// Reno-friendly window estimation
+    w_est: f64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl CCKind {
+    pub fn build(&self) -> Box<dyn CongestionControl> {
+        match self {
+            Self::NewReno => Box::new(NewReno::default()),
+            Self::Cubic => Box::new(Cubic::default()),
+        }
+    }
+}
+
+impl FromStr for CCKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "reno" | "newreno" => Self::NewReno,
+            "cubic" => Self::Cubic,
+            _ => Err(anyhow!("Unknown congestion control {s}"))?,
+        })
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+
+    fn cong_avoid(
+        &mut self,
+        cwnd: &mut u32,
+        ssthresh: u32,
+        mss: u32,
+        acked: u32,
+        _now: Instant,
+    ) {
+        if *cwnd < ssthresh {
+            tcp_slow_start(cwnd, acked, mss);
+            return;
+        }
+
+        self.bytes_acked += acked;
+
+        if self.bytes_acked >= *cwnd {
+            self.bytes_acked -= *cwnd;
+            *cwnd += mss;
+        }
+    }
+
+    fn ssthresh(&mut self, _cwnd: u32, flight: u32, mss: u32) -> u32 {
+        self.bytes_acked = 0;
+
+        (flight / 2).max(2 * mss)
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+
+    fn cong_avoid(
+        &mut self,
+        cwnd: &mut u32,
+        ssthresh: u32,
+        mss: u32,
+        acked: u32,
+        now: Instant,
+    ) {
+        if *cwnd < ssthresh {
+            tcp_slow_start(cwnd, acked, mss);
+            return;
+        }
+
+        let mss_f = mss as f64;
+        let cwnd_seg = *cwnd as f64 / mss_f;
+
+        let epoch = *self.epoch.get_or_insert_with(|| {
+            if cwnd_seg < self.w_max {
+                self.k = ((self.w_max - cwnd_seg) / CUBIC_C).cbrt();
+                self.origin = self.w_max;
+            }
+            else {
+                self.k = 0.0;
+                self.origin = cwnd_seg;
+            }
+
+            self.w_est = cwnd_seg;
+
+            now
+        });
+
+        let t = now.duration_since(epoch).as_secs_f64();
+        let target = (self.origin + CUBIC_C * (t - self.k).powi(3))
+            .clamp(cwnd_seg, cwnd_seg * 1.5);
+
+        /* RFC 9438 4.3 */
+
+        let alpha = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+
+        self.w_est += alpha * (acked as f64 / mss_f) / cwnd_seg;
+
+        let inc = if self.w_est > target {
+            self.w_est - cwnd_seg
+        }
+        else {
+            (target - cwnd_seg) / cwnd_seg * (acked as f64 / mss_f)
+        };
+
+        *cwnd += (inc.max(0.0) * mss_f) as u32;
+    }
+
+    fn ssthresh(&mut self, cwnd: u32, _flight: u32, mss: u32) -> u32 {
+        let cwnd_seg = cwnd as f64 / mss as f64;
+
+        self.epoch = None;
+
+        /* fast convergence (RFC 9438 4.7) */
+
+        self.w_max = if cwnd_seg < self.w_last_max {
+            cwnd_seg * (1.0 + CUBIC_BETA) / 2.0
+        }
+        else {
+            cwnd_seg
+        };
+
+        self.w_last_max = cwnd_seg;
+
+        ((cwnd as f64 * CUBIC_BETA) as u32).max(2 * mss)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Appropriate Byte Counting with L = 1 SMSS (RFC 3465)
+pub fn tcp_slow_start(cwnd: &mut u32, acked: u32, mss: u32) {
+    *cwnd += acked.min(mss);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    fn test_newreno() {
+        let mss = 1000;
+        let now = ManualClock::new().now();
+        let mut cc = NewReno::default();
+        let mut cwnd = 4 * mss;
+
+        // slow start counts at most one segment per ACK (RFC 3465)
+        cc.cong_avoid(&mut cwnd, u32::MAX, mss, 3 * mss, now);
+        cc.cong_avoid(&mut cwnd, u32::MAX, mss, mss / 2, now);
+
+        assert_eq!(cwnd, 5 * mss + mss / 2);
+
+        // one segment per window in congestion avoidance
+        let mut cwnd = 10 * mss;
+
+        for i in 1..=10 {
+            cc.cong_avoid(&mut cwnd, 5 * mss, mss, mss, now);
+
+            assert_eq!(cwnd, if i < 10 { 10 * mss } else { 11 * mss });
+        }
+
+        assert_eq!(cc.ssthresh(cwnd, 8 * mss, mss), 4 * mss);
+        assert_eq!(cc.ssthresh(cwnd, mss, mss), 2 * mss);
+    }
+
+    #[test]
+    fn test_cubic() {
+        let mss = 1000;
+        let rtt = Duration::from_millis(100);
+        let clock = ManualClock::new();
+        let mut cc = Cubic::default();
+
+        let ssthresh = cc.ssthresh(100 * mss, 100 * mss, mss);
+        let mut cwnd = ssthresh;
+
+        assert_eq!(ssthresh, 70 * mss);
+
+        // a window of ACKs per RTT until the plateau at W_max, K is about
+        // 4.2s for W_max 100 and cwnd 70 segments (RFC 9438 4.2)
+        let mut last = cwnd;
+        let mut incs = vec![];
+
+        while clock.elapsed() < Duration::from_millis(4200) {
+            for _ in 0..cwnd / mss {
+                cc.cong_avoid(&mut cwnd, ssthresh, mss, mss, clock.now());
+            }
+
+            assert!(cwnd >= last && cwnd <= 105 * mss);
+
+            incs.push(cwnd - last);
+            last = cwnd;
+            clock.advance(rtt);
+        }
+
+        assert!((95 * mss..=101 * mss).contains(&cwnd), "{cwnd}");
+
+        // concave: growth slows down approaching W_max
+        assert!(incs[1] > incs[incs.len() - 1]);
+
+        // convex: probing beyond W_max speeds up
+        let mut probes = vec![];
+
+        for _ in 0..20 {
+            for _ in 0..cwnd / mss {
+                cc.cong_avoid(&mut cwnd, ssthresh, mss, mss, clock.now());
+            }
+
+            probes.push(cwnd - last);
+            last = cwnd;
+            clock.advance(rtt);
+        }
+
+        assert!(probes[19] > probes[5]);
+    }
+}
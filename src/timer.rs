@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use m6ptr::LazyStatic;
+
+use crate::clock::clock_now;
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// Granularity of the wheel
+pub const TIMER_TICK: Duration = Duration::from_millis(10);
+
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SZ: usize = 1 << WHEEL_BITS;
+const WHEEL_MASK: u64 = WHEEL_SZ as u64 - 1;
+const WHEEL_LEVELS: usize = 4;
+
+/// Ticks covered by all levels, farther timers are clamped to it and
+/// queued again on expiry
+const WHEEL_SPAN: u64 = 1 << (WHEEL_BITS * WHEEL_LEVELS as u32);
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/// Timers of the stack, driven by `timer_start`, a reactor or
+/// `timer_advance`
+pub static TIMER_WHEEL: LazyStatic<TimerWheel> =
+    LazyStatic::new(|| TimerWheel::new());
+
+/// The only kind of driver running the wheel in the process
+static TIMER_DRIVER: Mutex<Option<TimerDriver>> = Mutex::new(None);
+
+/// Clock and tick when the wheel is first run by `timer_run`
+static TIMER_START: LazyStatic<(Instant, u64)> =
+    LazyStatic::new(|| (clock_now(), TIMER_WHEEL.read().unwrap().now()));
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// Who runs `timer_run` after each tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerDriver {
+    /// `timer_start`, for devices driven by `NetDevice::spawn`
+    Thread,
+    /// timerfd of `Reactor`
+    Reactor,
+}
+
+pub type TimerFn = Box<dyn FnOnce() + Send>;
+
+struct Timer {
+    /// In ticks
+    expires: u64,
+    f: TimerFn,
+}
+
+/// Hierarchical timing wheel (Varghese and Lauck), a slot of level `n`
+/// covers 64^n ticks and is cascaded into lower level when time reaches it
+pub struct TimerWheel {
+    /// Current tick
+    now: u64,
+    /// Timers of `slots[level][slot]`, cancelled ones are skipped lazily
+    slots: Vec<Vec<Vec<TimerId>>>,
+    timers: HashMap<TimerId, Timer>,
+    next_id: u64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            now: 0,
+            slots: vec![vec![Vec::new(); WHEEL_SZ]; WHEEL_LEVELS],
+            timers: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Ticks since the wheel is created
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    /// Run `f` after `delay` which is rounded up to ticks, it's at least
+    /// the next tick
+    pub fn add(&mut self, delay: Duration, f: TimerFn) -> TimerId {
+        let ticks = delay.as_nanos().div_ceil(TIMER_TICK.as_nanos()) as u64;
+        let id = TimerId(self.next_id);
+
+        self.next_id += 1;
+        self.timers.insert(
+            id,
+            Timer {
+                expires: self.now + ticks.max(1),
+                f,
+            },
+        );
+        self.place(id);
+
+        id
+    }
+
+    /// Returns if the timer was pending
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        self.timers.remove(&id).is_some()
+    }
+
+    /// Move forward one tick, returns expired callbacks to be run without
+    /// the wheel locked
+    pub fn tick(&mut self) -> Vec<TimerFn> {
+        self.now += 1;
+
+        /* cascade higher levels when lower ones wrap */
+
+        for level in 1..WHEEL_LEVELS {
+            if (self.now >> (WHEEL_BITS * level as u32 - WHEEL_BITS))
+                & WHEEL_MASK
+                != 0
+            {
+                break;
+            }
+
+            let slot = self.slot_of(self.now, level);
+
+            for id in std::mem::take(&mut self.slots[level][slot]) {
+                if self.timers.contains_key(&id) {
+                    self.place(id);
+                }
+            }
+        }
+
+        let slot = self.slot_of(self.now, 0);
+        let mut expired = Vec::new();
+
+        for id in std::mem::take(&mut self.slots[0][slot]) {
+            match self.timers.get(&id) {
+                Some(timer) if timer.expires <= self.now => {
+                    expired.push(self.timers.remove(&id).unwrap().f);
+                }
+                Some(_) => self.place(id),
+                None => (),
+            }
+        }
+
+        expired
+    }
+
+    fn place(&mut self, id: TimerId) {
+        let expires = self.timers[&id].expires;
+        let delta = expires.saturating_sub(self.now);
+
+        let (level, at) = match (0..WHEEL_LEVELS)
+            .find(|&level| delta < 1 << (WHEEL_BITS * (level as u32 + 1)))
+        {
+            Some(level) => (level, expires),
+            None => (WHEEL_LEVELS - 1, self.now + WHEEL_SPAN - 1),
+        };
+
+        let slot = self.slot_of(at, level);
+
+        self.slots[level][slot].push(id);
+    }
+
+    fn slot_of(&self, ticks: u64, level: usize) -> usize {
+        ((ticks >> (WHEEL_BITS * level as u32)) & WHEEL_MASK) as usize
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for TimerWheel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TimerWheel")
+            .field("now", &self.now)
+            .field("pending", &self.timers.len())
+            .finish()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+pub fn timer_add<F: FnOnce() + Send + 'static>(
+    delay: Duration,
+    f: F,
+) -> TimerId {
+    TIMER_WHEEL.write().unwrap().add(delay, Box::new(f))
+}
+
+pub fn timer_cancel(id: TimerId) -> bool {
+    TIMER_WHEEL.write().unwrap().cancel(id)
+}
+
+/// Move time of the stack forward by `dur` tick by tick, e.g. in tests
+/// without `timer_start`
+pub fn timer_advance(dur: Duration) {
+    let ticks = dur.as_nanos().div_ceil(TIMER_TICK.as_nanos()) as u64;
+
+    for _ in 0..ticks {
+        timer_tick();
+    }
+}
+
+fn timer_tick() {
+    let expired = TIMER_WHEEL.write().unwrap().tick();
+
+    for f in expired {
+        f();
+    }
+}
+
+/// Catch the wheel up with the clock of the stack, it's called after each
+/// `TIMER_TICK` by `timer_start` or an event loop
+pub fn timer_run() {
+    let (start, base) = *TIMER_START.read().unwrap();
+    let target = base
+        + (clock_now().duration_since(start).as_nanos()
+            / TIMER_TICK.as_nanos()) as u64;
+
+    loop {
+        let expired = {
+            let mut wheel = TIMER_WHEEL.write().unwrap();
+
+            if wheel.now() >= target {
+                break;
+            }
+
+            wheel.tick()
+        };
+
+        for f in expired {
+            f();
+        }
+    }
+}
+
+/// Claim the wheel for `driver`, it fails if the other kind has claimed it
+/// as the wheel would run twice as fast. Returns if the caller is the
+/// first of its kind, who should drive the wheel.
+pub fn timer_claim(driver: TimerDriver) -> io::Result<bool> {
+    let mut claimed = TIMER_DRIVER.lock().unwrap();
+
+    match *claimed {
+        None => {
+            *claimed = Some(driver);
+            Ok(true)
+        }
+        Some(old) if old == driver => Ok(false),
+        Some(old) => Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            format!("timer wheel is driven by {old:?}"),
+        )),
+    }
+}
+
+/// Drive the wheel in a background thread once
+pub fn timer_start() -> io::Result<()> {
+    if timer_claim(TimerDriver::Thread)? {
+        thread::Builder::new().name("timer".to_owned()).spawn(|| {
+            loop {
+                thread::sleep(TIMER_TICK);
+                timer_run();
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new();
+        let fired = Arc::new(Mutex::new(vec![]));
+        let delays = [1u64, 63, 64, 100, 4095, 4096, 5000, 300_000];
+
+        for ticks in delays {
+            let fired = fired.clone();
+
+            wheel.add(
+                TIMER_TICK * ticks as u32,
+                Box::new(move || fired.lock().unwrap().push(ticks)),
+            );
+        }
+
+        let cancelled = wheel.add(Duration::from_secs(1), Box::new(|| ()));
+
+        assert!(wheel.cancel(cancelled));
+        assert!(!wheel.cancel(cancelled));
+
+        while !wheel.is_empty() {
+            let expired = wheel.tick();
+
+            for f in expired {
+                f();
+                let last = *fired.lock().unwrap().last().unwrap();
+
+                assert_eq!(last, wheel.now());
+            }
+        }
+
+        assert_eq!(*fired.lock().unwrap(), delays);
+        assert_eq!(wheel.now(), 300_000);
+    }
+}
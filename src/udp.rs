@@ -0,0 +1,583 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    ops::RangeInclusive,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+    },
+    task::Waker,
+};
+
+use libc::{EADDRINUSE, EADDRNOTAVAIL, EMSGSIZE, ENETUNREACH};
+use log::trace;
+use m6ptr::LazyStatic;
+use m6tobytes::{as_raw_slice, from_raw_slice};
+use osimodel::network::{icmp::ICMPTypeKind, ip::ProtocolKind};
+
+use crate::{
+    dev::{NetDevice, is_local_addr},
+    icmp::{ICMP_PORT_UNREACH, ICMPErr, icmp_register_err_handler},
+    ip::{ip_output, ip_pseudo_cksum, ip_split},
+    route::ip_route_output,
+    snmp::SNMP_UDP,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant Variables
+
+/// IANA dynamic ports (RFC 6335)
+pub const UDP_EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Datagrams are dropped when receive queue is full
+pub const UDP_RXQ_SZ: usize = 128;
+
+/// Max payload of one datagram over IPv4 without options
+pub const UDP_MAX_PAYLOAD: usize = 65535 - 20 - size_of::<UDP>();
+
+////////////////////////////////////////////////////////////////////////////////
+//// Static Variables
+
+/// Receive queue length of each socket, `UDP_RXQ_SZ` by default
+pub static UDP_RXQ_MAX: AtomicUsize = AtomicUsize::new(UDP_RXQ_SZ);
+
+pub static UDP_TBL: LazyStatic<HashMap<UDPKey, Arc<UDPPcb>>> =
+    LazyStatic::new(|| HashMap::new());
+
+static UDP_PORT_NEXT: AtomicU16 = AtomicU16::new(*UDP_EPHEMERAL_PORTS.start());
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structures
+
+/// UDP header, all fields are in network byte order (RFC 768)
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct UDP {
+    pub src: u16,
+    pub dst: u16,
+    pub len: u16,
+    pub cksum: u16,
+}
+
+/// Unspecified local address is wildcard, `remote` is set after connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UDPKey {
+    pub local: SocketAddrV4,
+    pub remote: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Default)]
+pub struct UDPPcb {
+    /// (source, payload)
+    pub rxq: Mutex<VecDeque<(SocketAddrV4, Vec<u8>)>>,
+    /// Pending errno reported by ICMP
+    pub err: Mutex<Option<i32>>,
+    /// Notified on datagram or error arrival, paired with `rxq`
+    pub rx_cond: Condvar,
+    /// Tasks polling on `rxq`, registered with `rxq` locked
+    pub rx_wakers: Mutex<Vec<Waker>>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementations
+
+impl UDP {
+    pub fn new(src: u16, dst: u16, len: u16) -> Self {
+        Self {
+            src: src.to_be(),
+            dst: dst.to_be(),
+            len: len.to_be(),
+            cksum: 0,
+        }
+    }
+
+    pub fn src_port(&self) -> u16 {
+        u16::from_be(self.src)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        u16::from_be(self.dst)
+    }
+
+    pub fn length(&self) -> u16 {
+        u16::from_be(self.len)
+    }
+}
+
+impl UDPKey {
+    pub fn port(&self) -> u16 {
+        self.local.port()
+    }
+
+    /// Would both of them receive the same datagram
+    fn conflicts(&self, other: &Self) -> bool {
+        self.port() == other.port()
+            && (self.local.ip().is_unspecified()
+                || other.local.ip().is_unspecified()
+                || self.local.ip() == other.local.ip())
+            && self.remote == other.remote
+    }
+}
+
+impl UDPPcb {
+    pub fn take_err(&self) -> Option<io::Error> {
+        self.err
+            .lock()
+            .unwrap()
+            .take()
+            .map(io::Error::from_raw_os_error)
+    }
+
+    pub fn register_rx_waker(&self, waker: &Waker) {
+        let mut wakers = self.rx_wakers.lock().unwrap();
+
+        if !wakers.iter().any(|old| old.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    /// Wake both of blocking and async receivers, call it with `rxq` locked
+    pub fn wake_rx(&self) {
+        self.rx_cond.notify_all();
+
+        for waker in self.rx_wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl NetDevice {
+    /// Return the reason if the datagram is dropped
+    pub fn udp_input(&self, pkt: &[u8]) -> anyhow::Result<Option<String>> {
+        let (iph, seg) = ip_split(pkt);
+        let src: Ipv4Addr = iph.src.into();
+        let dst: Ipv4Addr = iph.dst.into();
+
+        if seg.len() < size_of::<UDP>() {
+            trace!("Uncomplete UDP header from {src}");
+            SNMP_UDP.in_errors.inc();
+            return Ok(Some("uncomplete UDP header".to_owned()));
+        }
+
+        let uh = from_raw_slice::<UDP>(seg);
+        let len = uh.length() as usize;
+
+        if len < size_of::<UDP>() || len > seg.len() {
+            trace!("Malformed UDP length {len} from {src}");
+            SNMP_UDP.in_errors.inc();
+            return Ok(Some(format!("malformed UDP length {len}")));
+        }
+
+        let seg = &seg[..len];
+
+        // zero means sender doesn't compute it
+        if uh.cksum != 0
+            && ip_pseudo_cksum(src, dst, ProtocolKind::UDP, seg) != 0
+        {
+            trace!("UDP verify cksum failed from {src}");
+            SNMP_UDP.in_errors.inc();
+            SNMP_UDP.in_csum_errors.inc();
+            return Ok(Some("bad UDP cksum".to_owned()));
+        }
+
+        let from = SocketAddrV4::new(src, uh.src_port());
+        let to = SocketAddrV4::new(dst, uh.dst_port());
+
+        let Some(pcb) = udp_lookup(to, from)
+        else {
+            trace!("No UDP socket on {to} for {from}");
+            SNMP_UDP.no_ports.inc();
+
+            self.icmp_send(
+                pkt,
+                ICMPTypeKind::DestinationUnreachable,
+                ICMP_PORT_UNREACH,
+                0,
+            )?;
+
+            return Ok(Some(format!("no UDP socket on {to}")));
+        };
+
+        let mut rxq = pcb.rxq.lock().unwrap();
+
+        if rxq.len() >= UDP_RXQ_MAX.load(Ordering::Relaxed) {
+            trace!("UDP receive queue of {to} is full, drop");
+            SNMP_UDP.in_errors.inc();
+            SNMP_UDP.rcvbuf_errors.inc();
+            return Ok(Some(format!("UDP receive queue of {to} is full")));
+        }
+
+        SNMP_UDP.in_datagrams.inc();
+
+        rxq.push_back((from, seg[size_of::<UDP>()..].to_vec()));
+        pcb.wake_rx();
+
+        Ok(None)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+/// Send `data` from `src` to `dst`, unspecified source address is decided
+/// by route
+pub fn udp_output(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    if data.len() > UDP_MAX_PAYLOAD {
+        Err(io::Error::from_raw_os_error(EMSGSIZE))?
+    }
+
+    let srcip = if src.ip().is_unspecified() {
+        let Some(rt) = ip_route_output(*dst.ip())
+        else {
+            Err(io::Error::from_raw_os_error(ENETUNREACH))?
+        };
+
+        rt.dev.ip
+    }
+    else {
+        *src.ip()
+    };
+
+    let len = size_of::<UDP>() + data.len();
+    let mut uh = UDP::new(src.port(), dst.port(), len as u16);
+    let mut seg = Vec::with_capacity(len);
+
+    seg.extend_from_slice(as_raw_slice(&uh));
+    seg.extend_from_slice(data);
+
+    let cksum = ip_pseudo_cksum(srcip, *dst.ip(), ProtocolKind::UDP, &seg);
+
+    // all ones in one's complement, zero is reserved for no checksum
+    uh.cksum = if cksum == 0 { 0xFFFF } else { cksum };
+
+    seg[..size_of::<UDP>()].copy_from_slice(as_raw_slice(&uh));
+
+    trace!("Output UDP {srcip}:{} -> {dst} {len} bytes", src.port());
+
+    ip_output(&seg, ProtocolKind::UDP, Some(srcip), *dst.ip())?;
+
+    SNMP_UDP.out_datagrams.inc();
+
+    Ok(())
+}
+
+/// Most specific socket first: connected, then bound to the address
+pub fn udp_lookup(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+) -> Option<Arc<UDPPcb>> {
+    let tbl = UDP_TBL.read().unwrap();
+    let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local.port());
+
+    [
+        (local, Some(remote)),
+        (any, Some(remote)),
+        (local, None),
+        (any, None),
+    ]
+    .into_iter()
+    .find_map(|(local, remote)| tbl.get(&UDPKey { local, remote }).cloned())
+}
+
+/// Bind on `local`, zero port means an ephemeral one
+pub fn udp_bind(local: SocketAddrV4) -> io::Result<(UDPKey, Arc<UDPPcb>)> {
+    if !local.ip().is_unspecified() && !is_local_addr(*local.ip()) {
+        Err(io::Error::from_raw_os_error(EADDRNOTAVAIL))?
+    }
+
+    let mut tbl = UDP_TBL.write().unwrap();
+
+    let port = if local.port() == 0 {
+        udp_ephemeral_port(&tbl, &UDP_PORT_NEXT)?
+    }
+    else {
+        local.port()
+    };
+
+    let key = UDPKey {
+        local: SocketAddrV4::new(*local.ip(), port),
+        remote: None,
+    };
+
+    if tbl.keys().any(|old| old.conflicts(&key)) {
+        Err(io::Error::from_raw_os_error(EADDRINUSE))?
+    }
+
+    let pcb = Arc::new(UDPPcb::default());
+
+    tbl.insert(key, pcb.clone());
+
+    trace!("UDP bind {}", key.local);
+
+    Ok((key, pcb))
+}
+
+/// Set default destination and filter others, local address is decided by
+/// route if it's unspecified
+pub fn udp_connect(key: UDPKey, remote: SocketAddrV4) -> io::Result<UDPKey> {
+    let mut local = key.local;
+
+    if local.ip().is_unspecified() {
+        let Some(rt) = ip_route_output(*remote.ip())
+        else {
+            Err(io::Error::from_raw_os_error(ENETUNREACH))?
+        };
+
+        local.set_ip(rt.dev.ip);
+    }
+
+    let mut tbl = UDP_TBL.write().unwrap();
+
+    let Some(pcb) = tbl.remove(&key)
+    else {
+        Err(io::Error::from(io::ErrorKind::NotFound))?
+    };
+
+    let newkey = UDPKey {
+        local,
+        remote: Some(remote),
+    };
+
+    if tbl.keys().any(|old| old.conflicts(&newkey)) {
+        tbl.insert(key, pcb);
+
+        return Err(io::Error::from_raw_os_error(EADDRINUSE));
+    }
+
+    tbl.insert(newkey, pcb);
+
+    Ok(newkey)
+}
+
+pub fn udp_unbind(key: &UDPKey) {
+    UDP_TBL.write().unwrap().remove(key);
+}
+
+/// Next free port from `next` on, it wraps around the range
+fn udp_ephemeral_port(
+    tbl: &HashMap<UDPKey, Arc<UDPPcb>>,
+    next: &AtomicU16,
+) -> io::Result<u16> {
+    let start = *UDP_EPHEMERAL_PORTS.start();
+    let n = UDP_EPHEMERAL_PORTS.len() as u16;
+
+    for _ in 0..n {
+        let off = next.fetch_add(1, Ordering::Relaxed).wrapping_sub(start) % n;
+        let port = start + off;
+
+        if !tbl.keys().any(|old| old.port() == port) {
+            return Ok(port);
+        }
+    }
+
+    Err(io::Error::from_raw_os_error(EADDRINUSE))
+}
+
+/// Hook UDP into the lower layers
+pub fn udp_init() {
+    icmp_register_err_handler(ProtocolKind::UDP, udp_err);
+}
+
+/// ICMP error handler, only connected sockets are told like Linux without
+/// `IP_RECVERR`
+pub fn udp_err(err: &ICMPErr) {
+    let uh = from_raw_slice::<UDP>(&err.quote);
+
+    let key = UDPKey {
+        local: SocketAddrV4::new(err.src, uh.src_port()),
+        remote: Some(SocketAddrV4::new(err.dst, uh.dst_port())),
+    };
+
+    let tbl = UDP_TBL.read().unwrap();
+
+    let Some(pcb) = tbl.get(&key).or_else(|| {
+        tbl.get(&UDPKey {
+            local: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, key.port()),
+            ..key
+        })
+    })
+    else {
+        return;
+    };
+
+    trace!("UDP {} got error {}", key.local, err.to_io_error());
+
+    *pcb.err.lock().unwrap() = Some(err.errno);
+
+    // waiters check error with `rxq` locked
+    let _rxq = pcb.rxq.lock().unwrap();
+
+    pcb.wake_rx();
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        dev::tests::{dev_pair, frame_input, frame_output, neigh_add},
+        ip::tests::ipv4_packet,
+    };
+
+    /// UDP segment with checksum from `src` to `dst`
+    pub(crate) fn udp_segment(
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let uh = UDP::new(src.port(), dst.port(), (8 + data.len()) as u16);
+        let mut seg = [as_raw_slice(&uh), data].concat();
+        let cksum =
+            ip_pseudo_cksum(*src.ip(), *dst.ip(), ProtocolKind::UDP, &seg);
+
+        seg[6..8].copy_from_slice(&cksum.to_ne_bytes());
+        seg
+    }
+
+    #[test]
+    fn test_udp_cksum() {
+        let (dev, peer) = dev_pair();
+        let (host, _) = neigh_add(&dev, 2);
+        let src = SocketAddrV4::new(dev.ip, 5353);
+        let dst = SocketAddrV4::new(host, 53);
+
+        udp_output(src, dst, b"hello").unwrap();
+
+        let (_, pkt) = frame_output(&peer).unwrap();
+        let seg = &pkt[20..];
+
+        // one's complement sum of pseudo header and segment by 16 bits
+        let mut sum =
+            [&dev.ip.octets()[..], &host.octets(), &[0, 17, 0, 13], seg]
+                .concat()
+                .chunks(2)
+                .map(|word| {
+                    u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])
+                        as u32
+                })
+                .sum::<u32>();
+
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        assert_eq!(seg[..6], [0x14, 0xe9, 0, 53, 0, 13]);
+        assert_eq!(seg[8..], *b"hello");
+        assert_eq!(sum, 0xFFFF);
+        assert_eq!(ip_pseudo_cksum(dev.ip, host, ProtocolKind::UDP, seg), 0);
+
+        /* bad checksum is dropped, zero means none */
+
+        let (key, _pcb) = udp_bind(SocketAddrV4::new(dev.ip, 0)).unwrap();
+        let from = SocketAddrV4::new(host, 53);
+        let mut seg = udp_segment(from, key.local, b"hello");
+        let input = |seg: &[u8]| {
+            dev.udp_input(&ipv4_packet(ProtocolKind::UDP, host, dev.ip, seg))
+                .unwrap()
+        };
+
+        assert_eq!(input(&seg), None);
+
+        seg[8] ^= 1;
+
+        assert_eq!(input(&seg).as_deref(), Some("bad UDP cksum"));
+
+        seg[6..8].fill(0);
+
+        assert_eq!(input(&seg), None);
+
+        udp_unbind(&key);
+    }
+
+    #[test]
+    fn test_udp_lookup() {
+        let (dev, peer) = dev_pair();
+        let (host, hwa) = neigh_add(&dev, 2);
+        let remote = SocketAddrV4::new(host, 53);
+        let other = SocketAddrV4::new(host, 54);
+
+        // connected first, the wildcard doesn't conflict with it
+        let (key, conn) = udp_bind(SocketAddrV4::new(dev.ip, 0)).unwrap();
+        let key = udp_connect(key, remote).unwrap();
+        let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, key.port());
+        let (any_key, wild) = udp_bind(any).unwrap();
+
+        assert_eq!(
+            udp_bind(any).unwrap_err().raw_os_error(),
+            Some(EADDRINUSE)
+        );
+        assert!(Arc::ptr_eq(&udp_lookup(key.local, remote).unwrap(), &conn));
+        assert!(Arc::ptr_eq(&udp_lookup(key.local, other).unwrap(), &wild));
+
+        for (from, data) in [(remote, b"conn"), (other, b"wild")] {
+            let seg = udp_segment(from, key.local, data);
+            let pkt = ipv4_packet(ProtocolKind::UDP, host, dev.ip, &seg);
+
+            frame_input(&dev, &peer, hwa, dev.hwa, &pkt).unwrap();
+        }
+
+        assert_eq!(
+            conn.rxq.lock().unwrap().pop_front(),
+            Some((remote, b"conn".to_vec()))
+        );
+        assert_eq!(
+            wild.rxq.lock().unwrap().pop_front(),
+            Some((other, b"wild".to_vec()))
+        );
+
+        udp_unbind(&key);
+        udp_unbind(&any_key);
+    }
+
+    #[test]
+    fn test_udp_ephemeral_port() {
+        let (start, end) =
+            (*UDP_EPHEMERAL_PORTS.start(), *UDP_EPHEMERAL_PORTS.end());
+        let next = AtomicU16::new(end);
+        let mut tbl = HashMap::new();
+
+        // it wraps around to the start of the range
+        assert_eq!(udp_ephemeral_port(&tbl, &next).unwrap(), end);
+        assert_eq!(udp_ephemeral_port(&tbl, &next).unwrap(), start);
+
+        // ports in use are skipped
+        for port in [end, start] {
+            let key = UDPKey {
+                local: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port),
+                remote: None,
+            };
+
+            tbl.insert(key, Arc::new(UDPPcb::default()));
+        }
+
+        next.store(end, Ordering::Relaxed);
+
+        assert_eq!(udp_ephemeral_port(&tbl, &next).unwrap(), start + 1);
+    }
+
+    #[test]
+    fn test_udp_port_unreach() {
+        let (dev, peer) = dev_pair();
+        let (host, hwa) = neigh_add(&dev, 2);
+        let seg = udp_segment(
+            SocketAddrV4::new(host, 5353),
+            SocketAddrV4::new(dev.ip, 9),
+            b"discard",
+        );
+        let pkt = ipv4_packet(ProtocolKind::UDP, host, dev.ip, &seg);
+
+        frame_input(&dev, &peer, hwa, dev.hwa, &pkt).unwrap();
+
+        let (ethh, err) = frame_output(&peer).unwrap();
+
+        assert_eq!(ethh.dst, hwa);
+        assert_eq!(err[12..20], [dev.ip.octets(), host.octets()].concat());
+        assert_eq!(err[20..22], [3, ICMP_PORT_UNREACH]);
+        assert_eq!(err[28..], pkt);
+    }
+}